@@ -0,0 +1,36 @@
+#!/bin/sh
+# Summarise a kernel test run from its serial log.
+#
+# Usage: test-results.sh <serial log> <QEMU exit status>
+#
+# The kernel prints "TEST <name> START" before each test and "TEST <name> OK" or
+# "TEST <name> FAIL <message>" after it. QEMU exits with 33 if every test passed, 35 if any
+# failed, and timeout(1) exits with 124 if the run hung.
+
+log=$1
+status=$2
+
+grep '^TEST' "$log"
+
+# The last test which started but never reported a result.
+hung=$(awk '$1 == "TEST" && $3 == "START" { name = $2 }
+            $1 == "TEST" && ($3 == "OK" || $3 == "FAIL") { name = "" }
+            END { print name }' "$log")
+
+case "$status" in
+    33)
+        echo "Tests passed."
+        ;;
+    35)
+        echo "Tests failed."
+        exit 1
+        ;;
+    124)
+        echo "Timed out${hung:+ in $hung}."
+        exit 1
+        ;;
+    *)
+        echo "QEMU exited unexpectedly with status $status${hung:+ during $hung}."
+        exit 1
+        ;;
+esac
@@ -29,13 +29,16 @@ impl Madt {
         let mut isos: StaticVec<&'static InterruptSourceOverride,
                     [&'static InterruptSourceOverride; 10]>
             = StaticVec::new();
+        let mut x2apics: StaticVec<&'static X2ApicEntry, [&'static X2ApicEntry; 20]>
+            = StaticVec::new();
+        let mut lapic_base = self.address as u64;
         
         let mut apic_manager = apic::ApicManager::new();
 
         for entry in self.iter() {
             match entry {
                 MadtEntry::Lapic(local_apic) => {
-                    use x86_64::registers::msr::{rdmsr, IA32_APIC_BASE};
+                    use arch::percpu;
 
                     // Check if this local APIC corresponds to an active application processor.
                     if local_apic.flags & 1 == 1 {
@@ -43,7 +46,8 @@ impl Madt {
                             "[ dev ] Found local APIC, id: {}, processor id: {}",
                             local_apic.id, local_apic.processor_id
                         );
-                        if rdmsr(IA32_APIC_BASE) & (1 << 8) == local_apic.id as u64 {
+                        // The MADT need not list the BSP first, so compare against our own ID.
+                        if local_apic.id as usize == percpu::this_cpu().apic_id {
                             println!("[ dev ] Found the BSP local APIC, id: {}", local_apic.id);
                         } else {
                             CPUS.fetch_add(1, Ordering::SeqCst);
@@ -79,16 +83,43 @@ impl Madt {
                     nmis.push(nmi).expect("Failed to push element to static vector.");
                 }
 
+                MadtEntry::LapicAddressOverride(address_override) => {
+                    lapic_base = address_override.address;
+                    println!("[ dev ] Local APIC address overridden to {:#x}", lapic_base);
+                }
+
+                MadtEntry::X2Apic(x2apic) => {
+                    use arch::percpu;
+
+                    if x2apic.flags & 1 == 1 {
+                        println!(
+                            "[ dev ] Found local x2APIC, id: {}, processor uid: {}",
+                            x2apic.id, x2apic.processor_uid
+                        );
+                        if x2apic.id as usize != percpu::this_cpu().apic_id {
+                            CPUS.fetch_add(1, Ordering::SeqCst);
+                        }
+                    } else {
+                        println!("Found disabled x2APIC core, id: {}", x2apic.id);
+                    }
+
+                    x2apics.push(x2apic).expect("Failed to push element to static vector");
+                }
+
+                MadtEntry::Unknown(ty) => {
+                    println!("[ acpi ] Skipping MADT entry of type {}", ty);
+                }
+
                 _ => {
-                    println!("[ acpi ] No more MADT entries...");
-                    return;
+                    println!("[ acpi ] Skipping malformed MADT entry");
                 }
             }
         }
         
-        apic_manager.lapic_base = self.address;
+        apic_manager.lapic_base = lapic_base;
         
         apic_manager.local_apics = local_apics;
+        apic_manager.x2apics = x2apics;
         apic_manager.io_apics = io_apics;
         apic_manager.nmis = nmis;
         apic_manager.isos = isos;
@@ -163,6 +194,26 @@ pub struct ApicNMI {
     pub lint_no: u8,
 }
 
+/// A 64-bit physical address for the local APICs, which replaces the 32-bit address in the MADT
+/// header.
+#[repr(packed)]
+pub struct LapicAddressOverride {
+    _resv: u16,
+    pub address: u64,
+}
+
+/// A local x2APIC. Processors with APIC IDs of 256 or more can only be described this way.
+#[repr(packed)]
+pub struct X2ApicEntry {
+    _resv: u16,
+    /// The x2APIC ID of this processor.
+    pub id: u32,
+    /// Flags - 1 means that the AP is enabled.
+    pub flags: u32,
+    /// The ACPI processor UID.
+    pub processor_uid: u32,
+}
+
 pub enum MadtEntry {
     Lapic(&'static LapicEntry),
     InvalidLapic(usize),
@@ -172,6 +223,11 @@ pub enum MadtEntry {
     InvalidIso(usize),
     Nmi(&'static ApicNMI),
     InvalidNmi(usize),
+    LapicAddressOverride(&'static LapicAddressOverride),
+    InvalidLapicAddressOverride(usize),
+    X2Apic(&'static X2ApicEntry),
+    InvalidX2Apic(usize),
+    /// An entry of a type we do not use, which is skipped over by its length.
     Unknown(u8),
 }
 
@@ -188,6 +244,12 @@ impl Iterator for MadtIter {
             let len = unsafe { *(self.sdt.data_address() as *const u8).offset(self.i as isize + 1) }
                 as usize;
 
+            // Every entry is at least its type and length. Anything shorter would never advance, so
+            // the rest of the table cannot be trusted.
+            if len < 2 {
+                return None;
+            }
+
             if self.i + len <= self.sdt.data_len() {
                 let item = match ty {
                     0 => if len == mem::size_of::<LapicEntry>() + 2 {
@@ -219,7 +281,22 @@ impl Iterator for MadtIter {
                         })
                     } else {
                         MadtEntry::InvalidNmi(len)
-                    },                   
+                    },
+                    5 => if len == mem::size_of::<LapicAddressOverride>() + 2 {
+                        MadtEntry::LapicAddressOverride(unsafe {
+                            &*((self.sdt.data_address() + self.i + 2)
+                                as *const LapicAddressOverride)
+                        })
+                    } else {
+                        MadtEntry::InvalidLapicAddressOverride(len)
+                    },
+                    9 => if len == mem::size_of::<X2ApicEntry>() + 2 {
+                        MadtEntry::X2Apic(unsafe {
+                            &*((self.sdt.data_address() + self.i + 2) as *const X2ApicEntry)
+                        })
+                    } else {
+                        MadtEntry::InvalidX2Apic(len)
+                    },
                     _ => MadtEntry::Unknown(ty),
                 };
 
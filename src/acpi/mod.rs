@@ -2,6 +2,7 @@ use arch::memory::paging::{ActivePageTable, Page, PhysicalAddress, VirtualAddres
 use arch::memory::Frame;
 use arch::memory::paging::entry::EntryFlags;
 use core::mem;
+use multiboot2::BootInformation;
 
 pub mod rsdp;
 pub mod sdt;
@@ -10,7 +11,13 @@ pub mod xsdt;
 pub mod madt;
 
 /// Retrieve an SDT from a pointer found using the RSDP
-fn get_sdt(address: usize, active_table: &mut ActivePageTable) -> &'static sdt::SdtHeader {
+fn get_sdt(
+    address: PhysicalAddress,
+    active_table: &mut ActivePageTable,
+) -> &'static sdt::SdtHeader {
+    // ACPI tables are identity mapped.
+    let address = address.get();
+
     {
         let page = Page::containing_address(VirtualAddress::new(address));
         if active_table.translate_page(page).is_none() {
@@ -42,29 +49,47 @@ fn get_sdt(address: usize, active_table: &mut ActivePageTable) -> &'static sdt::
     sdt
 }
 
-pub unsafe fn init(active_table: &mut ActivePageTable) {
-    let rsdp = rsdp::RsdpDescriptor::init(active_table).expect("Could not find rsdp, aborting ...");
+pub unsafe fn init(boot_info: &BootInformation, active_table: &mut ActivePageTable) {
+    let rsdp = rsdp::RsdpDescriptor::init(boot_info, active_table)
+        .expect("Could not find rsdp, aborting ...");
     let sdt = get_sdt(rsdp.sdt(), active_table);
-    let rsdt = rsdt::Rsdt::new(sdt);
 
-    println!(
-        "[ apci ] Found RSDT at address {:#x}",
-        rsdt.sdt as *const sdt::SdtHeader as usize
-    );
+    // ACPI 2.0 and later point to an XSDT, whose entries are 64-bit.
+    let madt = match xsdt::Xsdt::new(sdt) {
+        Some(xsdt) => {
+            println!(
+                "[ acpi ] Found XSDT at address {:#x}, pointing to {} tables",
+                xsdt.0 as *const sdt::SdtHeader as usize,
+                xsdt.len()
+            );
+
+            xsdt.find_sdt(b"APIC")
+        }
+        None => {
+            let rsdt = rsdt::Rsdt::new(sdt);
+
+            println!(
+                "[ apci ] Found RSDT at address {:#x}",
+                rsdt.sdt as *const sdt::SdtHeader as usize
+            );
+
+            println!(
+                "[ acpi ] RSDT length {}, data length {}",
+                rsdt.sdt.length,
+                rsdt.sdt.length as usize - mem::size_of::<sdt::SdtHeader>()
+            );
 
-    println!(
-        "[ acpi ] RSDT length {}, data length {}",
-        rsdt.sdt.length,
-        rsdt.sdt.length as usize - mem::size_of::<sdt::SdtHeader>()
-    );
+            println!(
+                "[ acpi ] RSDT points to {} tables",
+                rsdt.other_entries.len()
+            );
 
-    println!(
-        "[ acpi ] RSDT points to {} tables",
-        rsdt.other_entries.len()
-    );
+            rsdt.find_sdt(b"APIC")
+        }
+    };
 
     // let mut madt: madt::Madt = unsafe { *(&*(0 as *const madt::Madt)) };
-    match rsdt.find_sdt(b"APIC") {
+    match madt {
         Some(rsdt::TableType::Madt(mut m)) => {
             println!(
                 "[ apci ] Found MADT at address {:#x}",
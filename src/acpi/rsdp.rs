@@ -2,6 +2,12 @@ use arch::memory::Frame;
 use arch::memory::paging::{Page, PhysicalAddress, VirtualAddress};
 use arch::memory::paging::ActivePageTable;
 use arch::memory::paging::entry::EntryFlags;
+use arch::multiboot::{self, TAG_ACPI_NEW, TAG_ACPI_OLD};
+use core::{cmp, mem, ptr, slice};
+use multiboot2::BootInformation;
+
+/// Size of the part of the RSDP defined by ACPI 1.0, which the checksum covers.
+const RSDP_V1_LEN: usize = 20;
 
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
@@ -18,8 +24,26 @@ pub struct RsdpDescriptor {
 }
 
 impl RsdpDescriptor {
+    /// Find the RSDP. The copy in the multiboot ACPI tags is preferred, the new (ACPI 2.0) one
+    /// first, and the BIOS area is only searched if the bootloader gave us neither.
+    pub fn init(boot_info: &BootInformation, active_table: &mut ActivePageTable) -> Option<Self> {
+        for &typ in &[TAG_ACPI_NEW, TAG_ACPI_OLD] {
+            if let Some(tag) = multiboot::find_tag(boot_info, typ) {
+                match RsdpDescriptor::from_bytes(tag.data) {
+                    Some(rsdp) => {
+                        println!("[ acpi ] Found RSDP in multiboot tag {}.", typ);
+                        return Some(rsdp);
+                    }
+                    None => println!("[ acpi ] Ignoring invalid RSDP in multiboot tag {}.", typ),
+                }
+            }
+        }
+
+        RsdpDescriptor::scan(active_table)
+    }
+
     /// Map RSDP address space, search for RSDP.
-    pub fn init(active_table: &mut ActivePageTable) -> Option<Self> {
+    fn scan(active_table: &mut ActivePageTable) -> Option<Self> {
         // TODO: Search in EBDA as well.
 
         let rsdp_start: usize = 0xe0000;
@@ -46,25 +70,65 @@ impl RsdpDescriptor {
     /// Find and parse the RSDP.
     fn search(start_addr: usize, end_addr: usize) -> Option<RsdpDescriptor> {
         for i in 0..(end_addr + 1 - start_addr) / 16 {
-            let rsdp = unsafe { &*((start_addr + i * 16) as *const RsdpDescriptor) };
-            if &rsdp.signature == b"RSD PTR " {
-                println!(
-                    "[ acpi ] Found RSDP at {:#x}",
-                    rsdp as *const RsdpDescriptor as usize
-                );
-                return Some(*rsdp);
+            let address = start_addr + i * 16;
+            // Don't read past the end of the mapped range.
+            let len = cmp::min(mem::size_of::<RsdpDescriptor>(), end_addr + 1 - address);
+            let bytes = unsafe { slice::from_raw_parts(address as *const u8, len) };
+
+            if let Some(rsdp) = RsdpDescriptor::from_bytes(bytes) {
+                println!("[ acpi ] Found RSDP at {:#x}", address);
+                return Some(rsdp);
             }
         }
 
         None
     }
 
+    /// Parse an RSDP from `bytes`, which may hold just the ACPI 1.0 part. Returns `None` if the
+    /// signature or a checksum is wrong.
+    fn from_bytes(bytes: &[u8]) -> Option<RsdpDescriptor> {
+        if bytes.len() < RSDP_V1_LEN || &bytes[..8] != b"RSD PTR " {
+            return None;
+        }
+
+        // Fields missing from `bytes` are left zeroed.
+        let mut rsdp: RsdpDescriptor = unsafe { mem::zeroed() };
+        let len = cmp::min(bytes.len(), mem::size_of::<RsdpDescriptor>());
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), &mut rsdp as *mut _ as *mut u8, len);
+        }
+
+        if checksum(&bytes[..RSDP_V1_LEN]) != 0 {
+            return None;
+        }
+
+        // The extended checksum covers the whole structure, whose length is only present from
+        // revision 2 on.
+        if rsdp.revision >= 2 {
+            let length = rsdp.length as usize;
+            if length < mem::size_of::<RsdpDescriptor>() || length > bytes.len() {
+                return None;
+            }
+
+            if checksum(&bytes[..length]) != 0 {
+                return None;
+            }
+        }
+
+        Some(rsdp)
+    }
+
     /// Dependent on ACPI version, return the address of the XSDT/RSDT.
-    pub fn sdt(&self) -> usize {
+    pub fn sdt(&self) -> PhysicalAddress {
         if self.revision >= 2 {
-            self.xsdt_address as usize
+            PhysicalAddress::new(self.xsdt_address as usize)
         } else {
-            self.rsdt_address as usize
+            PhysicalAddress::new(self.rsdt_address as usize)
         }
     }
 }
+
+/// Sum `bytes`, wrapping. ACPI structures are valid if this is zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
@@ -1,7 +1,10 @@
+use super::madt::Madt;
+use super::rsdt::TableType;
 use super::sdt::SdtHeader;
+use core::ptr;
 
 #[derive(Debug)]
-pub struct Xsdt(&'static SdtHeader);
+pub struct Xsdt(pub &'static SdtHeader);
 
 impl Xsdt {
     pub fn new(sdt: &'static SdtHeader) -> Option<Xsdt> {
@@ -10,4 +13,37 @@ impl Xsdt {
             _ => None,
         }
     }
+
+    /// Return the number of tables the XSDT points to.
+    pub fn len(&self) -> usize {
+        self.0.data_len() / 8
+    }
+
+    /// Return the address of the `index`th table. The XSDT's entries are 64 bits wide but only
+    /// 4-byte aligned, so they are read unaligned.
+    fn entry(&self, index: usize) -> usize {
+        let entries = self.0.data_address() as *const u64;
+        unsafe { ptr::read_unaligned(entries.offset(index as isize)) as usize }
+    }
+
+    /// Retrieve a pointed-to table using a byte signature.
+    pub fn find_sdt(&self, signature: &[u8]) -> Option<TableType> {
+        for i in 0..self.len() {
+            let sdt = unsafe { &*(self.entry(i) as *const SdtHeader) };
+
+            let sig: &[u8] = &sdt.signature;
+
+            if sig != signature {
+                continue;
+            } else {
+                match signature {
+                    // TODO: Support more tables.
+                    b"APIC" => return Some(TableType::Madt(Madt::new(sdt))),
+                    _ => return None,
+                }
+            }
+        }
+
+        None
+    }
 }
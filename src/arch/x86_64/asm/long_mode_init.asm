@@ -12,6 +12,9 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
+    ; a zero frame pointer ends backtraces
+    xor rbp, rbp
+
     ; call rust main (with multiboot pointer in rdi)
     call kmain
 .os_returned:
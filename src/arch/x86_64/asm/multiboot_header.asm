@@ -8,6 +8,17 @@ header_start:
 
     ; insert optional multiboot tags here
 
+    ; ask for a linear framebuffer, with no preferred mode. Optional, so text mode still boots.
+    dw 5    ; type
+    dw 1    ; flags (optional)
+    dd 20   ; size
+    dd 0    ; width
+    dd 0    ; height
+    dd 32   ; depth
+
+    ; tags are 8-byte aligned
+    align 8
+
     ; required end tag
     dw 0    ; type
     dw 0    ; flags
@@ -0,0 +1,44 @@
+global probe_call
+global probe_landing
+
+section .text
+bits 64
+
+; u64 probe_call(Catcher *catcher, void (*f)(u8 *), u8 *data)
+;
+; Save the callee-saved registers and the stack pointer in `catcher`, then call `f(data)`. Returns
+; 0 when `f` returns, or 1 when a fault caught by `catcher` resumed at `probe_landing` instead.
+; The offsets must match `Catcher` in interrupts/probe.rs.
+probe_call:
+    mov [rdi], rbx
+    mov [rdi + 8], rbp
+    mov [rdi + 16], r12
+    mov [rdi + 24], r13
+    mov [rdi + 32], r14
+    mov [rdi + 40], r15
+    mov [rdi + 48], rsp
+
+    ; keep the stack 16-byte aligned across the call
+    sub rsp, 8
+    mov rax, rsi
+    mov rdi, rdx
+    call rax
+    add rsp, 8
+
+    xor eax, eax
+    ret
+
+; The exception handler resumes here after a caught fault, with gs:[72] pointing at the catcher
+; which caught it. Unwind to the state saved by `probe_call` and return 1 from it.
+probe_landing:
+    mov rax, [gs:72]
+    mov rbx, [rax]
+    mov rbp, [rax + 8]
+    mov r12, [rax + 16]
+    mov r13, [rax + 24]
+    mov r14, [rax + 32]
+    mov r15, [rax + 40]
+    mov rsp, [rax + 48]
+
+    mov eax, 1
+    ret
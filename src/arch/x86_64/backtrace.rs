@@ -0,0 +1,60 @@
+//! Walking the chain of saved frame pointers to find the return addresses on the stack.
+//!
+//! The target spec keeps frame pointers, so each frame starts with the caller's RBP followed by
+//! the return address. The chain ends at a zero RBP, which `long_mode_start` and new tasks start
+//! with. A frame pointer which is unmapped or does not move up the stack also ends the walk, so
+//! that a corrupt stack prints a short backtrace rather than faulting again.
+
+use arch::memory::paging::{ActivePageTable, Page, VirtualAddress};
+use arch::symbols;
+use core::fmt::{self, Write};
+use core::mem;
+
+/// Most frames printed, in case the chain loops.
+const MAX_FRAMES: usize = 64;
+
+/// Write the return addresses of the current call stack to `out`, one per line, with the function
+/// each one is in.
+pub fn write<W: Write>(out: &mut W) -> fmt::Result {
+    let mut rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) : : : "intel", "volatile") };
+
+    // Only the page tables are read, so no lock is needed.
+    let active_table = unsafe { ActivePageTable::new() };
+    let mapped = |address: usize| {
+        active_table.is_mapped(Page::containing_address(VirtualAddress::new(address)))
+    };
+    // The saved RBP and the return address may sit either side of a page boundary.
+    let readable = |address: usize| {
+        address % mem::size_of::<usize>() == 0 && mapped(address) && mapped(address + 8)
+    };
+
+    writeln!(out, "Backtrace:")?;
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0 || !readable(rbp) {
+            break;
+        }
+
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const usize;
+            (*frame, *frame.offset(1))
+        };
+        if return_address == 0 {
+            break;
+        }
+
+        match symbols::resolve(return_address) {
+            Some((name, offset)) => {
+                writeln!(out, "  {:2}: {:#018x} {}+{:#x}", depth, return_address, name, offset)?
+            }
+            None => writeln!(out, "  {:2}: {:#018x}", depth, return_address)?,
+        }
+
+        if caller_rbp <= rbp {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+//! The kernel command line. Options are separated by whitespace and are either bare flags, such as
+//! `quiet`, or `key=value` pairs, such as `kbd=dvorak`.
+
+use alloc::String;
+use multiboot2::BootInformation;
+use spin::Once;
+use super::multiboot;
+
+static CMDLINE: Once<String> = Once::new();
+
+/// Save the command line passed by the bootloader. This must be called after the heap is set up.
+pub fn init(boot_info: &BootInformation) {
+    let line = CMDLINE.call_once(|| String::from(multiboot::command_line(boot_info).unwrap_or("")));
+
+    println!("[ boot ] Command line: \"{}\".", line);
+}
+
+/// Return the whole command line, or an empty string if `init` has not run.
+pub fn get() -> &'static str {
+    CMDLINE.try().map(|line| line.as_str()).unwrap_or("")
+}
+
+/// Return the value of the option `key=value`, if it was given.
+pub fn option(key: &str) -> Option<&'static str> {
+    get().split_whitespace().filter_map(|opt| {
+        let mut parts = opt.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(value)) if k == key => Some(value),
+            _ => None,
+        }
+    }).next()
+}
+
+/// Return whether the bare flag `name` was given.
+pub fn flag(name: &str) -> bool {
+    get().split_whitespace().any(|opt| opt == name)
+}
@@ -0,0 +1,177 @@
+//! CPU identification through the `cpuid` instruction, by way of `raw_cpuid`, which checks the
+//! highest supported leaf before each query. Only the hypervisor leaves, which `raw_cpuid` does not
+//! cover, are read directly.
+
+use alloc::String;
+use core::str;
+use raw_cpuid::CpuId;
+use raw_cpuid::native_cpuid::cpuid_count;
+
+const LEAF_FEATURES: u32 = 1;
+const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+
+bitflags! {
+    pub struct CpuFeatures: u32 {
+        /// Streaming SIMD extensions.
+        const SSE =         1 << 0;
+        const SSE2 =        1 << 1;
+        /// Advanced vector extensions.
+        const AVX =         1 << 2;
+        /// An on-chip local APIC.
+        const APIC =        1 << 3;
+        /// The local APIC supports x2APIC mode.
+        const X2APIC =      1 << 4;
+        /// The no-execute page table bit.
+        const NX =          1 << 5;
+        /// Physical address extension.
+        const PAE =         1 << 6;
+        /// Process-context identifiers.
+        const PCID =        1 << 7;
+        /// The `rdfsbase` family of instructions.
+        const FSGSBASE =    1 << 8;
+        /// The time stamp counter.
+        const TSC =         1 << 9;
+        /// The TSC runs at a constant rate, whatever the CPU frequency.
+        const INVARIANT_TSC = 1 << 10;
+        /// We are running under a hypervisor.
+        const HYPERVISOR =  1 << 11;
+        /// 1 GiB pages.
+        const PAGE_1GB =    1 << 12;
+        /// Supervisor mode execution prevention.
+        const SMEP =        1 << 13;
+        /// Supervisor mode access prevention.
+        const SMAP =        1 << 14;
+    }
+}
+
+/// Return the vendor string, such as `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor() -> [u8; 12] {
+    let mut vendor = [0; 12];
+
+    if let Some(info) = CpuId::new().get_vendor_info() {
+        let bytes = info.as_string().as_bytes();
+        let len = bytes.len().min(vendor.len());
+        vendor[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    vendor
+}
+
+/// Return whether we are running under a hypervisor, going by bit 31 of ECX in leaf 1, which is
+/// reserved for this.
+fn under_hypervisor() -> bool {
+    cpuid_count(LEAF_FEATURES, 0).ecx & (1 << 31) != 0
+}
+
+/// Return the hypervisor's vendor string, such as `KVMKVMKVM\0\0\0`, or `None` if we are not
+/// running under a hypervisor. Hypervisor leaves are not covered by the basic maximum, so this
+/// relies on the hypervisor feature bit instead.
+pub fn hypervisor_vendor() -> Option<[u8; 12]> {
+    if !under_hypervisor() {
+        return None;
+    }
+
+    let result = cpuid_count(LEAF_HYPERVISOR, 0);
+    let mut vendor = [0; 12];
+
+    // Unlike the CPU vendor, this is stored in EBX, ECX, EDX order.
+    for (i, register) in [result.ebx, result.ecx, result.edx].iter().enumerate() {
+        for byte in 0..4 {
+            vendor[i * 4 + byte] = (register >> (byte * 8)) as u8;
+        }
+    }
+
+    Some(vendor)
+}
+
+/// The CPU's architectural performance monitoring, as described by leaf `0xa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perfmon {
+    pub version: u8,
+    /// Number of fixed-function counters. Only version 2 and later report these.
+    pub fixed_counters: u8,
+    /// Width in bits of the fixed-function counters.
+    pub fixed_counter_width: u8,
+}
+
+/// Return the CPU's architectural performance monitoring, or `None` if it has none. AMD CPUs have
+/// performance counters of their own, which are not described here.
+pub fn perfmon() -> Option<Perfmon> {
+    let info = CpuId::new().get_performance_monitoring_info()?;
+    if info.version_id() == 0 {
+        return None;
+    }
+
+    Some(Perfmon {
+        version: info.version_id(),
+        fixed_counters: info.fixed_function_counters(),
+        fixed_counter_width: info.fixed_function_counters_bit_width(),
+    })
+}
+
+/// Return the processor brand string, or `None` if the CPU does not have one.
+pub fn brand_string() -> Option<String> {
+    let info = CpuId::new().get_extended_function_info()?;
+
+    // The string is NUL-terminated, and often padded with leading spaces.
+    info.processor_brand_string()
+        .map(|brand| String::from(brand.split('\0').next().unwrap_or("").trim()))
+}
+
+/// Return the features this kernel cares about.
+pub fn features() -> CpuFeatures {
+    let cpu_id = CpuId::new();
+    let mut features = CpuFeatures::empty();
+
+    if let Some(info) = cpu_id.get_feature_info() {
+        features.set(CpuFeatures::SSE, info.has_sse());
+        features.set(CpuFeatures::SSE2, info.has_sse2());
+        features.set(CpuFeatures::APIC, info.has_apic());
+        features.set(CpuFeatures::PAE, info.has_pae());
+        features.set(CpuFeatures::TSC, info.has_tsc());
+        features.set(CpuFeatures::AVX, info.has_avx());
+        features.set(CpuFeatures::X2APIC, info.has_x2apic());
+        features.set(CpuFeatures::PCID, info.has_pcid());
+    }
+    features.set(CpuFeatures::HYPERVISOR, under_hypervisor());
+
+    if let Some(info) = cpu_id.get_extended_feature_info() {
+        features.set(CpuFeatures::FSGSBASE, info.has_fsgsbase());
+        features.set(CpuFeatures::SMEP, info.has_smep());
+        features.set(CpuFeatures::SMAP, info.has_smap());
+    }
+
+    if let Some(info) = cpu_id.get_extended_function_info() {
+        features.set(CpuFeatures::NX, info.has_execute_disable());
+        features.set(CpuFeatures::PAGE_1GB, info.has_1gib_pages());
+        features.set(CpuFeatures::INVARIANT_TSC, info.has_invariant_tsc());
+    }
+
+    features
+}
+
+/// Print the vendor, brand string and features, and register the `cpuinfo` command which prints
+/// them again. This must be called after the shell is set up.
+pub fn init() {
+    print_banner();
+
+    ::shell::register("cpuinfo", "Show the CPU's vendor, brand string and features.", cpuinfo)
+        .expect("cpuinfo registered twice");
+}
+
+fn cpuinfo(_args: &[&str]) -> i32 {
+    print_banner();
+    0
+}
+
+/// Print the vendor, brand string and features. This must be called after the heap is set up.
+pub fn print_banner() {
+    let vendor = vendor();
+
+    println!(
+        "[ cpu ] {} ({}).",
+        brand_string().as_ref().map_or("Unknown processor", |s| s.as_str()),
+        str::from_utf8(&vendor).unwrap_or("unknown vendor")
+    );
+    println!("[ cpu ] Features: {:?}.", features());
+}
@@ -0,0 +1,367 @@
+//! A small interactive debugger, entered on `int3` when the kernel is booted with the `debugger`
+//! command line flag.
+//!
+//! The breakpoint may have been hit while any lock in the kernel was held, including the serial,
+//! keyboard and scheduler locks. The debugger therefore takes no locks at all: it writes to COM1
+//! through `RawSerial`, and reads input by polling COM1 and the 8042 directly. Keyboard input is
+//! always decoded with the US layout, since looking up the current layout would take a lock.
+//!
+//! The `step` command sets the trap flag in the interrupted code's RFLAGS, so the CPU raises a
+//! debug exception after executing one instruction, and the debug exception handler re-enters the
+//! prompt. Interrupts are masked in the stepped code while stepping, so that a timer tick does not
+//! step us into an interrupt handler or the scheduler. `continue` clears the trap flag and restores
+//! the interrupt flag.
+
+use arch::cmdline;
+use arch::interrupts::set_frame_flags;
+use arch::memory;
+use arch::memory::paging::{dump_mappings, ActivePageTable, VirtualAddress};
+use arch::watchpoint;
+use core::fmt::Write;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use core::{ptr, slice, str};
+use device::io::Port;
+use device::keyboard::layout::us_std::US;
+use device::keyboard::{KeyCode, KeyState, ScancodeSet};
+use device::keyboard::scancode::Decoder;
+use device::serial::RawSerial;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether we set the trap flag in the interrupted code.
+static STEPPING: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether interrupts were enabled in the interrupted code before we started stepping.
+static SAVED_IF: AtomicBool = ATOMIC_BOOL_INIT;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INTERRUPT_FLAG: u64 = 1 << 9;
+/// Suppresses instruction breakpoints for the next instruction, so that `iretq` back to one which
+/// hit an execution watchpoint runs it.
+const RESUME_FLAG: u64 = 1 << 16;
+/// DR6: the debug exception was caused by single-stepping.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+macro_rules! out {
+    ($($arg:tt)*) => ({
+        let _ = write!(RawSerial, $($arg)*);
+    });
+}
+
+macro_rules! outln {
+    ($fmt:expr) => (out!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (out!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Enable the debugger if it was asked for on the command line.
+pub fn init() {
+    if cmdline::flag("debugger") {
+        ENABLED.store(true, Ordering::SeqCst);
+        println!("[ debug ] Debugger enabled, int3 will enter it.");
+    }
+}
+
+/// Return whether breakpoints enter the debugger.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Polled input from COM1 and the PS/2 keyboard.
+struct Input {
+    decoder: Decoder,
+    shift: bool,
+    status: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Input {
+    fn new() -> Self {
+        Input {
+            // Keyboards almost always reach us as set 1, since the 8042 translates by default.
+            decoder: Decoder::new(ScancodeSet::Set1),
+            shift: false,
+            status: unsafe { Port::new(0x64) },
+            data: unsafe { Port::new(0x60) },
+        }
+    }
+
+    /// Poll the keyboard once, returning a character if a key press completed.
+    fn poll_keyboard(&mut self) -> Option<u8> {
+        let status = self.status.read();
+
+        // Nothing waiting, or the byte is from the mouse.
+        if status & 0x1 == 0 || status & 0x20 != 0 {
+            return None;
+        }
+
+        let (code, state) = self.decoder.feed(self.data.read())?;
+        let pressed = state == KeyState::Pressed;
+
+        match code {
+            KeyCode::LeftShift | KeyCode::RightShift => {
+                self.shift = pressed;
+                None
+            }
+            _ if !pressed => None,
+            KeyCode::Enter | KeyCode::KeypadEnter => Some(b'\n'),
+            KeyCode::Backspace => Some(0x8),
+            KeyCode::Space => Some(b' '),
+            _ => US.map(code, self.shift, false)
+                .and_then(|c| if c.is_ascii() { Some(c as u8) } else { None }),
+        }
+    }
+
+    /// Wait for a byte from either serial or the keyboard.
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = RawSerial.try_read() {
+                return if byte == b'\r' { b'\n' } else { byte };
+            }
+
+            if let Some(byte) = self.poll_keyboard() {
+                return byte;
+            }
+
+            spin_loop_hint();
+        }
+    }
+
+    /// Read a line into `buffer`, echoing it, and return it.
+    fn read_line<'a>(&mut self, buffer: &'a mut [u8]) -> &'a str {
+        let mut len = 0;
+
+        loop {
+            match self.read_byte() {
+                b'\n' => {
+                    outln!("");
+                    break;
+                }
+                0x8 | 0x7f => if len > 0 {
+                    len -= 1;
+                    out!("\x08 \x08");
+                },
+                byte if len < buffer.len() && byte >= 0x20 && byte < 0x7f => {
+                    buffer[len] = byte;
+                    len += 1;
+                    out!("{}", byte as char);
+                }
+                _ => (),
+            }
+        }
+
+        str::from_utf8(&buffer[..len]).unwrap_or("")
+    }
+}
+
+/// What the debugger should do once the prompt exits.
+enum Resume {
+    Continue,
+    Step,
+}
+
+/// Enter the debugger. Returns when the user asks to continue or step, at which point the
+/// interrupted code resumes from `stack_frame`.
+pub fn enter(stack_frame: &mut ExceptionStackFrame) {
+    outln!("\n[ debug ] Breakpoint at {:#x}.", stack_frame.instruction_pointer.0);
+    run(stack_frame);
+}
+
+/// Called by the debug exception handler. Reports watchpoint hits, and re-enters the prompt after a
+/// single step we asked for, or after a watchpoint hit if the debugger is enabled. Returns true if
+/// the exception was handled, in which case the handler should return.
+pub fn debug_exception(stack_frame: &mut ExceptionStackFrame) -> bool {
+    let dr6 = watchpoint::read_dr6();
+    let stepped = STEPPING.load(Ordering::SeqCst) && dr6 & DR6_SINGLE_STEP != 0;
+    let watch_hit = watchpoint::hit(dr6);
+
+    // DR6 is never cleared by the CPU.
+    unsafe { watchpoint::write_dr6(dr6 & !DR6_SINGLE_STEP) };
+    watchpoint::clear_hits();
+
+    if let Some(index) = watch_hit {
+        outln!(
+            "\n[ debug ] Watchpoint {} on {:#x} hit, next instruction at {:#x}.",
+            index,
+            watchpoint::watchpoint_address(index).map_or(0, |a| a.get()),
+            stack_frame.instruction_pointer.0
+        );
+
+        // An execution watchpoint faults before the instruction runs, and would fault again on
+        // return without this.
+        if watchpoint::watchpoint_kind(index) == Some(watchpoint::WatchKind::Execute) {
+            let flags = stack_frame.cpu_flags | RESUME_FLAG;
+            set_frame_flags(stack_frame, flags);
+        }
+    }
+
+    if stepped {
+        outln!("[ debug ] Stepped to {:#x}.", stack_frame.instruction_pointer.0);
+    }
+
+    if stepped || (watch_hit.is_some() && is_enabled()) {
+        run(stack_frame);
+    }
+
+    stepped || watch_hit.is_some()
+}
+
+fn run(stack_frame: &mut ExceptionStackFrame) {
+    let flags = stack_frame.cpu_flags;
+
+    let flags = match prompt(stack_frame) {
+        Resume::Continue => {
+            outln!("[ debug ] Continuing.");
+
+            if STEPPING.swap(false, Ordering::SeqCst) {
+                let restored = if SAVED_IF.load(Ordering::SeqCst) {
+                    INTERRUPT_FLAG
+                } else {
+                    0
+                };
+                (flags & !TRAP_FLAG) | restored
+            } else {
+                flags
+            }
+        }
+        Resume::Step => {
+            if !STEPPING.swap(true, Ordering::SeqCst) {
+                SAVED_IF.store(flags & INTERRUPT_FLAG != 0, Ordering::SeqCst);
+            }
+
+            (flags | TRAP_FLAG) & !INTERRUPT_FLAG
+        }
+    };
+
+    set_frame_flags(stack_frame, flags);
+}
+
+fn prompt(stack_frame: &mut ExceptionStackFrame) -> Resume {
+    let mut input = Input::new();
+    let mut buffer = [0u8; 80];
+
+    loop {
+        out!("(debug) ");
+        let line = input.read_line(&mut buffer);
+        let mut words = line.split_whitespace();
+
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => (),
+            (Some("c"), _, _) | (Some("continue"), _, _) => return Resume::Continue,
+            (Some("s"), _, _) | (Some("step"), _, _) => return Resume::Step,
+            (Some("r"), _, _) | (Some("regs"), _, _) => print_registers(stack_frame),
+            (Some("x"), Some(address), count) => match (parse(address), count.map(parse)) {
+                (Some(address), None) => examine(address, 1),
+                (Some(address), Some(Some(count))) => examine(address, count),
+                _ => outln!("usage: x <address> [count]"),
+            },
+            (Some("db"), Some(address), count) => match (parse(address), count.map(parse)) {
+                (Some(address), None) => dump_bytes(address, 16),
+                (Some(address), Some(Some(count))) => dump_bytes(address, count),
+                _ => outln!("usage: db <address> [count]"),
+            },
+            (Some("w"), Some(address), Some(value)) => match (parse(address), parse(value)) {
+                (Some(address), Some(value)) => write(address, value as u64),
+                _ => outln!("usage: w <address> <value>"),
+            },
+            (Some("maps"), _, _) | (Some("m"), _, _) => print_mappings(),
+            (Some("help"), _, _) | (Some("h"), _, _) => print_help(),
+            _ => outln!("Unknown command, try `help`."),
+        }
+    }
+}
+
+fn print_help() {
+    outln!("regs, r              Print the interrupted code's registers.");
+    outln!("x <address> [count]  Print `count` quadwords starting at `address`.");
+    outln!("db <address> [count] Print `count` bytes starting at `address`, with ASCII.");
+    outln!("w <address> <value>  Write a quadword to `address`.");
+    outln!("maps, m              Print the mappings of the active page table.");
+    outln!("step, s              Execute one instruction and return to the prompt.");
+    outln!("continue, c          Resume execution.");
+    outln!("Numbers are hexadecimal, with or without a leading 0x.");
+}
+
+fn print_mappings() {
+    // The page tables are only read, so a second handle on the active table is harmless.
+    let active_table = unsafe { ActivePageTable::new() };
+    let _ = dump_mappings(&active_table, &mut RawSerial);
+}
+
+fn print_registers(stack_frame: &ExceptionStackFrame) {
+    outln!("rip    {:#018x}", stack_frame.instruction_pointer.0);
+    outln!("cs     {:#018x}", stack_frame.code_segment);
+    outln!("rflags {:#018x}", stack_frame.cpu_flags);
+    outln!("rsp    {:#018x}", stack_frame.stack_pointer.0);
+    outln!("ss     {:#018x}", stack_frame.stack_segment);
+}
+
+fn parse(number: &str) -> Option<usize> {
+    let digits = if number.starts_with("0x") {
+        &number[2..]
+    } else {
+        number
+    };
+
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn virtual_address(address: usize) -> Result<VirtualAddress, &'static str> {
+    VirtualAddress::try_new(address).ok_or("non-canonical address")
+}
+
+fn examine(address: usize, count: usize) {
+    for i in 0..count {
+        let current = match address.checked_add(i * 8) {
+            Some(current) => current,
+            None => return,
+        };
+
+        match virtual_address(current).and_then(|a| unsafe { memory::peek(a, 8) }) {
+            Ok(bytes) => {
+                let value = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const u64) };
+                outln!("{:#018x}: {:#018x}", current, value);
+            }
+            Err(e) => {
+                outln!("{:#018x}: {}", current, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Print `count` bytes starting at `address`, 16 to a line, with their ASCII alongside.
+fn dump_bytes(address: usize, count: usize) {
+    let bytes = match virtual_address(address).and_then(|a| unsafe { memory::peek(a, count) }) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            outln!("{:#018x}: {}", address, e);
+            return;
+        }
+    };
+
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        out!("{:#018x}:", address + line * 16);
+
+        for byte in chunk {
+            out!(" {:02x}", byte);
+        }
+
+        for _ in chunk.len()..16 {
+            out!("   ");
+        }
+
+        out!("  ");
+        for &byte in chunk {
+            let printable = byte >= 0x20 && byte < 0x7f;
+            out!("{}", if printable { byte as char } else { '.' });
+        }
+        outln!("");
+    }
+}
+
+fn write(address: usize, value: u64) {
+    let bytes = unsafe { slice::from_raw_parts(&value as *const u64 as *const u8, 8) };
+
+    if let Err(e) = virtual_address(address).and_then(|a| unsafe { memory::poke(a, bytes) }) {
+        outln!("{:#018x}: {}", address, e);
+    }
+}
@@ -1,6 +1,7 @@
 use super::interrupts;
 use super::memory;
 use device;
+use alloc::String;
 
 /// Main kernel init function. This sets everything up for us.
 pub unsafe fn init(multiboot_info: usize) {
@@ -19,34 +20,38 @@ pub unsafe fn init(multiboot_info: usize) {
 
         let boot_info = ::multiboot2::load(multiboot_info);
 
-        // Set safety bits in certain registers.
-        enable_nxe_bit();
-        enable_write_protect_bit();
+        // Per-CPU data must be reachable through GS before the scheduler is used.
+        super::percpu::init_bsp();
 
         // Setup memory management.
-        let mut memory_controller = memory::init(&boot_info);
+        let mut memory_controller = match memory::init(&boot_info) {
+            Ok(controller) => controller,
+            Err(e) => panic!("Memory init failed: {}", e),
+        };
         interrupts::init(&mut memory_controller);
 
+        // The command line is copied to the heap, so this must come after memory init.
+        super::cmdline::init(&boot_info);
+        super::symbols::init();
+        ::shell::init();
+        ::task::policy::init();
+        super::interrupts::stats::init();
+        memory::heap_allocator::init();
+        super::debugger::init();
+        super::profiler::init();
+        super::cpuid::init();
+        ::smbios::register_command();
+        super::user_access::init();
+        super::platform::print_banner();
+
         // Setup hardware devices.
         device::init();
+        super::time::init();
+        super::profiler::init_nmi();
+
+        ::syscall::create(memory::frame_pool::refill_task, String::from("frame-pool"));
     }
     asm!("sti");
 
     println!("[ OK ] Init successful, you may now type.")
 }
-
-pub fn enable_nxe_bit() {
-    use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER};
-
-    let nxe_bit = 1 << 11;
-    unsafe {
-        let efer = rdmsr(IA32_EFER);
-        wrmsr(IA32_EFER, efer | nxe_bit);
-    }
-}
-
-pub fn enable_write_protect_bit() {
-    use x86_64::registers::control_regs::{Cr0, cr0, cr0_write};
-
-    unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
-}
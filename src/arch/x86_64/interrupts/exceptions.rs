@@ -1,16 +1,258 @@
 //! Handlers for internal CPU exceptions. Currently, when an exception occurs, we just print some
-//! debug information and then spin the CPU. TODO: Figure out which exceptions are safe to return
-//! from.
+//! debug information and then halt the CPU, except that a GPF raised in user mode only kills the
+//! task which raised it. TODO: Figure out which exceptions are safe to return from.
 
+use arch::memory::{address_space, demand};
+use arch::memory::paging::cow;
+use arch::percpu::InterruptContext;
+use core::fmt;
 use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
-use super::disable_interrupts_and_then;
+use super::{disable_interrupts_and_then, halt_forever};
+use super::{probe, stats};
+
+// Exception vector numbers.
+pub const DIVIDE_BY_ZERO_VECTOR: u8 = 0;
+pub const DEBUG_VECTOR: u8 = 1;
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+pub const OVERFLOW_VECTOR: u8 = 4;
+pub const BOUND_RANGE_VECTOR: u8 = 5;
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE_VECTOR: u8 = 7;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const INVALID_TSS_VECTOR: u8 = 10;
+pub const SEGMENT_NOT_PRESENT_VECTOR: u8 = 11;
+pub const STACK_SEGMENT_FAULT_VECTOR: u8 = 12;
+pub const GPF_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+pub const X87_FP_VECTOR: u8 = 16;
+pub const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+pub const MACHINE_CHECK_VECTOR: u8 = 18;
+pub const SIMD_FP_VECTOR: u8 = 19;
+
+/// The general purpose registers as they were when an exception occurred, saved by the exception's
+/// entry trampoline. `ExceptionStackFrame` only holds RIP, CS, RFLAGS, RSP and SS.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rax {:#018x} rbx {:#018x} rcx {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "rdx {:#018x} rsi {:#018x} rdi {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "rbp {:#018x} r8  {:#018x} r9  {:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "r10 {:#018x} r11 {:#018x} r12 {:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "r13 {:#018x} r14 {:#018x} r15 {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Define an entry point for an exception which pushes an error code. The registers must be saved
+/// before any Rust code runs, so this is a naked function which pushes them as a `Registers` on the
+/// stack, then calls `$handler` as
+/// `extern "C" fn(&Registers, &mut ExceptionStackFrame, error_code: u64)`. If the handler returns,
+/// the registers are restored and the exception returns through the (possibly modified) frame.
+///
+/// If the exception came from user mode, the entry point swaps in the kernel's GS base on the way
+/// in and the user's on the way out, so that the handler can use per-CPU data.
+///
+/// The entry point has to be installed in the IDT with a transmute, since it is not an
+/// `extern "x86-interrupt"` function.
+macro_rules! exception_entry_with_error_code {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            // The CPU aligns the stack to 16 bytes and pushes 6 quadwords including the error
+            // code, so after 15 more pushes another 8 bytes are needed to align the call.
+            asm!(concat!("
+                test qword ptr [rsp + 16], 3
+                jz ", stringify!($name), "_from_kernel
+                swapgs
+            ", stringify!($name), "_from_kernel:
+                push r15
+                push r14
+                push r13
+                push r12
+                push r11
+                push r10
+                push r9
+                push r8
+                push rbp
+                push rdi
+                push rsi
+                push rdx
+                push rcx
+                push rbx
+                push rax
+                mov rdi, rsp
+                lea rsi, [rsp + 128]
+                mov rdx, [rsp + 120]
+                sub rsp, 8
+                call ", stringify!($handler), "
+                add rsp, 8
+                pop rax
+                pop rbx
+                pop rcx
+                pop rdx
+                pop rsi
+                pop rdi
+                pop rbp
+                pop r8
+                pop r9
+                pop r10
+                pop r11
+                pop r12
+                pop r13
+                pop r14
+                pop r15
+                add rsp, 8
+                test qword ptr [rsp + 8], 3
+                jz ", stringify!($name), "_to_kernel
+                swapgs
+            ", stringify!($name), "_to_kernel:
+                iretq")
+                : : : "memory" : "intel", "volatile");
+            ::core::intrinsics::unreachable();
+        }
+    };
+}
+
+exception_entry_with_error_code!(double_fault_entry, double_fault_handler);
+exception_entry_with_error_code!(gpf_entry, gpf_handler);
+exception_entry_with_error_code!(page_fault_entry, page_fault_handler);
+
+/// In test builds, let the test harness deal with a fault raised by a test. Returns true if the
+/// harness took the fault and `stack_frame` now points where it wants to resume, in which case the
+/// handler should return straight away.
+#[cfg(feature = "kernel-test")]
+#[inline(always)]
+fn notify_tests(
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &mut ExceptionStackFrame,
+) -> bool {
+    ::testing::exception_raised(vector, error_code, stack_frame)
+}
+
+#[cfg(not(feature = "kernel-test"))]
+#[inline(always)]
+fn notify_tests(_: u8, _: Option<u64>, _: &mut ExceptionStackFrame) -> bool {
+    false
+}
+
+/// Return whether the exception was raised by code running in ring 3, going by the privilege
+/// level of the saved code segment selector.
+pub fn from_user_mode(stack_frame: &ExceptionStackFrame) -> bool {
+    stack_frame.code_segment & 0x3 == 3
+}
+
+/// Mark this CPU as running an exception handler for as long as the returned context is alive.
+/// Handlers without an entry trampoline do not swap GS, so nothing is marked for an exception
+/// raised in user mode, where GS still holds the user's base.
+fn enter_context(stack_frame: &ExceptionStackFrame) -> Option<InterruptContext> {
+    if from_user_mode(stack_frame) {
+        None
+    } else {
+        InterruptContext::try_enter()
+    }
+}
+
+/// Terminate the current task after it raised a fault in user mode, and run something else. The
+/// kernel can carry on, since the fault only affected that task's own state.
+///
+/// A task running in ring 3 cannot be holding the task table lock, which is only ever taken by
+/// kernel code, so removing it cannot deadlock. A fault raised while the lock is held comes from
+/// ring 0 and is fatal, unless a recovery policy says otherwise (see `recovery`).
+fn kill_faulting_task(
+    fault: &str,
+    stack_frame: &ExceptionStackFrame,
+    context: Option<InterruptContext>,
+) -> ! {
+    use task::{ExitCode, Scheduling, SCHEDULER};
+
+    println!(
+        "[ task ] Killing {} (pid {}) after a {} at {:#x}.",
+        SCHEDULER.current_name(),
+        SCHEDULER.get_id().inner(),
+        fault,
+        stack_frame.instruction_pointer
+    );
+
+    // The task we switch to was not interrupted.
+    drop(context);
+
+    SCHEDULER.exit(ExitCode::FAULTED);
+    unreachable!("exited task was scheduled again");
+}
+
+/// Apply the recovery policy for `vector` to a fault raised in the kernel. Returns whether the
+/// handler should return and resume at `stack_frame`; if not, it should halt as usual. The
+/// handler's context is given up, since the faulting task may be killed.
+fn recover(
+    vector: u8,
+    fault: &str,
+    stack_frame: &mut ExceptionStackFrame,
+    context: Option<InterruptContext>,
+) -> bool {
+    use super::recovery::{self, RecoveryPolicy};
+    use task::{ProcessId, Scheduling, SCHEDULER};
+    use x86_64::VirtualAddress;
+
+    let policy = recovery::recovery_policy(vector);
+
+    if policy == RecoveryPolicy::SkipInstruction {
+        let instruction_pointer = stack_frame.instruction_pointer.0;
+
+        if let Some(len) = recovery::instruction_length(instruction_pointer) {
+            println!(
+                "[ fault ] Skipping the instruction at {:#x} after a {}.",
+                instruction_pointer, fault
+            );
+            stack_frame.instruction_pointer = VirtualAddress(instruction_pointer + len);
+            return true;
+        }
+    }
+
+    // The null process has nothing to switch to.
+    if policy != RecoveryPolicy::Halt && SCHEDULER.get_id() != ProcessId::NULL_PROC {
+        kill_faulting_task(fault, stack_frame, context);
+    }
+
+    false
+}
 
 /// Handler for the #DE Exception. This exception occurs when divinding any number by zero using
 /// either the DIV or IDIV instructions.
 pub extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(DIVIDE_BY_ZERO_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(DIVIDE_BY_ZERO_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(DIVIDE_BY_ZERO_VECTOR, "divide by zero", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
@@ -21,26 +263,60 @@ pub extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut Exception
 /// - I/O r/w breakpoint (Trap).
 /// - Single-step (Trap).
 /// - Task switch (Trap).
+///
+/// Single steps requested by the debugger are passed back to it.
 pub extern "x86-interrupt" fn debug_handler(stack_frame: &mut ExceptionStackFrame) {
+    use arch::debugger;
+
+    stats::count(DEBUG_VECTOR);
+    let _context = enter_context(stack_frame);
+
+    if debugger::debug_exception(stack_frame) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: DEBUG\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
 /// A non-maskable interrupt is a hardware-driven interrupt much like those sent by the PIC, except
-/// an NMI either goes directly to the CPU or via another controller. An NMI occurs for hardware
-/// errors, which are something we can do nothing about. TODO: Investigate how we might discover
-/// which piece of hardware is faulty.
+/// an NMI either goes directly to the CPU or via another controller. Besides the profiler's
+/// counter overflows, an NMI occurs for hardware errors, which are something we can do nothing
+/// about. TODO: Investigate how we might discover which piece of hardware is faulty.
 pub extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame) {
+    use arch::profiler;
+
+    stats::count(NMI_VECTOR);
+
+    // No `InterruptContext` is entered, since an NMI may arrive on the way into the kernel from
+    // user mode before GS is swapped, when per-CPU data cannot be reached.
+
+    // Profiling NMIs are expected, and may arrive while any lock is held.
+    if profiler::nmi_sample(stack_frame.instruction_pointer.0 as usize) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
-        loop {}
+        emergency_println!("\nEXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+        halt_forever();
     });
 }
 
-/// Hardware breakpoint exception. This can return without issues.
+/// Hardware breakpoint exception. This can return without issues. If the debugger is enabled we
+/// drop into it, otherwise we just print where the breakpoint was.
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
+    use arch::debugger;
+
+    stats::count(BREAKPOINT_VECTOR);
+    let _context = enter_context(stack_frame);
+
+    if debugger::is_enabled() {
+        debugger::enter(stack_frame);
+        return;
+    }
+
     println!(
         "\nEXCEPTION: BREAKPOINT at {:#x}\n{:#?}",
         stack_frame.instruction_pointer, stack_frame
@@ -51,9 +327,20 @@ pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStac
 /// OVERFLOW bit in RFLAGS is set to 1, or when the result of `DIV/IDIV` instruction is greater
 /// than the maximum value of a 64-bit integer.
 pub extern "x86-interrupt" fn overflow_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(OVERFLOW_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(OVERFLOW_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(OVERFLOW_VECTOR, "overflow", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
@@ -61,21 +348,47 @@ pub extern "x86-interrupt" fn overflow_handler(stack_frame: &mut ExceptionStackF
 /// out of bounds. The `BOUND` instruction takes an index into an array, and compares it with the
 /// upper and lower bounds of the array. If the index is out of bounds, this exception is thrown.
 pub extern "x86-interrupt" fn bound_range_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(BOUND_RANGE_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(BOUND_RANGE_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(BOUND_RANGE_VECTOR, "bound range exceeded", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
 /// If the processor tries to execute an instruction with an invalid or undefined exception (or if
 /// the instruction exceeds 15 bytes), an `INVALID OPCODE` exception is thrown.
 pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(INVALID_OPCODE_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if probe::catch(INVALID_OPCODE_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if notify_tests(INVALID_OPCODE_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(INVALID_OPCODE_VECTOR, "invalid opcode", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!(
             "\nEXCEPTION: INVALID OPCODE at {:#x}\n{:#?}",
             stack_frame.instruction_pointer, stack_frame
         );
-        loop {}
+        halt_forever();
     });
 }
 
@@ -83,37 +396,83 @@ pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut Exception
 /// is no x87 present. This is a very rare occurence, as only very old hardware will not have an
 /// FPU.
 pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(DEVICE_NOT_AVAILABLE_VECTOR);
+    let _context = enter_context(stack_frame);
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: FPU NOT AVAILABLE\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
 /// A Double Fault occurs when a) an exception is unhandled, b) when an exception occurs whilst the
 /// CPU is in the process of calling the exception handler for the first exception. This is an
 /// Abort, meaning it is not possible to recover from a Double Fault.
-pub extern "x86-interrupt" fn double_fault_handler(
+///
+/// Entered through `double_fault_entry`.
+#[no_mangle]
+pub extern "C" fn double_fault_handler(
+    registers: &Registers,
     stack_frame: &mut ExceptionStackFrame,
     _error_code: u64,
 ) {
+    stats::count(DOUBLE_FAULT_VECTOR);
+    let _context = InterruptContext::try_enter();
+
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-        loop {}
+        emergency_println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}\n{}", stack_frame, registers);
+
+        if overflowed_stack(stack_frame.stack_pointer.0) {
+            report_stack_overflow();
+        }
+
+        halt_forever();
     });
 }
 
+/// Return whether a fault with the stack pointer at `stack_pointer` was caused by running off the
+/// end of a stack into its guard page. The stack pointer may still be just above the guard page, if
+/// the push which faulted was the one that would have crossed into it.
+fn overflowed_stack(stack_pointer: usize) -> bool {
+    use arch::memory::stack_allocator::is_guard_page;
+
+    is_guard_page(stack_pointer) || is_guard_page(stack_pointer.wrapping_sub(1))
+}
+
+/// Say that the kernel stack overflowed, and which task it belonged to if the scheduler can tell.
+/// The double fault handler runs on its own stack, so it can still print.
+fn report_stack_overflow() {
+    use arch::percpu;
+    use task::SCHEDULER;
+
+    emergency_println!("KERNEL STACK OVERFLOW");
+
+    if !percpu::is_installed() {
+        return;
+    }
+
+    let pid = percpu::current_task_id();
+    match SCHEDULER.try_current_name() {
+        Some(name) => emergency_println!("The stack of task {} (pid {}) overflowed.", name, pid),
+        None => emergency_println!("The stack of task pid {} overflowed.", pid),
+    }
+}
+
 /// The Invalid TSS exception occurs when an invalid segment selector is referenced during
 /// control transfer through a gate descriptor.
 pub extern "x86-interrupt" fn invalid_tss_handler(
     stack_frame: &mut ExceptionStackFrame,
     error_code: u64,
 ) {
+    stats::count(INVALID_TSS_VECTOR);
+    let _context = enter_context(stack_frame);
+
     disable_interrupts_and_then(|| {
         println!(
             "\nEXCEPTION: INVALID TSS with code: {:?}\n{:#?}",
             error_code, stack_frame
         );
-        loop {}
+        halt_forever();
     });
 }
 
@@ -123,6 +482,17 @@ pub extern "x86-interrupt" fn seg_not_present_handler(
     stack_frame: &mut ExceptionStackFrame,
     error_code: u64,
 ) {
+    stats::count(SEGMENT_NOT_PRESENT_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(SEGMENT_NOT_PRESENT_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if recover(SEGMENT_NOT_PRESENT_VECTOR, "segment not present fault", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!(
             "\nEXCEPTION: SEGMENT NOT PRESENT\nerror code: \
@@ -130,7 +500,7 @@ pub extern "x86-interrupt" fn seg_not_present_handler(
             error_code, stack_frame
         );
 
-        loop {}
+        halt_forever();
     });
 }
 
@@ -142,6 +512,17 @@ pub extern "x86-interrupt" fn stack_seg_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
     error_code: u64,
 ) {
+    stats::count(STACK_SEGMENT_FAULT_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(STACK_SEGMENT_FAULT_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if recover(STACK_SEGMENT_FAULT_VECTOR, "stack segment fault", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!(
             "\nEXCEPTION: STACK SEGMENT FAULT\nerror code: \
@@ -149,7 +530,7 @@ pub extern "x86-interrupt" fn stack_seg_fault_handler(
             error_code, stack_frame
         );
 
-        loop {}
+        halt_forever();
     });
 }
 
@@ -160,10 +541,39 @@ pub extern "x86-interrupt" fn stack_seg_fault_handler(
 /// - Referencing the null segment descriptor.
 /// - Trying to access an unimplemented register (i.e in Protected Mode: `mov cr6, eax` is
 /// illegal).
-pub extern "x86-interrupt" fn gpf_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
+///
+/// Entered through `gpf_entry`.
+#[no_mangle]
+pub extern "C" fn gpf_handler(
+    registers: &Registers,
+    stack_frame: &mut ExceptionStackFrame,
+    error_code: u64,
+) {
+    stats::count(GPF_VECTOR);
+    let context = InterruptContext::try_enter();
+
+    if probe::catch(GPF_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if notify_tests(GPF_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if from_user_mode(stack_frame) {
+        kill_faulting_task("GPF", stack_frame, context);
+    }
+
+    if recover(GPF_VECTOR, "GPF", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
-        println!("\nEXCEPTION: GPF\n{:#?}", stack_frame);
-        loop {}
+        println!(
+            "\nEXCEPTION: GPF\nerror code: {:#x}\n{:#?}\n{}",
+            error_code, stack_frame, registers
+        );
+        halt_forever();
     });
 }
 
@@ -173,20 +583,69 @@ pub extern "x86-interrupt" fn gpf_handler(stack_frame: &mut ExceptionStackFrame,
 /// - A protection check on the page (r/w, priveleges) failed.
 /// - A reserved bit in the page directory or table entries is set to 1.
 /// The address that the CPU tried to access is saved in register `cr2`.
-pub extern "x86-interrupt" fn page_fault_handler(
+///
+/// Entered through `page_fault_entry`.
+#[no_mangle]
+pub extern "C" fn page_fault_handler(
+    registers: &Registers,
     stack_frame: &mut ExceptionStackFrame,
-    error_code: PageFaultErrorCode,
+    error_code: u64,
 ) {
+    stats::count(PAGE_FAULT_VECTOR);
+    let context = InterruptContext::try_enter();
+
+    // The first touch of a page in a demand-paged region, such as the heap, maps it. This comes
+    // before the test harness, since a test which touches one has not faulted as far as it is
+    // concerned.
+    let address = ::x86_64::registers::control_regs::cr2().0;
+    let code = PageFaultErrorCode::from_bits_truncate(error_code);
+    if demand::handle_fault(address, code) {
+        return;
+    }
+    // A write to a copy-on-write page copies it.
+    if cow::handle_fault(address, code) {
+        return;
+    }
+
+    if notify_tests(PAGE_FAULT_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if recover(PAGE_FAULT_VECTOR, "page fault", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
+        use arch::percpu;
+        use super::page_fault::{self, ErrorCodeWords, PageFaultKind};
         use x86_64::registers::control_regs;
+
+        let address = control_regs::cr2().0;
+        let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+        let kind = page_fault::classify(address, error_code);
+
         println!(
             "\nEXCEPTION: PAGE FAULT while accessing {:#x}\nerror code: \
-             {:?}\n{:#?}",
-            control_regs::cr2(),
+             {:?} ({})\n{:#?}\n{}",
+            address,
             error_code,
-            stack_frame
+            ErrorCodeWords(error_code),
+            stack_frame,
+            registers
         );
-        loop {}
+
+        // The scheduler may be locked, so name the task by PID alone.
+        if kind == PageFaultKind::StackOverflow && percpu::is_installed() {
+            println!("Reason: {} in task {}", kind.reason(), percpu::current_task_id());
+        } else {
+            println!("Reason: {}", kind.reason());
+        }
+        match address_space::area_at(address) {
+            Some(area) => println!("In area {}", area),
+            None => println!("In no area"),
+        }
+
+        halt_forever();
     });
 }
 
@@ -195,9 +654,20 @@ pub extern "x86-interrupt" fn page_fault_handler(
 /// - CR0.NE = 1,
 /// - an unmasked x87 floating point exception is pending.
 pub extern "x86-interrupt" fn x87_fp_exception_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(X87_FP_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(X87_FP_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(X87_FP_VECTOR, "x87 floating point exception", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nX87 FLOATING POINT EXCEPTION\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
@@ -206,11 +676,22 @@ pub extern "x86-interrupt" fn x87_fp_exception_handler(stack_frame: &mut Excepti
 /// `CPL = 3`.
 pub extern "x86-interrupt" fn alignment_check_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) {
+    stats::count(ALIGNMENT_CHECK_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(ALIGNMENT_CHECK_VECTOR, Some(error_code), stack_frame) {
+        return;
+    }
+
+    if recover(ALIGNMENT_CHECK_VECTOR, "alignment check", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!("\nEXCEPTION: ALIGNMENT CHECK\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
@@ -218,21 +699,34 @@ pub extern "x86-interrupt" fn alignment_check_handler(
 /// internal errors - i.e, bad memory, bad cache, faulty timings etc. The error information is
 /// placed in the model-specific registers.
 pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(MACHINE_CHECK_VECTOR);
+
     disable_interrupts_and_then(|| {
         // TODO: use the MSRs to get error information about the MC.
         println!("\nEXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
-        loop {}
+        halt_forever();
     });
 }
 
 /// If the `CR4.OSXMMEXCEPT` bit is set to 1 in `cr4`, then an unmasked 128-bit media instruction
 /// will cause this exception. Otherwise, an `Invalid Opcode` exception occurs.
 pub extern "x86-interrupt" fn simd_fp_exception_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(SIMD_FP_VECTOR);
+    let context = enter_context(stack_frame);
+
+    if notify_tests(SIMD_FP_VECTOR, None, stack_frame) {
+        return;
+    }
+
+    if recover(SIMD_FP_VECTOR, "SIMD floating point exception", stack_frame, context) {
+        return;
+    }
+
     disable_interrupts_and_then(|| {
         println!(
             "\nEXCEPTION: SIMD FLOATING POINT EXCEPTION\n{:#?}",
             stack_frame
         );
-        loop {}
+        halt_forever();
     });
 }
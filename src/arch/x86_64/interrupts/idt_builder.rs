@@ -0,0 +1,86 @@
+//! Construction of the IDT with a record of which vectors have been given a handler.
+//!
+//! Assigning a vector twice is an error, so two pieces of code which both claim a vector (for
+//! example two timers) are caught when the IDT is built rather than one silently replacing the
+//! other. `finalize` checks that the handlers the kernel cannot run without are present, then
+//! points every interrupt vector nobody assigned at a handler which reports it.
+
+use super::exceptions::DOUBLE_FAULT_VECTOR;
+use super::irq;
+use x86_64::structures::idt::{HandlerFunc, Idt};
+
+/// Vectors which must be assigned before the IDT can be used. Without a double fault handler, a
+/// fault while delivering another exception triple faults and resets the machine.
+const REQUIRED_VECTORS: &[u8] = &[DOUBLE_FAULT_VECTOR];
+
+/// The first vector which is not reserved for exceptions.
+const FIRST_INTERRUPT_VECTOR: usize = 32;
+
+pub struct IdtBuilder {
+    idt: Idt,
+    assigned: [bool; 256],
+}
+
+impl IdtBuilder {
+    pub fn new() -> Self {
+        IdtBuilder {
+            idt: Idt::new(),
+            assigned: [false; 256],
+        }
+    }
+
+    /// Claim `vector` and let `install` set its entry. The IDT's entries have different handler
+    /// types depending on whether the CPU pushes an error code, so `install` picks the entry
+    /// itself. Fails if `vector` has already been claimed.
+    pub fn set<F>(&mut self, vector: u8, install: F) -> Result<&mut Self, &'static str>
+    where
+        F: FnOnce(&mut Idt),
+    {
+        if self.assigned[vector as usize] {
+            return Err("IDT vector assigned twice");
+        }
+
+        self.assigned[vector as usize] = true;
+        install(&mut self.idt);
+
+        Ok(self)
+    }
+
+    /// Claim the interrupt (not exception) vector `vector` for `handler`.
+    pub fn interrupt(
+        &mut self,
+        vector: u8,
+        handler: HandlerFunc,
+    ) -> Result<&mut Self, &'static str> {
+        if (vector as usize) < FIRST_INTERRUPT_VECTOR {
+            return Err("IDT vector is reserved for exceptions");
+        }
+
+        self.set(vector, |idt| {
+            idt.interrupts[vector as usize - FIRST_INTERRUPT_VECTOR].set_handler_fn(handler);
+        })
+    }
+
+    /// Return whether `vector` has been claimed.
+    pub fn is_assigned(&self, vector: u8) -> bool {
+        self.assigned[vector as usize]
+    }
+
+    /// Check that every required vector has been assigned, and fill every unassigned interrupt
+    /// vector with a handler which reports it. Unassigned exception vectors are left not present,
+    /// since most of them are reserved.
+    pub fn finalize(mut self) -> Result<Idt, &'static str> {
+        if REQUIRED_VECTORS.iter().any(|&vector| !self.is_assigned(vector)) {
+            return Err("IDT is missing a required exception handler");
+        }
+
+        for vector in FIRST_INTERRUPT_VECTOR..256 {
+            if !self.assigned[vector] {
+                self.idt.interrupts[vector - FIRST_INTERRUPT_VECTOR]
+                    .set_handler_fn(irq::unhandled_handler(vector as u8));
+            }
+        }
+
+        Ok(self.idt)
+    }
+}
@@ -1,25 +1,53 @@
 use device::pic::PICS;
-use device::keyboard::ps2_keyboard::parse_key;
+use device::keyboard::ps2_keyboard::SCANCODES;
 use device::ps2_8042::read_char;
-use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc};
 use super::disable_interrupts_and_then;
 use device::apic;
+use arch::percpu::InterruptContext;
+use task::deferred;
+use super::stats;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Number of interrupts which arrived on a vector with no handler of its own.
+pub static UNHANDLED_INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
 
 /// Timer handler checks the tick counter and if it exceeds 10, performs a round-robin context
-/// switch to the next process.
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+/// switch to the next process. Installed on each timer vector through `counted_handler!`.
+pub fn timer(stack_frame: &mut ExceptionStackFrame) {
+    use arch::{percpu, profiler};
     use core::sync::atomic::Ordering;
+    use device::graphics::console;
     use device::pit::PIT_TICKS;
-    use task::{Scheduling, SCHEDULER};
+    use task::{preempt, sleep, Scheduling, SCHEDULER};
 
+    let context = InterruptContext::enter();
+    profiler::sample(stack_frame.instruction_pointer.0 as usize);
     println!("timer interrupt.");
 
     apic::eoi();
-    
+
+    // Wake the processes whose sleep ends on this tick.
+    sleep::tick();
+
+    // This also runs work deferred from outside an interrupt handler, at least once a tick.
+    deferred::irq_exit();
+
     // Check if allocated timeslice finished (~20ms).
     if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= 10 {
         PIT_TICKS.store(0, Ordering::SeqCst);
 
+        console::blink();
+
+        // Leave the switch to `preempt_enable`.
+        if preempt::preempt_disabled() {
+            percpu::set_resched_pending(true);
+            return;
+        }
+
+        // The task we switch to may not have been interrupted.
+        drop(context);
+
         unsafe {
             // Call scheduler.
             disable_interrupts_and_then(|| {
@@ -29,11 +57,74 @@ pub extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFra
     }
 }
 
+/// Queue a scancode from the PS/2 controller. This is not installed in the IDT yet, so keyboard
+/// IRQs arrive as unhandled interrupts.
 pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut ExceptionStackFrame) {
+    let _context = InterruptContext::enter();
     println!("keyboard interrupt.");
     let code = read_char();
 
-    parse_key(code);
-        
+    // Decoding happens outside the handler, so just queue the byte.
+    SCANCODES.push(code);
+
     apic::eoi();
 }
+
+/// Report an interrupt on a vector with nothing else installed, and acknowledge it to whichever
+/// controller delivered it so that it does not block lower priority interrupts.
+fn unhandled_interrupt(vector: u8, stack_frame: &mut ExceptionStackFrame) {
+    stats::count(vector);
+    let _context = InterruptContext::enter();
+    UNHANDLED_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+
+    println!(
+        "[ interrupts ] Unhandled interrupt {} ({:#x}) at {:#x}.",
+        vector, vector, stack_frame.instruction_pointer.0
+    );
+
+    if apic::in_service(vector) {
+        apic::eoi();
+    } else {
+        // This does nothing for vectors outside the PICs' range.
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+/// Define a handler which reports an interrupt on `$vector` as unhandled.
+macro_rules! unhandled_handler {
+    ($vector:expr) => {{
+        extern "x86-interrupt" fn handler(stack_frame: &mut ExceptionStackFrame) {
+            unhandled_interrupt($vector, stack_frame);
+        }
+        handler as HandlerFunc
+    }};
+}
+
+/// Build rows of 16 unhandled interrupt handlers, one row for each vector given.
+macro_rules! unhandled_handlers {
+    ($($row:expr),*) => {
+        [$([
+            unhandled_handler!($row | 0x0), unhandled_handler!($row | 0x1),
+            unhandled_handler!($row | 0x2), unhandled_handler!($row | 0x3),
+            unhandled_handler!($row | 0x4), unhandled_handler!($row | 0x5),
+            unhandled_handler!($row | 0x6), unhandled_handler!($row | 0x7),
+            unhandled_handler!($row | 0x8), unhandled_handler!($row | 0x9),
+            unhandled_handler!($row | 0xa), unhandled_handler!($row | 0xb),
+            unhandled_handler!($row | 0xc), unhandled_handler!($row | 0xd),
+            unhandled_handler!($row | 0xe), unhandled_handler!($row | 0xf),
+        ]),*]
+    };
+}
+
+/// Return a handler for `vector` which logs it as unhandled and returns. Every interrupt vector
+/// without a handler of its own gets one of these, so a stray interrupt is reported instead of
+/// raising a fault through a missing IDT entry. Each vector needs its own function, since a
+/// handler is not told which vector it was called through.
+pub fn unhandled_handler(vector: u8) -> HandlerFunc {
+    let handlers: [[HandlerFunc; 16]; 16] = unhandled_handlers!(
+        0x00, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0,
+        0xf0
+    );
+
+    handlers[(vector >> 4) as usize][(vector & 0xf) as usize]
+}
@@ -1,59 +1,138 @@
 use arch::memory::MemoryController;
+use arch::memory::paging::tlb;
+use device::apic;
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::structures::idt::{Idt, ExceptionStackFrame};
+use x86_64::structures::idt::{Idt, ExceptionStackFrame, HandlerFunc};
+use core::mem;
 use spin::Once;
 
+// Declared first so that `counted_handler!` can be used in the other modules.
+#[macro_use]
+pub mod stats;
 pub mod gdt;
 pub mod exceptions;
+pub mod idt_builder;
 pub mod irq;
+pub mod page_fault;
+pub mod probe;
+pub mod recovery;
 pub mod utils;
 
+pub use self::idt_builder::IdtBuilder;
+pub use self::probe::{probe, ProbeFault};
 pub use self::utils::*;
 
+/// Interrupt stack table slots. A page fault can turn into a double fault, and an NMI can arrive
+/// at any time, so each gets a stack of its own rather than sharing one or using whatever stack was
+/// in use, which may be the very stack that overflowed.
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+pub const PAGE_FAULT_IST_INDEX: usize = 1;
+const NMI_IST_INDEX: usize = 2;
+
+/// Size of each interrupt stack in pages. Every stack also has a guard page below it, but
+/// overflowing an interrupt stack cannot be recovered from, so they are sized generously.
+pub const IST_STACK_PAGES: usize = 4;
 
 lazy_static! {
-    static ref IDT: Idt = {
-        let mut idt = Idt::new();
-
-        println!("[ interrupts ] Installing exception handlers.");
-        idt.divide_by_zero.set_handler_fn(exceptions::divide_by_zero_handler);
-        idt.debug.set_handler_fn(exceptions::debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(exceptions::nmi_handler);
-        idt.breakpoint.set_handler_fn(exceptions::breakpoint_handler);
-        idt.overflow.set_handler_fn(exceptions::overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(exceptions::bound_range_handler);
-        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode_handler);
-        idt.device_not_available.set_handler_fn(exceptions::device_not_available_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(exceptions::double_fault_handler)
+    static ref IDT: Idt = build_idt().expect("could not build the IDT");
+}
+
+/// Register every exception and interrupt handler. Vectors nobody claims report themselves as
+/// unhandled.
+fn build_idt() -> Result<Idt, &'static str> {
+    use self::exceptions::*;
+
+    let mut builder = IdtBuilder::new();
+
+    println!("[ interrupts ] Installing exception handlers.");
+    builder
+        .set(DIVIDE_BY_ZERO_VECTOR, |idt| {
+            idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
+        })?
+        .set(DEBUG_VECTOR, |idt| {
+            idt.debug.set_handler_fn(debug_handler);
+        })?
+        .set(NMI_VECTOR, |idt| {
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(NMI_IST_INDEX as u16);
+        })?
+        .set(BREAKPOINT_VECTOR, |idt| {
+            idt.breakpoint.set_handler_fn(breakpoint_handler);
+        })?
+        .set(OVERFLOW_VECTOR, |idt| {
+            idt.overflow.set_handler_fn(overflow_handler);
+        })?
+        .set(BOUND_RANGE_VECTOR, |idt| {
+            idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+        })?
+        .set(INVALID_OPCODE_VECTOR, |idt| {
+            idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        })?
+        .set(DEVICE_NOT_AVAILABLE_VECTOR, |idt| {
+            idt.device_not_available.set_handler_fn(device_not_available_handler);
+        })?
+        // These entry points save every register for the handler to print, and are not
+        // `extern "x86-interrupt"` functions, so their types have to be forced.
+        .set(DOUBLE_FAULT_VECTOR, |idt| unsafe {
+            idt.double_fault
+                .set_handler_fn(mem::transmute(double_fault_entry as usize))
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
-        }
-        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(exceptions::seg_not_present_handler);
-        idt.stack_segment_fault.set_handler_fn(exceptions::stack_seg_fault_handler);
-        idt.general_protection_fault.set_handler_fn(exceptions::gpf_handler);
-        idt.page_fault.set_handler_fn(exceptions::page_fault_handler);
-        idt.x87_floating_point.set_handler_fn(exceptions::x87_fp_exception_handler);
-        idt.alignment_check.set_handler_fn(exceptions::alignment_check_handler);
-        idt.machine_check.set_handler_fn(exceptions::machine_check_handler);
-        idt.simd_floating_point.set_handler_fn(exceptions::simd_fp_exception_handler);
-
-        println!("[ interrupts ] Installing IRQs.");
-        idt.interrupts[0].set_handler_fn(irq::timer_handler);
-        // idt.interrupts[1].set_handler_fn(irq::keyboard_handler);
-        
-        idt.interrupts[0x30 - 0x20].set_handler_fn(irq::timer_handler);
-        // idt.interrupts[17].set_handler_fn(irq::keyboard_handler);
-        
-        // APIC NMI.
-        for vec in (0x90-0x20)..(0x97-0x20) {
-            idt.interrupts[vec].set_handler_fn(apic_nmi_handler);
-        }
-        idt.interrupts[0xff - 0x20].set_handler_fn(spurious_interrupt_handler);
-
-        idt
-    };
+        })?
+        .set(INVALID_TSS_VECTOR, |idt| {
+            idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        })?
+        .set(SEGMENT_NOT_PRESENT_VECTOR, |idt| {
+            idt.segment_not_present.set_handler_fn(seg_not_present_handler);
+        })?
+        .set(STACK_SEGMENT_FAULT_VECTOR, |idt| {
+            idt.stack_segment_fault.set_handler_fn(stack_seg_fault_handler);
+        })?
+        .set(GPF_VECTOR, |idt| unsafe {
+            idt.general_protection_fault
+                .set_handler_fn(mem::transmute(gpf_entry as usize));
+        })?
+        .set(PAGE_FAULT_VECTOR, |idt| unsafe {
+            idt.page_fault
+                .set_handler_fn(mem::transmute(page_fault_entry as usize))
+                .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
+        })?
+        .set(X87_FP_VECTOR, |idt| {
+            idt.x87_floating_point.set_handler_fn(x87_fp_exception_handler);
+        })?
+        .set(ALIGNMENT_CHECK_VECTOR, |idt| {
+            idt.alignment_check.set_handler_fn(alignment_check_handler);
+        })?
+        .set(MACHINE_CHECK_VECTOR, |idt| {
+            idt.machine_check.set_handler_fn(machine_check_handler);
+        })?
+        .set(SIMD_FP_VECTOR, |idt| {
+            idt.simd_floating_point.set_handler_fn(simd_fp_exception_handler);
+        })?;
+
+    println!("[ interrupts ] Installing IRQs.");
+    builder
+        .interrupt(0x20, counted_handler!(0x20, irq::timer))?
+        .interrupt(0x30, counted_handler!(0x30, irq::timer))?
+        .interrupt(apic::TIMER_VECTOR, counted_handler!(apic::TIMER_VECTOR, irq::timer))?
+        .interrupt(tlb::SHOOTDOWN_VECTOR, tlb::shootdown_handler)?;
+
+    // APIC NMI.
+    let apic_nmi_handlers = [
+        counted_handler!(0x90, apic_nmi),
+        counted_handler!(0x91, apic_nmi),
+        counted_handler!(0x92, apic_nmi),
+        counted_handler!(0x93, apic_nmi),
+        counted_handler!(0x94, apic_nmi),
+        counted_handler!(0x95, apic_nmi),
+        counted_handler!(0x96, apic_nmi),
+    ];
+    for (vector, &handler) in (0x90..0x97).zip(apic_nmi_handlers.iter()) {
+        builder.interrupt(vector, handler)?;
+    }
+    builder.interrupt(0xff, spurious_interrupt_handler)?;
+
+    builder.finalize()
 }
 
 static TSS: Once<TaskStateSegment> = Once::new();
@@ -67,13 +146,21 @@ pub fn init(memory_controller: &mut MemoryController) {
     use x86_64::VirtualAddress;
 
     let double_fault_stack = memory_controller
-        .alloc_stack(1)
+        .alloc_stack(IST_STACK_PAGES)
         .expect("could not allocate double fault stack");
+    let page_fault_stack = memory_controller
+        .alloc_stack(IST_STACK_PAGES)
+        .expect("could not allocate page fault stack");
+    let nmi_stack = memory_controller
+        .alloc_stack(IST_STACK_PAGES)
+        .expect("could not allocate NMI stack");
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
             VirtualAddress(double_fault_stack.top());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(page_fault_stack.top());
+        tss.interrupt_stack_table[NMI_IST_INDEX] = VirtualAddress(nmi_stack.top());
         //TODO allocate privilege stacks.
         tss
     });
@@ -106,11 +193,27 @@ pub fn init(memory_controller: &mut MemoryController) {
     println!("[ tables ] Successfully loaded IDT.")
 }
 
-pub extern "x86-interrupt" fn apic_nmi_handler(stack_frame: &mut ExceptionStackFrame) {
-    println!("NON-MASKABLE APIC INTERRUPT!");
-    loop {}
+/// Return the bottom and top of the interrupt stack in slot `index` of the interrupt stack table,
+/// or `None` if there is none or the TSS is not loaded yet.
+pub fn ist_stack(index: usize) -> Option<(usize, usize)> {
+    use arch::memory::PAGE_SIZE;
+
+    let top = TSS.try()?.interrupt_stack_table.get(index)?.0;
+    if top == 0 {
+        return None;
+    }
+
+    Some((top - IST_STACK_PAGES * PAGE_SIZE, top))
+}
+
+/// Handler for the NMIs routed through the local APIC's LINT pins, installed on each of their
+/// vectors through `counted_handler!`.
+pub fn apic_nmi(_stack_frame: &mut ExceptionStackFrame) {
+    emergency_println!("NON-MASKABLE APIC INTERRUPT!");
+    halt_forever()
 }
 
 pub extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut ExceptionStackFrame) {
+    stats::count(0xff);
     println!("SPURIOUS INTERRUPT!");
 }
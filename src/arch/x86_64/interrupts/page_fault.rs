@@ -0,0 +1,131 @@
+//! Working out why a page fault happened from the faulting address and the error code, so that the
+//! page fault handler can say "NULL dereference" instead of leaving the bits to be decoded by hand.
+
+use arch::memory::PAGE_SIZE;
+use arch::memory::{demand, stack_allocator};
+use arch::memory::paging::{EntryFlags, Mapper, Page, VirtualAddress};
+use core::fmt;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// The likely cause of a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// An access to the first page, which is left unmapped to catch NULL pointers.
+    NullDereference,
+    /// An access to the guard page below a kernel stack.
+    StackOverflow,
+    /// The first touch of a page in a demand-paged region, such as the heap, with no frame left to
+    /// map there.
+    DemandExhausted,
+    /// A write to a copy-on-write page, with no frame left to copy it to.
+    CopyOnWriteExhausted,
+    /// A page table entry had a reserved bit set.
+    MalformedPageTable,
+    /// User mode touched a page that is only accessible to the kernel.
+    UserAccessToKernel,
+    /// An instruction fetch from a page mapped no-execute.
+    ExecuteNoExecute,
+    /// A write to a page mapped read-only.
+    WriteToReadOnly,
+    /// An access to a page which is not mapped.
+    NotMapped,
+    /// A protection violation not covered above.
+    ProtectionViolation,
+}
+
+impl PageFaultKind {
+    /// A short description of the cause.
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            PageFaultKind::NullDereference => "NULL dereference",
+            PageFaultKind::StackOverflow => "kernel stack overflow",
+            PageFaultKind::DemandExhausted => "no frame left to map on demand",
+            PageFaultKind::CopyOnWriteExhausted => "no frame left to copy a copy-on-write page",
+            PageFaultKind::MalformedPageTable => "reserved bit set in a page table entry",
+            PageFaultKind::UserAccessToKernel => "user mode access to kernel memory",
+            PageFaultKind::ExecuteNoExecute => "instruction fetch from no-execute memory",
+            PageFaultKind::WriteToReadOnly => "write to read-only memory",
+            PageFaultKind::NotMapped => "access to unmapped memory",
+            PageFaultKind::ProtectionViolation => "protection violation",
+        }
+    }
+}
+
+/// Classify a page fault on `address` with the given error code.
+pub fn classify(address: usize, error_code: PageFaultErrorCode) -> PageFaultKind {
+    let present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        PageFaultKind::MalformedPageTable
+    } else if address < PAGE_SIZE {
+        PageFaultKind::NullDereference
+    } else if stack_allocator::is_guard_page(address) {
+        PageFaultKind::StackOverflow
+    } else if !present && demand::is_reserved(address) {
+        PageFaultKind::DemandExhausted
+    } else if !present {
+        PageFaultKind::NotMapped
+    } else if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        PageFaultKind::UserAccessToKernel
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        PageFaultKind::ExecuteNoExecute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && copy_on_write(address) {
+        PageFaultKind::CopyOnWriteExhausted
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        PageFaultKind::WriteToReadOnly
+    } else {
+        PageFaultKind::ProtectionViolation
+    }
+}
+
+/// Return whether `address` is mapped copy-on-write.
+fn copy_on_write(address: usize) -> bool {
+    let page = match VirtualAddress::try_new(address) {
+        Some(address) => Page::containing_address(address),
+        None => return false,
+    };
+    // Only reads the page tables, through the recursive mapping.
+    let mapper = unsafe { Mapper::new() };
+
+    mapper
+        .page_flags(page)
+        .map_or(false, |flags| flags.contains(EntryFlags::COPY_ON_WRITE))
+}
+
+/// Displays each bit of a page fault error code in words.
+pub struct ErrorCodeWords(pub PageFaultErrorCode);
+
+impl fmt::Display for ErrorCodeWords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+
+        write!(
+            f,
+            "{}, {}, {}",
+            if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                "page present"
+            } else {
+                "page not present"
+            },
+            if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                "write"
+            } else {
+                "read"
+            },
+            if code.contains(PageFaultErrorCode::USER_MODE) {
+                "user mode"
+            } else {
+                "kernel mode"
+            }
+        )?;
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set")?;
+        }
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            write!(f, ", instruction fetch")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Scoped catching of faults, for probing hardware which may not be there, such as an MSR the CPU
+//! might not implement.
+//!
+//! `probe` runs a closure with a catcher installed for the vectors asked for. If the closure
+//! raises one of them, the exception handler does not halt: it points the interrupted frame at
+//! `probe_landing` (in `probe.asm`), which restores the registers and stack pointer saved when the
+//! closure was called, so `probe` returns `Err` as if the closure had returned early.
+//!
+//! Catchers nest. Each one remembers the catcher it replaced and puts it back when it is done, and
+//! a fault goes to the innermost catcher which asked for its vector, abandoning any probes inside
+//! it. Only GPFs and invalid opcodes can be caught, and only when raised by the probing code itself
+//! rather than by an interrupt handler which happened to run during the probe.
+//!
+//! Nothing the closure was in the middle of is undone: values it owned are leaked, and any lock it
+//! held stays locked. Keep probes down to the instruction being tested.
+
+use super::exceptions::{from_user_mode, GPF_VECTOR, INVALID_OPCODE_VECTOR};
+use super::utils::redirect_frame;
+use arch::percpu;
+use task::{preempt_disable, preempt_enable};
+use x86_64::structures::idt::ExceptionStackFrame;
+
+/// The vectors `probe` can catch.
+const CATCHABLE: [u8; 2] = [GPF_VECTOR, INVALID_OPCODE_VECTOR];
+
+extern "C" {
+    fn probe_call(catcher: *mut Catcher, f: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+    fn probe_landing();
+}
+
+/// A fault caught by `probe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeFault {
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    pub instruction_pointer: usize,
+}
+
+/// An active probe. The saved registers must come first, in the order `probe.asm` uses.
+#[repr(C)]
+struct Catcher {
+    /// RBX, RBP, R12 to R15 and RSP as they were when the closure was called.
+    registers: [usize; 7],
+    /// The catcher this one replaced, or zero.
+    prev: usize,
+    /// Bit mask of the vectors to catch.
+    vectors: u32,
+    /// Interrupt nesting depth of the probing code.
+    interrupt_depth: usize,
+    /// Preemption disable count while the closure runs, to put back after abandoning inner probes.
+    preempt_count: usize,
+    fault: Option<ProbeFault>,
+}
+
+/// Run `f`, returning `Err` instead of halting if it raises one of the exceptions in `vectors`.
+/// Panics if asked to catch a vector other than `GPF_VECTOR` or `INVALID_OPCODE_VECTOR`.
+pub fn probe<F, T>(vectors: &[u8], f: F) -> Result<T, ProbeFault>
+where
+    F: FnOnce() -> T,
+{
+    let mut mask = 0;
+    for &vector in vectors {
+        assert!(CATCHABLE.contains(&vector), "probe cannot catch vector {}", vector);
+        mask |= 1 << vector;
+    }
+
+    let mut state: (Option<F>, Option<T>) = (Some(f), None);
+    let mut catcher = Catcher {
+        registers: [0; 7],
+        prev: percpu::probe_catcher(),
+        vectors: mask,
+        interrupt_depth: percpu::interrupt_depth(),
+        preempt_count: 0,
+        fault: None,
+    };
+
+    // The catcher belongs to this CPU, so we must not be moved off it mid-probe.
+    preempt_disable();
+    catcher.preempt_count = percpu::preempt_count();
+    percpu::set_probe_catcher(&mut catcher as *mut Catcher as usize);
+
+    let faulted = unsafe {
+        probe_call(
+            &mut catcher,
+            call_closure::<F, T>,
+            &mut state as *mut (Option<F>, Option<T>) as *mut u8,
+        ) != 0
+    };
+
+    percpu::set_probe_catcher(catcher.prev);
+    if faulted {
+        // Abandoned inner probes never re-enabled preemption.
+        percpu::set_preempt_count(catcher.preempt_count);
+    }
+    preempt_enable();
+
+    if faulted {
+        Err(catcher.fault.expect("probe resumed without a fault"))
+    } else {
+        Ok(state.1.take().expect("probe closure did not run"))
+    }
+}
+
+extern "C" fn call_closure<F, T>(data: *mut u8)
+where
+    F: FnOnce() -> T,
+{
+    let state = unsafe { &mut *(data as *mut (Option<F>, Option<T>)) };
+
+    if let Some(f) = state.0.take() {
+        state.1 = Some(f());
+    }
+}
+
+/// If an active probe on this CPU catches exception `vector`, record the fault and point
+/// `stack_frame` at the probe's landing code. Returns whether the handler should return at once.
+pub fn catch(vector: u8, error_code: Option<u64>, stack_frame: &mut ExceptionStackFrame) -> bool {
+    if from_user_mode(stack_frame) {
+        return false;
+    }
+
+    let mut current = percpu::probe_catcher();
+    while current != 0 {
+        let catcher = unsafe { &mut *(current as *mut Catcher) };
+
+        // Raised by an interrupt handler which ran during the probe, not by the probe. The
+        // exception handler calling this accounts for one level of nesting.
+        if catcher.interrupt_depth + 1 != percpu::interrupt_depth() {
+            return false;
+        }
+
+        if catcher.vectors & (1 << vector) != 0 {
+            catcher.fault = Some(ProbeFault {
+                vector: vector,
+                error_code: error_code,
+                instruction_pointer: stack_frame.instruction_pointer.0,
+            });
+
+            // Any probes inside this one are abandoned along with their stack frames.
+            percpu::set_probe_catcher(current);
+
+            redirect_frame(stack_frame, probe_landing as usize, catcher.registers[6]);
+            return true;
+        }
+
+        current = catcher.prev;
+    }
+
+    false
+}
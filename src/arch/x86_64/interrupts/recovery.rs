@@ -0,0 +1,152 @@
+//! Configurable responses to CPU exceptions, for runs such as fuzzing where a fault should not end
+//! the whole kernel.
+//!
+//! Every exception vector has a `RecoveryPolicy`, which starts as `Halt`. The handlers of the
+//! recoverable exceptions (divide by zero, overflow, bound range, invalid opcode, segment faults,
+//! GPF, page fault, alignment check and the floating point exceptions) consult it after the test
+//! harness has had its turn. Double faults, NMIs and machine checks always halt.
+//!
+//! # Safety
+//!
+//! Neither recovering policy is safe in general, which is why neither is the default:
+//!
+//! - `KillTask` abandons the task where it faulted. Any lock it held stays locked, and anything it
+//!   was halfway through updating stays half updated. If the faulting task is the null kernel
+//!   process, there is nothing to switch to, so the kernel halts instead.
+//! - `SkipInstruction` carries on as if the faulting instruction had done nothing. Whatever it
+//!   should have produced (a loaded value, a stored value, a quotient) is missing, and later code
+//!   runs on that garbage. This only makes sense for faults where the instruction is a probe whose
+//!   result does not matter. Only simple `mov` forms can be decoded, and any other instruction is
+//!   handled as `KillTask`.
+
+use arch::interrupts::InterruptGuard;
+use core::ptr;
+use spin::Mutex;
+
+/// What to do when an exception is raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Print the exception and halt the CPU.
+    Halt,
+    /// Terminate the task which raised the exception, and schedule another.
+    KillTask,
+    /// Resume after the faulting instruction, if its length can be decoded. Otherwise kill the
+    /// task.
+    SkipInstruction,
+}
+
+/// Number of exception vectors.
+const EXCEPTION_VECTORS: usize = 32;
+
+static POLICIES: Mutex<[RecoveryPolicy; EXCEPTION_VECTORS]> =
+    Mutex::new([RecoveryPolicy::Halt; EXCEPTION_VECTORS]);
+
+/// Set the response to exception `vector`.
+pub fn set_recovery_policy(vector: u8, policy: RecoveryPolicy) {
+    // Exception handlers take the lock, so it must not be held with interrupts enabled.
+    let _guard = InterruptGuard::new();
+
+    if let Some(slot) = POLICIES.lock().get_mut(vector as usize) {
+        *slot = policy;
+    }
+}
+
+/// Return the response to exception `vector`.
+pub fn recovery_policy(vector: u8) -> RecoveryPolicy {
+    let _guard = InterruptGuard::new();
+
+    POLICIES
+        .lock()
+        .get(vector as usize)
+        .cloned()
+        .unwrap_or(RecoveryPolicy::Halt)
+}
+
+/// Decode the length of the instruction at `address`. Only `mov` between a register and memory,
+/// `mov` of an immediate to memory, and `movzx`/`movsx` are understood; anything else returns
+/// `None`.
+///
+/// The instruction must be readable, which it is if it was just executed.
+pub fn instruction_length(address: usize) -> Option<usize> {
+    let byte = |offset: usize| unsafe { ptr::read_volatile((address + offset) as *const u8) };
+
+    let mut len = 0;
+    let mut operand_size_16 = false;
+    let mut rex_w = false;
+
+    // Legacy prefixes, then at most one REX prefix.
+    loop {
+        match byte(len) {
+            0x66 => operand_size_16 = true,
+            0x67 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => (),
+            _ => break,
+        }
+        len += 1;
+
+        // Instructions are at most 15 bytes.
+        if len >= 15 {
+            return None;
+        }
+    }
+
+    if byte(len) & 0xf0 == 0x40 {
+        rex_w = byte(len) & 0x08 != 0;
+        len += 1;
+    }
+
+    // Opcode, and the size of any immediate after the ModRM operand.
+    let immediate = match byte(len) {
+        0x88 | 0x89 | 0x8a | 0x8b => {
+            len += 1;
+            0
+        }
+        0xc6 => {
+            len += 1;
+            1
+        }
+        0xc7 => {
+            len += 1;
+            // A 64-bit mov still takes a 32-bit immediate.
+            if operand_size_16 && !rex_w {
+                2
+            } else {
+                4
+            }
+        }
+        0x0f => match byte(len + 1) {
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                len += 2;
+                0
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let modrm = byte(len);
+    len += 1;
+
+    let mode = modrm >> 6;
+    let rm = modrm & 0x7;
+
+    // A SIB byte follows when r/m is 100 and the operand is in memory.
+    if mode != 0b11 && rm == 0b100 {
+        let sib = byte(len);
+        len += 1;
+
+        // With no displacement mode, a SIB base of 101 means a 32-bit displacement.
+        if mode == 0b00 && sib & 0x7 == 0b101 {
+            len += 4;
+        }
+    }
+
+    len += match mode {
+        // r/m 101 without a displacement mode is RIP relative, with a 32-bit displacement.
+        0b00 if rm == 0b101 => 4,
+        0b01 => 1,
+        0b10 => 4,
+        _ => 0,
+    };
+
+    Some(len + immediate)
+}
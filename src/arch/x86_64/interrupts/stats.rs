@@ -0,0 +1,124 @@
+//! Counts of interrupts and exceptions taken on each vector, for spotting interrupt storms.
+//!
+//! Every handler counts its vector as the first thing it does. Counting is a single relaxed atomic
+//! increment with no lock, so it is safe even from the NMI handler. A handler which serves several
+//! vectors is installed through `counted_handler!`, which gives each vector its own entry point,
+//! since a handler is not told which vector it was called through.
+
+use super::exceptions::*;
+use core::sync::atomic::{AtomicU64, Ordering, ATOMIC_U64_INIT};
+
+/// A row of 16 counters.
+macro_rules! counter_row {
+    () => {
+        [
+            ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT,
+            ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT,
+            ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT,
+            ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT, ATOMIC_U64_INIT,
+        ]
+    };
+}
+
+/// Interrupts taken on each vector, indexed by the vector's high and low nibbles.
+static COUNTS: [[AtomicU64; 16]; 16] = [
+    counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+    counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+    counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+    counter_row!(), counter_row!(), counter_row!(), counter_row!(),
+];
+
+/// Define a handler for `$vector` which counts the interrupt and then calls `$handler`, an
+/// ordinary function taking the stack frame. This lets one handler serve several vectors, each
+/// counted separately.
+macro_rules! counted_handler {
+    ($vector:expr, $handler:path) => {{
+        extern "x86-interrupt" fn handler(stack_frame: &mut ExceptionStackFrame) {
+            ::arch::interrupts::stats::count($vector);
+            $handler(stack_frame);
+        }
+        handler as HandlerFunc
+    }};
+}
+
+fn counter(vector: u8) -> &'static AtomicU64 {
+    &COUNTS[(vector >> 4) as usize][(vector & 0xf) as usize]
+}
+
+/// Count an interrupt on `vector`.
+#[inline(always)]
+pub fn count(vector: u8) {
+    counter(vector).fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the number of interrupts taken on `vector` so far, on every CPU.
+pub fn count_of(vector: u8) -> u64 {
+    counter(vector).load(Ordering::Relaxed)
+}
+
+/// Return what `vector` is used for, or `None` if nothing in particular.
+pub fn vector_name(vector: u8) -> Option<&'static str> {
+    use arch::memory::paging::tlb::SHOOTDOWN_VECTOR;
+    use device::apic::TIMER_VECTOR;
+
+    let name = match vector {
+        DIVIDE_BY_ZERO_VECTOR => "divide by zero",
+        DEBUG_VECTOR => "debug",
+        NMI_VECTOR => "NMI",
+        BREAKPOINT_VECTOR => "breakpoint",
+        OVERFLOW_VECTOR => "overflow",
+        BOUND_RANGE_VECTOR => "bound range exceeded",
+        INVALID_OPCODE_VECTOR => "invalid opcode",
+        DEVICE_NOT_AVAILABLE_VECTOR => "device not available",
+        DOUBLE_FAULT_VECTOR => "double fault",
+        INVALID_TSS_VECTOR => "invalid TSS",
+        SEGMENT_NOT_PRESENT_VECTOR => "segment not present",
+        STACK_SEGMENT_FAULT_VECTOR => "stack segment fault",
+        GPF_VECTOR => "general protection fault",
+        PAGE_FAULT_VECTOR => "page fault",
+        X87_FP_VECTOR => "x87 floating point",
+        ALIGNMENT_CHECK_VECTOR => "alignment check",
+        MACHINE_CHECK_VECTOR => "machine check",
+        SIMD_FP_VECTOR => "SIMD floating point",
+        0x20 => "PIT timer (PIC)",
+        0x21 => "keyboard (PIC)",
+        0x30 => "PIT timer (I/O APIC)",
+        0x31 => "keyboard (I/O APIC)",
+        SHOOTDOWN_VECTOR => "TLB shootdown",
+        TIMER_VECTOR => "APIC timer",
+        0x90...0x96 => "APIC NMI",
+        0xff => "spurious",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+/// Register the `irqstats` command. This must be called after the shell is set up.
+pub fn init() {
+    ::shell::register("irqstats", "Show the interrupts taken on each vector.", irqstats)
+        .expect("irqstats registered twice");
+}
+
+fn irqstats(_args: &[&str]) -> i32 {
+    interrupt_stats();
+    0
+}
+
+/// Print the number of interrupts taken on every vector which has had any.
+pub fn interrupt_stats() {
+    println!("[ interrupts ] Interrupts taken per vector:");
+
+    for vector in 0..256 {
+        let vector = vector as u8;
+        let count = count_of(vector);
+        if count == 0 {
+            continue;
+        }
+
+        match vector_name(vector) {
+            Some(name) => println!("[ interrupts ] {:#04x} {}: {}", vector, name, count),
+            None => println!("[ interrupts ] {:#04x}: {}", vector, count),
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use core::ptr;
+use x86_64::VirtualAddress;
+use x86_64::structures::idt::ExceptionStackFrame;
+
 unsafe fn disable() {
     asm!("cli");
 }
@@ -6,6 +10,63 @@ unsafe fn enable() {
     asm!("sti");
 }
 
+/// Enable interrupts and halt until the next one. `sti` only takes effect after the next
+/// instruction, so no interrupt can slip in between the two and leave us halted with nothing to
+/// wake us. Call this with interrupts disabled after checking that there is nothing to do, and an
+/// interrupt which makes work either came before the check or ends the halt.
+pub unsafe fn enable_and_halt() {
+    asm!("sti; hlt" : : : "memory" : "volatile");
+}
+
+/// Halt the CPU for good with interrupts disabled. Only an NMI can wake it, after which it halts
+/// again. Use this after a fatal error, when no more interrupts must be taken.
+pub fn halt_forever() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" : : : : "volatile") };
+    }
+}
+
+/// Return true if maskable interrupts are currently enabled, i.e the IF bit in RFLAGS is set.
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe { asm!("pushfq; pop $0" : "=r"(rflags) : : "memory" : "intel", "volatile") };
+
+    rflags & (1 << 9) != 0
+}
+
+/// A scoped critical section. Creating a guard disables interrupts, and dropping it re-enables them
+/// only if they were enabled when the guard was created. This means guards nest correctly: an inner
+/// guard will never re-enable interrupts while an outer guard is still alive.
+#[must_use = "Interrupts are re-enabled as soon as the guard is dropped."]
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+impl InterruptGuard {
+    /// Save the current interrupt state and disable interrupts.
+    pub fn new() -> Self {
+        let was_enabled = interrupts_enabled();
+
+        unsafe {
+            disable();
+        }
+
+        InterruptGuard {
+            was_enabled: was_enabled,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe {
+                enable();
+            }
+        }
+    }
+}
+
 /// Disable all interrupts and save the PIC masks
 pub fn disable_interrupts() -> (u8, u8) {
     use device::pic::PICS;
@@ -81,3 +142,26 @@ where
 
     result
 }
+
+/// Make an exception handler return to `instruction_pointer` on the stack at `stack_pointer`,
+/// rather than to where the exception was raised, by rewriting `stack_frame`. The frame is read
+/// back by `iretq`, which the compiler cannot see, so the writes are volatile lest they be
+/// optimised away.
+pub fn redirect_frame(
+    stack_frame: &mut ExceptionStackFrame,
+    instruction_pointer: usize,
+    stack_pointer: usize,
+) {
+    unsafe {
+        ptr::write_volatile(
+            &mut stack_frame.instruction_pointer,
+            VirtualAddress(instruction_pointer),
+        );
+        ptr::write_volatile(&mut stack_frame.stack_pointer, VirtualAddress(stack_pointer));
+    }
+}
+
+/// Make an exception handler return with `RFLAGS` set to `flags`, like `redirect_frame`.
+pub fn set_frame_flags(stack_frame: &mut ExceptionStackFrame, flags: u64) {
+    unsafe { ptr::write_volatile(&mut stack_frame.cpu_flags, flags) };
+}
@@ -0,0 +1,61 @@
+//! Checked access to arbitrary kernel memory, for the debugger and for driver bring-up.
+//!
+//! Every page a `peek` or `poke` would touch is checked with the page tables before any access is
+//! made, so an unmapped or read-only address gives an error instead of a page fault. A range which
+//! is only partly mapped fails as a whole, without reading or writing anything.
+
+use super::paging::{EntryFlags, Mapper, Page, VirtualAddress};
+use core::slice;
+
+/// Check that `len` bytes at `start` are mapped, and writable if `write` is set.
+fn check_range(start: usize, len: usize, write: bool) -> Result<(), &'static str> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = start.checked_add(len - 1).ok_or("range wraps around")?;
+
+    // The range must lie entirely in the lower or the upper canonical half.
+    let lower_half = end < 0x0000_8000_0000_0000;
+    let upper_half = start >= 0xffff_8000_0000_0000;
+    if !lower_half && !upper_half {
+        return Err("non-canonical address");
+    }
+
+    // Only reads the page tables, through the recursive mapping.
+    let mapper = unsafe { Mapper::new() };
+    let first = Page::containing_address(VirtualAddress::new(start));
+    let last = Page::containing_address(VirtualAddress::new(end));
+
+    for page in Page::range_inclusive(first, last) {
+        let flags = mapper.page_flags(page).ok_or("page not mapped")?;
+
+        if write && !flags.contains(EntryFlags::WRITABLE) {
+            return Err("page not writable");
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the `len` bytes at `address`, failing if any of them is unmapped.
+///
+/// This is unsafe because the memory may be changed or unmapped while the slice is alive.
+pub unsafe fn peek(address: VirtualAddress, len: usize) -> Result<&'static [u8], &'static str> {
+    check_range(address.get(), len, false)?;
+
+    Ok(slice::from_raw_parts(address.get() as *const u8, len))
+}
+
+/// Copy `data` to `address`, failing without writing anything if any byte of the destination is
+/// unmapped or read-only.
+///
+/// This is unsafe because it can overwrite any writable kernel memory.
+pub unsafe fn poke(address: VirtualAddress, data: &[u8]) -> Result<(), &'static str> {
+    check_range(address.get(), data.len(), true)?;
+
+    let destination = slice::from_raw_parts_mut(address.get() as *mut u8, data.len());
+    destination.copy_from_slice(data);
+
+    Ok(())
+}
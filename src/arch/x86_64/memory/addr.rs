@@ -0,0 +1,56 @@
+//! Address alignment arithmetic, so that the memory code doesn't repeat it with masks and magic
+//! constants. Every alignment must be a power of two.
+
+use super::PAGE_SIZE;
+
+/// The size of a huge page, which a single P2 entry maps.
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * 512;
+/// The size of a 1 GiB page, which a single P3 entry maps.
+pub const HUGE_1GIB_PAGE_SIZE: usize = HUGE_PAGE_SIZE * 512;
+
+/// Round `addr` down to a multiple of `align`.
+pub fn align_down(addr: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two(), "alignment is not a power of two");
+    addr & !(align - 1)
+}
+
+/// Round `addr` up to a multiple of `align`, or return `None` if that would overflow.
+pub fn align_up(addr: usize, align: usize) -> Option<usize> {
+    debug_assert!(align.is_power_of_two(), "alignment is not a power of two");
+    addr.checked_add(align - 1).map(|addr| align_down(addr, align))
+}
+
+/// Return whether `addr` is a multiple of `align`.
+pub fn is_aligned(addr: usize, align: usize) -> bool {
+    align_down(addr, align) == addr
+}
+
+/// Round `addr` down to the start of its page.
+pub fn page_align_down(addr: usize) -> usize {
+    align_down(addr, PAGE_SIZE)
+}
+
+/// Round `addr` up to the start of a page, or return `None` if that would overflow.
+pub fn page_align_up(addr: usize) -> Option<usize> {
+    align_up(addr, PAGE_SIZE)
+}
+
+/// Return whether `addr` is the start of a page.
+pub fn is_page_aligned(addr: usize) -> bool {
+    is_aligned(addr, PAGE_SIZE)
+}
+
+/// Return the number of the page, or frame, which contains `addr`.
+pub fn page_number(addr: usize) -> usize {
+    page_align_down(addr) / PAGE_SIZE
+}
+
+/// Return the offset of `addr` into its page.
+pub fn page_offset(addr: usize) -> usize {
+    addr - page_align_down(addr)
+}
+
+/// Return the number of pages needed to hold `size` bytes, or `None` if that would overflow.
+pub fn pages_for(size: usize) -> Option<usize> {
+    page_align_up(size).map(|size| size / PAGE_SIZE)
+}
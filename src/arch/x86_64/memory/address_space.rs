@@ -0,0 +1,245 @@
+//! A record of which ranges of virtual memory are in use, what they may be used for, and what
+//! backs them, so that the page fault handler, and later `mmap`, can look an address up instead of
+//! guessing from the page tables.
+//!
+//! Every task shares the kernel's address space for now, so there is a single `AddressSpace`, set
+//! up by `memory::init` with the areas it reserves. The page tables stay the authority on what is
+//! mapped: an area says what may be mapped in a range, not that it all is.
+
+use alloc::btree_map::{self, BTreeMap};
+use arch::interrupts::disable_interrupts_and_then;
+use arch::memory::paging::{EntryFlags, Page, PhysicalAddress, VirtualAddress};
+use core::fmt;
+use spin::RwLock;
+use task::cwd::PathName;
+
+/// What the memory of an area comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Fresh frames, which start zeroed.
+    Anonymous,
+    /// The contents of a file, starting `offset` bytes in.
+    File { path: PathName, offset: usize },
+    /// Fixed physical memory starting at `physical`, such as device registers.
+    Device { physical: PhysicalAddress },
+}
+
+impl fmt::Display for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Backing::Anonymous => write!(f, "anonymous"),
+            Backing::File { ref path, offset } => write!(f, "{} at {:#x}", path, offset),
+            Backing::Device { physical } => write!(f, "physical {:#x}", physical.get()),
+        }
+    }
+}
+
+/// A range of virtual memory in use, with the flags its pages are mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualMemoryArea {
+    start: VirtualAddress,
+    /// One past the last byte.
+    end: usize,
+    flags: EntryFlags,
+    backing: Backing,
+    name: &'static str,
+}
+
+impl VirtualMemoryArea {
+    /// Describe the `size` bytes from `start`, both page aligned. Fails if the range is empty,
+    /// unaligned, or wraps around.
+    pub fn new(
+        start: VirtualAddress,
+        size: usize,
+        flags: EntryFlags,
+        backing: Backing,
+        name: &'static str,
+    ) -> Result<Self, &'static str> {
+        use arch::memory::addr::is_page_aligned;
+
+        if size == 0 || !is_page_aligned(start.get()) || !is_page_aligned(size) {
+            return Err("area is not page aligned");
+        }
+        let end = start.get().checked_add(size).ok_or("area wraps around")?;
+
+        Ok(VirtualMemoryArea {
+            start: start,
+            end: end,
+            flags: flags,
+            backing: backing,
+            name: name,
+        })
+    }
+
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// Return the address one past the last byte of the area.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start.get()
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Return whether `address` lies in the area.
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start.get() && address < self.end
+    }
+
+    /// Return whether the area shares any address with `other`.
+    pub fn overlaps(&self, other: &VirtualMemoryArea) -> bool {
+        self.start.get() < other.end && other.start.get() < self.end
+    }
+
+    /// Return the first and last pages of the area.
+    pub fn pages(&self) -> (Page, Page) {
+        (
+            Page::containing_address(self.start),
+            Page::containing_address(VirtualAddress::new(self.end - 1)),
+        )
+    }
+}
+
+impl fmt::Display for VirtualMemoryArea {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}-{:#x} {} ({}, {:?})",
+            self.start.get(),
+            self.end,
+            self.name,
+            self.backing,
+            self.flags
+        )
+    }
+}
+
+/// The areas in use in an address space, which never overlap.
+pub struct AddressSpace {
+    /// Keyed by start address.
+    areas: BTreeMap<usize, VirtualMemoryArea>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        AddressSpace {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Record `area`. Fails if it overlaps an area already recorded.
+    pub fn insert(&mut self, area: VirtualMemoryArea) -> Result<(), &'static str> {
+        // Only the areas on either side of the new one's start can overlap it.
+        let below = self.areas.range(..area.start.get()).next_back();
+        let above = self.areas.range(area.start.get()..).next();
+        let overlaps = below
+            .into_iter()
+            .chain(above)
+            .any(|(_, other)| other.overlaps(&area));
+        if overlaps {
+            return Err("area overlaps another");
+        }
+
+        self.areas.insert(area.start.get(), area);
+        Ok(())
+    }
+
+    /// Forget the area starting at `start`, and return it.
+    pub fn remove(&mut self, start: VirtualAddress) -> Option<VirtualMemoryArea> {
+        self.areas.remove(&start.get())
+    }
+
+    /// Return the area containing `address`, if any.
+    pub fn find(&self, address: usize) -> Option<&VirtualMemoryArea> {
+        self.areas
+            .range(..address.saturating_add(1))
+            .next_back()
+            .map(|(_, area)| area)
+            .and_then(|area| if area.contains(address) { Some(area) } else { None })
+    }
+
+    /// Return the lowest page aligned address between `lowest` and `highest` at which `size` bytes
+    /// are free of any area, for placing a new one.
+    pub fn find_free(&self, size: usize, lowest: usize, highest: usize) -> Option<VirtualAddress> {
+        let mut candidate = lowest;
+
+        for area in self.areas.values() {
+            if area.end <= candidate {
+                continue;
+            }
+            if area.start.get() >= candidate.checked_add(size)? {
+                break;
+            }
+            candidate = area.end;
+        }
+
+        if candidate.checked_add(size)? <= highest {
+            VirtualAddress::try_new(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the areas, lowest first.
+    pub fn iter(&self) -> btree_map::Values<usize, VirtualMemoryArea> {
+        self.areas.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+}
+
+lazy_static! {
+    /// The kernel's address space, which every task shares. Writers disable interrupts, so that
+    /// the page fault handler can look an address up without waiting.
+    static ref KERNEL_SPACE: RwLock<AddressSpace> = RwLock::new(AddressSpace::new());
+}
+
+/// Record `area` in the kernel's address space. Fails if it overlaps an area already recorded.
+pub fn insert(area: VirtualMemoryArea) -> Result<(), &'static str> {
+    disable_interrupts_and_then(|| KERNEL_SPACE.write().insert(area))
+}
+
+/// Forget the area starting at `start` in the kernel's address space, and return it.
+pub fn remove(start: VirtualAddress) -> Option<VirtualMemoryArea> {
+    disable_interrupts_and_then(|| KERNEL_SPACE.write().remove(start))
+}
+
+/// Return the area of the kernel's address space containing `address`, if any. Does not wait for
+/// the address space, so that it can be used while reporting a fault, and gives `None` if it is
+/// being changed.
+pub fn area_at(address: usize) -> Option<VirtualMemoryArea> {
+    let space = KERNEL_SPACE.try_read()?;
+    let area = space.find(address).cloned();
+    area
+}
+
+/// Print every area of the kernel's address space.
+pub fn print_areas() {
+    let space = KERNEL_SPACE.read();
+
+    println!("[ vmm ] {} areas in the kernel's address space:", space.len());
+    for area in space.iter() {
+        println!("[ vmm ] {}", area);
+    }
+}
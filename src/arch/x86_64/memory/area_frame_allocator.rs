@@ -1,19 +1,28 @@
-use arch::memory::{Frame, FrameAllocator};
-use multiboot2::{MemoryArea, MemoryAreaIter};
+use alloc::Vec;
+use arch::memory::{addr, Frame, FrameAllocator, LOW_MEMORY_END, PAGE_SIZE};
+use arch::memory::frame_bitmap::FrameBitmap;
+use arch::memory::memory_map::{MemoryAreas, PhysicalArea};
 use arch::memory::paging::PhysicalAddress;
 
-/// A frame allocator that uses the memory areas from the multiboot information structure as
-/// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
-/// already in use.
+/// A frame allocator that uses the memory areas from the bootloader's memory map as source. The
+/// {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is already in
+/// use.
 ///
 /// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+///
+/// Memory below `LOW_MEMORY_END` is kept apart from general allocation, for the AP trampoline and
+/// legacy DMA, and is only handed out by `allocate_low_frame`. Freed low frames are recorded in a
+/// bitmap of their own.
+///
+/// Freed frames are recorded in a `FrameBitmap`, and single frames are handed out from there
+/// before fresh ones are taken from the memory areas.
 pub struct AreaFrameAllocator {
     /// The next available physical frame.
     next_free_frame: Frame,
     /// The current memory area, detected by multiboot using the e820.
-    current_area: Option<&'static MemoryArea>,
+    current_area: Option<PhysicalArea>,
     /// An iterator over all memory areas.
-    areas: MemoryAreaIter,
+    areas: MemoryAreas,
     /// The starting frame of the kernel in physical memory.
     /// frame.start_address().get() == 1MiB.
     kernel_start: Frame,
@@ -23,16 +32,30 @@ pub struct AreaFrameAllocator {
     multiboot_start: Frame,
     /// The end frame of the multiboot data structure in physical memory.
     multiboot_end: Frame,
+    /// The next low memory frame to try in `allocate_low_frame`.
+    next_low_frame: Frame,
+    /// The number of frames handed out and not freed.
+    allocated: usize,
+    /// Frames which were handed out and then freed.
+    freed: FrameBitmap<'static>,
+    /// Low memory frames which were handed out and then freed.
+    low_freed: FrameBitmap<'static>,
 }
 
 impl AreaFrameAllocator {
+    /// Build an allocator over `memory_areas`, which records freed frames in `freed`, and freed
+    /// low memory frames in `low_freed`, which must cover low memory. Fails if no
+    /// frame is left for general allocation once low memory, the kernel and the multiboot
+    /// information are taken out, since every allocation would fail later with far less context.
     pub fn new(
         kernel_start: usize,
         kernel_end: usize,
         multiboot_start: usize,
         multiboot_end: usize,
-        memory_areas: MemoryAreaIter,
-    ) -> AreaFrameAllocator {
+        memory_areas: MemoryAreas,
+        freed: FrameBitmap<'static>,
+        low_freed: FrameBitmap<'static>,
+    ) -> Result<AreaFrameAllocator, &'static str> {
         let mut allocator = AreaFrameAllocator {
             next_free_frame: Frame::containing_address(PhysicalAddress::new(0)),
             current_area: None,
@@ -41,10 +64,40 @@ impl AreaFrameAllocator {
             kernel_end: Frame::containing_address(PhysicalAddress::new(kernel_end)),
             multiboot_start: Frame::containing_address(PhysicalAddress::new(multiboot_start)),
             multiboot_end: Frame::containing_address(PhysicalAddress::new(multiboot_end)),
+            // Frame 0 holds the real mode IVT and the BIOS data area, so it is never handed out.
+            next_low_frame: Frame { number: 1 },
+            allocated: 0,
+            freed: freed,
+            low_freed: low_freed,
         };
+
+        let usable = allocator.usable_frames();
+        if usable == 0 {
+            println!(
+                "[ pmm ] The memory map reports {} KiB usable, none of it outside low memory, the \
+                 kernel and the multiboot information.",
+                allocator.total_frames() * PAGE_SIZE / 1024
+            );
+            return Err("no usable physical memory");
+        }
+        println!(
+            "[ pmm ] {} KiB of physical memory is free for allocation.",
+            usable * PAGE_SIZE / 1024
+        );
+
         allocator.choose_next_area();
         allocator.allocate_frame(1);
-        allocator
+        Ok(allocator)
+    }
+
+    /// Return the number of frames general allocation can hand out, i.e those in usable areas
+    /// above `LOW_MEMORY_END` which hold neither the kernel nor the multiboot information.
+    fn usable_frames(&self) -> usize {
+        let areas = self.areas.clone().map(|area| (area.start_address(), area.size()));
+        let kernel = (self.kernel_start.number, self.kernel_end.number);
+        let multiboot = (self.multiboot_start.number, self.multiboot_end.number);
+
+        usable_frames(areas, kernel, multiboot)
     }
 
     /// Choose the next available memory area.
@@ -69,11 +122,171 @@ impl AreaFrameAllocator {
             }
         }
     }
-}
 
-impl FrameAllocator for AreaFrameAllocator {
-    /// Allocate a single frame. Return `None` if we are out of memory.
-    fn allocate_frame(&mut self, count: usize) -> Option<Frame> {
+    /// Get the number of frames in all usable memory areas.
+    pub fn total_frames(&self) -> usize {
+        self.areas
+            .clone()
+            .map(|area| {
+                let start_frame =
+                    Frame::containing_address(PhysicalAddress::new(area.start_address()));
+                let end_frame = Frame::containing_address(PhysicalAddress::new(
+                    area.start_address() + area.size() - 1,
+                ));
+
+                end_frame.number - start_frame.number + 1
+            })
+            .sum()
+    }
+
+    /// Get the number of frames handed out and not freed.
+    pub fn used_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// Allocate `count` contiguous frames which all lie below the physical address `below`, for
+    /// devices which can only address part of physical memory. Frames are handed out in address
+    /// order, so if the next free range is not below the limit, no later one is either. Nothing is
+    /// allocated in that case. Freed frames are not considered.
+    pub fn allocate_frames_below(&mut self, count: usize, below: PhysicalAddress) -> Option<Frame> {
+        let next_free_frame = self.next_free_frame.clone();
+        let current_area = self.current_area;
+        let allocated = self.allocated;
+
+        let start_frame = self.allocate_fresh(count)?;
+        let end = (start_frame.number + count).checked_mul(PAGE_SIZE);
+
+        if end.map_or(false, |end| end <= below.get()) {
+            Some(start_frame)
+        } else {
+            // Undo the allocation. Nothing has been handed out since, so this is all there is to
+            // it.
+            self.next_free_frame = next_free_frame;
+            self.current_area = current_area;
+            self.allocated = allocated;
+            None
+        }
+    }
+
+    /// Allocate a single frame below `LOW_MEMORY_END`, for code which must run or be addressed in
+    /// real mode. Freed low frames are handed out again before fresh ones.
+    pub fn allocate_low_frame(&mut self) -> Option<Frame> {
+        if let Some(frame) = self.low_freed.take() {
+            self.allocated += 1;
+            return Some(frame);
+        }
+
+        let low_end = Frame::containing_address(PhysicalAddress::new(LOW_MEMORY_END));
+
+        while self.next_low_frame < low_end {
+            let frame = self.next_low_frame.clone();
+            self.next_low_frame.number += 1;
+
+            if self.is_usable_low_frame(&frame) {
+                self.allocated += 1;
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// Record a frame from `allocate_low_frame` as free, so that it can be handed out by it again.
+    /// Frames which were never handed out are ignored.
+    pub fn deallocate_low_frame(&mut self, frame: Frame) {
+        if frame.number == 0 || frame >= self.next_low_frame {
+            return;
+        }
+
+        if self.low_freed.insert(frame).is_ok() {
+            self.allocated -= 1;
+        }
+    }
+
+    /// Return whether `frame` lies wholly inside a usable memory area and holds neither the kernel
+    /// nor the multiboot information.
+    fn is_usable_low_frame(&self, frame: &Frame) -> bool {
+        let start = frame.start_address().get();
+        let in_area = self.areas.clone().any(|area| {
+            area.start_address() <= start && start + PAGE_SIZE <= area.start_address() + area.size()
+        });
+
+        in_area && !(*frame >= self.kernel_start && *frame <= self.kernel_end)
+            && !(*frame >= self.multiboot_start && *frame <= self.multiboot_end)
+    }
+
+    /// Allocate `count` frames which need not be contiguous, so this succeeds as long as that many
+    /// frames are free, even if no single run of them is. Either all `count` frames are allocated
+    /// or none are. The frames are pushed onto `frames`, which must be empty and have room for
+    /// them, so that the allocator never grows the heap, which may take the allocator itself.
+    pub fn allocate_scattered(
+        &mut self,
+        count: usize,
+        mut frames: Vec<Frame>,
+    ) -> Option<Vec<Frame>> {
+        debug_assert!(frames.is_empty() && frames.capacity() >= count);
+
+        // Fail early on counts which cannot possibly fit, rather than allocating up to them.
+        if count > self.total_frames() - self.allocated {
+            return None;
+        }
+
+        let next_free_frame = self.next_free_frame.clone();
+        let current_area = self.current_area;
+        let allocated = self.allocated;
+
+        while frames.len() < count {
+            match self.allocate_frame(1) {
+                Some(frame) => frames.push(frame),
+                None => {
+                    // Hand the fresh frames back by undoing the allocations, as above. Frames
+                    // below the old `next_free_frame` came from the freed ones, so they go back
+                    // there.
+                    self.next_free_frame = next_free_frame;
+                    self.current_area = current_area;
+                    self.allocated = allocated;
+                    for frame in frames {
+                        if frame < self.next_free_frame {
+                            let _ = self.freed.insert(frame);
+                        }
+                    }
+                    return None;
+                }
+            }
+        }
+
+        Some(frames)
+    }
+
+    /// Allocate `count` contiguous frames, the first of which is numbered a multiple of `align`,
+    /// which must be a power of two. Fresh frames skipped to get to the alignment are recorded as
+    /// freed, so they are not lost.
+    pub fn allocate_aligned(&mut self, count: usize, align: usize) -> Option<Frame> {
+        loop {
+            while !addr::is_aligned(self.next_free_frame.number, align) {
+                let frame = self.allocate_fresh(1)?;
+                self.deallocate_frame(frame);
+            }
+
+            let start_frame = self.allocate_fresh(count)?;
+            if addr::is_aligned(start_frame.number, align) {
+                return Some(start_frame);
+            }
+
+            // Reserved frames were skipped on the way, which broke the alignment.
+            for number in start_frame.number..start_frame.number + count {
+                self.deallocate_frame(Frame { number: number });
+            }
+        }
+    }
+
+    /// Return the number of frames which have been freed and not handed out again.
+    pub fn freed_frames(&self) -> usize {
+        self.freed.len()
+    }
+
+    /// Allocate `count` contiguous frames which have never been handed out before.
+    fn allocate_fresh(&mut self, count: usize) -> Option<Frame> {
         if count == 0 {
             return None;
         } else if let Some(area) = self.current_area {
@@ -84,7 +297,7 @@ impl FrameAllocator for AreaFrameAllocator {
             };
 
             let end_frame = Frame {
-                number: self.next_free_frame.number + (count - 1),
+                number: self.next_free_frame.number.checked_add(count - 1)?,
             };
 
             // the last frame of the current area
@@ -93,7 +306,12 @@ impl FrameAllocator for AreaFrameAllocator {
                 Frame::containing_address(PhysicalAddress::new(address))
             };
 
-            if end_frame > current_area_last_frame {
+            let low_end = Frame::containing_address(PhysicalAddress::new(LOW_MEMORY_END));
+
+            if start_frame < low_end {
+                // low memory is left to `allocate_low_frame`
+                self.next_free_frame = low_end;
+            } else if end_frame > current_area_last_frame {
                 // all frames of current area are used, switch to next area
                 self.choose_next_area();
             } else if (start_frame >= self.kernel_start && start_frame <= self.kernel_end)
@@ -111,19 +329,48 @@ impl FrameAllocator for AreaFrameAllocator {
                     number: self.multiboot_end.number + 1,
                 };
             } else {
-                // frame is unused, increment `next_free_frame` and return it
-                self.next_free_frame.number += 1;
+                // frames are unused, move `next_free_frame` past them and return them
+                self.next_free_frame.number = end_frame.number + 1;
+                self.allocated += count;
                 return Some(start_frame);
             }
             // `frame` was not valid, try it again with the updated `next_free_frame`
-            self.allocate_frame(count)
+            self.allocate_fresh(count)
         } else {
             None // no free frames left
         }
     }
+}
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+impl FrameAllocator for AreaFrameAllocator {
+    /// Allocate `count` contiguous frames. A single frame is taken from the freed frames if there
+    /// are any. Return `None` if we are out of memory.
+    fn allocate_frame(&mut self, count: usize) -> Option<Frame> {
+        if count == 1 {
+            if let Some(frame) = self.freed.take() {
+                self.allocated += 1;
+                return Some(frame);
+            }
+        }
+
+        self.allocate_fresh(count)
+    }
+
+    /// Record `frame` as free, so that it can be handed out again. Low frames go back to
+    /// `allocate_low_frame`, and frames the bitmap does not cover are dropped. Frames which were
+    /// never handed out are ignored.
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let low_end = Frame::containing_address(PhysicalAddress::new(LOW_MEMORY_END));
+        if frame < low_end {
+            return self.deallocate_low_frame(frame);
+        }
+        if frame >= self.next_free_frame {
+            return;
+        }
+
+        if self.freed.insert(frame).is_ok() {
+            self.allocated -= 1;
+        }
     }
 
     /// Get a count of available free frames.
@@ -147,6 +394,43 @@ impl FrameAllocator for AreaFrameAllocator {
             }
         }
 
-        count
+        count + self.freed.len()
     }
 }
+
+/// Return the number of frames in the areas given as `(start address, size)`, leaving out low
+/// memory and the two reserved ranges, given as inclusive frame numbers. The reserved ranges may
+/// overlap.
+pub fn usable_frames<I>(areas: I, first: (usize, usize), second: (usize, usize)) -> usize
+where
+    I: Iterator<Item = (usize, usize)>,
+{
+    // Number of frames in both inclusive ranges.
+    let overlap = |a: (usize, usize), b: (usize, usize)| {
+        let (start, end) = (a.0.max(b.0), a.1.min(b.1));
+        if start <= end {
+            end - start + 1
+        } else {
+            0
+        }
+    };
+    let both = (first.0.max(second.0), first.1.min(second.1));
+    let low_end = Frame::containing_address(PhysicalAddress::new(LOW_MEMORY_END)).number;
+
+    areas
+        .filter(|&(_, size)| size != 0)
+        .map(|(start, size)| {
+            let first_frame = Frame::containing_address(PhysicalAddress::new(start));
+            let last_frame = Frame::containing_address(PhysicalAddress::new(start + size - 1));
+            (first_frame.number.max(low_end), last_frame.number)
+        })
+        .filter(|&(start, end)| start <= end)
+        .map(|area| {
+            let mut frames = area.1 - area.0 + 1 - overlap(area, first) - overlap(area, second);
+            if both.0 <= both.1 {
+                frames += overlap(area, both);
+            }
+            frames
+        })
+        .sum()
+}
@@ -0,0 +1,271 @@
+//! Demand paging: regions of virtual memory which are reserved up front, but only backed by frames
+//! as they are touched.
+//!
+//! The first access to an unmapped page of a region faults, and the page fault handler calls
+//! `handle_fault`, which maps a zeroed frame there and resumes the faulting instruction. Frames
+//! come from the page fault handler's pool (see `frame_pool`), since the fault may have interrupted
+//! the frame allocator. Only once the pool is empty is the frame allocator tried, and then only if
+//! nobody holds it. The page tables for a region are all created when it is reserved, so that
+//! mapping a page never allocates one.
+
+use arch::interrupts::disable_interrupts_and_then;
+use arch::memory::{addr, deallocate_frame, frame_pool, try_allocate_frame, PAGE_SIZE};
+use arch::memory::paging::{phys_to_virt, ActivePageTable, EntryFlags, Page, VirtualAddress};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::{Mutex, RwLock};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Most regions which can be reserved at once.
+const MAX_REGIONS: usize = 16;
+
+/// A reserved range of pages.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    first: Page,
+    last: Page,
+    flags: EntryFlags,
+}
+
+impl Region {
+    fn contains(&self, page: Page) -> bool {
+        self.first <= page && page <= self.last
+    }
+}
+
+/// The reserved regions. Writers disable interrupts, and the page fault handler only reads.
+static REGIONS: RwLock<[Option<Region>; MAX_REGIONS]> = RwLock::new([None; MAX_REGIONS]);
+
+/// Pages mapped by `handle_fault` in the region in the same slot of `REGIONS`.
+static MAPPED: [AtomicUsize; MAX_REGIONS] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Held while `handle_fault` maps a page, so that two CPUs faulting on the same page do not both
+/// map it.
+static MAPPING: Mutex<()> = Mutex::new(());
+
+/// Reserve the `size` bytes from `start`, both page aligned, to be backed by frames mapped with
+/// `flags` as they are touched. Nothing in the range may be mapped yet. Fails if the range
+/// overlaps another region, every slot is taken, or we run out of frames for page tables.
+pub fn reserve(
+    active_table: &mut ActivePageTable,
+    start: VirtualAddress,
+    size: usize,
+    flags: EntryFlags,
+) -> Result<(), &'static str> {
+    if size == 0 || !addr::is_page_aligned(start.get()) || !addr::is_page_aligned(size) {
+        return Err("demand-paged region is not page aligned");
+    }
+
+    let region = Region {
+        first: Page::containing_address(start),
+        last: Page::containing_address(VirtualAddress::new(start.get() + size - 1)),
+        flags: flags,
+    };
+
+    disable_interrupts_and_then(|| {
+        let mut regions = REGIONS.write();
+
+        let overlaps = regions.iter().filter_map(|slot| *slot).any(|other| {
+            other.first <= region.last && region.first <= other.last
+        });
+        if overlaps {
+            return Err("demand-paged region overlaps another");
+        }
+        let index = regions
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or("too many demand-paged regions")?;
+
+        // One P1 table covers a huge page's worth of pages.
+        for page in Page::range_inclusive(region.first, region.last) {
+            let table_start = addr::is_aligned(page.start_address().get(), addr::HUGE_PAGE_SIZE);
+            if page == region.first || table_start {
+                active_table.try_create_tables(page)?;
+            }
+        }
+
+        MAPPED[index].store(0, Ordering::SeqCst);
+        regions[index] = Some(region);
+        Ok(())
+    })
+}
+
+/// Grow the region starting at `start` by `size` bytes, page aligned, at its end. Fails if there
+/// is no such region, the grown region would overlap another, or we run out of frames for page
+/// tables.
+pub fn extend(
+    active_table: &mut ActivePageTable,
+    start: VirtualAddress,
+    size: usize,
+) -> Result<(), &'static str> {
+    if size == 0 || !addr::is_page_aligned(size) {
+        return Err("demand-paged region is not page aligned");
+    }
+    let first = Page::containing_address(start);
+
+    disable_interrupts_and_then(|| {
+        let mut regions = REGIONS.write();
+        let index = regions
+            .iter()
+            .position(|slot| slot.map_or(false, |region| region.first == first))
+            .ok_or("no demand-paged region starts there")?;
+        let region = regions[index].unwrap();
+
+        let old_end = region.last.start_address().get() + PAGE_SIZE;
+        let new_end = old_end.checked_add(size).ok_or("demand-paged region wraps around")?;
+        let grown = Region {
+            last: Page::containing_address(
+                VirtualAddress::try_new(new_end - 1).ok_or("demand-paged region is not canonical")?,
+            ),
+            ..region
+        };
+
+        let overlaps = regions
+            .iter()
+            .enumerate()
+            .filter(|&(other, _)| other != index)
+            .filter_map(|(_, slot)| *slot)
+            .any(|other| other.first <= grown.last && grown.first <= other.last);
+        if overlaps {
+            return Err("demand-paged region overlaps another");
+        }
+
+        for page in Page::range_inclusive(region.last + 1, grown.last) {
+            let table_start = addr::is_aligned(page.start_address().get(), addr::HUGE_PAGE_SIZE);
+            if page == region.last + 1 || table_start {
+                active_table.try_create_tables(page)?;
+            }
+        }
+
+        regions[index] = Some(grown);
+        Ok(())
+    })
+}
+
+/// Give up the region starting at `start`, unmapping every page of it which was touched and
+/// freeing its frame.
+pub fn release(
+    active_table: &mut ActivePageTable,
+    start: VirtualAddress,
+) -> Result<(), &'static str> {
+    let first = Page::containing_address(start);
+
+    disable_interrupts_and_then(|| {
+        let mut regions = REGIONS.write();
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |region| region.first == first))
+            .ok_or("no demand-paged region starts there")?;
+        let region = slot.take().unwrap();
+
+        for page in Page::range_inclusive(region.first, region.last) {
+            if active_table.translate_page(page).is_some() {
+                let (result, frame) = active_table.unmap(page);
+                result.flush(active_table);
+                deallocate_frame(frame);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Return the number of pages of the region starting at `start` which have been mapped on demand,
+/// or `None` if no region starts there.
+pub fn mapped_pages(start: VirtualAddress) -> Option<usize> {
+    let first = Page::containing_address(start);
+    let regions = REGIONS.read();
+
+    regions
+        .iter()
+        .position(|slot| slot.map_or(false, |region| region.first == first))
+        .map(|index| MAPPED[index].load(Ordering::SeqCst))
+}
+
+/// Return whether `address` lies in a demand-paged region. Does not wait for the regions, so that
+/// it can be used while reporting a fault, and gives false if they are being changed.
+pub fn is_reserved(address: usize) -> bool {
+    let page = Page::containing_address(VirtualAddress::new(address));
+
+    REGIONS.try_read().map_or(false, |regions| {
+        regions.iter().filter_map(|slot| *slot).any(|region| region.contains(page))
+    })
+}
+
+/// Map a zeroed frame at the page containing `address` after a page fault there, if it lies in a
+/// demand-paged region. Returns whether the fault was the first touch of such a page and has been
+/// dealt with. If not, or if there is no frame to be had, the fault is a genuine one.
+///
+/// Called from the page fault handler, which may have interrupted the frame allocator or the heap,
+/// so this never waits for a lock, and never prints. It only takes a frame from the allocator
+/// through `try_allocate_frame`, which gives up if the allocator is locked.
+pub fn handle_fault(address: usize, error_code: PageFaultErrorCode) -> bool {
+    let unexpected = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::INSTRUCTION_FETCH
+        | PageFaultErrorCode::MALFORMED_TABLE;
+    if !(error_code & unexpected).is_empty() {
+        return false;
+    }
+
+    // Addresses in the non-canonical hole cannot be pages.
+    if address >= 0x0000_8000_0000_0000 && address < 0xffff_8000_0000_0000 {
+        return false;
+    }
+    let page = Page::containing_address(VirtualAddress::new(address));
+
+    let regions = REGIONS.read();
+    let (index, region) = match regions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, slot)| slot.map(|region| (index, region)))
+        .find(|&(_, region)| region.contains(page))
+    {
+        Some(found) => found,
+        None => return false,
+    };
+
+    // User mode may only touch regions meant for it.
+    let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+    if user && !region.flags.contains(EntryFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+
+    let _mapping = MAPPING.lock();
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    // Another CPU faulted on the same page and mapped it first.
+    if active_table.translate_page(page).is_some() {
+        return true;
+    }
+
+    // A burst of faults can empty the pool before the refill task runs, in which case the frame
+    // allocator is tried, if nobody holds it.
+    let frame = match frame_pool::take().or_else(try_allocate_frame) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    // The frame may have been used before, so clear it through the linear mapping before anyone
+    // can see it.
+    match phys_to_virt(frame.start_address()) {
+        Some(virt) => unsafe { ptr::write_bytes(virt.get() as *mut u8, 0, PAGE_SIZE) },
+        None => {
+            let _ = frame_pool::give_back(frame);
+            return false;
+        }
+    }
+
+    // `reserve` created the page tables, so this does not allocate.
+    match active_table.try_map_to(page, frame, region.flags) {
+        Ok(result) => result.flush(&mut active_table),
+        Err(_) => return false,
+    }
+    MAPPED[index].fetch_add(1, Ordering::SeqCst);
+
+    true
+}
@@ -0,0 +1,101 @@
+//! Buffers for devices which read and write memory themselves, and so need its physical address.
+//!
+//! A buffer is physically contiguous, since a device sees physical memory, and is identity mapped
+//! uncached, so that what the CPU writes reaches memory before the device reads it.
+
+use arch::memory::{addr, allocate_frames_below, deallocate_frame, Frame, PAGE_SIZE};
+use arch::memory::paging::{ActivePageTable, EntryFlags, Page, PhysicalAddress, VirtualAddress};
+
+/// A physically contiguous buffer mapped for DMA. Dropping it unmaps and frees its frames.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    /// Address the CPU accesses the buffer through.
+    pub virt: VirtualAddress,
+    /// Address to give the device.
+    pub phys: PhysicalAddress,
+    /// Length in bytes, rounded up to a whole number of pages.
+    pub len: usize,
+}
+
+/// Allocate a DMA buffer of at least `size` bytes which lies wholly below the physical address
+/// `below`, for example 4 GiB for a device limited to 32-bit addresses.
+pub fn alloc_dma(
+    active_table: &mut ActivePageTable,
+    size: usize,
+    below: usize,
+) -> Result<DmaBuffer, &'static str> {
+    if size == 0 {
+        return Err("DMA buffer has zero size");
+    }
+
+    let pages = addr::pages_for(size).ok_or("DMA buffer too large")?;
+    let start_frame = allocate_frames_below(pages, below).ok_or("no frames for DMA buffer")?;
+    let phys = start_frame.start_address();
+    let end_frame = Frame::containing_address(PhysicalAddress::new(
+        phys.get() + pages * PAGE_SIZE - 1,
+    ));
+
+    let mut mapped = 0;
+    for frame in Frame::range_inclusive(start_frame.clone(), end_frame.clone()) {
+        match active_table.try_identity_map(frame, EntryFlags::mmio()) {
+            Ok(result) => result.flush(active_table),
+            Err(error) => {
+                undo_alloc_dma(active_table, start_frame, end_frame, mapped);
+                return Err(error);
+            }
+        }
+        mapped += 1;
+    }
+
+    Ok(DmaBuffer {
+        virt: VirtualAddress::new(phys.get()),
+        phys: phys,
+        len: pages * PAGE_SIZE,
+    })
+}
+
+/// Unmap the first `mapped` pages of a DMA buffer which failed to map, and free every one of its
+/// frames, mapped or not.
+fn undo_alloc_dma(
+    active_table: &mut ActivePageTable,
+    start_frame: Frame,
+    end_frame: Frame,
+    mapped: usize,
+) {
+    for (i, frame) in Frame::range_inclusive(start_frame, end_frame).enumerate() {
+        if i < mapped {
+            let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
+            let (result, _) = active_table.unmap(page);
+            result.flush(active_table);
+        }
+        deallocate_frame(frame);
+    }
+}
+
+impl DmaBuffer {
+    /// Return a pointer to the start of the buffer.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.virt.get() as *mut u8
+    }
+}
+
+impl Drop for DmaBuffer {
+    /// Unmap the buffer and free its frames. The device must be done with the buffer.
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let start_page = Page::containing_address(self.virt);
+        let end_page =
+            Page::containing_address(VirtualAddress::new(self.virt.get() + self.len - 1));
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let (result, frame) = active_table.unmap(page);
+            result.flush(&mut active_table);
+            deallocate_frame(frame);
+        }
+    }
+}
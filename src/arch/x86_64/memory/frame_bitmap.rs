@@ -0,0 +1,126 @@
+//! A bitmap of frames which have been freed and can be handed out again.
+//!
+//! The area frame allocator only ever moves forward through memory, so on its own it cannot take
+//! frames back. Freed frames are recorded here instead, one bit per frame, and are handed out again
+//! before the allocator moves on to fresh ones. The bitmap lives in the kernel's `.bss` rather than
+//! on the heap, since freeing a frame must not allocate. It covers the first 4 GiB of physical
+//! memory. A frame above that cannot be recorded, and is dropped when freed.
+//!
+//! Frames below `LOW_MEMORY_END` are kept in a bitmap of their own, so that general allocation
+//! never hands them out.
+
+use arch::memory::{Frame, LOW_MEMORY_END, PAGE_SIZE};
+
+/// Number of 64-bit words in the boot bitmap, enough for 4 GiB of frames.
+const BOOT_WORDS: usize = 16 * 1024;
+
+static mut BOOT_BITMAP: [u64; BOOT_WORDS] = [0; BOOT_WORDS];
+
+/// Number of 64-bit words in the low memory bitmap.
+const LOW_WORDS: usize = LOW_MEMORY_END / PAGE_SIZE / 64;
+
+static mut LOW_BITMAP: [u64; LOW_WORDS] = [0; LOW_WORDS];
+
+/// A set of free frames, stored as one bit per frame.
+pub struct FrameBitmap<'a> {
+    words: &'a mut [u64],
+    /// Number of bits set.
+    free: usize,
+    /// No word below this one has a bit set.
+    first_word: usize,
+}
+
+impl<'a> FrameBitmap<'a> {
+    /// Build an empty bitmap in `words`, which covers 64 frames per word starting at frame zero.
+    pub fn new(words: &'a mut [u64]) -> Self {
+        for word in words.iter_mut() {
+            *word = 0;
+        }
+
+        FrameBitmap {
+            words: words,
+            free: 0,
+            first_word: 0,
+        }
+    }
+
+    /// Return whether `frame` can be recorded in this bitmap.
+    pub fn covers(&self, frame: &Frame) -> bool {
+        frame.number / 64 < self.words.len()
+    }
+
+    /// Return whether `frame` is in the set.
+    pub fn contains(&self, frame: &Frame) -> bool {
+        self.covers(frame) && self.words[frame.number / 64] & (1 << (frame.number % 64)) != 0
+    }
+
+    /// Add `frame` to the set, or give it back if the bitmap does not cover it. Panics if the
+    /// frame is already in the set, since it must have been freed twice.
+    pub fn insert(&mut self, frame: Frame) -> Result<(), Frame> {
+        if !self.covers(&frame) {
+            return Err(frame);
+        }
+
+        assert!(
+            !self.contains(&frame),
+            "frame {:#x} freed twice",
+            frame.start_address().get()
+        );
+
+        let word = frame.number / 64;
+        self.words[word] |= 1 << (frame.number % 64);
+        self.free += 1;
+        if word < self.first_word {
+            self.first_word = word;
+        }
+
+        Ok(())
+    }
+
+    /// Take the lowest frame out of the set, or return `None` if it is empty.
+    pub fn take(&mut self) -> Option<Frame> {
+        if self.free == 0 {
+            return None;
+        }
+
+        while self.words[self.first_word] == 0 {
+            self.first_word += 1;
+        }
+
+        let word = &mut self.words[self.first_word];
+        let bit = word.trailing_zeros() as usize;
+        *word &= !(1 << bit);
+        self.free -= 1;
+
+        Some(Frame {
+            number: self.first_word * 64 + bit,
+        })
+    }
+
+    /// Return the number of frames in the set.
+    pub fn len(&self) -> usize {
+        self.free
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free == 0
+    }
+}
+
+/// Return the bitmap the kernel's frame allocator records freed frames in.
+///
+/// # Unsafety
+///
+/// This must be called at most once, since every call returns the same storage.
+pub unsafe fn boot_bitmap() -> FrameBitmap<'static> {
+    FrameBitmap::new(&mut BOOT_BITMAP)
+}
+
+/// Return the bitmap the kernel's frame allocator records freed low memory frames in.
+///
+/// # Unsafety
+///
+/// This must be called at most once, since every call returns the same storage.
+pub unsafe fn low_bitmap() -> FrameBitmap<'static> {
+    FrameBitmap::new(&mut LOW_BITMAP)
+}
@@ -0,0 +1,115 @@
+//! A small pool of frames set aside at boot for the page fault handler, which grows the heap with
+//! them.
+//!
+//! The page fault handler cannot use `allocate_frames`: the fault may have been raised while the
+//! allocator lock was held on this CPU, and allocating could fault again. Instead it takes frames
+//! from this pool, which is a fixed array of slots taken and filled with atomic swaps, so it never
+//! blocks and never allocates.
+//!
+//! The `frame-pool` task refills the pool from the frame allocator, and otherwise waits on a
+//! `WaitQueue`. When the pool runs low, a flag is set and the task is woken. The handler cannot
+//! wake the task itself, since waking takes the scheduler lock, so it defers the wakeup to the end
+//! of the next interrupt handler.
+
+use arch::memory::{allocate_frames, deallocate_frame, Frame};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use task::{deferred, WaitQueue};
+
+/// Number of frames the pool holds when full. Filling a fresh heap allocation takes a frame per
+/// page, so there must be enough for a burst of those before the refill task gets to run.
+const POOL_SIZE: usize = 32;
+/// The pool is refilled once fewer than this many frames are left.
+const LOW_WATER: usize = 16;
+
+/// Each slot holds a frame number plus one, or zero if it is empty.
+static SLOTS: [AtomicUsize; POOL_SIZE] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// Set when the pool has dropped below `LOW_WATER`.
+static NEEDS_REFILL: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    /// Where the refill task waits for the pool to run low.
+    static ref REFILL_WAITERS: WaitQueue = WaitQueue::new();
+}
+
+/// Fill the pool from the frame allocator. This must run after the frame allocator is set up.
+pub fn init() {
+    refill();
+    println!("[ pmm ] Reserved {} frames for the page fault handler.", available());
+}
+
+/// Take a frame from the pool without blocking or allocating, so this is safe to call from the
+/// page fault handler. Returns `None` if the pool is empty, in which case the handler may try
+/// `try_allocate_frame`, but must not fall back on `allocate_frames`.
+pub fn take() -> Option<Frame> {
+    let frame = SLOTS.iter().map(|slot| slot.swap(0, Ordering::SeqCst)).find(|&n| n != 0);
+
+    // Only the first take below the low water mark wakes the refill task. If the wakeup cannot be
+    // queued, the flag is cleared again so that the next take tries once more.
+    if available() < LOW_WATER && !NEEDS_REFILL.swap(true, Ordering::SeqCst) {
+        if !deferred::defer(wake_refill_task) {
+            NEEDS_REFILL.store(false, Ordering::SeqCst);
+        }
+    }
+
+    frame.map(|number| Frame { number: number - 1 })
+}
+
+/// Put a frame into the pool. Gives the frame back if the pool is full.
+pub fn give_back(frame: Frame) -> Result<(), Frame> {
+    for slot in SLOTS.iter() {
+        if slot
+            .compare_exchange(0, frame.number + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+
+    Err(frame)
+}
+
+/// Return the number of frames in the pool.
+pub fn available() -> usize {
+    SLOTS.iter().filter(|slot| slot.load(Ordering::SeqCst) != 0).count()
+}
+
+/// Top the pool up from the frame allocator, stopping early if it runs out of frames.
+fn refill() {
+    while available() < POOL_SIZE {
+        let frame = match allocate_frames(1) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        // Someone else filled the last slot first, so the frame goes back to the allocator.
+        if let Err(frame) = give_back(frame) {
+            deallocate_frame(frame);
+            return;
+        }
+    }
+}
+
+/// Wake the refill task. Deferred by `take`, so that it runs outside the page fault handler.
+fn wake_refill_task() {
+    REFILL_WAITERS.wake_one();
+}
+
+/// Entry point of the task which refills the pool whenever it runs low. It sleeps on
+/// `REFILL_WAITERS` in between, so that it never keeps an idle CPU from halting.
+pub extern "C" fn refill_task() {
+    loop {
+        REFILL_WAITERS.wait_until(|| NEEDS_REFILL.load(Ordering::SeqCst));
+        NEEDS_REFILL.store(false, Ordering::SeqCst);
+        refill();
+    }
+}
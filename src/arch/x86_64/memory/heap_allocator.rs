@@ -1,12 +1,131 @@
+//! The kernel heap.
+//!
+//! A large virtual range is reserved for the heap, but only its start is mapped at boot. The rest
+//! is a demand-paged region (see `demand`), mapped a page at a time the first time each page is
+//! touched. So the heap only takes up as much physical memory as has actually been used.
+//!
+//! The heap starts out `HEAP_SIZE` bytes long. When an allocation does not fit, the heap grows at
+//! its end, by extending the demand-paged region, up to a limit of `HEAP_MAX_SIZE` bytes, or less
+//! if the `heap_max=<MiB>` option is given. Only then does allocation fail.
+
 use alloc::allocator::{Alloc, AllocErr, Layout};
 use linked_list_allocator::LockedHeap;
 use arch::interrupts::disable_interrupts_and_then;
+use arch::memory::{addr, demand, slab, PAGE_SIZE};
+use arch::memory::slab::{CacheStats, SlabCache, SIZE_CLASSES};
+use arch::memory::paging::{ActivePageTable, EntryFlags, Page, VirtualAddress};
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 500 * 1024;
+/// Size of the heap at boot.
+pub const HEAP_SIZE: usize = 16 * 1024 * 1024;
+/// Most the heap can grow to. This much virtual memory is set aside for it.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+/// Least the heap grows by at a time.
+const HEAP_GROW_STEP: usize = 4 * 1024 * 1024;
+/// Bytes at the start of the heap which are mapped at boot. Everything allocated before the IDT is
+/// loaded has to fit, since until then a fault cannot grow the heap.
+pub const HEAP_MAPPED_AT_BOOT: usize = 256 * 1024;
+
+/// Heap pages mapped at boot.
+static MAPPED_AT_BOOT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Most the heap may grow to, or zero for `HEAP_MAX_SIZE`.
+static LIMIT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Held while the heap grows, so that two CPUs do not grow it at once.
+static GROWING: Mutex<()> = Mutex::new(());
+
+/// Flags heap pages are mapped with.
+fn heap_flags() -> EntryFlags {
+    EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+}
+
+/// Return whether `address` lies in the range reserved for the heap.
+pub fn in_heap(address: usize) -> bool {
+    address >= HEAP_START && address < HEAP_START + HEAP_MAX_SIZE
+}
+
+/// Return the size the heap may grow to.
+pub fn limit() -> usize {
+    match LIMIT.load(Ordering::SeqCst) {
+        0 => HEAP_MAX_SIZE,
+        limit => limit,
+    }
+}
+
+/// Set the size the heap may grow to. It is capped at `HEAP_MAX_SIZE`, and the heap never shrinks
+/// to meet it.
+pub fn set_limit(limit: usize) {
+    LIMIT.store(cmp::max(cmp::min(limit, HEAP_MAX_SIZE), 1), Ordering::SeqCst);
+}
+
+/// Return the number of heap pages mapped so far.
+pub fn mapped_pages() -> usize {
+    let on_demand = demand::mapped_pages(VirtualAddress::new(HEAP_START + HEAP_MAPPED_AT_BOOT));
+    MAPPED_AT_BOOT.load(Ordering::SeqCst) + on_demand.unwrap_or(0)
+}
+
+/// Map the start of the heap, and reserve the rest of it to be mapped on demand. Fails if we run
+/// out of frames.
+pub fn map_heap(active_table: &mut ActivePageTable) -> Result<(), &'static str> {
+    let start_page = Page::containing_address(VirtualAddress::new(HEAP_START));
+    let mapped_end = Page::containing_address(VirtualAddress::new(
+        HEAP_START + HEAP_MAPPED_AT_BOOT - 1,
+    ));
+    for page in Page::range_inclusive(start_page, mapped_end) {
+        let result = active_table.map(page, heap_flags());
+        // Flush this vaddr translation from the TLB.
+        result.flush(active_table);
+        MAPPED_AT_BOOT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    demand::reserve(
+        active_table,
+        VirtualAddress::new(HEAP_START + HEAP_MAPPED_AT_BOOT),
+        HEAP_SIZE - HEAP_MAPPED_AT_BOOT,
+        heap_flags(),
+    )
+}
+
+/// Apply the `heap_max` option and register the `slabinfo` command. This must be called after
+/// the command line and the shell are set up.
+pub fn init() {
+    if let Some(value) = ::arch::cmdline::option("heap_max") {
+        match value.parse::<usize>() {
+            Ok(mebibytes) => set_limit(mebibytes.saturating_mul(1024 * 1024)),
+            Err(_) => println!("[ heap ] Ignoring heap_max={}, which is not a number.", value),
+        }
+    }
+    println!("[ heap ] The heap can grow to {} MiB.", limit() / (1024 * 1024));
+
+    ::shell::register("slabinfo", "Show the heap's slab caches.", slabinfo)
+        .expect("slabinfo registered twice");
+}
+
+fn slabinfo(_args: &[&str]) -> i32 {
+    print_slab_stats();
+    0
+}
 
+/// Print the statistics of every slab cache.
+pub fn print_slab_stats() {
+    println!("[ heap ] size  in use    free   slabs  allocations");
+
+    for class in 0..SIZE_CLASSES {
+        let stats = ::HEAP_ALLOCATOR.slab_stats(class);
+        println!(
+            "[ heap ] {:>4} {:>7} {:>7} {:>7} {:>12}",
+            stats.object_size, stats.in_use, stats.free, stats.slabs, stats.allocations
+        );
+    }
+}
+
+/// The kernel heap: slab caches for small allocations, in front of a linked list allocator for
+/// the rest and for the slabs themselves.
 pub struct HeapAllocator {
     inner: LockedHeap,
+    caches: [SlabCache; SIZE_CLASSES],
 }
 
 impl HeapAllocator {
@@ -14,6 +133,16 @@ impl HeapAllocator {
     pub const fn new() -> Self {
         HeapAllocator {
             inner: LockedHeap::empty(),
+            caches: [
+                SlabCache::new(16),
+                SlabCache::new(32),
+                SlabCache::new(64),
+                SlabCache::new(128),
+                SlabCache::new(256),
+                SlabCache::new(512),
+                SlabCache::new(1024),
+                SlabCache::new(2048),
+            ],
         }
     }
 
@@ -27,22 +156,86 @@ impl HeapAllocator {
         self.inner.lock().init(heap_bottom, heap_size);
     }
 
-    pub unsafe fn extend(&mut self, by: usize) {
+    pub unsafe fn extend(&self, by: usize) {
         self.inner.lock().extend(by);
     }
+
+    /// Return the current size of the heap in bytes.
+    pub fn size(&self) -> usize {
+        disable_interrupts_and_then(|| self.inner.lock().size())
+    }
+
+    /// Grow the heap by at least `needed` bytes, if its limit allows. Returns whether it grew.
+    ///
+    /// Interrupts must be disabled.
+    fn grow(&self, needed: usize) -> bool {
+        let _growing = GROWING.lock();
+
+        let size = self.inner.lock().size();
+        let step = match addr::page_align_up(cmp::max(needed, HEAP_GROW_STEP)) {
+            Some(step) => cmp::min(step, limit().saturating_sub(size)),
+            None => return false,
+        };
+        if step < needed || step == 0 {
+            return false;
+        }
+
+        let mut active_table = unsafe { ActivePageTable::new() };
+        let region = VirtualAddress::new(HEAP_START + HEAP_MAPPED_AT_BOOT);
+        if demand::extend(&mut active_table, region, step).is_err() {
+            return false;
+        }
+        unsafe { self.extend(step) };
+
+        true
+    }
+
+    /// Allocate from the linked list allocator, growing the heap if the allocation does not fit.
+    ///
+    /// Interrupts must be disabled.
+    fn alloc_from_heap(&self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        let result = self.inner.lock().alloc(layout.clone());
+
+        match result {
+            Err(_) if self.grow(layout.size().saturating_add(layout.align())) => {
+                self.inner.lock().alloc(layout)
+            }
+            result => result,
+        }
+    }
+
+    /// Return the statistics of the slab cache for size class `class`.
+    pub fn slab_stats(&self, class: usize) -> CacheStats {
+        self.caches[class].stats()
+    }
+
+    /// Take a page from the linked list allocator for a new slab.
+    fn new_slab(&self) -> Option<usize> {
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE)?;
+        let slab = self.alloc_from_heap(layout).ok()?;
+        Some(slab as usize)
+    }
 }
 
 /// Wrappers for inner Alloc implementation
 unsafe impl<'a> Alloc for &'a HeapAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
         disable_interrupts_and_then(|| -> Result<*mut u8, AllocErr> {
-            self.inner.lock().alloc(layout)
+            match slab::size_class(layout.size(), layout.align()) {
+                Some(class) => self.caches[class]
+                    .alloc(|| self.new_slab())
+                    .ok_or(AllocErr::Exhausted { request: layout }),
+                None => self.alloc_from_heap(layout),
+            }
         })
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
         disable_interrupts_and_then(|| {
-            self.inner.lock().dealloc(ptr, layout);
+            match slab::size_class(layout.size(), layout.align()) {
+                Some(class) => self.caches[class].dealloc(ptr),
+                None => self.inner.lock().dealloc(ptr, layout),
+            }
         });
     }
 
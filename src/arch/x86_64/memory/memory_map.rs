@@ -0,0 +1,140 @@
+//! The usable areas of physical memory, from whichever memory map the bootloader gave us.
+//!
+//! Under UEFI, GRUB passes the firmware's own memory map in an EFI memory map tag, which may be
+//! more accurate than the classic memory map tag, or the only one present. It is preferred when
+//! there is one, and the classic tag is used otherwise.
+
+use arch::multiboot::{self, TAG_EFI_MEMORY_MAP};
+use core::{mem, ptr};
+use multiboot2::{BootInformation, MemoryAreaIter};
+
+/// EFI memory types which are free for the kernel once boot services have exited, which GRUB
+/// does before jumping to us: boot services code and data, and conventional memory.
+const EFI_USABLE_TYPES: [u32; 3] = [3, 4, 7];
+
+/// A usable range of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalArea {
+    start: usize,
+    size: usize,
+}
+
+impl PhysicalArea {
+    pub fn new(start: usize, size: usize) -> Self {
+        PhysicalArea {
+            start: start,
+            size: size,
+        }
+    }
+
+    pub fn start_address(&self) -> usize {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Iterator over the usable areas of an EFI memory map.
+#[derive(Clone)]
+pub struct EfiMemoryAreaIter {
+    descriptors: &'static [u8],
+    /// Size of each descriptor, which may be larger than the fields we know of.
+    descriptor_size: usize,
+}
+
+impl EfiMemoryAreaIter {
+    /// Parse the contents of an EFI memory map tag: the descriptor size and version, followed by
+    /// the descriptors. Returns `None` if the descriptor size is too small to be real.
+    pub fn new(data: &'static [u8]) -> Option<Self> {
+        if data.len() < 8 {
+            return None;
+        }
+
+        let descriptor_size = read_u32(data, 0) as usize;
+        // Type and padding, then the physical start, virtual start, page count and attributes.
+        if descriptor_size < 8 + 4 * mem::size_of::<u64>() {
+            return None;
+        }
+
+        Some(EfiMemoryAreaIter {
+            descriptors: &data[8..],
+            descriptor_size: descriptor_size,
+        })
+    }
+}
+
+impl Iterator for EfiMemoryAreaIter {
+    type Item = PhysicalArea;
+
+    fn next(&mut self) -> Option<PhysicalArea> {
+        while self.descriptors.len() >= self.descriptor_size {
+            let (descriptor, rest) = self.descriptors.split_at(self.descriptor_size);
+            self.descriptors = rest;
+
+            let typ = read_u32(descriptor, 0);
+            let start = read_u64(descriptor, 8) as usize;
+            let pages = read_u64(descriptor, 24) as usize;
+
+            // EFI pages are always 4 KiB, whatever page size the kernel uses.
+            if EFI_USABLE_TYPES.contains(&typ) && pages != 0 {
+                return Some(PhysicalArea::new(start, pages * 4096));
+            }
+        }
+
+        None
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 8].as_ptr() as *const u64) }
+}
+
+/// The usable areas of physical memory, from one memory map or the other.
+#[derive(Clone)]
+pub enum MemoryAreas {
+    Efi(EfiMemoryAreaIter),
+    Multiboot(MemoryAreaIter),
+}
+
+impl MemoryAreas {
+    /// Return the name of the memory map the areas come from, for logging.
+    pub fn source(&self) -> &'static str {
+        match *self {
+            MemoryAreas::Efi(_) => "EFI memory map",
+            MemoryAreas::Multiboot(_) => "multiboot memory map",
+        }
+    }
+}
+
+impl Iterator for MemoryAreas {
+    type Item = PhysicalArea;
+
+    fn next(&mut self) -> Option<PhysicalArea> {
+        match *self {
+            MemoryAreas::Efi(ref mut areas) => areas.next(),
+            MemoryAreas::Multiboot(ref mut areas) => areas
+                .next()
+                .map(|area| PhysicalArea::new(area.start_address(), area.size())),
+        }
+    }
+}
+
+/// Return the usable memory areas, preferring the EFI memory map to the classic one. Returns
+/// `None` if the boot information has neither.
+pub fn areas(boot_info: &BootInformation) -> Option<MemoryAreas> {
+    let efi = multiboot::find_tag(boot_info, TAG_EFI_MEMORY_MAP)
+        .and_then(|tag| EfiMemoryAreaIter::new(tag.data));
+
+    match efi {
+        Some(areas) => Some(MemoryAreas::Efi(areas)),
+        None => boot_info
+            .memory_map_tag()
+            .map(|tag| MemoryAreas::Multiboot(tag.memory_areas())),
+    }
+}
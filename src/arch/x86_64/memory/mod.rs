@@ -1,42 +1,80 @@
+pub use self::access::{peek, poke};
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::dma::DmaBuffer;
 pub use self::paging::ActivePageTable;
 pub use self::stack_allocator::Stack;
 use self::paging::{PhysicalAddress, VirtualAddress};
-use self::paging::entry::EntryFlags;
 use acpi;
+use alloc::Vec;
+use arch::interrupts::InterruptGuard;
+use device;
 use multiboot2::BootInformation;
-use spin::Mutex;
+use smbios;
+use sync::{LockRank, RankedMutex};
 
+pub mod access;
+pub mod address_space;
+pub mod addr;
 pub mod area_frame_allocator;
+pub mod demand;
+pub mod dma;
+pub mod frame_bitmap;
+pub mod frame_pool;
 pub mod heap_allocator;
+pub mod memory_map;
 pub mod paging;
+pub mod shared;
+pub mod slab;
 pub mod stack_allocator;
 
 /// The size of a physical page on x86.
 pub const PAGE_SIZE: usize = 4096;
 
-pub static ALLOCATOR: Mutex<Option<AreaFrameAllocator>> = Mutex::new(None);
+/// The end of low memory, the first 1 MiB, which real mode code can address. General allocation
+/// never returns frames below this.
+pub const LOW_MEMORY_END: usize = 0x10_0000;
 
-pub fn init(boot_info: &BootInformation) -> MemoryController {
+/// Pages reserved for kernel stacks, just above the heap.
+const STACK_AREA_PAGES: usize = stack_allocator::MAX_RANGE_PAGES;
+
+/// The physical frame allocator. Its entry points take the lock with interrupts disabled, so an
+/// interrupt handler never finds it held by the code it interrupted on the same CPU.
+///
+/// The lock must never be held across an instruction that can fault into the allocator, such as a
+/// touch of a demand-paged address: the page fault handler would spin on the lock forever. For the
+/// same reason, the page fault handler must not call `allocate_frames` itself.
+pub static ALLOCATOR: RankedMutex<Option<AreaFrameAllocator>> =
+    RankedMutex::new(LockRank::FrameAllocator, None);
+
+/// Set up physical and virtual memory management: build the frame allocator from the memory map,
+/// remap the kernel, map the heap and find the ACPI and SMBIOS tables. Everything which depends on
+/// the order these happen in is done here, so callers only need this one call.
+///
+/// Fails, instead of panicking part way through, if the boot information is missing something we
+/// need or we run out of frames while building the kernel's page tables.
+pub fn init(boot_info: &BootInformation) -> Result<MemoryController, &'static str> {
     assert_has_not_been_called!("memory::init must be called only once");
 
-    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
-    let elf_sections_tag = boot_info
-        .elf_sections_tag()
-        .expect("Elf sections tag required");
+    let memory_areas = memory_map::areas(boot_info).ok_or("no memory map tag")?;
+    let elf_sections_tag = boot_info.elf_sections_tag().ok_or("no ELF sections tag")?;
+
+    if memory_areas.clone().next().is_none() {
+        return Err("memory map has no usable areas");
+    }
+    println!("[ pmm ] Using the {}.", memory_areas.source());
 
     let kernel_start = elf_sections_tag
         .sections()
         .filter(|s| s.is_allocated())
         .map(|s| s.start_address())
         .min()
-        .unwrap();
+        .ok_or("no allocated kernel sections")?;
     let kernel_end = elf_sections_tag
         .sections()
         .filter(|s| s.is_allocated())
         .map(|s| s.start_address() + s.size())
         .max()
-        .unwrap();
+        .ok_or("no allocated kernel sections")?;
 
     println!(
         "[ pmm ] Kernel start: {:#x}, kernel end: {:#x}",
@@ -54,55 +92,138 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
         kernel_end as usize,
         boot_info.start_address(),
         boot_info.end_address(),
-        memory_map_tag.memory_areas(),
-    );
+        memory_areas,
+        // `init` is only called once.
+        unsafe { frame_bitmap::boot_bitmap() },
+        unsafe { frame_bitmap::low_bitmap() },
+    )?;
 
     *ALLOCATOR.lock() = Some(frame_allocator);
 
-    let mut active_table = paging::init(&boot_info);
+    // The kernel's data sections are mapped no-execute, which faults unless NXE is on. WP makes
+    // read-only mappings apply to the kernel as well.
+    enable_nxe_bit();
+    enable_write_protect_bit();
 
-    use self::paging::Page;
-    use self::heap_allocator::{HEAP_SIZE, HEAP_START};
+    let frames_before_paging = used_frames();
+    let mut active_table = paging::init(&boot_info).map_err(|e| {
+        print_frame_stats();
+        e
+    })?;
+    println!(
+        "[ vmm ] Kernel page tables took {} frames.",
+        used_frames() - frames_before_paging
+    );
 
-    // The beginning and end of the heap.
-    let heap_start_page = Page::containing_address(VirtualAddress::new(HEAP_START));
-    let heap_end_page = Page::containing_address(VirtualAddress::new(HEAP_START + HEAP_SIZE - 1));
+    frame_pool::init();
 
-    println!("[ vmm ] Mapping heap pages ...");
+    use self::paging::Page;
+    use self::heap_allocator::{HEAP_MAPPED_AT_BOOT, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START};
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = active_table.map(page, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-        // Flush this vaddr translation from the TLB.
-        result.flush(&mut active_table);
-    }
+    // The end of the range the heap can grow into.
+    let heap_end_page =
+        Page::containing_address(VirtualAddress::new(HEAP_START + HEAP_MAX_SIZE - 1));
+
+    println!(
+        "[ vmm ] Reserving {} KiB for the heap, {} KiB mapped up front.",
+        HEAP_SIZE / 1024,
+        HEAP_MAPPED_AT_BOOT / 1024
+    );
+    heap_allocator::map_heap(&mut active_table)?;
 
     unsafe { ::HEAP_ALLOCATOR.init(HEAP_START, HEAP_SIZE) };
 
-    let stack_allocator = {
-        let stack_start_page = heap_end_page + 1;
-        let stack_end_page = stack_start_page + 100;
-        let stack_alloc_range = Page::range_inclusive(stack_start_page, stack_end_page);
-        stack_allocator::StackAllocator::new(stack_alloc_range)
-    };
-    unsafe { acpi::init(&mut active_table) };
-    MemoryController {
+    let stack_start_page = heap_end_page + 1;
+    let stack_end_page = stack_start_page + (STACK_AREA_PAGES - 1);
+    stack_allocator::init(Page::range_inclusive(stack_start_page, stack_end_page));
+    record_areas()?;
+
+    unsafe { acpi::init(boot_info, &mut active_table) };
+    smbios::init(boot_info, &mut active_table);
+    device::graphics::init(boot_info, &mut active_table);
+    Ok(MemoryController {
         active_table: active_table,
-        stack_allocator: stack_allocator,
+    })
+}
+
+/// Record the areas `init` set up in the kernel's address space.
+fn record_areas() -> Result<(), &'static str> {
+    use self::address_space::{self, Backing, VirtualMemoryArea};
+    use self::heap_allocator::{HEAP_MAX_SIZE, HEAP_START};
+    use self::paging::{physmap, EntryFlags, PHYSICAL_MEMORY_OFFSET};
+
+    let data = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let areas = [
+        (HEAP_START, HEAP_MAX_SIZE, Backing::Anonymous, "heap"),
+        (HEAP_START + HEAP_MAX_SIZE, STACK_AREA_PAGES * PAGE_SIZE, Backing::Anonymous, "stacks"),
+        (
+            PHYSICAL_MEMORY_OFFSET,
+            physmap::mapped_end(),
+            Backing::Device {
+                physical: PhysicalAddress::new(0),
+            },
+            "physical memory",
+        ),
+    ];
+
+    for &(start, size, backing, name) in areas.iter() {
+        let area = VirtualMemoryArea::new(VirtualAddress::new(start), size, data, backing, name)?;
+        address_space::insert(area)?;
     }
+
+    address_space::print_areas();
+    Ok(())
+}
+
+fn enable_nxe_bit() {
+    use arch::msr::{EFER, EFER_NXE};
+
+    unsafe {
+        let efer = EFER.read();
+        EFER.write(efer | EFER_NXE);
+    }
+}
+
+fn enable_write_protect_bit() {
+    use x86_64::registers::control_regs::{Cr0, cr0, cr0_write};
+
+    unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
 }
 
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    stack_allocator: stack_allocator::StackAllocator,
 }
 
 impl MemoryController {
+    /// Allocate a kernel stack of `size_in_pages` pages, with a guard page below it.
     pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
-        let &mut MemoryController {
-            ref mut active_table,
-            ref mut stack_allocator,
-        } = self;
-        stack_allocator.alloc_stack(active_table, size_in_pages)
+        stack_allocator::alloc_stack(&mut self.active_table, size_in_pages)
+    }
+
+    /// Give back a stack from `alloc_stack` which is no longer in use.
+    pub fn free_stack(&mut self, stack: Stack) {
+        stack_allocator::free_stack(&mut self.active_table, stack)
+    }
+
+    /// Allocate a DMA buffer of at least `size` bytes which lies wholly below the physical address
+    /// `below`.
+    pub fn alloc_dma(&mut self, size: usize, below: usize) -> Result<DmaBuffer, &'static str> {
+        dma::alloc_dma(&mut self.active_table, size, below)
+    }
+
+    /// Unmap `page` and return the frame it mapped, which is left allocated. Use this when the
+    /// frame is still in use elsewhere, or must never be reused, like the frame behind a guard
+    /// page. Panics if `page` is not mapped.
+    pub fn unmap(&mut self, page: paging::Page) -> Frame {
+        let (result, frame) = self.active_table.unmap(page);
+        result.flush(&mut self.active_table);
+        frame
+    }
+
+    /// Unmap `page` and free the frame it mapped. Panics if `page` is not mapped.
+    pub fn unmap_and_free(&mut self, page: paging::Page) {
+        let frame = self.unmap(page);
+        deallocate_frame(frame);
     }
 
     /* pub fn allocate_frame(&mut self, count: usize) -> Option<Frame> {
@@ -125,7 +246,7 @@ impl Frame {
     /// Return the frame that contains the given physical address.
     pub fn containing_address(address: PhysicalAddress) -> Frame {
         Frame {
-            number: address.get() / PAGE_SIZE,
+            number: addr::page_number(address.get()),
         }
     }
 
@@ -159,7 +280,17 @@ impl Iterator for FrameIter {
     fn next(&mut self) -> Option<Frame> {
         if self.start <= self.end {
             let frame = self.start.clone();
-            self.start.number += 1;
+
+            match self.start.number.checked_add(1) {
+                Some(next) if next <= self.end.number => self.start.number = next,
+                // `frame` is the last of the range. Empty the iterator without stepping past it,
+                // which could wrap around.
+                _ => {
+                    self.start.number = 1;
+                    self.end.number = 0;
+                }
+            }
+
             Some(frame)
         } else {
             None
@@ -173,11 +304,150 @@ pub trait FrameAllocator {
     fn free_frames(&mut self) -> usize;
 }
 
-/// Allocate a frame.
+/// Allocate `count` contiguous frames.
 pub fn allocate_frames(count: usize) -> Option<Frame> {
+    let _guard = InterruptGuard::new();
+
     if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
         return frame_allocator.allocate_frame(count);
     } else {
         panic!("Frame allocator called before init.");
     }
 }
+
+/// Allocate a frame if the frame allocator is free, without waiting for it. This is for the page
+/// fault handler once the frame pool is empty: the fault may have interrupted the allocator's
+/// holder on this CPU, so it must not spin on the lock.
+pub fn try_allocate_frame() -> Option<Frame> {
+    let _guard = InterruptGuard::new();
+
+    let mut allocator = ALLOCATOR.try_lock()?;
+    allocator.as_mut()?.allocate_frame(1)
+}
+
+/// Allocate `count` contiguous frames which all lie below the physical address `below`.
+pub fn allocate_frames_below(count: usize, below: usize) -> Option<Frame> {
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.allocate_frames_below(count, PhysicalAddress::new(below))
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Allocate `count` contiguous frames, the first of which is numbered a multiple of `align`, for
+/// example 512 frames aligned to 512 for a huge page.
+pub fn allocate_aligned_frames(count: usize, align: usize) -> Option<Frame> {
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.allocate_aligned(count, align)
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Allocate `count` frames which need not be contiguous, for mappings which do not need physically
+/// contiguous memory. This succeeds in fragmented memory where `allocate_frames` fails. Returns
+/// either all `count` frames or `None`, in which case nothing was allocated.
+pub fn allocate_scattered_frames(count: usize) -> Option<Vec<Frame>> {
+    // The list is allocated before the allocator is taken, since the heap may need frames for it.
+    // A count which cannot fit fails first, rather than asking the heap for a list that long.
+    let unused = {
+        let _guard = InterruptGuard::new();
+        ALLOCATOR
+            .lock()
+            .as_ref()
+            .map_or(0, |allocator| allocator.total_frames() - allocator.used_frames())
+    };
+    if count > unused {
+        return None;
+    }
+    let frames = Vec::with_capacity(count);
+
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.allocate_scattered(count, frames)
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Allocate a frame below `LOW_MEMORY_END`, for the AP trampoline and legacy DMA. Never returns the
+/// first frame, which holds the IVT and BIOS data area.
+pub fn allocate_low_frame() -> Option<Frame> {
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.allocate_low_frame()
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Free a frame from `allocate_low_frame`, so that it can be handed out by it again.
+pub fn deallocate_low_frame(frame: Frame) {
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.deallocate_low_frame(frame)
+    } else {
+        panic!("Frame allocator called before init.");
+    }
+}
+
+/// Free a frame. It goes into the page fault handler's pool if that has room, and otherwise back
+/// to the frame allocator to be handed out again.
+pub fn deallocate_frame(frame: Frame) {
+    // Low frames go back to low memory, so that general allocation never sees them.
+    if frame.start_address().get() < LOW_MEMORY_END {
+        return deallocate_low_frame(frame);
+    }
+
+    let frame = match frame_pool::give_back(frame) {
+        Ok(()) => return,
+        Err(frame) => frame,
+    };
+
+    let _guard = InterruptGuard::new();
+
+    if let Some(ref mut frame_allocator) = *ALLOCATOR.lock() {
+        frame_allocator.deallocate_frame(frame);
+    }
+}
+
+/// Return the number of frames handed out and not freed.
+pub fn used_frames() -> usize {
+    let _guard = InterruptGuard::new();
+
+    ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.used_frames())
+}
+
+/// Print how many physical frames there are and how many are in use. This does not allocate, so it
+/// can be used to explain running out of frames.
+pub fn print_frame_stats() {
+    let _guard = InterruptGuard::new();
+
+    // Don't deadlock if we failed while the allocator was locked.
+    let mut allocator = match ALLOCATOR.try_lock() {
+        Some(allocator) => allocator,
+        None => {
+            println!("[ pmm ] Frame allocator is locked, no statistics available.");
+            return;
+        }
+    };
+
+    if let Some(ref mut allocator) = *allocator {
+        println!(
+            "[ pmm ] Frames: {} total, {} used, {} free.",
+            allocator.total_frames(),
+            allocator.used_frames(),
+            allocator.free_frames()
+        );
+    }
+}
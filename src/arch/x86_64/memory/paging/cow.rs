@@ -0,0 +1,183 @@
+//! Copy-on-write pages: a frame mapped read-only at several pages at once, which is only copied
+//! when one of them is written to. This is what lets `fork` share an address space instead of
+//! copying it up front.
+//!
+//! `share` makes a mapping read-only, marks it `COPY_ON_WRITE` and counts another reference to its
+//! frame, so the frame can then be mapped at a second page with the flags it returns. A write to
+//! any of the pages faults, and the page fault handler calls `handle_fault`, which gives the page a
+//! private copy of the frame, or simply makes it writable again if no other page shares the frame
+//! any more. `unmap` drops a page's reference, and frees the frame along with the last one.
+//!
+//! The reference counts are a byte per frame in the kernel's `.bss`, covering the first 4 GiB of
+//! physical memory like the frame bitmap, so a frame above that cannot be shared. Only references
+//! beyond the first are counted, so a frame with no count has a single owner.
+
+use super::{phys_to_virt, ActivePageTable, EntryFlags, Page, VirtualAddress};
+use super::mapper::{Mapper, MapperFlush};
+use arch::interrupts::InterruptGuard;
+use arch::memory::{deallocate_frame, frame_pool, Frame, PAGE_SIZE};
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// Number of frames a reference count is kept for, enough for 4 GiB.
+const COUNTED_FRAMES: usize = 1024 * 1024;
+
+/// References to each frame beyond the first. Taken with interrupts disabled, since the page fault
+/// handler takes it too.
+static EXTRA_REFERENCES: Mutex<[u8; COUNTED_FRAMES]> = Mutex::new([0; COUNTED_FRAMES]);
+
+/// Return the number of pages which map `frame` copy-on-write, or one if it is not shared.
+pub fn references(frame: &Frame) -> usize {
+    let _guard = InterruptGuard::new();
+
+    if frame.number < COUNTED_FRAMES {
+        EXTRA_REFERENCES.lock()[frame.number] as usize + 1
+    } else {
+        1
+    }
+}
+
+/// Make the mapping of `page` read-only and copy-on-write, and count another reference to its
+/// frame. Returns the frame, and the flags to map it at another page with. Fails if `page` is not
+/// mapped, is part of a huge page, or its frame cannot be counted or is shared too many times.
+pub fn share(
+    mapper: &mut Mapper,
+    page: Page,
+) -> Result<(MapperFlush, Frame, EntryFlags), &'static str> {
+    let (frame, flags) = mapper.translate_with_flags(page).ok_or("page is not mapped")?;
+    if flags.contains(EntryFlags::HUGE_PAGE) {
+        return Err("huge pages cannot be shared");
+    }
+    if frame.number >= COUNTED_FRAMES {
+        return Err("frame is too high to be shared");
+    }
+
+    let mut shared = flags - EntryFlags::ACCESSED - EntryFlags::DIRTY;
+    if flags.contains(EntryFlags::WRITABLE) {
+        shared = shared - EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE;
+    }
+
+    let _guard = InterruptGuard::new();
+    let mut counts = EXTRA_REFERENCES.lock();
+    let count = &mut counts[frame.number];
+    *count = count.checked_add(1).ok_or("frame is shared too many times")?;
+
+    let result = mapper.remap(page, frame.clone(), shared);
+    Ok((result, frame, shared))
+}
+
+/// Drop one reference to `frame`. Returns whether it was the last, so the frame is free.
+fn drop_reference(frame: &Frame) -> bool {
+    let _guard = InterruptGuard::new();
+
+    if frame.number >= COUNTED_FRAMES {
+        return true;
+    }
+
+    let mut counts = EXTRA_REFERENCES.lock();
+    let count = &mut counts[frame.number];
+    if *count == 0 {
+        true
+    } else {
+        *count -= 1;
+        false
+    }
+}
+
+/// Unmap `page`, and free its frame unless another page still shares it.
+pub fn unmap(mapper: &mut Mapper, page: Page) -> MapperFlush {
+    let (result, frame) = mapper.unmap(page);
+    if drop_reference(&frame) {
+        deallocate_frame(frame);
+    }
+    result
+}
+
+/// Give the page containing `address` a writable frame of its own after a write to it faulted, if
+/// it is copy-on-write. Returns whether the fault was dealt with. If not, or if the frame pool is
+/// empty, the fault is a genuine one.
+///
+/// Called from the page fault handler, which may have interrupted the frame allocator or the heap,
+/// so this neither allocates nor prints.
+pub fn handle_fault(address: usize, error_code: PageFaultErrorCode) -> bool {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let unexpected = PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::MALFORMED_TABLE;
+    if !error_code.contains(expected) || !(error_code & unexpected).is_empty() {
+        return false;
+    }
+    let page = match VirtualAddress::try_new(address) {
+        Some(address) => Page::containing_address(address),
+        None => return false,
+    };
+
+    // Held throughout, so that two CPUs writing to the same page do not both copy it.
+    let mut counts = EXTRA_REFERENCES.lock();
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let (frame, flags) = match (active_table.translate_page(page), active_table.page_flags(page)) {
+        (Some(frame), Some(flags)) => (frame, flags),
+        _ => return false,
+    };
+
+    let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+
+    // Another CPU dealt with the page first, and this one faulted on a stale translation. Not so
+    // for the kernel writing to a user page, which faults under SMAP however the page is mapped,
+    // and would only fault again.
+    if flags.contains(EntryFlags::WRITABLE) && !flags.contains(EntryFlags::COPY_ON_WRITE) {
+        if !user && flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            return false;
+        }
+        active_table.flush(page);
+        return true;
+    }
+    if !flags.contains(EntryFlags::COPY_ON_WRITE) || flags.contains(EntryFlags::HUGE_PAGE) {
+        return false;
+    }
+    if user && !flags.contains(EntryFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+
+    let writable = flags - EntryFlags::COPY_ON_WRITE | EntryFlags::WRITABLE;
+
+    // The last page sharing the frame can have it to itself.
+    let count = if frame.number < COUNTED_FRAMES {
+        &mut counts[frame.number]
+    } else {
+        return false;
+    };
+    if *count == 0 {
+        active_table.remap(page, frame, writable).flush(&mut active_table);
+        return true;
+    }
+
+    let copy = match frame_pool::take() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    // Both frames are reached through the physical map: under SMAP the kernel cannot read a user
+    // page through its own address.
+    let (source, destination) = match (
+        phys_to_virt(frame.start_address()),
+        phys_to_virt(copy.start_address()),
+    ) {
+        (Some(source), Some(destination)) => (source, destination),
+        _ => {
+            let _ = frame_pool::give_back(copy);
+            return false;
+        }
+    };
+    unsafe {
+        ptr::copy_nonoverlapping(
+            source.get() as *const u8,
+            destination.get() as *mut u8,
+            PAGE_SIZE,
+        );
+    }
+
+    active_table.remap(page, copy, writable).flush(&mut active_table);
+    *count -= 1;
+
+    true
+}
@@ -0,0 +1,82 @@
+//! Access to the `cr3` register, which holds the physical frame of the active P4 table, and TLB
+//! maintenance.
+//!
+//! The low 12 bits of `cr3` are not part of the frame address. Without PCIDs they hold the PWT and
+//! PCD cache bits for the P4 table, and with `CR4.PCIDE` set they hold the current PCID. A write
+//! that zeroes them silently changes the cache policy or address-space ID, so `write` always takes
+//! the flags explicitly and `ActivePageTable::switch` passes back the flags it read. Writing back
+//! the pair returned by `read` leaves `cr3` unchanged.
+
+use super::{Page, PhysicalAddress};
+use arch::memory::Frame;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Mask of the P4 frame address in `cr3`.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Number of times `flush_all` has run, on any CPU.
+static FULL_FLUSHES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The low 12 bits of `cr3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cr3Flags(u64);
+
+impl Cr3Flags {
+    /// Page-level write-through for the P4 table (PWT).
+    pub const WRITE_THROUGH: u64 = 1 << 3;
+    /// Page-level cache disable for the P4 table (PCD).
+    pub const NO_CACHE: u64 = 1 << 4;
+
+    /// Create flags from the low 12 bits of `bits`.
+    pub fn new(bits: u64) -> Self {
+        Cr3Flags(bits & 0xfff)
+    }
+
+    /// No flags set.
+    pub fn empty() -> Self {
+        Cr3Flags(0)
+    }
+
+    /// Return the raw flag bits.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Return the PCID, meaningful only when `CR4.PCIDE` is set.
+    pub fn pcid(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+/// Return the frame of the active P4 table and the flags stored alongside it.
+pub fn read() -> (Frame, Cr3Flags) {
+    let value: u64;
+    unsafe { asm!("mov $0, cr3" : "=r"(value) : : "memory" : "intel", "volatile") };
+
+    let frame = Frame::containing_address(PhysicalAddress::new((value & ADDRESS_MASK) as usize));
+    (frame, Cr3Flags::new(value))
+}
+
+/// Load a new P4 table. This flushes all non-global TLB entries.
+pub unsafe fn write(frame: Frame, flags: Cr3Flags) {
+    let value = (frame.start_address().get() as u64 & ADDRESS_MASK) | flags.bits();
+    asm!("mov cr3, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+/// Invalidate the TLB entry for a single page.
+pub fn flush(page: Page) {
+    unsafe { asm!("invlpg ($0)" :: "r"(page.start_address().get()) : "memory") };
+}
+
+/// Invalidate all non-global TLB entries by reloading `cr3` with its current value. This is only
+/// needed when the recursive mapping changes: a change to a single mapping is flushed with `flush`.
+pub fn flush_all() {
+    FULL_FLUSHES.fetch_add(1, Ordering::Relaxed);
+    let (frame, flags) = read();
+    unsafe { write(frame, flags) };
+}
+
+/// Return the number of times the whole TLB has been flushed since boot.
+pub fn full_flushes() -> usize {
+    FULL_FLUSHES.load(Ordering::Relaxed)
+}
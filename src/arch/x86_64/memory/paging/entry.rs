@@ -2,18 +2,35 @@ use arch::memory::Frame;
 use multiboot2::ElfSection;
 use arch::memory::paging::PhysicalAddress;
 
+/// Bits 52 to 61 of an entry, which the CPU ignores while protection keys are off. In the first
+/// entry of a table they count the entries of the table in use (see `Table::used_entries`), so
+/// setting an entry leaves them alone.
+const COUNTER_MASK: u64 = 0x3ff << COUNTER_SHIFT;
+const COUNTER_SHIFT: u64 = 52;
+
 /// A page table entry.
 pub struct Entry(u64);
 
 impl Entry {
     /// Check if the entry is used or not.
     pub fn is_unused(&self) -> bool {
-        self.0 == 0
+        self.0 & !COUNTER_MASK == 0
     }
 
     /// Set this entry as unused.
     pub fn set_unused(&mut self) {
-        self.0 = 0;
+        self.0 &= COUNTER_MASK;
+    }
+
+    /// Return the counter held in the ignored bits of the entry.
+    pub fn counter(&self) -> usize {
+        ((self.0 & COUNTER_MASK) >> COUNTER_SHIFT) as usize
+    }
+
+    /// Store `count`, which must fit in ten bits, in the ignored bits of the entry.
+    pub fn set_counter(&mut self, count: usize) {
+        assert!(count as u64 <= COUNTER_MASK >> COUNTER_SHIFT, "counter overflowed");
+        self.0 = (self.0 & !COUNTER_MASK) | (count as u64) << COUNTER_SHIFT;
     }
 
     /// Return the current flags on the page.
@@ -32,10 +49,15 @@ impl Entry {
         }
     }
 
+    /// Return the frame the entry holds, whether or not it is present.
+    pub fn frame(&self) -> Frame {
+        Frame::containing_address(PhysicalAddress::new(self.0 as usize & 0x000fffff_fffff000))
+    }
+
     /// Set some flags on an entry.
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address().get() & !0x000fffff_fffff000 == 0);
-        self.0 = (frame.start_address().get() as u64) | flags.bits();
+        self.0 = (self.0 & COUNTER_MASK) | (frame.start_address().get() as u64) | flags.bits();
     }
 }
 
@@ -48,9 +70,11 @@ bitflags! {
         /// Page is accesible from ring-3
         const USER_ACCESSIBLE = 1 << 2;
         /// Write through caching is performed
-        /// on this page.
+        /// on this page (PWT).
         const WRITE_THROUGH =   1 << 3;
-        /// This page should not be cached.
+        /// This page should not be cached (PCD). MMIO mappings must set this, or reads may be
+        /// served from the cache and writes delayed or combined, so the device sees stale or
+        /// reordered accesses.
         const NO_CACHE =        1 << 4;
         /// This page has been accessed.
         const ACCESSED =        1 << 5;
@@ -61,12 +85,22 @@ bitflags! {
         /// This page's address will not be updated in the TLB,
         /// if CR3 is reset.
         const GLOBAL =          1 << 8;
+        /// Page shares its frame copy-on-write: it is mapped read-only, and the first write gives
+        /// it a private copy. Ignored by the CPU.
+        const COPY_ON_WRITE =   1 << 9;
         /// Non-executable page.
         const NO_EXECUTE =      1 << 63;
     }
 }
 
 impl EntryFlags {
+    /// Flags for mapping device registers: writable, uncached and not executable.
+    ///
+    /// TODO: A framebuffer is better mapped write-combining, which needs the PAT.
+    pub fn mmio() -> EntryFlags {
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE
+    }
+
     /// Parse the flags on an ELF section to our `EntryFlags` struct.
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
         use multiboot2::ElfSectionFlags;
@@ -1,22 +1,36 @@
-use super::{ActivePageTable, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::{ActivePageTable, Page, PageIter, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
 use super::entry::EntryFlags;
-use super::table::{self, Level4, Table};
-use arch::memory::{allocate_frames, Frame, PAGE_SIZE};
+use super::table::{self, Level1, Level4, Table};
+use arch::memory::{addr, allocate_aligned_frames, allocate_frames, deallocate_frame, Frame};
+use arch::memory::PAGE_SIZE;
 use core::ptr::Unique;
-use core::mem;
+use core::{cmp, mem};
 
 /// A helper struct which does most of the paging gruntwork.
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
+    /// Set while `ActivePageTable::with` points the recursive mapping at an inactive table.
+    editing_inactive: bool,
 }
 
 impl Mapper {
     pub unsafe fn new() -> Mapper {
         Mapper {
             p4: Unique::new_unchecked(table::P4),
+            editing_inactive: false,
         }
     }
 
+    /// Return whether this mapper is editing an inactive table. No CPU is using that table, so its
+    /// translations can't be in any TLB and changes to it need no flushing.
+    pub fn editing_inactive(&self) -> bool {
+        self.editing_inactive
+    }
+
+    pub(super) fn set_editing_inactive(&mut self, inactive: bool) {
+        self.editing_inactive = inactive;
+    }
+
     pub fn p4(&self) -> &Table<Level4> {
         unsafe { self.p4.as_ref() }
     }
@@ -27,7 +41,7 @@ impl Mapper {
 
     /// Translate a virtual address to a physical address.
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = virtual_address.get() % PAGE_SIZE;
+        let offset = addr::page_offset(virtual_address.get());
         self.translate_page(Page::containing_address(virtual_address))
             .map(|frame| PhysicalAddress::new(frame.number * PAGE_SIZE + offset))
     }
@@ -75,17 +89,351 @@ impl Mapper {
             .or_else(huge_page)
     }
 
+    /// Return the frame `page` is mapped to, and the flags of the entry which maps it, or `None` if
+    /// it is not mapped. Unlike `page_flags`, the flags are the entry's own rather than those of
+    /// the whole walk, so they can be passed back to `change_flags`.
+    pub fn translate_with_flags(&self, page: Page) -> Option<(Frame, EntryFlags)> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE) {
+            let frame = Frame {
+                number: p3_entry.frame().number + page.p2_index() * ENTRY_COUNT + page.p1_index(),
+            };
+            return Some((frame, p3_entry.flags()));
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE) {
+            let frame = Frame {
+                number: p2_entry.frame().number + page.p1_index(),
+            };
+            return Some((frame, p2_entry.flags()));
+        }
+
+        let p1 = p2.next_table(page.p2_index())?;
+        let entry = &p1[page.p1_index()];
+        entry.pointed_frame().map(|frame| (frame, entry.flags()))
+    }
+
+    /// Return whether `page` is mapped, either by a P1 entry or as part of a huge page. This is
+    /// cheaper than `translate_page` when the frame is not needed, as it stops at the first level
+    /// which is not present.
+    pub fn is_mapped(&self, page: Page) -> bool {
+        let present = |flags: EntryFlags| flags.contains(EntryFlags::PRESENT);
+        let huge = |flags: EntryFlags| flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return false,
+        };
+        if huge(p3[page.p3_index()].flags()) {
+            return true;
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return false,
+        };
+        if huge(p2[page.p2_index()].flags()) {
+            return true;
+        }
+
+        match p2.next_table(page.p2_index()) {
+            Some(p1) => present(p1[page.p1_index()].flags()),
+            None => false,
+        }
+    }
+
+    /// Return the effective flags of the mapping for `page`, or `None` if it is not mapped. Every
+    /// level of the walk is taken into account: the page is only writable or user accessible if
+    /// every level allows it, and is no-execute if any level forbids execution.
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        let mut walk = [EntryFlags::empty(); 4];
+        let mut depth = 1;
+
+        walk[0] = self.p4()[page.p4_index()].flags();
+        let p3 = self.p4().next_table(page.p4_index())?;
+
+        walk[1] = p3[page.p3_index()].flags();
+        depth += 1;
+
+        if !walk[1].contains(EntryFlags::HUGE_PAGE) {
+            let p2 = p3.next_table(page.p3_index())?;
+            walk[2] = p2[page.p2_index()].flags();
+            depth += 1;
+
+            if !walk[2].contains(EntryFlags::HUGE_PAGE) {
+                let p1 = p2.next_table(page.p2_index())?;
+                walk[3] = p1[page.p1_index()].flags();
+                depth += 1;
+            }
+        }
+
+        let walk = &walk[..depth];
+        let mut flags = walk[depth - 1];
+        if !flags.contains(EntryFlags::PRESENT) {
+            return None;
+        }
+
+        for level in walk {
+            if !level.contains(EntryFlags::WRITABLE) {
+                flags.remove(EntryFlags::WRITABLE);
+            }
+            if !level.contains(EntryFlags::USER_ACCESSIBLE) {
+                flags.remove(EntryFlags::USER_ACCESSIBLE);
+            }
+            if level.contains(EntryFlags::NO_EXECUTE) {
+                flags.insert(EntryFlags::NO_EXECUTE);
+            }
+        }
+
+        Some(flags)
+    }
+
     /// Map a page to a frame by getting reference to the page tables and setting the index in the
     /// P1 table to the given frame.
+    ///
+    /// The page and frame can be anything, and no frame is allocated, so this is also how MMIO
+    /// registers are mapped at a chosen page. MMIO mappings should pass `EntryFlags::NO_CACHE`.
     pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> MapperFlush {
-        let p3 = self.p4_mut().next_table_create(page.p4_index());
-        let p2 = p3.next_table_create(page.p3_index());
-        let p1 = p2.next_table_create(page.p2_index());
+        self.try_map_to(page, frame, flags).expect("out of memory")
+    }
+
+    /// Like `map_to`, but return an error instead of panicking if a frame for one of the page
+    /// tables cannot be allocated.
+    pub fn try_map_to(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlush, &'static str> {
+        let p1 = self.try_p1_create(page, flags)?;
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        p1.add_used();
 
-        MapperFlush::new(page)
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Map the 1 GiB page starting at `page` to the 1 GiB of frames starting at `frame`, with a
+    /// single huge P3 entry. Both must be 1 GiB aligned, and the CPU must support 1 GiB pages.
+    /// Fails if a frame for one of the page tables cannot be allocated.
+    pub fn try_map_to_1gib(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlush, &'static str> {
+        assert!(
+            page.p2_index() == 0 && page.p1_index() == 0,
+            "huge page is not 1 GiB aligned"
+        );
+        assert!(
+            addr::is_aligned(frame.start_address().get(), addr::HUGE_1GIB_PAGE_SIZE),
+            "huge frame is not 1 GiB aligned"
+        );
+
+        if flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            self.try_create_user_tables(page, 1)?;
+        }
+
+        let p3 = self.p4_mut().try_next_table_create(page.p4_index())?;
+
+        assert!(p3[page.p3_index()].is_unused());
+        p3[page.p3_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+        p3.add_used();
+
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Return whether the 2 MiB page starting at `page` can be mapped with a huge page, i.e.
+    /// nothing in its range is mapped and it has no P1 table.
+    pub fn huge_page_is_free(&self, page: Page) -> bool {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return true,
+        };
+        if p3[page.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            return false;
+        }
+
+        p3.next_table(page.p3_index())
+            .map_or(true, |p2| p2[page.p2_index()].is_unused())
+    }
+
+    /// Map the 2 MiB page starting at `page` to the 2 MiB of frames starting at `frame`, with a
+    /// single huge P2 entry. Both must be 2 MiB aligned, and the page must be free (see
+    /// `huge_page_is_free`).
+    pub fn map_to_huge(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> MapperFlush {
+        self.try_map_to_huge(page, frame, flags).expect("out of memory")
+    }
+
+    /// Map the 2 MiB page starting at `page` to 2 MiB of newly allocated, contiguous frames.
+    pub fn map_huge(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
+        let frame = allocate_aligned_frames(ENTRY_COUNT, ENTRY_COUNT).expect("out of memory");
+        self.map_to_huge(page, frame, flags)
+    }
+
+    /// Identity map the 2 MiB of frames starting at `frame`, which must be 2 MiB aligned, with a
+    /// huge page.
+    pub fn identity_map_huge(&mut self, frame: Frame, flags: EntryFlags) -> MapperFlush {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
+        self.map_to_huge(page, frame, flags)
+    }
+
+    /// Map the 2 MiB page starting at `page` to the 2 MiB of frames starting at `frame`, with a
+    /// single huge P2 entry. Both must be 2 MiB aligned. Fails if a frame for one of the page tables
+    /// cannot be allocated.
+    pub fn try_map_to_huge(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlush, &'static str> {
+        assert!(page.p1_index() == 0, "huge page is not 2 MiB aligned");
+        assert!(
+            addr::is_aligned(frame.start_address().get(), addr::HUGE_PAGE_SIZE),
+            "huge frame is not 2 MiB aligned"
+        );
+
+        if flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            self.try_create_user_tables(page, 2)?;
+        }
+
+        let p3 = self.p4_mut().try_next_table_create(page.p4_index())?;
+        let p2 = p3.try_next_table_create(page.p3_index())?;
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+        p2.add_used();
+
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Create the page tables needed to map `page` without mapping it, so that mapping it later
+    /// does not allocate. Fails if a frame for one of the tables cannot be allocated.
+    pub fn try_create_tables(&mut self, page: Page) -> Result<(), &'static str> {
+        let p3 = self.p4_mut().try_next_table_create(page.p4_index())?;
+        let p2 = p3.try_next_table_create(page.p3_index())?;
+        p2.try_next_table_create(page.p2_index())?;
+
+        Ok(())
+    }
+
+    /// Map the pages of `pages` to consecutive frames, the first to `frame`, with `flags`. The
+    /// tables are walked once per 2 MiB window rather than once per page, and the pages are
+    /// flushed together. Panics if a page is already mapped, or if we run out of frames.
+    pub fn map_range(
+        &mut self,
+        pages: PageIter,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> MapperFlushAll {
+        self.try_map_range(pages, frame, flags).expect("out of memory")
+    }
+
+    /// Like `map_range`, but return an error instead of panicking if a frame for one of the page
+    /// tables cannot be allocated. The pages before the window which failed stay mapped.
+    pub fn try_map_range(
+        &mut self,
+        pages: PageIter,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlushAll, &'static str> {
+        let mut result = MapperFlushAll::new();
+        if pages.start > pages.end {
+            return Ok(result);
+        }
+
+        let (first, last) = (pages.start.number, pages.end.number);
+        let mut number = first;
+        while number <= last {
+            let page = Page { number: number };
+            // The rest of the range which this P1 table maps.
+            let window_end = cmp::min(last, number | (ENTRY_COUNT - 1));
+
+            let p1 = match self.try_p1_create(page, flags) {
+                Ok(p1) => p1,
+                Err(e) => {
+                    // Every page mapped so far was not present before, so no TLB can hold it.
+                    unsafe { result.forget() };
+                    return Err(e);
+                }
+            };
+            for number in number..window_end + 1 {
+                let page = Page { number: number };
+                let frame = Frame {
+                    number: frame.number + (number - first),
+                };
+
+                assert!(p1[page.p1_index()].is_unused());
+                p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+                p1.add_used();
+                result.consume(MapperFlush::new(page));
+            }
+
+            number = window_end + 1;
+        }
+
+        Ok(result)
+    }
+
+    /// Return the P1 table for `page`, creating any table on the way which does not exist yet, and
+    /// letting user mode through them if `flags` make the page user accessible.
+    fn try_p1_create(
+        &mut self,
+        page: Page,
+        flags: EntryFlags,
+    ) -> Result<&mut Table<Level1>, &'static str> {
+        if flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            self.try_create_user_tables(page, 3)?;
+        }
+
+        let p3 = self.p4_mut().try_next_table_create(page.p4_index())?;
+        let p2 = p3.try_next_table_create(page.p3_index())?;
+        p2.try_next_table_create(page.p2_index())
+    }
+
+    /// Identity map the frames from `first` to `last` inclusive, like `try_map_range`.
+    pub fn try_identity_map_range(
+        &mut self,
+        first: Frame,
+        last: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlushAll, &'static str> {
+        let pages = Page::range_inclusive(
+            Page::containing_address(VirtualAddress::new(first.start_address().get())),
+            Page::containing_address(VirtualAddress::new(last.start_address().get())),
+        );
+        self.try_map_range(pages, first, flags)
+    }
+
+    /// Create the page tables needed to map `page`, like `try_create_tables`, and let user mode
+    /// through every level above it, so that mapping the page user accessible is enough. `levels`
+    /// is the number of levels above the entry which maps the page: 3 for a 4 KiB page, 2 for a
+    /// 2 MiB page mapped by a P2 entry, and 1 for a 1 GiB page mapped by a P3 entry.
+    fn try_create_user_tables(&mut self, page: Page, levels: usize) -> Result<(), &'static str> {
+        let p4 = self.p4_mut();
+        p4.try_next_table_create(page.p4_index())?;
+        p4.allow_user_access(page.p4_index());
+        if levels == 1 {
+            return Ok(());
+        }
+
+        let p3 = p4.next_table_mut(page.p4_index()).unwrap();
+        p3.try_next_table_create(page.p3_index())?;
+        p3.allow_user_access(page.p3_index());
+        if levels == 2 {
+            return Ok(());
+        }
+
+        let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+        p2.try_next_table_create(page.p2_index())?;
+        p2.allow_user_access(page.p2_index());
+
+        Ok(())
     }
 
     /// Map a page by allocating a free frame and mapping a page to that frame.
@@ -100,26 +448,227 @@ impl Mapper {
         self.map_to(page, frame, flags)
     }
 
-    /// Unmap a page from a physical frame.
-    pub fn unmap(&mut self, page: Page) -> MapperFlush {
-        use x86_64;
-        use x86_64::instructions::tlb;
+    /// Like `identity_map`, but return an error instead of panicking if a frame for one of the page
+    /// tables cannot be allocated.
+    pub fn try_identity_map(
+        &mut self,
+        frame: Frame,
+        flags: EntryFlags,
+    ) -> Result<MapperFlush, &'static str> {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
+        self.try_map_to(page, frame, flags)
+    }
 
-        // Check if the page is already unmapped (page not mapped to frame, translation failed).
-        assert!(self.translate(page.start_address()).is_some());
+    /// Point the existing mapping of `page` at `frame` with `flags`, in place of the frame and
+    /// flags it had. The old frame is not freed. Panics if `page` is not mapped by a P1 entry.
+    pub fn remap(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> MapperFlush {
+        use super::tlb;
 
         let p1 = self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
-        tlb::flush(x86_64::VirtualAddress(page.start_address().get()));
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+            .expect("remap of a page which is not mapped");
+        assert!(
+            !p1[page.p1_index()].is_unused(),
+            "remap of page {:#x}, which is not mapped",
+            page.start_address().get()
+        );
+
+        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        // Other CPUs may still cache the old translation, unless the table is not in use at all.
+        if !self.editing_inactive {
+            tlb::shootdown(page);
+        }
         MapperFlush::new(page)
     }
+
+    /// Give the mapping of `page` the flags `flags`, keeping its frame, for instance to make a page
+    /// read-only. Fails if `page` is not mapped by a P1 entry.
+    pub fn change_flags(
+        &mut self,
+        page: Page,
+        flags: EntryFlags,
+    ) -> Result<MapperFlush, &'static str> {
+        use super::tlb;
+
+        let editing_inactive = self.editing_inactive;
+        {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .ok_or("page is not mapped by a P1 entry")?;
+            let frame = p1[page.p1_index()]
+                .pointed_frame()
+                .ok_or("page is not mapped")?;
+
+            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        }
+        // The tables above exist already, so this only lets user mode through them.
+        if flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            self.try_create_user_tables(page, 3)?;
+        }
+        // Other CPUs may still cache the old permissions, unless the table is not in use at all.
+        if !editing_inactive {
+            tlb::shootdown(page);
+        }
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Unmap `page` and return the frame it mapped. The frame is not freed, since only the caller
+    /// knows whether anything else still uses it: pass it to `memory::deallocate_frame` if not.
+    /// Page tables left mapping nothing are freed. Panics if `page` is not mapped.
+    pub fn unmap(&mut self, page: Page) -> (MapperFlush, Frame) {
+        use super::tlb;
+
+        assert!(
+            self.translate(page.start_address()).is_some(),
+            "unmap of page {:#x}, which is not mapped",
+            page.start_address().get()
+        );
+
+        let editing_inactive = self.editing_inactive;
+        let frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .expect("mapping code does not support huge pages");
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            p1[page.p1_index()].set_unused();
+            p1.remove_used();
+            frame
+        };
+        // Other CPUs may still cache the old translation, unless the table is not in use at all.
+        if !editing_inactive {
+            tlb::shootdown(page);
+        }
+        self.free_empty_tables(page);
+        (MapperFlush::new(page), frame)
+    }
+
+    /// Free the page tables on the way to `page` which no longer map anything, lowest first. Each
+    /// is flushed from every TLB before its frame is freed. The P4 table is never freed.
+    fn free_empty_tables(&mut self, page: Page) {
+        use super::{flush, tlb};
+
+        let mut unlinked = [None, None, None];
+        {
+            let p4 = self.p4_mut();
+            if let Some(p3) = p4.next_table_mut(page.p4_index()) {
+                if let Some(p2) = p3.next_table_mut(page.p3_index()) {
+                    unlinked[0] = p2.unlink_empty_table(page.p2_index());
+                }
+                unlinked[1] = p3.unlink_empty_table(page.p3_index());
+            }
+            unlinked[2] = p4.unlink_empty_table(page.p4_index());
+        }
+
+        for table in unlinked.iter_mut() {
+            if let Some((frame, address)) = table.take() {
+                // Invalidating the table's own address also drops any cached walk through it.
+                let table_page = Page::containing_address(VirtualAddress::new(address));
+                if self.editing_inactive {
+                    flush(table_page);
+                } else {
+                    tlb::shootdown(table_page);
+                }
+                deallocate_frame(frame);
+            }
+        }
+    }
+
+    /// Unmap every page of `pages`, and pass each page and the frame it mapped to `unmapped`, which
+    /// may free the frame. Every CPU's TLB is shot down once per 2 MiB window, before the frames
+    /// of the window are handed over. Panics if a page is not mapped by a P1 entry.
+    pub fn unmap_range<F>(&mut self, pages: PageIter, mut unmapped: F)
+    where
+        F: FnMut(Page, Frame),
+    {
+        use super::tlb;
+
+        if pages.start > pages.end {
+            return;
+        }
+
+        let last = pages.end.number;
+        let mut number = pages.start.number;
+        while number <= last {
+            let page = Page { number: number };
+            let window_end = cmp::min(last, number | (ENTRY_COUNT - 1));
+            let editing_inactive = self.editing_inactive;
+
+            {
+                let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .expect("unmap of a range which is not mapped");
+
+                // Clear the present bits first, keeping the frames in the entries until every TLB
+                // has dropped them.
+                for number in number..window_end + 1 {
+                    let entry = &mut p1[Page { number: number }.p1_index()];
+                    let flags = entry.flags();
+                    assert!(
+                        flags.contains(EntryFlags::PRESENT),
+                        "unmap of page {:#x}, which is not mapped",
+                        Page { number: number }.start_address().get()
+                    );
+                    let frame = entry.frame();
+                    entry.set(frame, flags - EntryFlags::PRESENT);
+                }
+                if !editing_inactive {
+                    tlb::shootdown_range(page, Page { number: window_end });
+                }
+
+                for number in number..window_end + 1 {
+                    let page = Page { number: number };
+                    let frame = p1[page.p1_index()].frame();
+                    p1[page.p1_index()].set_unused();
+                    p1.remove_used();
+                    unmapped(page, frame);
+                }
+            }
+            self.free_empty_tables(page);
+
+            number = window_end + 1;
+        }
+    }
+
+    /// Unmap the huge page starting at `page` and return the first of the 2 MiB of frames it
+    /// mapped, which are not freed. Panics if `page` is not the start of a huge page.
+    pub fn unmap_huge(&mut self, page: Page) -> (MapperFlush, Frame) {
+        use super::tlb;
+
+        assert!(page.p1_index() == 0, "huge page is not 2 MiB aligned");
+        let editing_inactive = self.editing_inactive;
+        let frame = {
+            let p2 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .expect("unmap of a huge page which is not mapped");
+            assert!(
+                p2[page.p2_index()]
+                    .flags()
+                    .contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE),
+                "unmap of page {:#x}, which is not a huge page",
+                page.start_address().get()
+            );
+
+            let frame = p2[page.p2_index()].pointed_frame().unwrap();
+            p2[page.p2_index()].set_unused();
+            p2.remove_used();
+            frame
+        };
+        // Invalidating any address in a huge page drops the whole translation.
+        if !editing_inactive {
+            tlb::shootdown(page);
+        }
+        self.free_empty_tables(page);
+        (MapperFlush::new(page), frame)
+    }
 }
 
 /// A promise to flush a virtual address.
@@ -147,9 +696,17 @@ impl MapperFlush {
     }
 }
 
-/// A way to flush the entire active page table.
+/// Most pages `MapperFlushAll` invalidates one at a time. Past this, reloading `cr3` is cheaper.
+const MAX_TARGETED_FLUSHES: usize = 32;
+
+/// A promise to flush a batch of virtual addresses. Up to `MAX_TARGETED_FLUSHES` pages are
+/// invalidated one at a time with `invlpg`, and only a larger batch flushes the whole TLB.
 #[must_use = "The active page table must be flushed, or the changes ignored"]
-pub struct MapperFlushAll(bool);
+pub struct MapperFlushAll {
+    pages: [Option<Page>; MAX_TARGETED_FLUSHES],
+    /// Pages consumed, which may be more than `pages` holds.
+    count: usize,
+}
 
 impl Drop for MapperFlushAll {
     fn drop(&mut self) {
@@ -159,17 +716,27 @@ impl Drop for MapperFlushAll {
 
 impl MapperFlushAll {
     pub fn new() -> Self {
-        MapperFlushAll(false)
+        MapperFlushAll {
+            pages: [None; MAX_TARGETED_FLUSHES],
+            count: 0,
+        }
     }
 
     pub fn consume(&mut self, flush: MapperFlush) {
-        self.0 = true;
+        if self.count < MAX_TARGETED_FLUSHES {
+            self.pages[self.count] = Some(flush.0);
+        }
+        self.count += 1;
         mem::forget(flush);
     }
 
     pub fn flush(self, table: &mut ActivePageTable) {
-        if self.0 {
+        if self.count > MAX_TARGETED_FLUSHES {
             unsafe { table.flush_all() };
+        } else {
+            for page in self.pages.iter().filter_map(|page| *page) {
+                table.flush(page);
+            }
         }
 
         mem::forget(self);
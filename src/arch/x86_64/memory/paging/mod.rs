@@ -1,21 +1,35 @@
 pub use self::entry::EntryFlags;
 pub use self::mapper::Mapper;
-use arch::memory::{Frame, PAGE_SIZE};
+pub use self::walker::{dump_mappings, PageTableWalker};
+pub use self::permissions::{verify_kernel_permissions, PermissionReport};
+pub use self::physmap::{phys_to_virt, virt_to_phys, PHYSICAL_MEMORY_OFFSET};
+pub use self::cr3::{flush, flush_all, full_flushes};
+use arch::memory::{addr, Frame, PAGE_SIZE};
 use arch::memory::allocate_frames;
 use self::temporary_page::TemporaryPage;
+use arch::multiboot;
+use core::cmp;
 use core::ops::{Add, Deref, DerefMut};
 use multiboot2::BootInformation;
 
+pub mod cow;
+pub mod cr3;
 pub mod entry;
 mod table;
 mod temporary_page;
 pub mod mapper;
+pub mod permissions;
+pub mod physmap;
+pub mod tlb;
+pub mod walker;
 
 /// Maximum number of entries a page table can hold.
 const ENTRY_COUNT: usize = 512;
 
-/// A physical memory address.
-pub struct PhysicalAddress(pub usize);
+/// A physical memory address. Kept distinct from `VirtualAddress` so that the two cannot be mixed
+/// up, and only created through `new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysicalAddress(usize);
 
 impl PhysicalAddress {
     pub fn new(addr: usize) -> Self {
@@ -28,13 +42,23 @@ impl PhysicalAddress {
     }
 }
 
-/// A virtual memory address.
-pub struct VirtualAddress(pub usize);
+/// A canonical virtual memory address, meaning bits 48-63 are copies of bit 47.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtualAddress(usize);
 
 impl VirtualAddress {
-    /// Create a new virtual address.
+    /// Create a new virtual address. Panics if `addr` is not canonical.
     pub fn new(addr: usize) -> Self {
-        VirtualAddress(addr)
+        VirtualAddress::try_new(addr).expect("non-canonical virtual address")
+    }
+
+    /// Create a new virtual address, or return `None` if `addr` is not canonical.
+    pub fn try_new(addr: usize) -> Option<Self> {
+        if addr < 0x0000_8000_0000_0000 || addr >= 0xffff_8000_0000_0000 {
+            Some(VirtualAddress(addr))
+        } else {
+            None
+        }
     }
 
     /// Return the inner address this `VirtualAddress` wraps.
@@ -52,13 +76,9 @@ pub struct Page {
 impl Page {
     /// Return the number of the page which contains the given `VirtualAddress`.
     pub fn containing_address(address: VirtualAddress) -> Page {
-        assert!(
-            address.get() < 0x0000_8000_0000_0000 || address.get() >= 0xffff_8000_0000_0000,
-            "invalid address: 0x{:x}",
-            address.get()
-        );
+        // `VirtualAddress` is always canonical, so there is nothing to check.
         Page {
-            number: address.get() / PAGE_SIZE,
+            number: addr::page_number(address.get()),
         }
     }
 
@@ -80,6 +100,21 @@ impl Page {
         (self.number >> 0) & 0o777
     }
 
+    /// Return the page `rhs` pages after this one, or `None` if that would overflow or land in, or
+    /// past, the non-canonical hole.
+    pub fn checked_add(self, rhs: usize) -> Option<Page> {
+        let number = self.number.checked_add(rhs)?;
+        let address = number.checked_mul(PAGE_SIZE)?;
+
+        // Both halves must agree, so a lower half page can't be moved into the upper half either.
+        let is_upper = |address: usize| address >= 0xffff_8000_0000_0000;
+        if is_upper(address) != is_upper(self.start_address().get()) {
+            return None;
+        }
+
+        VirtualAddress::try_new(address).map(|_| Page { number: number })
+    }
+
     /// Return an iterator between the given two pages.
     pub fn range_inclusive(start: Page, end: Page) -> PageIter {
         PageIter {
@@ -92,10 +127,11 @@ impl Page {
 impl Add<usize> for Page {
     type Output = Page;
 
+    /// Panics if the result would not be a canonical page, rather than wrapping around to a low
+    /// page which may already hold something.
     fn add(self, rhs: usize) -> Page {
-        Page {
-            number: self.number + rhs,
-        }
+        self.checked_add(rhs)
+            .expect("page arithmetic overflowed or entered the non-canonical hole")
     }
 }
 
@@ -112,7 +148,17 @@ impl Iterator for PageIter {
     fn next(&mut self) -> Option<Page> {
         if self.start <= self.end {
             let page = self.start;
-            self.start.number += 1;
+
+            match self.start.checked_add(1) {
+                Some(next) if next <= self.end => self.start = next,
+                // `page` is the last page of the range, or of its half of the address space. Empty
+                // the iterator without stepping past it, which could wrap around.
+                _ => {
+                    self.start = Page { number: 1 };
+                    self.end = Page { number: 0 };
+                }
+            }
+
             Some(page)
         } else {
             None
@@ -148,25 +194,24 @@ impl ActivePageTable {
 
     /// Get the start address of the current P4 table as stored in `cr3`.
     pub fn address(&self) -> usize {
-        use x86_64::registers::control_regs;
-        control_regs::cr3().0 as usize
+        cr3::read().0.start_address().get()
     }
 
-    pub fn with<F>(
+    /// Run `f` with a mapper which edits `table` instead of the active table, by pointing the
+    /// recursive entry at it. The TLB is flushed in full when the recursive entry is switched each
+    /// way, and individual page flushes made by `f` are skipped, since they would be redundant.
+    pub fn with<F, T>(
         &mut self,
         table: &mut InactivePageTable,
         temporary_page: &mut temporary_page::TemporaryPage,
         f: F,
-    ) where
-        F: FnOnce(&mut Mapper),
+    ) -> T
+    where
+        F: FnOnce(&mut Mapper) -> T,
     {
-        use x86_64::registers::control_regs;
-        use x86_64::instructions::tlb;
-
-        {
+        let result = {
             // Get reference to current P4 table.
-            let backup =
-                Frame::containing_address(PhysicalAddress::new(control_regs::cr3().0 as usize));
+            let (backup, _) = cr3::read();
 
             // map temporary_page to current P4 table
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
@@ -176,46 +221,50 @@ impl ActivePageTable {
                 table.p4_frame.clone(),
                 EntryFlags::PRESENT | EntryFlags::WRITABLE,
             );
-            tlb::flush_all();
+            flush_all();
 
-            // execute f in the new context
-            f(self);
+            // execute f in the new context. Page flushes are skipped while it runs: the mappings
+            // it changes belong to the inactive table, so the TLB holds none of them, and the
+            // recursive mapping used to reach them is flushed as a whole below.
+            self.set_editing_inactive(true);
+            let result = f(self);
+            self.set_editing_inactive(false);
 
             // restore recursive mapping to original P4 table
             p4_table[511].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
-        }
+            flush_all();
+
+            result
+        };
 
         temporary_page.unmap(self);
+        result
     }
 
-    /// Switch the active page table, and return the old page table.
+    /// Switch the active page table, and return the old page table. The flag bits of `cr3` are
+    /// carried over to the new table.
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-        use x86_64;
-        use x86_64::registers::control_regs;
-
+        let (old_frame, flags) = cr3::read();
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(PhysicalAddress::new(
-                control_regs::cr3().0 as usize,
-            )),
+            p4_frame: old_frame,
         };
 
         unsafe {
-            control_regs::cr3_write(x86_64::PhysicalAddress(
-                new_table.p4_frame.start_address().get() as u64,
-            ));
+            cr3::write(new_table.p4_frame, flags);
         }
         old_table
     }
 
     pub fn flush(&mut self, page: Page) {
-        unsafe { asm!("invlpg ($0)" :: "r"(page.start_address().get())) };
+        if !self.editing_inactive() {
+            flush(page);
+        }
     }
 
     pub unsafe fn flush_all(&mut self) {
-        use x86_64::registers::control_regs::{cr3, cr3_write};
-
-        cr3_write(cr3());
+        if !self.editing_inactive() {
+            flush_all();
+        }
     }
 }
 
@@ -245,75 +294,26 @@ impl InactivePageTable {
 /// and turning the previous kernel stack into a guard page - this prevents silent stack overflows, as
 /// given that the guard page is unmapped, any stack overflow into this page will instantly cause a
 /// page fault. Returns the currently active kernel page table.
-pub fn init(boot_info: &BootInformation) -> ActivePageTable {
+///
+/// Fails if we run out of physical frames for the new page tables. The new table is then abandoned
+/// half built, and the boot page table stays active.
+pub fn init(boot_info: &BootInformation) -> Result<ActivePageTable, &'static str> {
     let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe });
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         // Allocate a frame for the PML4.
-        let frame = allocate_frames(1).expect("out of memory");
+        let frame = allocate_frames(1).ok_or("no frame available for the new PML4")?;
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
 
     // Do important mapping work.
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        println!("[ vmm ] Initialising paging.");
-
-        let elf_sections_tag = boot_info
-            .elf_sections_tag()
-            .expect("Memory map tag required");
-
-        // identity map the entire kernel.
-        for section in elf_sections_tag.sections() {
-            if !section.is_allocated() {
-                // section is not loaded to memory
-                continue;
-            }
-
-            assert!(
-                section.start_address() as usize % PAGE_SIZE == 0,
-                "sections need to be page aligned"
-            );
-            println!(
-                "[ vmm ] Identity mapping kernel section at addr: {:#x}, size: {} KiB",
-                section.start_address(),
-                section.size() / 1024,
-            );
-
-            // Translate ELF section flags to paging flags, and map the kernel sections
-            // into the virtual address space using these flags.
-            let flags = EntryFlags::from_elf_section_flags(&section);
-
-            let start_frame =
-                Frame::containing_address(PhysicalAddress::new(section.start_address() as usize));
-            let end_frame = Frame::containing_address(PhysicalAddress::new(
-                (section.end_address() - 1) as usize,
-            ));
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                let result = mapper.identity_map(frame, flags);
-                // Ignore this result since this table is not currently active.
-                unsafe { result.ignore() };
-            }
-        }
-
-        // identity map the VGA text buffer
-        println!("[ vmm ] Identity mapping the VGA text buffer.");
-        let vga_buffer_frame = Frame::containing_address(PhysicalAddress::new(0xb8000));
-        let res = mapper.identity_map(vga_buffer_frame, EntryFlags::WRITABLE);
-        unsafe { res.ignore() };
-
-        // identity map the multiboot info structure.
-        println!("[ vmm ] Identity mapping multiboot structures.");
-        let multiboot_start =
-            Frame::containing_address(PhysicalAddress::new(boot_info.start_address()));
-        let multiboot_end =
-            Frame::containing_address(PhysicalAddress::new(boot_info.end_address() - 1));
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            let result = mapper.identity_map(frame, EntryFlags::PRESENT);
-            unsafe { result.ignore() };
-        }
-    });
+    let physical_end = active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        identity_map_sections(mapper, boot_info)?;
+        physmap::map_physical_memory(mapper, boot_info)
+    })?;
 
     let old_table = active_table.switch(new_table);
+    physmap::set_mapped_end(physical_end);
     println!(
         "[ vmm ] Switched to new page table. PML4 at {:#x}",
         active_table.address()
@@ -324,14 +324,212 @@ pub fn init(boot_info: &BootInformation) -> ActivePageTable {
         old_table.p4_frame.start_address().get(),
     ));
 
-    let result = active_table.unmap(old_p4_page);
+    // The old P4 frame is leaked rather than freed. It is never handed out again, so nothing can
+    // end up mapped at the guard page.
+    let (result, _old_p4_frame) = active_table.unmap(old_p4_page);
     // Flush old p4 in TLB.
     result.flush(&mut active_table);
 
+    ::arch::memory::stack_allocator::add_guard_page(old_p4_page.start_address().get())?;
     println!(
         "[ vmm ] Guard page at {:#x}.",
         old_p4_page.start_address().get()
     );
 
-    active_table
+    let wx_log = multiboot::command_line(boot_info)
+        .map_or(false, |line| line.split_whitespace().any(|opt| opt == "wx=log"));
+    permissions::set_wx_log_only(wx_log);
+
+    let report = verify_kernel_permissions(&active_table, boot_info);
+    println!(
+        "[ vmm ] Kernel permissions checked: {} wrong, {} shared, {} writable and executable.",
+        report.wrong, report.shared, report.writable_executable
+    );
+    assert!(report.wrong == 0, "kernel sections are mapped with the wrong permissions");
+    permissions::set_boot_report(report);
+    permissions::enforce_writable_executable(report.writable_executable);
+
+    Ok(active_table)
+}
+
+/// Identity map the kernel sections, the VGA buffer and the multiboot structures into the table
+/// being built by `init`.
+fn identity_map_sections(
+    mapper: &mut Mapper,
+    boot_info: &BootInformation,
+) -> Result<(), &'static str> {
+    println!("[ vmm ] Initialising paging.");
+
+    let elf_sections_tag = boot_info
+        .elf_sections_tag()
+        .expect("Memory map tag required");
+
+    // Where the kernel's permissions allow it, map whole 2 MiB regions with huge pages, which
+    // need no P1 table. `nohugepages` on the command line turns this off.
+    let huge_pages = !multiboot::command_line(boot_info)
+        .map_or(false, |line| line.split_whitespace().any(|opt| opt == "nohugepages"));
+    let (boot_p4, _) = cr3::read();
+    let mut last_region = None;
+
+    // identity map the entire kernel.
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() {
+            // section is not loaded to memory
+            continue;
+        }
+
+        assert!(
+            addr::is_page_aligned(section.start_address() as usize),
+            "sections need to be page aligned"
+        );
+        println!(
+            "[ vmm ] Identity mapping kernel section at addr: {:#x}, size: {} KiB",
+            section.start_address(),
+            section.size() / 1024,
+        );
+
+        // Translate ELF section flags to paging flags, and map the kernel sections
+        // into the virtual address space using these flags.
+        let flags = EntryFlags::from_elf_section_flags(&section);
+
+        let start_frame =
+            Frame::containing_address(PhysicalAddress::new(section.start_address() as usize));
+        let end_frame = Frame::containing_address(PhysicalAddress::new(
+            (section.end_address() - 1) as usize,
+        ));
+        if !huge_pages {
+            identity_map_new(mapper, start_frame, end_frame, flags, "kernel section")?;
+            continue;
+        }
+
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            let region = frame.number / ENTRY_COUNT;
+            let uniform = match last_region {
+                Some((last, uniform)) if last == region => uniform,
+                _ => {
+                    let uniform = region_is_uniform(boot_info, region, &boot_p4);
+                    last_region = Some((region, uniform));
+                    uniform
+                }
+            };
+
+            if uniform {
+                // The first frame of the region maps all of it.
+                if frame.number % ENTRY_COUNT == 0 {
+                    identity_map_huge_new(mapper, frame, flags)?;
+                }
+                continue;
+            }
+
+            identity_map_new(mapper, frame.clone(), frame, flags, "kernel section")?;
+        }
+    }
+
+    // identity map the VGA text buffer, which takes two pages in the 80x50 mode
+    println!("[ vmm ] Identity mapping the VGA text buffer.");
+    let vga_buffer_start = Frame::containing_address(PhysicalAddress::new(0xb8000));
+    let vga_buffer_end = Frame::containing_address(PhysicalAddress::new(0xb9fff));
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    identity_map_new(mapper, vga_buffer_start, vga_buffer_end, flags, "VGA buffer")?;
+
+    // identity map the multiboot info structure.
+    println!("[ vmm ] Identity mapping multiboot structures.");
+    let multiboot_start =
+        Frame::containing_address(PhysicalAddress::new(boot_info.start_address()));
+    let multiboot_end =
+        Frame::containing_address(PhysicalAddress::new(boot_info.end_address() - 1));
+    let flags = EntryFlags::PRESENT | EntryFlags::NO_EXECUTE;
+    identity_map_new(mapper, multiboot_start, multiboot_end, flags, "multiboot structure")?;
+
+    Ok(())
+}
+
+/// Return whether the 2 MiB region of frames numbered `region * 512` to `region * 512 + 511` can be
+/// identity mapped with one huge page. A huge page has one set of flags for its whole range, so the
+/// region must be covered entirely by kernel sections with the same flags. Any permission boundary
+/// or gap inside the region forces 4 KiB pages.
+///
+/// The boot P4 table is unmapped to become a guard page once we switch tables, and the mapper
+/// cannot unmap part of a huge page, so its region always uses 4 KiB pages.
+fn region_is_uniform(boot_info: &BootInformation, region: usize, boot_p4: &Frame) -> bool {
+    let first = region * ENTRY_COUNT;
+    let last = first + ENTRY_COUNT - 1;
+
+    if boot_p4.number >= first && boot_p4.number <= last {
+        return false;
+    }
+
+    let elf_sections_tag = match boot_info.elf_sections_tag() {
+        Some(tag) => tag,
+        None => return false,
+    };
+
+    let mut region_flags = None;
+    let mut covered = 0;
+
+    for section in elf_sections_tag.sections().filter(|s| s.is_allocated()) {
+        let start = addr::page_number(section.start_address() as usize);
+        let end = addr::page_number((section.end_address() - 1) as usize);
+
+        if end < first || start > last {
+            continue;
+        }
+
+        let flags = EntryFlags::from_elf_section_flags(&section);
+        if *region_flags.get_or_insert(flags) != flags {
+            return false;
+        }
+
+        covered += cmp::min(end, last) - cmp::max(start, first) + 1;
+    }
+
+    covered == ENTRY_COUNT
+}
+
+/// Identity map the 2 MiB region starting at `frame` with a huge page, in the table being built by
+/// `init`.
+fn identity_map_huge_new(
+    mapper: &mut Mapper,
+    frame: Frame,
+    flags: EntryFlags,
+) -> Result<(), &'static str> {
+    let address = frame.start_address().get();
+    let page = Page::containing_address(VirtualAddress::new(address));
+
+    match mapper.try_map_to_huge(page, frame, flags) {
+        Ok(result) => {
+            // Ignore this result since this table is not currently active.
+            unsafe { result.ignore() };
+            Ok(())
+        }
+        Err(e) => {
+            println!("[ vmm ] Failed to identity map kernel huge page at {:#x}.", address);
+            Err(e)
+        }
+    }
+}
+
+/// Identity map the frames from `first` to `last` inclusive in the table being built by `init`. If
+/// we run out of frames, say what was being mapped, since the caller only gets the error. This
+/// must not allocate on the heap, which does not exist yet.
+fn identity_map_new(
+    mapper: &mut Mapper,
+    first: Frame,
+    last: Frame,
+    flags: EntryFlags,
+    what: &str,
+) -> Result<(), &'static str> {
+    let address = first.start_address().get();
+
+    match mapper.try_identity_map_range(first, last, flags) {
+        Ok(result) => {
+            // Forget this result since this table is not currently active.
+            unsafe { result.forget() };
+            Ok(())
+        }
+        Err(e) => {
+            println!("[ vmm ] Failed to identity map {} at {:#x}.", what, address);
+            Err(e)
+        }
+    }
 }
@@ -0,0 +1,260 @@
+//! Checking that the kernel ended up mapped with the permissions its ELF sections ask for: code
+//! executable and read-only, read-only data neither writable nor executable, and data writable but
+//! not executable. A page which is both writable and executable lets a stray write become code, so
+//! any such page in the whole address space is reported too.
+//!
+//! No page may be both writable and executable (W^X), unless a range has been allowed to be with
+//! `allow_writable_executable`. `paging::init` panics if the kernel's own table breaks the rule,
+//! or only warns if `wx=log` is on the command line.
+
+use super::{Mapper, Page, VirtualAddress};
+use super::entry::EntryFlags;
+use super::walker::PageTableWalker;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use multiboot2::{BootInformation, ElfSection};
+use spin::{Mutex, Once};
+
+/// Most ranges which can be allowed to be writable and executable at once.
+const MAX_WX_EXCEPTIONS: usize = 8;
+
+/// A range allowed to be writable and executable, and why.
+#[derive(Debug, Clone, Copy)]
+struct WxException {
+    start: usize,
+    /// One past the last byte.
+    end: usize,
+    reason: &'static str,
+}
+
+static WX_EXCEPTIONS: Mutex<[Option<WxException>; MAX_WX_EXCEPTIONS]> =
+    Mutex::new([None; MAX_WX_EXCEPTIONS]);
+
+/// Set when W^X violations are only logged, rather than panicked on.
+static WX_LOG_ONLY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// What `verify_kernel_permissions` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionReport {
+    /// Pages of kernel sections which are unmapped, or mapped with other permissions than their
+    /// section asks for.
+    pub wrong: usize,
+    /// Pages shared by kernel sections which ask for different permissions. Only one section can
+    /// have its way, so these are warned about rather than counted as wrong.
+    pub shared: usize,
+    /// Mappings anywhere in the table which are both writable and executable.
+    pub writable_executable: usize,
+    /// Mappings which are both writable and executable, but lie in a range allowed to be.
+    pub allowed_writable_executable: usize,
+}
+
+/// The report on the kernel's own page table, made by `paging::init` right after switching to it.
+static BOOT_REPORT: Once<PermissionReport> = Once::new();
+
+/// Return the report made when the kernel's page table was switched to, or `None` before then.
+pub fn boot_report() -> Option<PermissionReport> {
+    BOOT_REPORT.try().cloned()
+}
+
+/// Record the report on the kernel's own page table. Only the first call has any effect.
+pub fn set_boot_report(report: PermissionReport) {
+    BOOT_REPORT.call_once(|| report);
+}
+
+/// Allow the `size` bytes from `start` to be mapped writable and executable, for `reason`, such as
+/// code generated at run time. Fails if the range is empty or wraps around, or if too many ranges
+/// are allowed already.
+pub fn allow_writable_executable(
+    start: VirtualAddress,
+    size: usize,
+    reason: &'static str,
+) -> Result<(), &'static str> {
+    let end = start.get().checked_add(size).ok_or("range wraps around")?;
+    if size == 0 {
+        return Err("range is empty");
+    }
+
+    let mut exceptions = WX_EXCEPTIONS.lock();
+    let slot = exceptions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or("too many writable and executable ranges")?;
+    *slot = Some(WxException {
+        start: start.get(),
+        end: end,
+        reason: reason,
+    });
+    Ok(())
+}
+
+/// Withdraw the exception for the range starting at `start`. Returns whether there was one.
+pub fn disallow_writable_executable(start: VirtualAddress) -> bool {
+    let mut exceptions = WX_EXCEPTIONS.lock();
+    let slot = exceptions
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |exception| exception.start == start.get()));
+
+    match slot {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Return why `page` may be writable and executable, or `None` if it may not be.
+pub fn writable_executable_reason(page: Page) -> Option<&'static str> {
+    let start = page.start_address().get();
+    let exceptions = WX_EXCEPTIONS.lock();
+    let reason = exceptions
+        .iter()
+        .filter_map(|slot| *slot)
+        .find(|exception| start >= exception.start && start < exception.end)
+        .map(|exception| exception.reason);
+    reason
+}
+
+/// Only warn about W^X violations from now on, instead of panicking. Set by `wx=log`.
+pub fn set_wx_log_only(log_only: bool) {
+    WX_LOG_ONLY.store(log_only, Ordering::SeqCst);
+}
+
+/// Apply the W^X policy to an audit which found `violations` mappings writable and executable
+/// without being allowed to be: panic, or only warn if `set_wx_log_only` was called.
+pub fn enforce_writable_executable(violations: usize) {
+    if violations == 0 {
+        return;
+    }
+
+    if WX_LOG_ONLY.load(Ordering::SeqCst) {
+        println!(
+            "[ vmm ] Warning: {} mappings are writable and executable.",
+            violations
+        );
+    } else {
+        panic!("{} mappings are writable and executable", violations);
+    }
+}
+
+/// Look for writable and executable mappings anywhere in `mapper`, logging each one. Returns the
+/// number which are not allowed to be, and the number which are.
+pub fn audit_writable_executable(mapper: &Mapper) -> (usize, usize) {
+    let (mut violations, mut allowed) = (0, 0);
+
+    for (page, _, _) in PageTableWalker::new(mapper) {
+        let flags = match mapper.page_flags(page) {
+            Some(flags) => flags,
+            None => continue,
+        };
+        if permissions(flags) != EntryFlags::WRITABLE {
+            continue;
+        }
+
+        match writable_executable_reason(page) {
+            Some(reason) => {
+                println!(
+                    "[ vmm ] Mapping at {:#x} is writable and executable, for {}.",
+                    page.start_address().get(),
+                    reason
+                );
+                allowed += 1;
+            }
+            None => {
+                println!(
+                    "[ vmm ] Mapping at {:#x} is writable and executable.",
+                    page.start_address().get()
+                );
+                violations += 1;
+            }
+        }
+    }
+
+    (violations, allowed)
+}
+
+/// Return the pages `section` covers.
+fn section_pages(section: &ElfSection) -> (Page, Page) {
+    (
+        Page::containing_address(VirtualAddress::new(section.start_address() as usize)),
+        Page::containing_address(VirtualAddress::new(section.end_address() as usize - 1)),
+    )
+}
+
+/// Return the flags which make up a page's permissions.
+fn permissions(flags: EntryFlags) -> EntryFlags {
+    flags & (EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)
+}
+
+/// Check every page of the kernel's allocated ELF sections against the permissions of its section,
+/// and look for writable and executable mappings anywhere in `mapper`. Each problem is logged.
+pub fn verify_kernel_permissions(mapper: &Mapper, boot_info: &BootInformation) -> PermissionReport {
+    let mut report = PermissionReport {
+        wrong: 0,
+        shared: 0,
+        writable_executable: 0,
+        allowed_writable_executable: 0,
+    };
+
+    if let Some(elf_sections_tag) = boot_info.elf_sections_tag() {
+        let sections = || elf_sections_tag.sections().filter(|s| s.is_allocated());
+
+        for section in sections() {
+            let expected = permissions(EntryFlags::from_elf_section_flags(&section));
+            let (first, last) = section_pages(&section);
+
+            for page in Page::range_inclusive(first, last) {
+                // Whether sections which start below and above this one share the page and ask
+                // for different permissions.
+                let (below, above) = sections()
+                    .filter(|other| {
+                        let (other_first, other_last) = section_pages(other);
+                        other_first <= page && page <= other_last
+                            && permissions(EntryFlags::from_elf_section_flags(other)) != expected
+                    })
+                    .fold((false, false), |(below, above), other| {
+                        let lower = other.start_address() < section.start_address();
+                        (below || lower, above || !lower)
+                    });
+
+                // Warn once per page, from the lowest of the sections sharing it.
+                if above && !below {
+                    println!(
+                        "[ vmm ] Warning: page {:#x} is shared by sections with different \
+                         permissions.",
+                        page.start_address().get()
+                    );
+                    report.shared += 1;
+                }
+                if below || above {
+                    continue;
+                }
+
+                match mapper.page_flags(page) {
+                    Some(flags) if permissions(flags) == expected => (),
+                    Some(flags) => {
+                        println!(
+                            "[ vmm ] Kernel page {:#x} is mapped {:?}, expected {:?}.",
+                            page.start_address().get(),
+                            permissions(flags),
+                            expected
+                        );
+                        report.wrong += 1;
+                    }
+                    None => {
+                        println!(
+                            "[ vmm ] Kernel page {:#x} is not mapped.",
+                            page.start_address().get()
+                        );
+                        report.wrong += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let (violations, allowed) = audit_writable_executable(mapper);
+    report.writable_executable = violations;
+    report.allowed_writable_executable = allowed;
+
+    report
+}
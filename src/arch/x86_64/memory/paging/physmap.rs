@@ -0,0 +1,241 @@
+//! A linear mapping of physical memory at a fixed offset in the higher half, so that any physical
+//! address can be reached without mapping it first, and without going through the recursive
+//! mapping.
+//!
+//! `paging::init` maps every frame from zero up to the end of RAM, or up to 4 GiB if that is
+//! higher so that the usual MMIO hole below 4 GiB is covered too. It uses 1 GiB pages if the CPU
+//! has them, and 2 MiB pages otherwise, falling back on smaller pages where a huge page would
+//! cover memory of more than one kind. The whole mapping is no-execute. RAM is writable and
+//! cached, except for the kernel image, which is read-only here so that its code and read-only
+//! data cannot be changed through this back door. Everything else, which may be device registers,
+//! is uncached. A driver which needs another memory type, such as write-combining for a
+//! framebuffer, should still map its registers itself.
+
+use super::{Mapper, Page, PhysicalAddress, VirtualAddress};
+use super::entry::EntryFlags;
+use arch::cpuid::{self, CpuFeatures};
+use arch::memory::{addr, Frame, PAGE_SIZE};
+use arch::memory::memory_map;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use multiboot2::BootInformation;
+
+/// Where physical address zero is mapped.
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8000_0000_0000;
+
+/// The linear mapping always covers at least the first 4 GiB.
+const MIN_PHYSICAL_END: usize = 0x1_0000_0000;
+
+/// The end of the physical memory which is mapped, or zero before the mapping is in use.
+static MAPPED_END: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Return the address `address` is mapped at in the linear mapping, or `None` if it lies past the
+/// end of the mapping, or the mapping is not set up yet.
+pub fn phys_to_virt(address: PhysicalAddress) -> Option<VirtualAddress> {
+    if address.get() < MAPPED_END.load(Ordering::SeqCst) {
+        Some(VirtualAddress::new(PHYSICAL_MEMORY_OFFSET + address.get()))
+    } else {
+        None
+    }
+}
+
+/// Return the physical address `address` is mapped to. Addresses in the linear mapping are worked
+/// out directly, and any other is looked up in the page tables.
+pub fn virt_to_phys(address: VirtualAddress) -> Option<PhysicalAddress> {
+    let offset = address.get().wrapping_sub(PHYSICAL_MEMORY_OFFSET);
+    if address.get() >= PHYSICAL_MEMORY_OFFSET && offset < MAPPED_END.load(Ordering::SeqCst) {
+        return Some(PhysicalAddress::new(offset));
+    }
+
+    // Only reads the page tables, through the recursive mapping.
+    let mapper = unsafe { Mapper::new() };
+    mapper.translate(address)
+}
+
+/// Return the end of the physical memory which is mapped, or zero before the mapping is in use.
+pub fn mapped_end() -> usize {
+    MAPPED_END.load(Ordering::SeqCst)
+}
+
+/// Record that the linear mapping up to `end` is in use, once the table holding it is active.
+pub fn set_mapped_end(end: usize) {
+    MAPPED_END.store(end, Ordering::SeqCst);
+}
+
+/// Map physical memory at `PHYSICAL_MEMORY_OFFSET` in the table being built by `paging::init`.
+/// Returns the end of the physical memory mapped. Fails if we run out of frames for page tables.
+pub fn map_physical_memory(
+    mapper: &mut Mapper,
+    boot_info: &BootInformation,
+) -> Result<usize, &'static str> {
+    let ram_end = memory_map::areas(boot_info).map_or(0, |areas| {
+        areas
+            .map(|area| area.start_address() + area.size())
+            .max()
+            .unwrap_or(0)
+    });
+    let end = addr::align_up(cmp::max(ram_end, MIN_PHYSICAL_END), addr::HUGE_1GIB_PAGE_SIZE)
+        .ok_or("physical memory too large to map")?;
+
+    let gigabyte_pages = cpuid::features().contains(CpuFeatures::PAGE_1GB);
+    let (step, size) = if gigabyte_pages {
+        (addr::HUGE_1GIB_PAGE_SIZE, "1 GiB")
+    } else {
+        (addr::HUGE_PAGE_SIZE, "2 MiB")
+    };
+    println!(
+        "[ vmm ] Mapping {} MiB of physical memory at {:#x} with {} pages.",
+        end / (1024 * 1024),
+        PHYSICAL_MEMORY_OFFSET,
+        size
+    );
+
+    let layout = Layout::new(boot_info)?;
+    let mut physical = 0;
+    while physical < end {
+        map_chunk(mapper, &layout, physical, step)?;
+        physical += step;
+    }
+
+    Ok(end)
+}
+
+/// What a range of physical memory holds, which decides how it is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ram,
+    Kernel,
+    /// Neither usable RAM nor the kernel, so possibly device registers.
+    Other,
+}
+
+impl Kind {
+    fn flags(&self) -> EntryFlags {
+        match *self {
+            Kind::Ram => EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            Kind::Kernel => EntryFlags::NO_EXECUTE,
+            Kind::Other => EntryFlags::mmio(),
+        }
+    }
+}
+
+/// Where RAM and the kernel image lie in physical memory.
+struct Layout<'a> {
+    boot_info: &'a BootInformation,
+    kernel_start: usize,
+    kernel_end: usize,
+}
+
+impl<'a> Layout<'a> {
+    fn new(boot_info: &'a BootInformation) -> Result<Self, &'static str> {
+        // The kernel is identity mapped, so its sections' addresses are physical addresses.
+        let elf_sections_tag = boot_info.elf_sections_tag().ok_or("no ELF sections tag")?;
+        let kernel_start = elf_sections_tag
+            .sections()
+            .filter(|s| s.is_allocated())
+            .map(|s| s.start_address())
+            .min()
+            .ok_or("no allocated kernel sections")?;
+        let kernel_end = elf_sections_tag
+            .sections()
+            .filter(|s| s.is_allocated())
+            .map(|s| s.start_address() + s.size())
+            .max()
+            .ok_or("no allocated kernel sections")?;
+
+        Ok(Layout {
+            boot_info: boot_info,
+            kernel_start: kernel_start as usize,
+            kernel_end: kernel_end as usize,
+        })
+    }
+
+    fn overlaps_kernel(&self, start: usize, end: usize) -> bool {
+        start < self.kernel_end && self.kernel_start < end
+    }
+
+    /// Return what the memory from `start` to `end` holds, or `None` if it is a mixture.
+    fn kind(&self, start: usize, end: usize) -> Option<Kind> {
+        if self.overlaps_kernel(start, end) {
+            let inside = self.kernel_start <= start && end <= self.kernel_end;
+            return if inside { Some(Kind::Kernel) } else { None };
+        }
+
+        let mut overlaps_ram = false;
+        if let Some(areas) = memory_map::areas(self.boot_info) {
+            for area in areas {
+                let area_start = area.start_address();
+                let area_end = area_start + area.size();
+                if area_start <= start && end <= area_end {
+                    return Some(Kind::Ram);
+                }
+                overlaps_ram |= start < area_end && area_start < end;
+            }
+        }
+
+        if overlaps_ram {
+            None
+        } else {
+            Some(Kind::Other)
+        }
+    }
+
+    /// Return how to treat the page of memory at `start`. A page which is partly RAM, as when an
+    /// area is not page aligned, is treated as RAM, and one which is partly kernel as kernel.
+    fn page_kind(&self, start: usize) -> Kind {
+        match self.kind(start, start + PAGE_SIZE) {
+            Some(kind) => kind,
+            None if self.overlaps_kernel(start, start + PAGE_SIZE) => Kind::Kernel,
+            None => Kind::Ram,
+        }
+    }
+}
+
+/// Map the `size` bytes of physical memory from `start`, a 1 GiB or 2 MiB chunk, with a single
+/// huge page if it is all of one kind, and otherwise with pages of the next size down.
+fn map_chunk(
+    mapper: &mut Mapper,
+    layout: &Layout,
+    start: usize,
+    size: usize,
+) -> Result<(), &'static str> {
+    let page_at = |physical: usize| {
+        Page::containing_address(VirtualAddress::new(PHYSICAL_MEMORY_OFFSET + physical))
+    };
+    let frame_at = |physical: usize| Frame::containing_address(PhysicalAddress::new(physical));
+
+    // Results are ignored since this table is not currently active.
+    match layout.kind(start, start + size) {
+        Some(kind) if size == addr::HUGE_1GIB_PAGE_SIZE => unsafe {
+            mapper
+                .try_map_to_1gib(page_at(start), frame_at(start), kind.flags())?
+                .ignore()
+        },
+        Some(kind) => unsafe {
+            mapper
+                .try_map_to_huge(page_at(start), frame_at(start), kind.flags())?
+                .ignore()
+        },
+        None if size == addr::HUGE_1GIB_PAGE_SIZE => {
+            let mut physical = start;
+            while physical < start + size {
+                map_chunk(mapper, layout, physical, addr::HUGE_PAGE_SIZE)?;
+                physical += addr::HUGE_PAGE_SIZE;
+            }
+        }
+        None => {
+            let mut physical = start;
+            while physical < start + size {
+                let flags = layout.page_kind(physical).flags();
+                unsafe {
+                    mapper
+                        .try_map_to(page_at(physical), frame_at(physical), flags)?
+                        .ignore()
+                };
+                physical += PAGE_SIZE;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use arch::memory::paging::entry::EntryFlags;
 use arch::memory::paging::entry::*;
 use arch::memory::paging::ENTRY_COUNT;
-use arch::memory::allocate_frames;
+use arch::memory::{allocate_frames, Frame};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
@@ -22,6 +22,31 @@ where
         for entry in self.entries.iter_mut() {
             entry.set_unused();
         }
+        self.entries[0].set_counter(0);
+    }
+
+    /// Return the number of entries in use, as counted by `add_used` and `remove_used`. Entries
+    /// set some other way, like the recursive entry of a P4 table, are not counted.
+    pub fn used_entries(&self) -> usize {
+        self.entries[0].counter()
+    }
+
+    /// Count an entry which has just been put to use.
+    pub fn add_used(&mut self) {
+        let count = self.entries[0].counter();
+        self.entries[0].set_counter(count + 1);
+    }
+
+    /// Stop counting an entry which has just been set unused.
+    pub fn remove_used(&mut self) {
+        let count = self.entries[0].counter();
+        self.entries[0].set_counter(count.saturating_sub(1));
+    }
+
+    /// Return whether the table maps nothing at all. This checks every entry rather than trust the
+    /// count.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
     }
 }
 
@@ -55,16 +80,55 @@ where
     }
 
     pub fn next_table_create(&mut self, index: usize) -> &mut Table<L::NextLevel> {
+        self.try_next_table_create(index).expect("no frames available")
+    }
+
+    /// Return the next table, creating it if it does not exist yet. Fails if there is no free frame
+    /// to hold a new table.
+    pub fn try_next_table_create(
+        &mut self,
+        index: usize,
+    ) -> Result<&mut Table<L::NextLevel>, &'static str> {
         if self.next_table(index).is_none() {
             assert!(
                 !self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
                 "mapping code does not support huge pages"
             );
-            let frame = allocate_frames(1).expect("no frames available");
+            let frame = allocate_frames(1).ok_or("no frames available for a page table")?;
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            self.add_used();
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
+    }
+
+    /// Let user mode through the entry at `index`. The CPU only allows a user access if every level
+    /// of the walk does, so the last level alone then decides.
+    pub fn allow_user_access(&mut self, index: usize) {
+        let flags = self.entries[index].flags();
+        if !flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            let frame = self.entries[index].frame();
+            self.entries[index].set(frame, flags | EntryFlags::USER_ACCESSIBLE);
+        }
+    }
+
+    /// Unlink the next table at `index` if it maps nothing, and return its frame and the address it
+    /// was reachable at through the recursive mapping, so that the caller can flush that address
+    /// and free the frame.
+    pub fn unlink_empty_table(&mut self, index: usize) -> Option<(Frame, usize)> {
+        let address = self.next_table_address(index)?;
+        let empty = {
+            let table = self.next_table(index)?;
+            table.used_entries() == 0 && table.is_empty()
+        };
+        if !empty {
+            return None;
+        }
+
+        let frame = self.entries[index].pointed_frame()?;
+        self.entries[index].set_unused();
+        self.remove_used();
+        Some((frame, address))
     }
 }
 
@@ -37,7 +37,8 @@ impl TemporaryPage {
 
     /// Unmaps the temporary page in the active table.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        let result = active_table.unmap(self.page);
+        // The frame belongs to whoever asked for it to be mapped.
+        let (result, _frame) = active_table.unmap(self.page);
         result.flush(active_table);
     }
 }
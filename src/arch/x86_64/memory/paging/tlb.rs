@@ -0,0 +1,114 @@
+//! TLB shootdowns. Every CPU shares the kernel address space, so when a mapping is removed on one
+//! CPU the stale translation has to be invalidated on all the others before the frame behind it can
+//! be reused.
+//!
+//! A request covers a range of pages, so unmapping many pages costs one round of IPIs rather than
+//! one per page. A CPU flushes a range of more than `MAX_TARGETED_PAGES` pages by reloading `cr3`.
+//!
+//! A CPU may start a shootdown with interrupts disabled, so it cannot count on taking the IPI for
+//! somebody else's request while it waits for its own turn. It polls for one instead, and flushes
+//! and acknowledges as the handler would.
+
+use super::{flush, flush_all, Page, VirtualAddress};
+use arch::interrupts::{stats, InterruptGuard};
+use arch::percpu;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use device::apic;
+use spin::Mutex;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+/// The vector the shootdown IPI is delivered on.
+pub const SHOOTDOWN_VECTOR: u8 = 0x40;
+
+/// Most pages flushed one at a time. A larger range flushes the whole TLB.
+const MAX_TARGETED_PAGES: usize = 32;
+
+/// Serialises shootdowns, since there is a single request slot.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+/// Address of the first page being shot down.
+static SHOOTDOWN_ADDRESS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Number of pages being shot down.
+static SHOOTDOWN_PAGES: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Bit mask of the CPUs, by `cpu_id`, which have not yet flushed the pages.
+static PENDING_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Invalidate `page` in the TLB of every CPU. This returns only once every other CPU has
+/// acknowledged the flush, so the frame the page pointed to can be reused safely afterwards.
+pub fn shootdown(page: Page) {
+    shootdown_range(page, page);
+}
+
+/// Invalidate the pages from `start` to `end` inclusive in the TLB of every CPU, with a single
+/// IPI to each. Like `shootdown`, this waits for every other CPU to acknowledge.
+pub fn shootdown_range(start: Page, end: Page) {
+    let pages = (end.number + 1).saturating_sub(start.number);
+    flush_pages(start, pages);
+
+    let others = percpu::online_cpus().saturating_sub(1);
+    if others == 0 || pages == 0 {
+        return;
+    }
+
+    // The CPU holding the lock waits for this one to acknowledge its request.
+    let _lock = loop {
+        if let Some(lock) = SHOOTDOWN_LOCK.try_lock() {
+            break lock;
+        }
+        service_request();
+        spin_loop_hint();
+    };
+
+    let this_cpu = 1 << percpu::this_cpu().cpu_id;
+    let online = percpu::online_mask();
+
+    SHOOTDOWN_ADDRESS.store(start.start_address().get(), Ordering::SeqCst);
+    SHOOTDOWN_PAGES.store(pages, Ordering::SeqCst);
+    PENDING_CPUS.store(online & !this_cpu, Ordering::SeqCst);
+
+    apic::broadcast_ipi(SHOOTDOWN_VECTOR);
+
+    // Any other initiator is spinning on the lock, servicing this request as it does, so there is
+    // nothing aimed at this CPU to service meanwhile.
+    while PENDING_CPUS.load(Ordering::SeqCst) != 0 {
+        spin_loop_hint();
+    }
+}
+
+/// If this CPU has yet to flush the pages of the current request, flush them and acknowledge.
+fn service_request() {
+    // The IPI handler must not service the same request in between the check and the
+    // acknowledgement, lest this then acknowledge the next request without flushing its pages.
+    let _guard = InterruptGuard::new();
+
+    let this_cpu = 1 << percpu::this_cpu().cpu_id;
+    if PENDING_CPUS.load(Ordering::SeqCst) & this_cpu == 0 {
+        return;
+    }
+
+    let address = SHOOTDOWN_ADDRESS.load(Ordering::SeqCst);
+    let pages = SHOOTDOWN_PAGES.load(Ordering::SeqCst);
+    flush_pages(Page::containing_address(VirtualAddress::new(address)), pages);
+
+    PENDING_CPUS.fetch_and(!this_cpu, Ordering::SeqCst);
+}
+
+/// Flush `pages` pages from `start` on this CPU, or the whole TLB if there are too many.
+fn flush_pages(start: Page, pages: usize) {
+    if pages > MAX_TARGETED_PAGES {
+        flush_all();
+    } else {
+        for offset in 0..pages {
+            flush(start + offset);
+        }
+    }
+}
+
+/// Handler for the shootdown IPI. Flushes the requested pages and acknowledges, unless this CPU
+/// already did while waiting to start a shootdown of its own.
+pub extern "x86-interrupt" fn shootdown_handler(_stack_frame: &mut ExceptionStackFrame) {
+    stats::count(SHOOTDOWN_VECTOR);
+    let _context = percpu::InterruptContext::enter();
+    service_request();
+
+    apic::eoi();
+}
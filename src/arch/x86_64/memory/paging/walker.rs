@@ -0,0 +1,169 @@
+//! Walking every present mapping of the active page table through the recursive mapping, for
+//! checking the address space and for debugging.
+
+use super::{Mapper, Page, VirtualAddress, ENTRY_COUNT};
+use super::entry::EntryFlags;
+use arch::memory::{Frame, PAGE_SIZE};
+use core::fmt::{self, Write};
+
+/// The P4 entry which maps the page tables themselves. It is skipped, since otherwise every table
+/// would be reported as a mapping of its own.
+const RECURSIVE_ENTRY: usize = 511;
+
+/// An iterator over the present mappings of a page table, in address order. Each item is the
+/// first page of a mapping, the frame it maps to and the entry's flags. A huge page is yielded
+/// once, with `EntryFlags::HUGE_PAGE` set.
+pub struct PageTableWalker<'a> {
+    mapper: &'a Mapper,
+    /// Index of the next entry to look at in the P4, P3, P2 and P1 tables.
+    next: [usize; 4],
+}
+
+impl<'a> PageTableWalker<'a> {
+    pub fn new(mapper: &'a Mapper) -> Self {
+        PageTableWalker {
+            mapper: mapper,
+            next: [0; 4],
+        }
+    }
+
+    /// Move past the current entry at `level` (0 for P4, 3 for P1) and everything below it.
+    fn skip(&mut self, level: usize) {
+        self.next[level] += 1;
+        for index in self.next[level + 1..].iter_mut() {
+            *index = 0;
+        }
+
+        if level > 0 && self.next[level] == ENTRY_COUNT {
+            self.skip(level - 1);
+        }
+    }
+
+    /// Return the page at the current indices.
+    fn page(&self) -> Page {
+        let address = self.next[0] << 39 | self.next[1] << 30 | self.next[2] << 21
+            | self.next[3] << 12;
+
+        // Sign extend bit 47, so that upper half addresses are canonical.
+        let address = if address & (1 << 47) != 0 {
+            address | 0xffff_0000_0000_0000
+        } else {
+            address
+        };
+
+        Page::containing_address(VirtualAddress::new(address))
+    }
+}
+
+impl<'a> Iterator for PageTableWalker<'a> {
+    type Item = (Page, Frame, EntryFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_size().map(|(mapping, _)| mapping)
+    }
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// Return the next mapping and the number of bytes it maps.
+    fn next_with_size(&mut self) -> Option<((Page, Frame, EntryFlags), usize)> {
+        const P2_SIZE: usize = PAGE_SIZE * ENTRY_COUNT;
+        const P3_SIZE: usize = P2_SIZE * ENTRY_COUNT;
+
+        // The tables are reached through the mapper, not through `self`, which is updated below.
+        let mapper = self.mapper;
+
+        while self.next[0] < RECURSIVE_ENTRY {
+            let p3 = match mapper.p4().next_table(self.next[0]) {
+                Some(p3) => p3,
+                None => {
+                    self.skip(0);
+                    continue;
+                }
+            };
+
+            let entry = &p3[self.next[1]];
+            if !entry.flags().contains(EntryFlags::PRESENT) {
+                self.skip(1);
+                continue;
+            }
+            if entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                let mapping = (self.page(), entry.pointed_frame()?, entry.flags());
+                self.skip(1);
+                return Some((mapping, P3_SIZE));
+            }
+
+            let p2 = p3.next_table(self.next[1])?;
+            let entry = &p2[self.next[2]];
+            if !entry.flags().contains(EntryFlags::PRESENT) {
+                self.skip(2);
+                continue;
+            }
+            if entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                let mapping = (self.page(), entry.pointed_frame()?, entry.flags());
+                self.skip(2);
+                return Some((mapping, P2_SIZE));
+            }
+
+            let p1 = p2.next_table(self.next[2])?;
+            let entry = &p1[self.next[3]];
+            let mapping = if entry.flags().contains(EntryFlags::PRESENT) {
+                Some((self.page(), entry.pointed_frame()?, entry.flags()))
+            } else {
+                None
+            };
+            self.skip(3);
+
+            if let Some(mapping) = mapping {
+                return Some((mapping, PAGE_SIZE));
+            }
+        }
+
+        None
+    }
+}
+
+/// Write every present mapping of `mapper` to `out`, one line per run of pages which map
+/// contiguous frames with the same flags.
+pub fn dump_mappings<W: Write>(mapper: &Mapper, out: &mut W) -> fmt::Result {
+    // The run being built: first page address, first frame address, length and flags.
+    let mut run: Option<(usize, usize, usize, EntryFlags)> = None;
+    let mut walker = PageTableWalker::new(mapper);
+
+    while let Some(((page, frame, flags), size)) = walker.next_with_size() {
+        let virt = page.start_address().get();
+        let phys = frame.start_address().get();
+
+        if let Some((start, start_phys, len, run_flags)) = run {
+            if start + len == virt && start_phys + len == phys && run_flags == flags {
+                run = Some((start, start_phys, len + size, run_flags));
+                continue;
+            }
+
+            write_run(out, start, start_phys, len, run_flags)?;
+        }
+
+        run = Some((virt, phys, size, flags));
+    }
+
+    match run {
+        Some((start, start_phys, len, flags)) => write_run(out, start, start_phys, len, flags),
+        None => Ok(()),
+    }
+}
+
+fn write_run<W: Write>(
+    out: &mut W,
+    start: usize,
+    start_phys: usize,
+    len: usize,
+    flags: EntryFlags,
+) -> fmt::Result {
+    writeln!(
+        out,
+        "{:#018x}-{:#018x} -> {:#x} {:?}",
+        start,
+        start + len - 1,
+        start_phys,
+        flags
+    )
+}
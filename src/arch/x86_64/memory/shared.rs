@@ -0,0 +1,169 @@
+//! Physical memory shared between tasks, for passing data without copying it.
+//!
+//! `create_shared` allocates the frames and returns a `ShmHandle`, which can be cloned and passed
+//! to other tasks. Each task maps the frames with `map_shared`, at a page of its choosing. The
+//! frames are reference counted: every handle and every mapping holds a reference, and the frames
+//! are only freed once the last of them is dropped. So the creator can unmap and drop its handle
+//! while another task still uses the memory.
+//!
+//! Tasks share the kernel's address space for now, so two mappings in different tasks must still
+//! be at different pages.
+
+use alloc::arc::Arc;
+use arch::memory::{allocate_frames, deallocate_frame, Frame, PAGE_SIZE};
+use arch::memory::paging::{ActivePageTable, EntryFlags, Page, VirtualAddress};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Number of shared regions whose frames have not been freed yet.
+static LIVE_REGIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The frames of a shared region. Freed when the last reference is dropped.
+struct SharedFrames {
+    /// Number of the first frame. The frames are contiguous.
+    start: usize,
+    pages: usize,
+    /// Number of mappings of the frames, as opposed to handles.
+    mappings: AtomicUsize,
+}
+
+impl SharedFrames {
+    fn frame(&self, index: usize) -> Frame {
+        Frame {
+            number: self.start + index,
+        }
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        for index in 0..self.pages {
+            deallocate_frame(self.frame(index));
+        }
+
+        LIVE_REGIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A reference to a shared region, which can be cloned and mapped by any task.
+#[derive(Clone)]
+pub struct ShmHandle(Arc<SharedFrames>);
+
+impl ShmHandle {
+    /// Return the size of the region in pages.
+    pub fn pages(&self) -> usize {
+        self.0.pages
+    }
+
+    /// Return the number of mappings of the region which have not been dropped.
+    pub fn mappings(&self) -> usize {
+        self.0.mappings.load(Ordering::SeqCst)
+    }
+}
+
+/// Allocate a shared region of `pages` contiguous frames, cleared so that no task sees what was
+/// left in them.
+pub fn create_shared(pages: usize) -> Result<ShmHandle, &'static str> {
+    if pages == 0 {
+        return Err("shared region has zero size");
+    }
+
+    let start = allocate_frames(pages).ok_or("no frames for shared region")?;
+    LIVE_REGIONS.fetch_add(1, Ordering::SeqCst);
+
+    let frames = SharedFrames {
+        start: start.number,
+        pages: pages,
+        mappings: AtomicUsize::new(0),
+    };
+
+    // Free frames are not mapped anywhere, so each is cleared through an identity mapping.
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    for index in 0..pages {
+        let frame = frames.frame(index);
+        let address = frame.start_address().get();
+        let page = Page::containing_address(VirtualAddress::new(address));
+
+        let result = active_table.try_identity_map(frame, flags)?;
+        result.flush(&mut active_table);
+        unsafe { ptr::write_bytes(address as *mut u8, 0, PAGE_SIZE) };
+        let (result, _frame) = active_table.unmap(page);
+        result.flush(&mut active_table);
+    }
+
+    Ok(ShmHandle(Arc::new(frames)))
+}
+
+/// Map the region behind `handle` at `page` and the pages after it. `flags` should include
+/// `EntryFlags::WRITABLE` unless the task only reads. Fails, without mapping anything, if one of
+/// the pages is already mapped.
+pub fn map_shared(
+    active_table: &mut ActivePageTable,
+    handle: &ShmHandle,
+    page: Page,
+    flags: EntryFlags,
+) -> Result<SharedMapping, &'static str> {
+    let frames = &handle.0;
+    let end_page = page.checked_add(frames.pages - 1).ok_or("shared mapping out of range")?;
+
+    if Page::range_inclusive(page, end_page).any(|page| active_table.is_mapped(page)) {
+        return Err("shared mapping overlaps a mapped page");
+    }
+
+    // Grows as pages are mapped, so that if mapping fails, dropping it undoes what was done.
+    let mut mapping = SharedMapping {
+        frames: frames.clone(),
+        start: page,
+        pages: 0,
+    };
+    frames.mappings.fetch_add(1, Ordering::SeqCst);
+
+    for (index, page) in Page::range_inclusive(page, end_page).enumerate() {
+        let result = active_table.try_map_to(page, frames.frame(index), flags)?;
+        result.flush(active_table);
+        mapping.pages += 1;
+    }
+
+    Ok(mapping)
+}
+
+/// A task's mapping of a shared region. Dropping it unmaps the pages, and frees the frames if
+/// nothing else refers to them.
+pub struct SharedMapping {
+    frames: Arc<SharedFrames>,
+    start: Page,
+    /// Number of pages mapped so far.
+    pages: usize,
+}
+
+impl SharedMapping {
+    /// Return the address of the start of the mapping.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start.start_address()
+    }
+
+    /// Return a pointer to the start of the mapping.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.start_address().get() as *mut u8
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        // The frames are freed with the last handle to them, not with each mapping.
+        for index in 0..self.pages {
+            let (result, _frame) = active_table.unmap(self.start + index);
+            result.flush(&mut active_table);
+        }
+
+        self.frames.mappings.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Return the number of shared regions whose frames have not been freed.
+pub fn live_regions() -> usize {
+    LIVE_REGIONS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,156 @@
+//! Slab caches for small heap allocations.
+//!
+//! Most kernel objects, such as processes, wait queue nodes and address space areas, are small and
+//! allocated and freed over and over. Rather than have the linked list heap search and split its
+//! free list each time, an allocation of up to `MAX_OBJECT_SIZE` bytes is rounded up to a power of
+//! two size class, and taken from that class's cache. A cache carves page-sized slabs into objects
+//! of its size, and keeps the free ones on a list threaded through the objects themselves, so
+//! allocating and freeing are a couple of pointer moves. Objects of one size never share a slab
+//! with another, which keeps small allocations from fragmenting the heap.
+//!
+//! Slabs come from the heap, and are never given back to it.
+
+use arch::interrupts::disable_interrupts_and_then;
+use arch::memory::PAGE_SIZE;
+use spin::Mutex;
+
+/// Size of the smallest class, which must hold the free list link.
+const MIN_OBJECT_SIZE: usize = 16;
+/// Size of the largest class. Larger allocations go straight to the heap.
+pub const MAX_OBJECT_SIZE: usize = 2048;
+/// Number of size classes, one for each power of two from `MIN_OBJECT_SIZE` to `MAX_OBJECT_SIZE`.
+pub const SIZE_CLASSES: usize = 8;
+
+/// Return the index of the size class which holds an object of `size` bytes aligned to `align`,
+/// or `None` if it is too large for any of them. Objects of a class are aligned to its size.
+pub fn size_class(size: usize, align: usize) -> Option<usize> {
+    let size = if size > align { size } else { align };
+    if size > MAX_OBJECT_SIZE {
+        return None;
+    }
+
+    let size = if size < MIN_OBJECT_SIZE {
+        MIN_OBJECT_SIZE
+    } else {
+        size.next_power_of_two()
+    };
+    Some((size / MIN_OBJECT_SIZE).trailing_zeros() as usize)
+}
+
+/// Return the size of objects in size class `class`.
+pub fn class_size(class: usize) -> usize {
+    MIN_OBJECT_SIZE << class
+}
+
+/// The statistics of a cache at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Size of each object.
+    pub object_size: usize,
+    /// Objects handed out and not yet freed.
+    pub in_use: usize,
+    /// Objects on the free list.
+    pub free: usize,
+    /// Slabs carved up so far.
+    pub slabs: usize,
+    /// Allocations served since boot.
+    pub allocations: u64,
+}
+
+struct CacheState {
+    /// Address of the first free object, or zero. Each free object holds the address of the next.
+    free_list: usize,
+    free: usize,
+    in_use: usize,
+    slabs: usize,
+    allocations: u64,
+}
+
+/// A cache of objects of one size.
+pub struct SlabCache {
+    object_size: usize,
+    state: Mutex<CacheState>,
+}
+
+impl SlabCache {
+    /// Create an empty cache of `object_size` byte objects. The size must be a power of two of at
+    /// least 16 bytes, and at most a page.
+    pub const fn new(object_size: usize) -> Self {
+        SlabCache {
+            object_size: object_size,
+            state: Mutex::new(CacheState {
+                free_list: 0,
+                free: 0,
+                in_use: 0,
+                slabs: 0,
+                allocations: 0,
+            }),
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Take a free object. If there is none, `new_slab` is called for the address of a fresh
+    /// page-sized, page-aligned slab, and the object comes from that. Returns `None` if there is
+    /// no free object and `new_slab` fails.
+    ///
+    /// Interrupts must be disabled, since the cache is locked.
+    pub fn alloc<F>(&self, new_slab: F) -> Option<*mut u8>
+    where
+        F: FnOnce() -> Option<usize>,
+    {
+        let mut state = self.state.lock();
+
+        if state.free_list == 0 {
+            let slab = new_slab()?;
+            // Thread every object of the slab onto the free list, lowest first.
+            let objects = PAGE_SIZE / self.object_size;
+            for index in (0..objects).rev() {
+                let object = slab + index * self.object_size;
+                unsafe { *(object as *mut usize) = state.free_list };
+                state.free_list = object;
+            }
+            state.free += objects;
+            state.slabs += 1;
+        }
+
+        let object = state.free_list;
+        state.free_list = unsafe { *(object as *const usize) };
+        state.free -= 1;
+        state.in_use += 1;
+        state.allocations += 1;
+
+        Some(object as *mut u8)
+    }
+
+    /// Put an object back on the free list.
+    ///
+    /// # Unsafety
+    ///
+    /// `object` must have come from `alloc` on this cache and not be freed already. Interrupts
+    /// must be disabled, since the cache is locked.
+    pub unsafe fn dealloc(&self, object: *mut u8) {
+        let mut state = self.state.lock();
+
+        *(object as *mut usize) = state.free_list;
+        state.free_list = object as usize;
+        state.free += 1;
+        state.in_use -= 1;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        disable_interrupts_and_then(|| {
+            let state = self.state.lock();
+
+            CacheStats {
+                object_size: self.object_size,
+                in_use: state.in_use,
+                free: state.free,
+                slabs: state.slabs,
+                allocations: state.allocations,
+            }
+        })
+    }
+}
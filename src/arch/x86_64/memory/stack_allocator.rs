@@ -1,19 +1,161 @@
-use arch::memory::paging::{ActivePageTable, Page, PageIter};
-use arch::memory::PAGE_SIZE;
+//! Kernel stacks, for interrupts and tasks alike. Every stack has an unmapped guard page below it,
+//! so running off the end of a stack faults instead of quietly overwriting whatever lies below.
+//!
+//! Stacks are carved out of a range of virtual memory which `memory::init` sets aside. Which pages
+//! of the range are guard pages is kept in a bitmap, so that the page fault handler can tell a
+//! stack overflow from any other fault. Guard pages elsewhere, like the one below the boot stack,
+//! are registered with `add_guard_page`.
+//!
+//! A stack given back with `free_stack` is unmapped and its frames freed, but its pages stay set
+//! aside, guard page and all, for the next stack of the same size.
+
+use arch::memory::paging::{ActivePageTable, Page, PageIter, VirtualAddress};
+use arch::memory::{deallocate_frame, PAGE_SIZE};
 use arch::memory::paging::EntryFlags;
+use spin::Mutex;
+
+/// Most pages the stack range can have, so that the guard page bitmap can be a fixed size.
+pub const MAX_RANGE_PAGES: usize = 4096;
+/// Most guard pages outside the stack range which can be registered.
+const MAX_OTHER_GUARD_PAGES: usize = 4;
+/// Most freed stacks kept for reuse. The pages of any stack freed beyond this are lost, though its
+/// frames are still freed.
+const MAX_FREE_STACKS: usize = 64;
+
+/// The guard pages below the stacks handed out so far, so that a page fault on one can be reported
+/// as a stack overflow.
+struct GuardPages {
+    /// Address of the first page of the stack range.
+    range_start: usize,
+    /// One bit per page of the stack range, set for guard pages.
+    bitmap: [u64; MAX_RANGE_PAGES / 64],
+    /// Guard pages outside the stack range.
+    others: [usize; MAX_OTHER_GUARD_PAGES],
+    others_len: usize,
+}
+
+static GUARD_PAGES: Mutex<GuardPages> = Mutex::new(GuardPages {
+    range_start: 0,
+    bitmap: [0; MAX_RANGE_PAGES / 64],
+    others: [0; MAX_OTHER_GUARD_PAGES],
+    others_len: 0,
+});
+
+/// The allocator every kernel stack comes from, once `init` has run.
+static ALLOCATOR: Mutex<Option<StackAllocator>> = Mutex::new(None);
+
+/// Hand out stacks from `range`, which must not be mapped, and hold at most `MAX_RANGE_PAGES`.
+pub fn init(range: PageIter) {
+    let first = range.clone().next().expect("stack range is empty");
+    assert!(range.count() <= MAX_RANGE_PAGES, "stack range is too large");
+
+    GUARD_PAGES.lock().range_start = first.start_address().get();
+    *ALLOCATOR.lock() = Some(StackAllocator::new(range));
+}
+
+/// Allocate a stack of `size_in_pages` mapped pages, with a guard page below. Returns `None` if
+/// the stack range is used up, or `init` has not run.
+pub fn alloc_stack(active_table: &mut ActivePageTable, size_in_pages: usize) -> Option<Stack> {
+    match *ALLOCATOR.lock() {
+        Some(ref mut allocator) => allocator.alloc_stack(active_table, size_in_pages),
+        None => None,
+    }
+}
+
+/// Unmap `stack`, free its frames, and keep its pages to hand out again. The stack must have come
+/// from `alloc_stack`, and must not be in use any more.
+pub fn free_stack(active_table: &mut ActivePageTable, stack: Stack) {
+    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
+        allocator.free_stack(active_table, stack);
+    }
+}
+
+/// Return the number of pages of the stack range which have never been handed out.
+pub fn pages_left() -> usize {
+    ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.range.clone().count())
+}
+
+/// Record that the page starting at `address`, outside the stack range, is the guard page of a
+/// stack. Fails if too many have been registered already.
+pub fn add_guard_page(address: usize) -> Result<(), &'static str> {
+    let mut guard_pages = GUARD_PAGES.lock();
+    let len = guard_pages.others_len;
+    if len == MAX_OTHER_GUARD_PAGES {
+        return Err("too many guard pages");
+    }
+
+    guard_pages.others[len] = address;
+    guard_pages.others_len += 1;
+    Ok(())
+}
+
+/// Return whether `address` is in the guard page of a stack. This is used by the page fault
+/// handler, so it gives up and returns `false` rather than wait for the lock.
+pub fn is_guard_page(address: usize) -> bool {
+    let guard_pages = match GUARD_PAGES.try_lock() {
+        Some(guard_pages) => guard_pages,
+        None => return false,
+    };
+
+    let others = guard_pages.others[..guard_pages.others_len]
+        .iter()
+        .any(|&start| address >= start && address < start + PAGE_SIZE);
+    if others {
+        return true;
+    }
+
+    let index = match address.checked_sub(guard_pages.range_start) {
+        Some(offset) if guard_pages.range_start != 0 => offset / PAGE_SIZE,
+        _ => return false,
+    };
+    index < MAX_RANGE_PAGES && guard_pages.bitmap[index / 64] & (1 << (index % 64)) != 0
+}
+
+/// Mark `page`, which lies in the stack range, as a guard page.
+fn mark_guard_page(page: Page) {
+    let mut guard_pages = GUARD_PAGES.lock();
+    let index = (page.start_address().get() - guard_pages.range_start) / PAGE_SIZE;
+    guard_pages.bitmap[index / 64] |= 1 << (index % 64);
+}
+
+/// The pages of a freed stack, waiting to be reused. Its guard page is still marked.
+#[derive(Copy, Clone)]
+struct FreeStack {
+    first: Page,
+    pages: usize,
+}
 
 /// A stack allocator.
 #[derive(Copy, Clone)]
 pub struct StackAllocator {
     range: PageIter,
+    freed: [Option<FreeStack>; MAX_FREE_STACKS],
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            freed: [None; MAX_FREE_STACKS],
+        }
     }
 }
 
+/// Map the pages from `start` to `end` inclusive, and return the stack they make up.
+fn map_stack(active_table: &mut ActivePageTable, start: Page, end: Page) -> Stack {
+    for page in Page::range_inclusive(start, end) {
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let result = active_table.map(page, flags);
+        result.flush(active_table);
+    }
+
+    let top_of_stack = end.start_address().get() + PAGE_SIZE;
+    Stack::new(top_of_stack, start.start_address().get())
+}
+
 impl StackAllocator {
     /// Allocate a range of pages to use as a stack.
     pub fn alloc_stack(
@@ -25,6 +167,16 @@ impl StackAllocator {
             return None; /* a zero sized stack makes no sense */
         }
 
+        // reuse the pages of a freed stack of the same size, if there is one
+        let reusable = self.freed
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |free| free.pages == size_in_pages));
+        if let Some(slot) = reusable {
+            let free = slot.take().expect("slot was checked to be full");
+            let end = free.first + (size_in_pages - 1);
+            return Some(map_stack(active_table, free.first, end));
+        }
+
         // clone the range, since we only want to change it on success
         let mut range = self.range.clone();
 
@@ -40,26 +192,39 @@ impl StackAllocator {
         };
 
         match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
+            (Some(guard), Some(start), Some(end)) => {
                 // success! write back updated range
                 self.range = range;
 
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    let result = active_table.map(page, EntryFlags::PRESENT);
-                    result.flush(active_table);
-                }
+                mark_guard_page(guard);
 
-                // create a new stack
-                let top_of_stack = end.start_address().get() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address().get()))
+                Some(map_stack(active_table, start, end))
             }
             _ => None, /* not enough pages */
         }
     }
+
+    /// Unmap `stack` and free its frames, keeping its pages for reuse if there is room.
+    pub fn free_stack(&mut self, active_table: &mut ActivePageTable, stack: Stack) {
+        let first = Page::containing_address(VirtualAddress::new(stack.bottom()));
+        let last = Page::containing_address(VirtualAddress::new(stack.top() - 1));
+
+        active_table.unmap_range(Page::range_inclusive(first, last), |_, frame| {
+            deallocate_frame(frame)
+        });
+
+        let pages = (stack.top() - stack.bottom()) / PAGE_SIZE;
+        if let Some(slot) = self.freed.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(FreeStack {
+                first: first,
+                pages: pages,
+            });
+        }
+    }
 }
 
-/// A stack that grows downwards.
+/// A stack that grows downwards. It cannot be cloned, since it is freed with `free_stack` by
+/// value, and a copy would let the same pages be freed twice.
 #[derive(Debug)]
 pub struct Stack {
     top: usize,
@@ -79,7 +244,6 @@ impl Stack {
         self.top
     }
 
-    #[allow(dead_code)]
     pub fn bottom(&self) -> usize {
         self.bottom
     }
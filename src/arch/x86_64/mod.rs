@@ -1,7 +1,20 @@
 //! Architecture-specific code for AMD64.
 
+pub mod backtrace;
+pub mod cmdline;
+pub mod cpuid;
+pub mod debugger;
 pub mod interrupts;
 pub mod memory;
+pub mod msr;
+pub mod multiboot;
+pub mod percpu;
+pub mod platform;
+pub mod profiler;
+pub mod symbols;
+pub mod time;
+pub mod user_access;
+pub mod watchpoint;
 pub mod init;
 
 pub use self::init::init;
@@ -0,0 +1,91 @@
+//! Typed access to model-specific registers.
+
+use arch::interrupts::{probe, ProbeFault};
+use arch::interrupts::exceptions::GPF_VECTOR;
+use x86_64::registers::msr::{rdmsr, wrmsr};
+
+/// A model-specific register, identified by its address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+impl Msr {
+    /// Read the 64-bit value of this MSR. Reading an MSR that the CPU does not implement causes a
+    /// general protection fault.
+    pub unsafe fn read(&self) -> u64 {
+        rdmsr(self.0)
+    }
+
+    /// Read this MSR, returning `Err` rather than faulting if the CPU does not implement it.
+    pub fn try_read(&self) -> Result<u64, ProbeFault> {
+        probe(&[GPF_VECTOR], || unsafe { rdmsr(self.0) })
+    }
+
+    /// Write a 64-bit value to this MSR.
+    pub unsafe fn write(&self, value: u64) {
+        wrmsr(self.0, value);
+    }
+}
+
+/// Local APIC base address and enable bits.
+pub const IA32_APIC_BASE: Msr = Msr(0x1b);
+/// Extended feature enable register.
+pub const EFER: Msr = Msr(0xc000_0080);
+/// Base address of the FS segment.
+pub const FS_BASE: Msr = Msr(0xc000_0100);
+/// Base address of the GS segment.
+pub const GS_BASE: Msr = Msr(0xc000_0101);
+/// The value swapped into `GS_BASE` by `swapgs`.
+pub const KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+/// Fixed-function performance counter 1, which counts unhalted core cycles.
+pub const IA32_FIXED_CTR1: Msr = Msr(0x30a);
+/// Enable bits of the fixed-function performance counters.
+pub const IA32_FIXED_CTR_CTRL: Msr = Msr(0x38d);
+/// Overflow status of the performance counters.
+pub const IA32_PERF_GLOBAL_STATUS: Msr = Msr(0x38e);
+/// Global enable bits of the performance counters.
+pub const IA32_PERF_GLOBAL_CTRL: Msr = Msr(0x38f);
+/// Writing a bit here clears the matching bit of `IA32_PERF_GLOBAL_STATUS`.
+pub const IA32_PERF_GLOBAL_OVF_CTRL: Msr = Msr(0x390);
+
+/// x2APIC ID register.
+pub const IA32_X2APIC_APICID: Msr = Msr(0x802);
+/// x2APIC version register.
+pub const IA32_X2APIC_VERSION: Msr = Msr(0x803);
+/// x2APIC task priority register.
+pub const IA32_X2APIC_TPR: Msr = Msr(0x808);
+/// x2APIC end of interrupt register.
+pub const IA32_X2APIC_EOI: Msr = Msr(0x80b);
+/// x2APIC spurious interrupt vector register.
+pub const IA32_X2APIC_SIVR: Msr = Msr(0x80f);
+/// x2APIC interrupt command register.
+pub const IA32_X2APIC_ICR: Msr = Msr(0x830);
+/// x2APIC LVT timer register.
+pub const IA32_X2APIC_LVT_TIMER: Msr = Msr(0x832);
+/// x2APIC LVT LINT0 register.
+pub const IA32_X2APIC_LVT_LINT0: Msr = Msr(0x835);
+/// x2APIC LVT LINT1 register.
+pub const IA32_X2APIC_LVT_LINT1: Msr = Msr(0x836);
+/// x2APIC timer initial count register.
+pub const IA32_X2APIC_INIT_COUNT: Msr = Msr(0x838);
+/// x2APIC timer current count register.
+pub const IA32_X2APIC_CUR_COUNT: Msr = Msr(0x839);
+/// x2APIC timer divide configuration register.
+pub const IA32_X2APIC_DIV_CONF: Msr = Msr(0x83e);
+
+/// EFER: enable `syscall`/`sysret`.
+pub const EFER_SCE: u64 = 1 << 0;
+/// EFER: long mode enable.
+pub const EFER_LME: u64 = 1 << 8;
+/// EFER: long mode active.
+pub const EFER_LMA: u64 = 1 << 10;
+/// EFER: enable the no-execute bit in page table entries.
+pub const EFER_NXE: u64 = 1 << 11;
+
+/// APIC base: this processor is the bootstrap processor.
+pub const APIC_BASE_BSP: u64 = 1 << 8;
+/// APIC base: x2APIC mode is enabled.
+pub const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+/// APIC base: the local APIC is globally enabled.
+pub const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+/// APIC base: mask of the physical base address of the local APIC registers.
+pub const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
@@ -0,0 +1,86 @@
+//! Raw access to multiboot2 tags which the `multiboot2` crate does not expose.
+//!
+//! The boot information structure starts with its total size and a reserved field, followed by a
+//! list of tags. Each tag has an 8-byte header giving its type and size, and is padded so that the
+//! next tag starts on an 8-byte boundary. The list ends with a tag of type 0.
+//!
+//! `paging::init` identity maps the boot information and the frame allocator never hands its
+//! frames out, so tag data stays valid for the lifetime of the kernel.
+
+use core::{slice, str};
+use multiboot2::BootInformation;
+
+/// Tag type ending the list.
+pub const TAG_END: u32 = 0;
+/// Tag type holding the kernel command line.
+pub const TAG_COMMAND_LINE: u32 = 1;
+/// Tag type describing the framebuffer.
+pub const TAG_FRAMEBUFFER: u32 = 8;
+/// Tag type holding the SMBIOS version and a copy of the SMBIOS entry point.
+pub const TAG_SMBIOS: u32 = 13;
+/// Tag type holding a copy of an ACPI 1.0 RSDP.
+pub const TAG_ACPI_OLD: u32 = 14;
+/// Tag type holding a copy of an ACPI 2.0 or later RSDP.
+pub const TAG_ACPI_NEW: u32 = 15;
+/// Tag type holding the EFI memory map.
+pub const TAG_EFI_MEMORY_MAP: u32 = 17;
+
+/// A single multiboot2 tag.
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    pub typ: u32,
+    /// The contents of the tag, after the 8-byte header.
+    pub data: &'static [u8],
+}
+
+/// Iterator over the tags in the boot information structure.
+pub struct TagIter {
+    current: usize,
+    end: usize,
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.current + 8 > self.end {
+            return None;
+        }
+
+        let (typ, size) = unsafe {
+            let header = self.current as *const u32;
+            (*header, *header.offset(1) as usize)
+        };
+
+        if typ == TAG_END || size < 8 || self.current + size > self.end {
+            return None;
+        }
+
+        let data = unsafe { slice::from_raw_parts((self.current + 8) as *const u8, size - 8) };
+
+        self.current = (self.current + size + 7) & !7;
+
+        Some(Tag { typ: typ, data: data })
+    }
+}
+
+/// Return an iterator over every tag.
+pub fn tags(boot_info: &BootInformation) -> TagIter {
+    TagIter {
+        current: boot_info.start_address() + 8,
+        end: boot_info.end_address(),
+    }
+}
+
+/// Return the first tag of the given type.
+pub fn find_tag(boot_info: &BootInformation, typ: u32) -> Option<Tag> {
+    tags(boot_info).find(|tag| tag.typ == typ)
+}
+
+/// Return the command line the bootloader passed to the kernel.
+pub fn command_line(boot_info: &BootInformation) -> Option<&'static str> {
+    find_tag(boot_info, TAG_COMMAND_LINE).and_then(|tag| {
+        let len = tag.data.iter().position(|&b| b == 0).unwrap_or(tag.data.len());
+        str::from_utf8(&tag.data[..len]).ok()
+    })
+}
@@ -0,0 +1,263 @@
+//! Per-CPU data, reached through the GS segment base.
+//!
+//! Every CPU has its own `PerCpu` block, and the address of that block is loaded into the `GS_BASE`
+//! MSR of the CPU which owns it. Fields are then read with `gs:[offset]`, so each CPU sees its own
+//! copy without needing to know its CPU number first.
+//!
+//! While in the kernel, `GS_BASE` holds the per-CPU block and `KERNEL_GS_BASE` holds the user's GS
+//! base. Any entry point reachable from ring 3 (interrupts, `syscall`) must execute `swapgs` on
+//! entry and again before returning to user mode, so that the two are exchanged.
+
+use alloc::boxed::Box;
+use arch::msr::{GS_BASE, KERNEL_GS_BASE};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Data owned by a single CPU. The layout is fixed, since the accessors below read fields by
+/// offset.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Address of this block (`gs:[0]`), so the block itself can be found through GS.
+    self_ptr: usize,
+    /// PID of the process currently running on this CPU (`gs:[8]`).
+    current_task: usize,
+    /// Top of the kernel stack used when entering the kernel from user mode (`gs:[16]`).
+    kernel_stack_top: usize,
+    /// Logical CPU number, with the BSP being 0 (`gs:[24]`).
+    pub cpu_id: usize,
+    /// Local APIC ID of this CPU (`gs:[32]`).
+    pub apic_id: usize,
+    /// How many interrupt handlers this CPU is nested in (`gs:[40]`).
+    interrupt_depth: usize,
+    /// Number of locks of each rank held by the task running on this CPU, a byte per rank, kept
+    /// in debug builds (`gs:[48]`).
+    held_locks: usize,
+    /// How many times preemption has been disabled on this CPU (`gs:[56]`).
+    preempt_count: usize,
+    /// Whether a timer tick wanted to resched while preemption was disabled (`gs:[64]`).
+    resched_pending: usize,
+    /// Address of the innermost active fault probe, or zero (`gs:[72]`). The probe landing code
+    /// in `probe.asm` reads this.
+    probe_catcher: usize,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_ptr: 0,
+            current_task: 0,
+            kernel_stack_top: 0,
+            cpu_id: 0,
+            apic_id: 0,
+            interrupt_depth: 0,
+            held_locks: 0,
+            preempt_count: 0,
+            resched_pending: 0,
+            probe_catcher: 0,
+        }
+    }
+}
+
+/// Most CPUs the kernel supports.
+pub const MAX_CPUS: usize = 8;
+
+/// Number of CPUs which have installed their per-CPU data and are running kernel code.
+static ONLINE_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The same CPUs as a bit mask, a bit per `cpu_id`.
+static ONLINE_MASK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The bootstrap processor's block. This is static so that it can be set up before the heap.
+static mut BSP_CPU: PerCpu = PerCpu::new();
+
+/// Point `GS_BASE` at `block`.
+unsafe fn install(block: &'static mut PerCpu, cpu_id: usize, apic_id: usize) {
+    let address = block as *mut PerCpu as usize;
+    block.self_ptr = address;
+    block.cpu_id = cpu_id;
+    block.apic_id = apic_id;
+
+    GS_BASE.write(address as u64);
+    KERNEL_GS_BASE.write(0);
+
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    if cpu_id < MAX_CPUS {
+        ONLINE_MASK.fetch_or(1 << cpu_id, Ordering::SeqCst);
+    }
+}
+
+/// Return the number of CPUs that are online.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+/// Return the CPUs that are online as a bit mask, a bit per `cpu_id`. CPUs numbered `MAX_CPUS` or
+/// higher are left out.
+pub fn online_mask() -> usize {
+    ONLINE_MASK.load(Ordering::SeqCst)
+}
+
+/// Return whether this CPU has installed its per-CPU data, so that the accessors below are safe.
+pub fn is_installed() -> bool {
+    unsafe { GS_BASE.read() != 0 }
+}
+
+/// Set up per-CPU data for the bootstrap processor. This must run before anything reads per-CPU
+/// data, including the scheduler.
+pub unsafe fn init_bsp() {
+    use raw_cpuid::CpuId;
+
+    let apic_id = CpuId::new()
+        .get_feature_info()
+        .map(|info| info.initial_local_apic_id() as usize)
+        .unwrap_or(0);
+
+    install(&mut BSP_CPU, 0, apic_id);
+    println!("[ smp ] Per-CPU data for BSP (APIC id {}) installed.", apic_id);
+}
+
+/// Set up per-CPU data for an application processor. This must be called on the AP itself.
+pub unsafe fn init_ap(cpu_id: usize, apic_id: usize) {
+    let block = Box::into_raw(Box::new(PerCpu::new()));
+    install(&mut *block, cpu_id, apic_id);
+}
+
+/// Return the per-CPU block of the CPU we are running on.
+pub fn this_cpu() -> &'static PerCpu {
+    let ptr: usize;
+    unsafe { asm!("mov $0, gs:[0]" : "=r"(ptr) : : "memory" : "intel", "volatile") };
+
+    unsafe { &*(ptr as *const PerCpu) }
+}
+
+/// Return the PID of the process running on this CPU.
+pub fn current_task_id() -> usize {
+    let id: usize;
+    unsafe { asm!("mov $0, gs:[8]" : "=r"(id) : : "memory" : "intel", "volatile") };
+
+    id
+}
+
+/// Set the PID of the process running on this CPU.
+pub fn set_current_task_id(id: usize) {
+    unsafe { asm!("mov gs:[8], $0" : : "r"(id) : "memory" : "intel", "volatile") };
+}
+
+/// Return how many interrupt handlers marked with `InterruptContext` this CPU is nested in.
+pub fn interrupt_depth() -> usize {
+    let depth: usize;
+    unsafe { asm!("mov $0, gs:[40]" : "=r"(depth) : : "memory" : "intel", "volatile") };
+
+    depth
+}
+
+/// Return whether this CPU is running an interrupt handler marked with `InterruptContext`.
+pub fn in_interrupt() -> bool {
+    interrupt_depth() != 0
+}
+
+/// Marks this CPU as running an interrupt handler for as long as it is alive, so that code which
+/// acts on the current task can tell it is not running on the task's behalf.
+///
+/// A handler which may switch tasks must drop this before calling the scheduler, since the task
+/// switched to was not necessarily interrupted.
+pub struct InterruptContext {
+    _private: (),
+}
+
+impl InterruptContext {
+    pub fn enter() -> Self {
+        unsafe { asm!("add qword ptr gs:[40], 1" : : : "memory" : "intel", "volatile") };
+
+        InterruptContext { _private: () }
+    }
+
+    /// Like `enter`, but mark nothing if this CPU has not installed its per-CPU data yet, for
+    /// exception handlers, which may run before it has.
+    pub fn try_enter() -> Option<Self> {
+        if is_installed() {
+            Some(InterruptContext::enter())
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        unsafe { asm!("sub qword ptr gs:[40], 1" : : : "memory" : "intel", "volatile") };
+    }
+}
+
+/// Return the counts of locks of each rank held on this CPU. See `sync`.
+pub fn held_locks() -> usize {
+    let counts: usize;
+    unsafe { asm!("mov $0, gs:[48]" : "=r"(counts) : : "memory" : "intel", "volatile") };
+
+    counts
+}
+
+/// Set the counts of locks of each rank held on this CPU.
+pub fn set_held_locks(counts: usize) {
+    unsafe { asm!("mov gs:[48], $0" : : "r"(counts) : "memory" : "intel", "volatile") };
+}
+
+/// Return how many times preemption has been disabled on this CPU. See `task::preempt`.
+pub fn preempt_count() -> usize {
+    let count: usize;
+    unsafe { asm!("mov $0, gs:[56]" : "=r"(count) : : "memory" : "intel", "volatile") };
+
+    count
+}
+
+/// Set the preemption disable count of this CPU.
+pub fn set_preempt_count(count: usize) {
+    unsafe { asm!("mov gs:[56], $0" : : "r"(count) : "memory" : "intel", "volatile") };
+}
+
+/// Return whether a resched was deferred because preemption was disabled.
+pub fn resched_pending() -> bool {
+    let pending: usize;
+    unsafe { asm!("mov $0, gs:[64]" : "=r"(pending) : : "memory" : "intel", "volatile") };
+
+    pending != 0
+}
+
+/// Record whether a resched is waiting for preemption to be enabled again.
+pub fn set_resched_pending(pending: bool) {
+    let pending = pending as usize;
+    unsafe { asm!("mov gs:[64], $0" : : "r"(pending) : "memory" : "intel", "volatile") };
+}
+
+/// Return the address of the innermost active fault probe on this CPU. See `interrupts::probe`.
+pub fn probe_catcher() -> usize {
+    let catcher: usize;
+    unsafe { asm!("mov $0, gs:[72]" : "=r"(catcher) : : "memory" : "intel", "volatile") };
+
+    catcher
+}
+
+/// Set the innermost active fault probe on this CPU.
+pub fn set_probe_catcher(catcher: usize) {
+    unsafe { asm!("mov gs:[72], $0" : : "r"(catcher) : "memory" : "intel", "volatile") };
+}
+
+/// Return the kernel stack used when this CPU enters the kernel from user mode.
+pub fn kernel_stack_top() -> usize {
+    let top: usize;
+    unsafe { asm!("mov $0, gs:[16]" : "=r"(top) : : "memory" : "intel", "volatile") };
+
+    top
+}
+
+/// Set the kernel stack used when this CPU enters the kernel from user mode.
+pub fn set_kernel_stack_top(top: usize) {
+    unsafe { asm!("mov gs:[16], $0" : : "r"(top) : "memory" : "intel", "volatile") };
+}
+
+/// Exchange `GS_BASE` and `KERNEL_GS_BASE`. Must be executed on every transition between ring 3
+/// and ring 0.
+#[inline(always)]
+pub unsafe fn swapgs() {
+    asm!("swapgs" : : : "memory" : "intel", "volatile");
+}
@@ -0,0 +1,65 @@
+//! Telling an emulator from real hardware, so that emulator-only shortcuts such as the
+//! `isa-debug-exit` device are never tried on a real machine, where the port may belong to
+//! something else.
+//!
+//! A hypervisor sets the hypervisor bit in CPUID and gives its vendor string in leaf
+//! `0x4000_0000`. QEMU's own emulator reports `TCGTCGTCGTCG` and KVM, which QEMU usually drives,
+//! reports `KVMKVMKVM`. Other hypervisors report their own strings and are not taken to be QEMU.
+//! A QEMU whose CPU model hides the hypervisor bit is still recognised by its SMBIOS manufacturer.
+
+use arch::cpuid;
+use core::str;
+use smbios;
+
+/// The hypervisor we are running under, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    /// No hypervisor, or one which hides itself.
+    None,
+    /// QEMU's Tiny Code Generator, i.e QEMU without hardware acceleration.
+    Tcg,
+    Kvm,
+    /// Any other hypervisor, with its vendor string.
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    /// Work out the hypervisor from its CPUID vendor string.
+    pub fn from_vendor(vendor: [u8; 12]) -> Self {
+        match &vendor {
+            b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            _ => Hypervisor::Other(vendor),
+        }
+    }
+}
+
+/// Return the hypervisor we are running under.
+pub fn hypervisor() -> Hypervisor {
+    cpuid::hypervisor_vendor().map_or(Hypervisor::None, Hypervisor::from_vendor)
+}
+
+/// Return whether we are running under QEMU, with or without KVM. Before SMBIOS is parsed, this
+/// only goes by CPUID.
+pub fn is_qemu() -> bool {
+    match hypervisor() {
+        Hypervisor::Tcg | Hypervisor::Kvm => true,
+        Hypervisor::Other(_) => false,
+        Hypervisor::None => smbios::system_info()
+            .and_then(|info| info.manufacturer.as_ref())
+            .map_or(false, |manufacturer| manufacturer == "QEMU"),
+    }
+}
+
+/// Print the hypervisor we are running under, if any.
+pub fn print_banner() {
+    match hypervisor() {
+        Hypervisor::None => println!("[ cpu ] No hypervisor detected."),
+        Hypervisor::Tcg => println!("[ cpu ] Running under QEMU (TCG)."),
+        Hypervisor::Kvm => println!("[ cpu ] Running under KVM."),
+        Hypervisor::Other(vendor) => println!(
+            "[ cpu ] Running under an unknown hypervisor ({}).",
+            str::from_utf8(&vendor).unwrap_or("unprintable vendor")
+        ),
+    }
+}
@@ -0,0 +1,374 @@
+//! A statistical profiler which samples the interrupted instruction pointer on each timer tick,
+//! or on each overflow of a performance counter.
+//!
+//! Samples in the kernel's text are counted in buckets of `BUCKET_SIZE` bytes, which can be
+//! matched up with the kernel's symbol map to find where time is spent. Samples which are not in
+//! ordinary kernel code are counted separately:
+//!
+//! - idle: the CPU was halted, which shows up as the instruction after a `hlt`.
+//! - interrupt: the sample interrupted an interrupt handler.
+//! - other: the address is outside the kernel's text, or past the last bucket.
+//!
+//! Timer ticks cannot sample code running with interrupts disabled. Booting with `profile=nmi`
+//! instead programs a fixed performance counter to overflow every `NMI_PERIOD` unhalted cycles,
+//! and has the local APIC deliver each overflow as an NMI, which arrives even in `cli` sections.
+//! The NMI may interrupt code holding any lock, so the NMI handler only pushes onto a lock-free
+//! ring, which timer ticks and the functions here empty into the histogram. The counter stops
+//! while the CPU is halted, so NMI sampling never counts idle time.
+//!
+//! Profiling is off unless the kernel is booted with the `profile` flag or `profile=nmi`, or
+//! `set_enabled` is called.
+
+use arch::{cmdline, cpuid, percpu};
+use arch::msr::{IA32_FIXED_CTR1, IA32_FIXED_CTR_CTRL, IA32_PERF_GLOBAL_CTRL};
+use arch::msr::{IA32_PERF_GLOBAL_OVF_CTRL, IA32_PERF_GLOBAL_STATUS};
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use device::apic;
+use spin::Mutex;
+
+/// Size in bytes of the address range each bucket covers.
+const BUCKET_SIZE: usize = 256;
+/// Number of buckets, which covers 1 MiB of kernel text.
+const BUCKETS: usize = 4096;
+/// Most buckets `profile_report` will print.
+const MAX_REPORTED: usize = 32;
+/// Opcode of `hlt`.
+const HLT: u8 = 0xf4;
+
+/// Unhalted cycles between NMI samples.
+const NMI_PERIOD: u64 = 1_000_000;
+/// Number of NMI samples which can wait to be counted.
+const RING_SIZE: usize = 256;
+/// `IA32_FIXED_CTR_CTRL` field of fixed counter 1: count in rings 0 and 3, and interrupt on
+/// overflow.
+const FIXED_CTR1_CTRL: u64 = 0b1011 << 4;
+/// Bit of fixed counter 1 in the global control, status and overflow registers.
+const FIXED_CTR1_GLOBAL: u64 = 1 << 33;
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether a performance counter is sending NMIs to be sampled.
+static NMI_SAMPLING: AtomicBool = ATOMIC_BOOL_INIT;
+/// Value the counter is reset to after each overflow, so that it overflows again `NMI_PERIOD`
+/// cycles later.
+static NMI_RELOAD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// Where a sample is counted.
+#[derive(Debug, Clone, Copy)]
+enum Sample {
+    Bucket(usize),
+    Idle,
+    Interrupt,
+    Other,
+}
+
+struct Profile {
+    buckets: [u32; BUCKETS],
+    idle: u32,
+    interrupt: u32,
+    other: u32,
+}
+
+impl Profile {
+    const fn new() -> Self {
+        Profile {
+            buckets: [0; BUCKETS],
+            idle: 0,
+            interrupt: 0,
+            other: 0,
+        }
+    }
+
+    fn record(&mut self, sample: Sample) {
+        match sample {
+            Sample::Bucket(index) => self.buckets[index] += 1,
+            Sample::Idle => self.idle += 1,
+            Sample::Interrupt => self.interrupt += 1,
+            Sample::Other => self.other += 1,
+        }
+    }
+
+    fn total(&self) -> u32 {
+        self.buckets.iter().sum::<u32>() + self.idle + self.interrupt + self.other
+    }
+
+    /// Count the samples waiting in the NMI ring. Holding the profile's lock makes this the only
+    /// consumer of the ring.
+    fn drain_ring(&mut self) {
+        let tail = RING_TAIL.load(Ordering::SeqCst);
+        let head = RING_HEAD.load(Ordering::SeqCst);
+
+        for i in 0..head.wrapping_sub(tail) {
+            let slot = tail.wrapping_add(i) % RING_SIZE;
+            self.record(unsafe { ptr::read_volatile(&RING[slot]) });
+        }
+
+        RING_TAIL.store(head, Ordering::SeqCst);
+    }
+}
+
+static PROFILE: Mutex<Profile> = Mutex::new(Profile::new());
+
+/// Samples taken by the NMI handler, waiting to be counted. The handler is the only producer, as
+/// only one CPU samples from NMIs and NMIs do not nest, so it needs no lock. A slot is only
+/// written between `RING_TAIL` and `RING_TAIL + RING_SIZE`, and only read below `RING_HEAD`.
+static mut RING: [Sample; RING_SIZE] = [Sample::Other; RING_SIZE];
+/// Number of samples ever pushed onto the ring.
+static RING_HEAD: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Number of samples ever counted from the ring.
+static RING_TAIL: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Number of NMI samples dropped because the ring was full.
+static RING_DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Push a sample from the NMI handler, dropping it if the ring is full.
+fn ring_push(sample: Sample) {
+    let head = RING_HEAD.load(Ordering::SeqCst);
+    if head.wrapping_sub(RING_TAIL.load(Ordering::SeqCst)) >= RING_SIZE {
+        RING_DROPPED.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+
+    unsafe { ptr::write_volatile(&mut RING[head % RING_SIZE], sample) };
+    RING_HEAD.store(head.wrapping_add(1), Ordering::SeqCst);
+}
+
+/// Enable profiling if it was asked for on the command line.
+pub fn init() {
+    if cmdline::flag("profile") {
+        set_enabled(true);
+        println!("[ prof ] Profiling enabled.");
+    }
+}
+
+/// Start sampling from NMIs if the kernel was booted with `profile=nmi`, falling back to timer
+/// ticks if the CPU cannot. This must be called after the local APIC is set up.
+pub fn init_nmi() {
+    if cmdline::option("profile") != Some("nmi") {
+        return;
+    }
+
+    match start_nmi_sampling() {
+        Ok(()) => println!(
+            "[ prof ] Profiling enabled, sampling every {} cycles from NMIs.",
+            NMI_PERIOD
+        ),
+        Err(e) => {
+            set_enabled(true);
+            println!("[ prof ] Cannot sample from NMIs ({}), using timer ticks.", e);
+        }
+    }
+}
+
+/// Enable profiling, driven by NMIs from this CPU's fixed cycle counter. While NMIs drive
+/// sampling, timer ticks on any CPU only count the samples they have taken. Fails if the CPU has
+/// no architectural performance counters, as under QEMU without KVM.
+pub fn start_nmi_sampling() -> Result<(), &'static str> {
+    let perfmon = cpuid::perfmon().ok_or("no architectural performance counters")?;
+    if perfmon.version < 2 || perfmon.fixed_counters < 2 {
+        return Err("no fixed cycle counter");
+    }
+
+    // The counter interrupts when it wraps, so start it `NMI_PERIOD` short of its width.
+    let width = perfmon.fixed_counter_width as u64;
+    let mask = if width >= 64 { !0 } else { (1 << width) - 1 };
+    let reload = 0u64.wrapping_sub(NMI_PERIOD) & mask;
+    NMI_RELOAD.store(reload as usize, Ordering::SeqCst);
+
+    unsafe {
+        IA32_FIXED_CTR1.write(reload);
+        let ctrl = IA32_FIXED_CTR_CTRL.read() & !(0xf << 4);
+        IA32_FIXED_CTR_CTRL.write(ctrl | FIXED_CTR1_CTRL);
+        IA32_PERF_GLOBAL_OVF_CTRL.write(FIXED_CTR1_GLOBAL);
+    }
+    apic::set_perf_nmi(true);
+
+    set_enabled(true);
+    NMI_SAMPLING.store(true, Ordering::SeqCst);
+    unsafe { IA32_PERF_GLOBAL_CTRL.write(IA32_PERF_GLOBAL_CTRL.read() | FIXED_CTR1_GLOBAL) };
+
+    Ok(())
+}
+
+/// Stop sampling from NMIs, which must have been started on this CPU. Profiling stays enabled,
+/// driven by timer ticks again.
+pub fn stop_nmi_sampling() {
+    unsafe {
+        IA32_PERF_GLOBAL_CTRL.write(IA32_PERF_GLOBAL_CTRL.read() & !FIXED_CTR1_GLOBAL);
+        IA32_PERF_GLOBAL_OVF_CTRL.write(FIXED_CTR1_GLOBAL);
+    }
+    apic::set_perf_nmi(false);
+    NMI_SAMPLING.store(false, Ordering::SeqCst);
+}
+
+pub fn nmi_sampling() -> bool {
+    NMI_SAMPLING.load(Ordering::SeqCst)
+}
+
+/// Turn sampling on or off. Samples taken so far are kept.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn profiling_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Return the bounds of the kernel's text.
+fn text_range() -> (usize, usize) {
+    unsafe {
+        (
+            &__text_start as *const u8 as usize,
+            &__text_end as *const u8 as usize,
+        )
+    }
+}
+
+/// Work out where to count a sample of `ip`, taken by a handler which is itself `nesting`
+/// interrupts deep.
+fn classify(ip: usize, nesting: usize) -> Sample {
+    let (start, end) = text_range();
+
+    if percpu::is_installed() && percpu::interrupt_depth() > nesting {
+        Sample::Interrupt
+    } else if ip < start || ip > end {
+        Sample::Other
+    } else if ip > start && unsafe { *((ip - 1) as *const u8) } == HLT {
+        // A `hlt` leaves the instruction pointer just past it. The byte before is only read if
+        // it lies in `.text`.
+        Sample::Idle
+    } else if (ip - start) / BUCKET_SIZE < BUCKETS {
+        Sample::Bucket((ip - start) / BUCKET_SIZE)
+    } else {
+        Sample::Other
+    }
+}
+
+/// Record a sample of the instruction pointer `ip`. This is called from the timer interrupt.
+pub fn sample(ip: usize) {
+    if !profiling_enabled() {
+        return;
+    }
+
+    // Drop the sample rather than spin in an interrupt handler.
+    let mut profile = match PROFILE.try_lock() {
+        Some(profile) => profile,
+        None => return,
+    };
+
+    if nmi_sampling() {
+        profile.drain_ring();
+    } else {
+        // The timer handler itself accounts for one level of nesting.
+        profile.record(classify(ip, 1));
+    }
+}
+
+/// Sample the instruction pointer `ip` if this NMI came from the profiling counter, and set the
+/// counter up for the next one. Returns false if the NMI came from somewhere else. This is called
+/// from the NMI handler, so it must not take any locks.
+pub fn nmi_sample(ip: usize) -> bool {
+    if !nmi_sampling() || unsafe { IA32_PERF_GLOBAL_STATUS.read() } & FIXED_CTR1_GLOBAL == 0 {
+        return false;
+    }
+
+    if profiling_enabled() {
+        // The NMI handler does not enter an `InterruptContext`, so any nesting is the code we
+        // interrupted.
+        ring_push(classify(ip, 0));
+    }
+
+    unsafe {
+        IA32_FIXED_CTR1.write(NMI_RELOAD.load(Ordering::SeqCst) as u64);
+        IA32_PERF_GLOBAL_OVF_CTRL.write(FIXED_CTR1_GLOBAL);
+    }
+    apic::unmask_perf_nmi();
+
+    true
+}
+
+/// Return the number of samples counted in the bucket containing `ip`.
+pub fn samples_at(ip: usize) -> u32 {
+    let (start, _) = text_range();
+    let mut profile = PROFILE.lock();
+    profile.drain_ring();
+
+    ip.checked_sub(start)
+        .and_then(|offset| profile.buckets.get(offset / BUCKET_SIZE))
+        .cloned()
+        .unwrap_or(0)
+}
+
+/// Forget every sample taken so far.
+pub fn reset() {
+    let mut profile = PROFILE.lock();
+    profile.drain_ring();
+
+    for count in profile.buckets.iter_mut() {
+        *count = 0;
+    }
+    profile.idle = 0;
+    profile.interrupt = 0;
+    profile.other = 0;
+}
+
+/// Print the `count` buckets with the most samples, up to `MAX_REPORTED`, followed by the idle,
+/// interrupt and other counts.
+pub fn profile_report(count: usize) {
+    let count = cmp::min(count, MAX_REPORTED);
+
+    // Pick out the busiest buckets under the lock, then print without holding up sampling. `top`
+    // is kept sorted by descending samples.
+    let mut top = [(0u32, 0usize); MAX_REPORTED];
+    let (idle, interrupt, other, total) = {
+        let mut profile = PROFILE.lock();
+        profile.drain_ring();
+
+        for (index, &samples) in profile.buckets.iter().enumerate() {
+            if count == 0 || samples <= top[count - 1].0 {
+                continue;
+            }
+
+            let mut position = count - 1;
+            while position > 0 && top[position - 1].0 < samples {
+                top[position] = top[position - 1];
+                position -= 1;
+            }
+            top[position] = (samples, index);
+        }
+
+        (profile.idle, profile.interrupt, profile.other, profile.total())
+    };
+
+    println!("[ prof ] {} samples.", total);
+    if total == 0 {
+        return;
+    }
+
+    let percent = |samples: u32| samples as u64 * 100 / total as u64;
+    let (start, _) = text_range();
+
+    for &(samples, index) in top[..count].iter().filter(|&&(samples, _)| samples > 0) {
+        let address = start + index * BUCKET_SIZE;
+        println!(
+            "[ prof ] {:#x}-{:#x}: {} ({}%)",
+            address,
+            address + BUCKET_SIZE - 1,
+            samples,
+            percent(samples)
+        );
+    }
+
+    println!("[ prof ] idle: {} ({}%)", idle, percent(idle));
+    println!("[ prof ] interrupt: {} ({}%)", interrupt, percent(interrupt));
+    println!("[ prof ] other: {} ({}%)", other, percent(other));
+
+    let dropped = RING_DROPPED.load(Ordering::SeqCst);
+    if dropped != 0 {
+        println!("[ prof ] {} NMI samples dropped.", dropped);
+    }
+}
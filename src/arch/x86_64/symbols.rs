@@ -0,0 +1,88 @@
+//! The kernel's symbol table, for printing code addresses as `function+0x1c`.
+//!
+//! The Makefile links the kernel twice. The symbols of the first link are written out with
+//! `nm -n -C` and linked into the `.ksyms` section of the second, which the linker script places
+//! after everything else so that no address moves. Each line is `address type name`, in address
+//! order. A kernel linked without the second pass has an empty table, and nothing resolves.
+
+use alloc::Vec;
+use core::{slice, str};
+use spin::Once;
+
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// The code symbols, sorted by address.
+static SYMBOLS: Once<Vec<(usize, &'static str)>> = Once::new();
+
+/// Parse the symbol table linked into the kernel. This must be called after the heap is set up.
+pub fn init() {
+    let symbols = SYMBOLS.call_once(|| parse(raw_table()));
+
+    if symbols.is_empty() {
+        println!("[ boot ] No kernel symbol table, backtraces will show bare addresses.");
+    } else {
+        println!("[ boot ] Loaded {} kernel symbols.", symbols.len());
+    }
+}
+
+fn raw_table() -> &'static str {
+    let (start, end) = unsafe {
+        (
+            &__ksyms_start as *const u8 as usize,
+            &__ksyms_end as *const u8 as usize,
+        )
+    };
+
+    let bytes = unsafe { slice::from_raw_parts(start as *const u8, end - start) };
+    str::from_utf8(bytes).unwrap_or("")
+}
+
+/// Parse `nm` output into a list sorted by address, keeping only code symbols. A line which is out
+/// of order is dropped, so that the list can be binary searched.
+pub fn parse(table: &'static str) -> Vec<(usize, &'static str)> {
+    let mut symbols: Vec<(usize, &'static str)> = Vec::new();
+
+    for line in table.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (address, kind, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(address), Some(kind), Some(name)) => (address, kind, name),
+            _ => continue,
+        };
+
+        if kind != "T" && kind != "t" {
+            continue;
+        }
+
+        let address = match usize::from_str_radix(address, 16) {
+            Ok(address) => address,
+            Err(_) => continue,
+        };
+
+        if symbols.last().map_or(true, |&(last, _)| address >= last) {
+            symbols.push((address, name));
+        }
+    }
+
+    symbols
+}
+
+/// Return the symbol in `symbols` at or before `address`, and the offset of `address` from it.
+pub fn nearest<'a>(symbols: &[(usize, &'a str)], address: usize) -> Option<(&'a str, usize)> {
+    let index = match symbols.binary_search_by_key(&address, |&(start, _)| start) {
+        Ok(index) => index,
+        // Before the first symbol.
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+
+    let (start, name) = symbols[index];
+    Some((name, address - start))
+}
+
+/// Return the kernel function containing `address`, and the offset of `address` into it.
+pub fn resolve(address: usize) -> Option<(&'static str, usize)> {
+    SYMBOLS.try().and_then(|symbols| nearest(symbols, address))
+}
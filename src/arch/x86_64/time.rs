@@ -0,0 +1,62 @@
+//! Fine-grained timestamps from the time stamp counter (TSC).
+//!
+//! The TSC counts CPU cycles, so its rate has to be measured before cycles can be turned into
+//! time. `init` measures it against the PIT, the same way the APIC timer is calibrated. On CPUs
+//! without an invariant TSC the rate changes with frequency scaling, so conversions are only
+//! approximate there.
+
+use arch::cpuid::{self, CpuFeatures};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use device::pit;
+
+/// How long to measure the TSC against the PIT for, in milliseconds.
+const CALIBRATION_MS: u32 = 10;
+
+/// TSC cycles per millisecond, or zero before calibration.
+static TSC_PER_MS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Read the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "volatile") };
+
+    (high as u64) << 32 | low as u64
+}
+
+/// Measure the TSC rate against the PIT. This must run after the PIT is set up, and is most
+/// accurate with interrupts disabled.
+pub fn init() {
+    let features = cpuid::features();
+
+    if !features.contains(CpuFeatures::TSC) {
+        println!("[ time ] No TSC, fine timestamps are unavailable.");
+        return;
+    }
+
+    let start = rdtsc();
+    pit::wait_ms(CALIBRATION_MS);
+    let per_ms = (rdtsc() - start) / CALIBRATION_MS as u64;
+
+    TSC_PER_MS.store(per_ms as usize, Ordering::SeqCst);
+    println!("[ time ] TSC runs at {} kHz.", per_ms);
+
+    if !features.contains(CpuFeatures::INVARIANT_TSC) {
+        println!("[ time ] Warning: TSC is not invariant, its rate varies with CPU frequency.");
+    }
+}
+
+/// Return the calibrated TSC rate in cycles per millisecond, or `None` before calibration.
+pub fn tsc_per_ms() -> Option<u64> {
+    match TSC_PER_MS.load(Ordering::SeqCst) {
+        0 => None,
+        per_ms => Some(per_ms as u64),
+    }
+}
+
+/// Convert a number of TSC cycles to nanoseconds, or `None` if the TSC has not been calibrated.
+pub fn tsc_to_ns(cycles: u64) -> Option<u64> {
+    let per_ms = tsc_per_ms()?;
+
+    // Split the division so that `cycles * 1_000_000` cannot overflow.
+    Some(cycles / per_ms * 1_000_000 + cycles % per_ms * 1_000_000 / per_ms)
+}
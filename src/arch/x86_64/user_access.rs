@@ -0,0 +1,136 @@
+//! Keeping the kernel away from user memory. With SMEP on, the CPU faults if the kernel executes
+//! a user page, and with SMAP on, if it reads or writes one. Either would only happen by mistake,
+//! such as following a pointer handed in by a user task without checking it.
+//!
+//! The kernel still has to read and write user buffers on purpose, so `copy_from_user` and
+//! `copy_to_user` check that the whole buffer is user memory, mapped with the right permissions,
+//! and only then set `RFLAGS.AC` with `stac` for the length of the copy, which SMAP allows.
+
+use arch::cpuid::{self, CpuFeatures};
+use arch::interrupts::disable_interrupts_and_then;
+use arch::memory::paging::{ActivePageTable, EntryFlags, Page, VirtualAddress};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+/// `CR4.SMEP`: fault on executing a user page in ring 0.
+const CR4_SMEP: u64 = 1 << 20;
+/// `CR4.SMAP`: fault on accessing a user page in ring 0 unless `RFLAGS.AC` is set.
+const CR4_SMAP: u64 = 1 << 21;
+
+/// One past the highest user address: the lower half of the address space.
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Set once SMAP is on, after which `stac` and `clac` exist. They are undefined opcodes on a CPU
+/// without SMAP.
+static SMAP_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov $0, cr4" : "=r"(value) : : "memory" : "intel", "volatile") };
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+/// Turn on SMEP and SMAP, each only if the CPU has it.
+pub fn init() {
+    let features = cpuid::features();
+    let mut cr4 = read_cr4();
+
+    if features.contains(CpuFeatures::SMEP) {
+        cr4 |= CR4_SMEP;
+    }
+    if features.contains(CpuFeatures::SMAP) {
+        cr4 |= CR4_SMAP;
+    }
+    unsafe { write_cr4(cr4) };
+    SMAP_ENABLED.store(cr4 & CR4_SMAP != 0, Ordering::SeqCst);
+
+    println!(
+        "[ cpu ] SMEP {}, SMAP {}.",
+        if cr4 & CR4_SMEP != 0 { "on" } else { "unsupported" },
+        if cr4 & CR4_SMAP != 0 { "on" } else { "unsupported" }
+    );
+}
+
+/// Return whether SMEP is on.
+pub fn smep_enabled() -> bool {
+    read_cr4() & CR4_SMEP != 0
+}
+
+/// Return whether SMAP is on.
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Check that the `len` bytes from `address` are user memory mapped with at least `flags`, and if
+/// so run `f` with access to user pages allowed, by setting `RFLAGS.AC` around it. An interrupt
+/// does not clear the flag, so interrupts are disabled meanwhile, lest a handler run with SMAP off.
+/// The check is made with them disabled too, so that nothing can unmap the buffer in between on
+/// this CPU.
+fn with_user_access<F>(
+    address: usize,
+    len: usize,
+    flags: EntryFlags,
+    f: F,
+) -> Result<(), &'static str>
+where
+    F: FnOnce(),
+{
+    disable_interrupts_and_then(|| {
+        check_user_range(address, len, flags)?;
+
+        let smap = smap_enabled();
+        if smap {
+            unsafe { asm!("stac" : : : "memory" : "volatile") };
+        }
+        f();
+        if smap {
+            unsafe { asm!("clac" : : : "memory" : "volatile") };
+        }
+        Ok(())
+    })
+}
+
+/// Check that the `len` bytes from `address` are all user memory, mapped with at least `flags`.
+fn check_user_range(address: usize, len: usize, flags: EntryFlags) -> Result<(), &'static str> {
+    let end = address.checked_add(len).ok_or("user buffer wraps around")?;
+    if end > USER_END {
+        return Err("user buffer is not in user memory");
+    }
+    if len == 0 {
+        return Ok(());
+    }
+
+    let active_table = unsafe { ActivePageTable::new() };
+    let first = Page::containing_address(VirtualAddress::new(address));
+    let last = Page::containing_address(VirtualAddress::new(end - 1));
+    let required = flags | EntryFlags::USER_ACCESSIBLE;
+
+    for page in Page::range_inclusive(first, last) {
+        match active_table.page_flags(page) {
+            Some(page_flags) if page_flags.contains(required) => (),
+            _ => return Err("user buffer is not mapped for user access"),
+        }
+    }
+    Ok(())
+}
+
+/// Copy `destination.len()` bytes from the user buffer at `source` into `destination`. Fails,
+/// copying nothing, unless the whole buffer is user memory mapped user accessible.
+pub fn copy_from_user(destination: &mut [u8], source: usize) -> Result<(), &'static str> {
+    let len = destination.len();
+    with_user_access(source, len, EntryFlags::empty(), || unsafe {
+        ptr::copy_nonoverlapping(source as *const u8, destination.as_mut_ptr(), len)
+    })
+}
+
+/// Copy `source` into the user buffer at `destination`. Fails, copying nothing, unless the whole
+/// buffer is user memory mapped user accessible and writable.
+pub fn copy_to_user(destination: usize, source: &[u8]) -> Result<(), &'static str> {
+    with_user_access(destination, source.len(), EntryFlags::WRITABLE, || unsafe {
+        ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len())
+    })
+}
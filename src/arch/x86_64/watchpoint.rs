@@ -0,0 +1,195 @@
+//! Hardware watchpoints using the debug registers.
+//!
+//! DR0-DR3 each hold the linear address of one watchpoint. DR7 controls them, with these bits for
+//! watchpoint `n`:
+//!
+//! - bit `2n`: local enable (`Ln`). We only use local enables, since the registers are per-CPU.
+//! - bits `16 + 4n` and `17 + 4n`: the access which triggers it (`R/Wn`). `00` is instruction
+//!   execution, `01` is data writes, `11` is data reads or writes. `10` is I/O and needs `CR4.DE`,
+//!   so it is not offered.
+//! - bits `18 + 4n` and `19 + 4n`: the length (`LENn`). `00` is 1 byte, `01` is 2 bytes, `11` is 4
+//!   bytes and `10` is 8 bytes. The address must be aligned to the length, and execution
+//!   watchpoints must have a length of 1 byte.
+//!
+//! When a watchpoint fires the CPU raises a debug exception (#DB) and sets bit `n` of DR6. Data
+//! watchpoints are traps, so the saved instruction pointer is that of the instruction after the
+//! access. Execution watchpoints are faults, taken before the instruction runs, so the handler must
+//! set `RFLAGS.RF` in the saved flags to run it rather than hit the watchpoint again.
+//!
+//! The debug registers belong to the CPU which sets them, so a watchpoint only catches accesses
+//! made on that CPU.
+
+use arch::memory::paging::VirtualAddress;
+
+/// Number of debug address registers.
+pub const WATCHPOINT_COUNT: usize = 4;
+
+/// DR6 bits saying which watchpoint fired.
+const DR6_HIT_MASK: u64 = 0xf;
+
+/// Size of the watched region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchLen {
+    Byte = 0b00,
+    Word = 0b01,
+    Dword = 0b11,
+    Qword = 0b10,
+}
+
+impl WatchLen {
+    fn bytes(&self) -> usize {
+        match *self {
+            WatchLen::Byte => 1,
+            WatchLen::Word => 2,
+            WatchLen::Dword => 4,
+            WatchLen::Qword => 8,
+        }
+    }
+}
+
+/// The kind of access which triggers a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+/// Set a watchpoint on `len` bytes at `address`. Returns the index of the debug register used.
+pub fn set_watchpoint(
+    address: VirtualAddress,
+    len: WatchLen,
+    kind: WatchKind,
+) -> Result<usize, &'static str> {
+    if address.get() % len.bytes() != 0 {
+        return Err("watchpoint address is not aligned to its length");
+    }
+
+    if kind == WatchKind::Execute && len != WatchLen::Byte {
+        return Err("execution watchpoints must be one byte long");
+    }
+
+    let dr7 = read_dr7();
+    let index = (0..WATCHPOINT_COUNT)
+        .find(|&i| dr7 & local_enable(i) == 0)
+        .ok_or("all watchpoints are in use")?;
+
+    let control = ((kind as u64) | ((len as u64) << 2)) << (16 + 4 * index);
+    let control_mask = 0xf << (16 + 4 * index);
+
+    unsafe {
+        write_address(index, address.get() as u64);
+        write_dr7((dr7 & !control_mask) | control | local_enable(index));
+    }
+
+    Ok(index)
+}
+
+/// Disable the watchpoint in debug register `index`.
+pub fn clear_watchpoint(index: usize) -> Result<(), &'static str> {
+    if index >= WATCHPOINT_COUNT {
+        return Err("no such watchpoint");
+    }
+
+    let dr7 = read_dr7();
+    if dr7 & local_enable(index) == 0 {
+        return Err("watchpoint is not set");
+    }
+
+    unsafe {
+        write_dr7(dr7 & !local_enable(index) & !(0xf << (16 + 4 * index)));
+        write_address(index, 0);
+    }
+
+    Ok(())
+}
+
+/// Return the address watched by debug register `index`, if it is enabled.
+pub fn watchpoint_address(index: usize) -> Option<VirtualAddress> {
+    if index >= WATCHPOINT_COUNT || read_dr7() & local_enable(index) == 0 {
+        return None;
+    }
+
+    Some(VirtualAddress::new(read_address(index) as usize))
+}
+
+/// Return the kind of access watched by debug register `index`, if it is enabled.
+pub fn watchpoint_kind(index: usize) -> Option<WatchKind> {
+    if index >= WATCHPOINT_COUNT {
+        return None;
+    }
+
+    let dr7 = read_dr7();
+    if dr7 & local_enable(index) == 0 {
+        return None;
+    }
+
+    match (dr7 >> (16 + 4 * index)) & 0b11 {
+        0b00 => Some(WatchKind::Execute),
+        0b01 => Some(WatchKind::Write),
+        _ => Some(WatchKind::ReadWrite),
+    }
+}
+
+/// Given the value of DR6 in a debug exception, return the index of an enabled watchpoint which
+/// fired.
+pub fn hit(dr6: u64) -> Option<usize> {
+    let dr7 = read_dr7();
+
+    (0..WATCHPOINT_COUNT).find(|&i| dr6 & (1 << i) != 0 && dr7 & local_enable(i) != 0)
+}
+
+/// Clear the watchpoint hit bits in DR6. The CPU never clears them itself.
+pub fn clear_hits() {
+    unsafe { write_dr6(read_dr6() & !DR6_HIT_MASK) };
+}
+
+fn local_enable(index: usize) -> u64 {
+    1 << (2 * index)
+}
+
+pub fn read_dr6() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov $0, dr6" : "=r"(value) : : : "intel", "volatile") };
+
+    value
+}
+
+pub unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, $0" : : "r"(value) : : "intel", "volatile");
+}
+
+fn read_dr7() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov $0, dr7" : "=r"(value) : : : "intel", "volatile") };
+
+    value
+}
+
+unsafe fn write_dr7(value: u64) {
+    asm!("mov dr7, $0" : : "r"(value) : : "intel", "volatile");
+}
+
+fn read_address(index: usize) -> u64 {
+    let value: u64;
+
+    unsafe {
+        match index {
+            0 => asm!("mov $0, dr0" : "=r"(value) : : : "intel", "volatile"),
+            1 => asm!("mov $0, dr1" : "=r"(value) : : : "intel", "volatile"),
+            2 => asm!("mov $0, dr2" : "=r"(value) : : : "intel", "volatile"),
+            _ => asm!("mov $0, dr3" : "=r"(value) : : : "intel", "volatile"),
+        }
+    }
+
+    value
+}
+
+unsafe fn write_address(index: usize, address: u64) {
+    match index {
+        0 => asm!("mov dr0, $0" : : "r"(address) : : "intel", "volatile"),
+        1 => asm!("mov dr1, $0" : : "r"(address) : : "intel", "volatile"),
+        2 => asm!("mov dr2, $0" : : "r"(address) : : "intel", "volatile"),
+        _ => asm!("mov dr3, $0" : : "r"(address) : : "intel", "volatile"),
+    }
+}
@@ -1,19 +1,61 @@
 #![allow(unused_imports)]
-use x86_64::registers::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+use arch::msr::{APIC_BASE_X2APIC_ENABLE, IA32_APIC_BASE, IA32_X2APIC_APICID};
 use core::ptr;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{spin_loop_hint, AtomicU32, AtomicUsize, Ordering, ATOMIC_U32_INIT};
+use core::sync::atomic::ATOMIC_USIZE_INIT;
 use arch::memory::paging::{Page, VirtualAddress, PhysicalAddress, ActivePageTable};
 use arch::memory::paging::entry::EntryFlags;
 use arch::memory::Frame;
 use heapless::Vec as StaticVec;
 use spin::Mutex;
 use acpi::madt;
+use device::pit;
+
+/// Vector of the local APIC timer interrupt.
+pub const TIMER_VECTOR: u8 = 0x41;
+
+// Local APIC timer registers.
+const LVT_TIMER: u32 = 0x320;
+const TIMER_INITIAL_COUNT: u32 = 0x380;
+const TIMER_CURRENT_COUNT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3e0;
+
+/// Divide configuration value for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// LVT timer mode bit for periodic rather than one-shot.
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// LVT mask bit.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Local APIC performance counter overflow register.
+const LVT_PERF: u32 = 0x340;
+/// LVT delivery mode for a non-maskable interrupt. The vector is ignored.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// How long to measure the APIC timer against the PIT for, in milliseconds. Shorter windows are
+/// less accurate, since the PIT reads are a larger share of the time measured; 10 ms keeps the
+/// error well under 1% without noticeably slowing boot.
+pub const CALIBRATION_MS: u32 = 10;
+
+/// Local APIC ID register.
+const LAPIC_ID: u32 = 0x20;
+/// Local APIC end of interrupt register.
+const LAPIC_EOI: u32 = 0xb0;
+
+/// APIC ID of the bootstrap processor, read from its local APIC by `init`.
+static BSP_APIC_ID: AtomicU32 = ATOMIC_U32_INIT;
+/// Address of the local APIC registers once they are mapped, for `unmask_perf_nmi` and `eoi`,
+/// which cannot take the lock.
+static LAPIC_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
 
 /// This will manage all the apic hardware on the system.
 pub struct ApicManager {
-    /// The base address of the local APIC register space.
-    pub lapic_base: u32,
+    /// The base address of the local APIC register space, taken from the MADT's address override
+    /// entry if it has one.
+    pub lapic_base: u64,
     pub local_apics: StaticVec<&'static madt::LapicEntry, [&'static madt::LapicEntry; 20]>,
+    /// Local x2APICs, which describe processors whose APIC IDs do not fit in a byte.
+    pub x2apics: StaticVec<&'static madt::X2ApicEntry, [&'static madt::X2ApicEntry; 20]>,
     /// All the I/O APICs on a system. FIXME: Figure out how to set the size of the backing
     /// array dynamically.
     pub io_apics: StaticVec<&'static madt::IoApic, [&'static madt::IoApic; 10]>,
@@ -28,6 +70,7 @@ impl ApicManager {
         ApicManager {
             lapic_base: 0,
             local_apics: StaticVec::new(),
+            x2apics: StaticVec::new(),
             io_apics: StaticVec::new(),
             nmis: StaticVec::new(),
             isos: StaticVec::new(),
@@ -35,11 +78,44 @@ impl ApicManager {
     }
 
     pub fn lapic_read(&self, register: u32) -> u32 {
-        unsafe { ptr::read_volatile(&(self.lapic_base + register) as *const u32) }
+        unsafe { ptr::read_volatile((self.lapic_base + register as u64) as *const u32) }
     }
 
     pub fn lapic_write(&self, register: u32, value: u32) {
-        unsafe { ptr::write_volatile(&mut (self.lapic_base + register) as *mut u32, value) }
+        unsafe { ptr::write_volatile((self.lapic_base + register as u64) as *mut u32, value) }
+    }
+
+    /// Return the APIC ID of the CPU we are running on. In xAPIC mode this is the top byte of the
+    /// ID register; in x2APIC mode it is a full 32-bit value read from an MSR instead.
+    pub fn lapic_id(&self) -> u32 {
+        unsafe {
+            if IA32_APIC_BASE.read() & APIC_BASE_X2APIC_ENABLE != 0 {
+                IA32_X2APIC_APICID.read() as u32
+            } else {
+                self.lapic_read(LAPIC_ID) >> 24
+            }
+        }
+    }
+
+    /// Send an inter-processor interrupt with the given vector to the local APIC `apic_id`.
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.lapic_write(0x310, (apic_id as u32) << 24);
+        self.lapic_write(0x300, vector as u32);
+        self.wait_for_ipi_delivery();
+    }
+
+    /// Send an inter-processor interrupt with the given vector to every CPU except this one.
+    pub fn broadcast_ipi(&self, vector: u8) {
+        // Destination shorthand 0b11: all excluding self.
+        self.lapic_write(0x300, vector as u32 | (0b11 << 18));
+        self.wait_for_ipi_delivery();
+    }
+
+    /// Spin until the delivery status bit of the ICR is clear.
+    fn wait_for_ipi_delivery(&self) {
+        while self.lapic_read(0x300) & (1 << 12) != 0 {
+            spin_loop_hint();
+        }
     }
 
     pub fn lapic_set_nmi(&self, vec: u8, flags: u16, lint: u8) {
@@ -82,6 +158,32 @@ impl ApicManager {
         self.lapic_write(0xf0, read | (0x100 | 0xff));
     }
 
+    /// Measure how many APIC timer counts pass per millisecond on this CPU, by letting the timer
+    /// count down from its maximum while the PIT waits `pit_ms` milliseconds. The rate depends on
+    /// the bus frequency, so it has to be measured. The timer is left stopped.
+    pub fn calibrate_timer(&self, pit_ms: u32) -> u32 {
+        self.lapic_write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.lapic_write(LVT_TIMER, LVT_MASKED);
+        self.lapic_write(TIMER_INITIAL_COUNT, u32::max_value());
+
+        pit::wait_ms(pit_ms);
+
+        let elapsed = u32::max_value() - self.lapic_read(TIMER_CURRENT_COUNT);
+        self.lapic_write(TIMER_INITIAL_COUNT, 0);
+
+        elapsed / pit_ms
+    }
+
+    /// Start this CPU's APIC timer interrupting `hz` times a second on `vector`, given the rate
+    /// measured by `calibrate_timer`.
+    pub fn init_timer(&self, vector: u8, hz: u32, counts_per_ms: u32) {
+        let count = (counts_per_ms as u64 * 1000 / hz as u64) as u32;
+
+        self.lapic_write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.lapic_write(LVT_TIMER, vector as u32 | TIMER_PERIODIC);
+        self.lapic_write(TIMER_INITIAL_COUNT, count);
+    }
+
     pub fn io_apic_read(&self, reg: u32, num: usize) -> u32 {
         // First, find the base address of the I/O APIC referenced by `num`
         // in our list of entries.
@@ -151,18 +253,33 @@ impl ApicManager {
             println!("[ dev ] Redirecting IRQ {}, redirection data: {}", irq, redirection);
 
             self.io_apic_write(ioredtbl, io_apic, redirection as u32);
-            self.io_apic_write(ioredtbl + 1, io_apic, redirection as u32);
+            self.io_apic_write(ioredtbl + 1, io_apic, (redirection >> 32) as u32);
         }
     }
 
-    pub fn install_redirects(&self) {
+    /// Route every interrupt source override to the CPU with APIC ID `bsp_id`. The MADT need not
+    /// list the BSP first, so the caller must find its ID rather than use the first entry's.
+    pub fn install_redirects(&self, bsp_id: u32) {
+        // The I/O APIC's physical destination field is a single byte.
+        if bsp_id > 0xff {
+            println!("[ apic ] Error: BSP APIC id {} cannot be an I/O APIC destination.", bsp_id);
+            return;
+        }
+
         for iso in self.isos.iter() {
-            self.set_redirect(iso.irq_source, iso.gsi, iso.flags, self.local_apics[0].id)
+            self.set_redirect(iso.irq_source, iso.gsi, iso.flags, bsp_id as u8)
         }
     }
 
     pub fn eoi(&self) {
-        self.lapic_write(0xb0, 0);
+        self.lapic_write(LAPIC_EOI, 0);
+    }
+
+    /// Return whether the local APIC is servicing an interrupt on `vector`, going by its in-service
+    /// register. This is false for software interrupts, which never go through the APIC.
+    pub fn in_service(&self, vector: u8) -> bool {
+        let register = 0x100 + 0x10 * (vector as u32 / 32);
+        self.lapic_read(register) & (1 << (vector % 32)) != 0
     }
 }
 
@@ -178,21 +295,21 @@ pub fn init(active_table: &mut ActivePageTable) {
         {
             let page = Page::containing_address(VirtualAddress::new(apic_manager.lapic_base as usize));
             let frame = Frame::containing_address(PhysicalAddress::new(apic_manager.lapic_base as usize));
-            let result = active_table.map_to(page, frame,
-                                             EntryFlags::PRESENT |
-                                             EntryFlags::WRITABLE |
-                                             EntryFlags::NO_EXECUTE);
+            let result = active_table.map_to(page, frame, EntryFlags::mmio());
             result.flush(active_table);
         }
+        LAPIC_BASE.store(apic_manager.lapic_base as usize, Ordering::SeqCst);
+
+        // This runs on the BSP, so the local APIC we can now read is the BSP's.
+        let bsp_id = apic_manager.lapic_id();
+        BSP_APIC_ID.store(bsp_id, Ordering::SeqCst);
+        println!("[ dev ] BSP local APIC id: {}", bsp_id);
 
         {
             for io_apic in apic_manager.io_apics.iter() {
                 let page = Page::containing_address(VirtualAddress::new(io_apic.address as usize));
                 let frame = Frame::containing_address(PhysicalAddress::new(io_apic.address as usize));
-                let result = active_table.map_to(page, frame,
-                                                 EntryFlags::PRESENT |
-                                                 EntryFlags::WRITABLE |
-                                                 EntryFlags::NO_EXECUTE);
+                let result = active_table.map_to(page, frame, EntryFlags::mmio());
                 result.flush(active_table);
             }
         }
@@ -200,20 +317,85 @@ pub fn init(active_table: &mut ActivePageTable) {
         println!("[ dev ] Installing non-maskable interrupts...");
         apic_manager.install_nmis();
         println!("[ dev ] Installing interrupt source overrides...");
-        apic_manager.install_redirects();
+        apic_manager.install_redirects(bsp_id);
         println!("[ dev ] Enabling Local APIC");
         apic_manager.lapic_enable();
     }
 }
 
-pub fn eoi() {
-    if let Some(ref mut apic_manager) = *APIC_MANAGER.lock() {
-        apic_manager.eoi();
+/// Return the APIC ID of the bootstrap processor, as read from its own local APIC during `init`.
+pub fn bsp_apic_id() -> u32 {
+    BSP_APIC_ID.load(Ordering::SeqCst)
+}
+
+/// Calibrate this CPU's APIC timer and start it interrupting `hz` times a second on
+/// `TIMER_VECTOR`. Every CPU has to do this for itself, as each has its own timer.
+pub fn init_timer(hz: u32) {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        let counts_per_ms = apic_manager.calibrate_timer(CALIBRATION_MS);
+        println!(
+            "[ dev ] APIC timer runs at {} counts per ms, interrupting at {} Hz.",
+            counts_per_ms, hz
+        );
+        apic_manager.init_timer(TIMER_VECTOR, hz, counts_per_ms);
+    } else {
+        panic!("apic not initialised");
+    }
+}
+
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        apic_manager.send_ipi(apic_id, vector);
+    } else {
+        panic!("apic not initialised");
+    }
+}
+
+pub fn broadcast_ipi(vector: u8) {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        apic_manager.broadcast_ipi(vector);
+    } else {
+        panic!("apic not initialised");
+    }
+}
+
+/// Deliver this CPU's performance counter overflow interrupt as an NMI, or mask it.
+pub fn set_perf_nmi(enabled: bool) {
+    if let Some(ref apic_manager) = *APIC_MANAGER.lock() {
+        let lvt = if enabled { LVT_DELIVERY_NMI } else { LVT_MASKED };
+        apic_manager.lapic_write(LVT_PERF, lvt);
     } else {
         panic!("apic not initialised");
     }
 }
 
+/// Unmask this CPU's performance counter NMI, which the local APIC masks each time it delivers
+/// one. This takes no locks, so it is safe to call from the NMI handler.
+pub fn unmask_perf_nmi() {
+    let base = LAPIC_BASE.load(Ordering::SeqCst);
+    if base != 0 {
+        unsafe { ptr::write_volatile((base + LVT_PERF as usize) as *mut u32, LVT_DELIVERY_NMI) };
+    }
+}
+
+/// Signal the end of an interrupt to this CPU's local APIC. This takes no locks, since a handler
+/// may have interrupted code holding `APIC_MANAGER`, or another CPU may hold it while it waits
+/// for this one, as a TLB shootdown does.
+pub fn eoi() {
+    let base = LAPIC_BASE.load(Ordering::SeqCst);
+    assert!(base != 0, "apic not initialised");
+    unsafe { ptr::write_volatile((base + LAPIC_EOI as usize) as *mut u32, 0) };
+}
+
+/// Return whether this CPU's local APIC is servicing an interrupt on `vector`. This is false if
+/// the APIC has not been set up.
+pub fn in_service(vector: u8) -> bool {
+    match *APIC_MANAGER.lock() {
+        Some(ref apic_manager) => apic_manager.in_service(vector),
+        None => false,
+    }
+}
+
 lazy_static! {
     pub static ref APIC_MANAGER: Mutex<Option<ApicManager>> = Mutex::new(None);
 }
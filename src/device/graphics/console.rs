@@ -0,0 +1,353 @@
+//! A text console drawn on the framebuffer with the bitmap font.
+//!
+//! Every line written is kept in a scrollback buffer of up to `SCROLLBACK_LINES` lines, and the
+//! screen shows a window onto it, normally the newest lines. Shift+PageUp and Shift+PageDown move
+//! the window through the scrollback.
+//!
+//! Drawing a glyph is slow compared to storing a character, so the console remembers what each
+//! cell on the screen shows and after each write only redraws the cells whose character or colour
+//! changed. Scrolling a screen of mostly short log lines only redraws where the old and new lines
+//! differ.
+
+use alloc::VecDeque;
+use arch::interrupts::InterruptGuard;
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+use device::graphics::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use device::graphics::framebuffer::Framebuffer;
+use device::keyboard::{KeyCode, KeyInput, KeyState};
+use device::vga::vga::Color;
+use sync::{LockRank, RankedMutex};
+
+/// Number of lines kept for scrolling back, including those on screen.
+const SCROLLBACK_LINES: usize = 1000;
+
+/// The standard VGA palette, indexed by `Color`.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0xaa),
+    (0x00, 0xaa, 0x00),
+    (0x00, 0xaa, 0xaa),
+    (0xaa, 0x00, 0x00),
+    (0xaa, 0x00, 0xaa),
+    (0xaa, 0x55, 0x00),
+    (0xaa, 0xaa, 0xaa),
+    (0x55, 0x55, 0x55),
+    (0x55, 0x55, 0xff),
+    (0x55, 0xff, 0x55),
+    (0x55, 0xff, 0xff),
+    (0xff, 0x55, 0x55),
+    (0xff, 0x55, 0xff),
+    (0xff, 0xff, 0x55),
+    (0xff, 0xff, 0xff),
+];
+
+/// The framebuffer console, if there is a framebuffer.
+pub static CONSOLE: RankedMutex<Option<Console>> = RankedMutex::new(LockRank::Screen, None);
+
+/// A character and its colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    byte: u8,
+    fg: Color,
+    bg: Color,
+}
+
+pub struct Console {
+    framebuffer: Framebuffer,
+    /// `PALETTE` converted to the framebuffer's pixel format.
+    palette: [u32; 16],
+    cols: usize,
+    rows: usize,
+    /// Lines of text, oldest first. New text goes on the last line.
+    lines: VecDeque<Vec<Cell>>,
+    /// Where the next character goes on the last line.
+    column: usize,
+    fg: Color,
+    bg: Color,
+    /// How many lines the view is scrolled back from the newest line.
+    scrollback: usize,
+    /// What each cell on the screen currently shows, row by row.
+    shown: Vec<Cell>,
+    /// Whether the blinking cursor is in its visible phase.
+    cursor_on: bool,
+}
+
+impl Console {
+    /// Create a console covering the whole framebuffer, and clear the screen.
+    pub fn new(framebuffer: Framebuffer) -> Self {
+        let cols = framebuffer.width() / GLYPH_WIDTH;
+        let rows = framebuffer.height() / GLYPH_HEIGHT;
+
+        let mut palette = [0; 16];
+        for (pixel, &(red, green, blue)) in palette.iter_mut().zip(PALETTE.iter()) {
+            *pixel = framebuffer.color(red, green, blue);
+        }
+
+        let mut console = Console {
+            framebuffer: framebuffer,
+            palette: palette,
+            cols: cols,
+            rows: rows,
+            lines: VecDeque::new(),
+            column: 0,
+            fg: Color::LightGray,
+            bg: Color::Black,
+            scrollback: 0,
+            shown: Vec::new(),
+            cursor_on: true,
+        };
+
+        let blank = console.blank();
+        console.lines.push_back(vec![blank; cols]);
+        console.shown = vec![blank; cols * rows];
+
+        let (width, height) = (console.framebuffer.width(), console.framebuffer.height());
+        let bg = console.palette[console.bg as usize];
+        console.framebuffer.fill_rect(0, 0, width, height, bg);
+        console.render();
+
+        console
+    }
+
+    /// Return the size of the text grid as (columns, rows).
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Set the colours used for text written from now on.
+    pub fn set_color(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Scroll the view back by a screen, towards older lines.
+    pub fn page_up(&mut self) {
+        let most = self.lines.len().saturating_sub(self.rows);
+        self.scrollback = cmp::min(self.scrollback + self.page(), most);
+        self.render();
+    }
+
+    /// Scroll the view forward by a screen, towards the newest line.
+    pub fn page_down(&mut self) {
+        self.scrollback = self.scrollback.saturating_sub(self.page());
+        self.render();
+    }
+
+    /// Toggle the cursor between shown and hidden. Call this periodically to make it blink.
+    pub fn blink(&mut self) {
+        self.cursor_on = !self.cursor_on;
+        self.render();
+    }
+
+    /// Lines to move by for a page, keeping one line of context.
+    fn page(&self) -> usize {
+        cmp::max(self.rows, 2) - 1
+    }
+
+    fn blank(&self) -> Cell {
+        Cell {
+            byte: b' ',
+            fg: self.fg,
+            bg: self.bg,
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            // Backspace.
+            0x8 => if self.column > 0 {
+                self.column -= 1;
+                let blank = self.blank();
+                let column = self.column;
+                self.current_line()[column] = blank;
+            },
+            b'\t' => for _ in 0..4 {
+                self.write_byte(b' ');
+            },
+            byte => {
+                if self.column >= self.cols {
+                    self.new_line();
+                }
+
+                let cell = Cell {
+                    byte: byte,
+                    fg: self.fg,
+                    bg: self.bg,
+                };
+                let column = self.column;
+                self.current_line()[column] = cell;
+                self.column += 1;
+            }
+        }
+    }
+
+    fn current_line(&mut self) -> &mut Vec<Cell> {
+        self.lines.back_mut().expect("console has no lines")
+    }
+
+    /// Start a new line, dropping the oldest line if the scrollback is full.
+    fn new_line(&mut self) {
+        let blank = self.blank();
+
+        let line = if self.lines.len() >= SCROLLBACK_LINES {
+            let mut line = self.lines.pop_front().expect("console has no lines");
+            for cell in line.iter_mut() {
+                *cell = blank;
+            }
+            line
+        } else {
+            vec![blank; self.cols]
+        };
+
+        self.lines.push_back(line);
+        self.column = 0;
+
+        // Keep a scrolled back view on the same lines while new ones arrive.
+        if self.scrollback > 0 {
+            let most = self.lines.len().saturating_sub(self.rows);
+            self.scrollback = cmp::min(self.scrollback + 1, most);
+        }
+    }
+
+    /// Index in `lines` of the line shown on the top row.
+    fn top_line(&self) -> usize {
+        self.lines
+            .len()
+            .saturating_sub(self.rows)
+            .saturating_sub(self.scrollback)
+    }
+
+    /// Return what the cell at (`col`, `row`) should show.
+    fn cell_at(&self, col: usize, row: usize) -> Cell {
+        let index = self.top_line() + row;
+
+        let mut cell = match self.lines.get(index) {
+            Some(line) => line[col],
+            None => self.blank(),
+        };
+
+        // The cursor is drawn by swapping the colours of the cell it is on.
+        let cursor_column = cmp::min(self.column, self.cols - 1);
+        let is_cursor = self.scrollback == 0 && index + 1 == self.lines.len()
+            && col == cursor_column;
+
+        if is_cursor && self.cursor_on {
+            cell = Cell {
+                byte: cell.byte,
+                fg: cell.bg,
+                bg: cell.fg,
+            };
+        }
+
+        cell
+    }
+
+    /// Redraw every cell whose contents differ from what is on the screen.
+    fn render(&mut self) {
+        if self.cols == 0 {
+            return;
+        }
+
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let cell = self.cell_at(col, row);
+                let shown = &mut self.shown[row * self.cols + col];
+
+                if *shown == cell {
+                    continue;
+                }
+
+                *shown = cell;
+                self.framebuffer.draw_glyph(
+                    col * GLYPH_WIDTH,
+                    row * GLYPH_HEIGHT,
+                    font::glyph(cell.byte),
+                    GLYPH_WIDTH,
+                    self.palette[cell.fg as usize],
+                    self.palette[cell.bg as usize],
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_byte(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+
+        // New output snaps the cursor back to visible so that it does not vanish while typing.
+        self.cursor_on = true;
+        self.render();
+
+        Ok(())
+    }
+}
+
+/// Write to the framebuffer console. Returns `false` if there is no framebuffer console, in which
+/// case the caller should fall back to the VGA text buffer.
+///
+/// Like every other use of `CONSOLE` outside an interrupt handler, this holds the lock with
+/// interrupts disabled, since the timer interrupt prints.
+pub fn print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    let _guard = InterruptGuard::new();
+    match *CONSOLE.lock() {
+        Some(ref mut console) => {
+            let _ = console.write_fmt(args);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Return whether the console is on a framebuffer, rather than in VGA text mode.
+pub fn is_active() -> bool {
+    let _guard = InterruptGuard::new();
+    CONSOLE.lock().is_some()
+}
+
+/// Start using `framebuffer` for the console.
+pub fn init(framebuffer: Framebuffer) {
+    let console = Console::new(framebuffer);
+    let _guard = InterruptGuard::new();
+    *CONSOLE.lock() = Some(console);
+}
+
+/// Handle Shift+PageUp and Shift+PageDown, which scroll through the console's scrollback. Returns
+/// whether the key was used, in which case it should not be handled any further.
+pub fn handle_key(key: &KeyInput) -> bool {
+    if !key.shift || key.state != KeyState::Pressed {
+        return false;
+    }
+
+    let _guard = InterruptGuard::new();
+    let mut console = CONSOLE.lock();
+    let console = match *console {
+        Some(ref mut console) => console,
+        None => return false,
+    };
+
+    match key.code {
+        KeyCode::PageUp => console.page_up(),
+        KeyCode::PageDown => console.page_down(),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Blink the cursor. This is called from the timer interrupt, so it gives up rather than waiting
+/// if the console is in use.
+pub fn blink() {
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(ref mut console) = *console {
+            console.blink();
+        }
+    }
+}
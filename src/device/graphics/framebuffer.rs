@@ -0,0 +1,197 @@
+//! A linear framebuffer set up by the bootloader, described by the multiboot framebuffer tag.
+//!
+//! The tag gives the physical address, the pitch (bytes per row), the size in pixels and the bits
+//! per pixel. For direct colour framebuffers it is followed by the position and size of the red,
+//! green and blue fields within a pixel. Only 24 and 32 bit direct colour framebuffers are
+//! supported.
+
+use arch::memory::{addr, Frame, PAGE_SIZE};
+use arch::memory::paging::{ActivePageTable, Page, PhysicalAddress, VirtualAddress};
+use arch::memory::paging::entry::EntryFlags;
+use core::{cmp, ptr};
+
+/// `framebuffer_type` of a direct colour framebuffer.
+const TYPE_RGB: u8 = 1;
+
+/// The position and size in bits of a colour field within a pixel.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    position: u8,
+    size: u8,
+}
+
+impl Field {
+    /// Scale an 8-bit colour value to this field and move it into place.
+    fn pack(&self, value: u8) -> u32 {
+        ((value as u32) >> (8 - self.size)) << self.position
+    }
+}
+
+#[derive(Debug)]
+pub struct Framebuffer {
+    address: usize,
+    pitch: usize,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    red: Field,
+    green: Field,
+    blue: Field,
+}
+
+impl Framebuffer {
+    /// Parse the contents of a multiboot framebuffer tag.
+    pub fn from_tag(data: &[u8]) -> Result<Framebuffer, &'static str> {
+        if data.len() < 30 {
+            return Err("framebuffer tag is too short");
+        }
+
+        if data[21] != TYPE_RGB {
+            return Err("framebuffer is not direct colour");
+        }
+
+        let bytes_per_pixel = match data[20] {
+            24 => 3,
+            32 => 4,
+            _ => return Err("framebuffer depth is not 24 or 32 bits"),
+        };
+
+        let field = |offset: usize| Field {
+            position: data[offset],
+            size: data[offset + 1],
+        };
+
+        let (red, green, blue) = (field(24), field(26), field(28));
+        if [red, green, blue].iter().any(|f| f.size == 0 || f.size > 8) {
+            return Err("framebuffer colour fields are not 1 to 8 bits");
+        }
+
+        let framebuffer = Framebuffer {
+            address: read_u64(data, 0) as usize,
+            pitch: read_u32(data, 8) as usize,
+            width: read_u32(data, 12) as usize,
+            height: read_u32(data, 16) as usize,
+            bytes_per_pixel: bytes_per_pixel,
+            red: red,
+            green: green,
+            blue: blue,
+        };
+
+        if framebuffer.pitch < framebuffer.width * bytes_per_pixel {
+            return Err("framebuffer pitch is smaller than a row");
+        }
+
+        Ok(framebuffer)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.bytes_per_pixel * 8
+    }
+
+    /// Identity map the framebuffer uncached, skipping pages which are already mapped. Whole
+    /// 2 MiB stretches of it are mapped with huge pages, which take far fewer TLB entries.
+    pub fn map(&self, active_table: &mut ActivePageTable) {
+        let len = self.pitch * self.height;
+        let start_page = Page::containing_address(VirtualAddress::new(self.address));
+        let end_page = Page::containing_address(VirtualAddress::new(self.address + len - 1));
+        let pages_per_huge_page = addr::HUGE_PAGE_SIZE / PAGE_SIZE;
+
+        let mut page = start_page;
+        while page <= end_page {
+            let physical = PhysicalAddress::new(page.start_address().get());
+            let frame = Frame::containing_address(physical);
+            let huge_end = page + (pages_per_huge_page - 1);
+
+            if addr::is_aligned(physical.get(), addr::HUGE_PAGE_SIZE) && huge_end <= end_page
+                && active_table.huge_page_is_free(page)
+            {
+                let result = active_table.map_to_huge(page, frame, EntryFlags::mmio());
+                result.flush(active_table);
+                page = huge_end;
+            } else if active_table.translate_page(page).is_none() {
+                let result = active_table.map_to(page, frame, EntryFlags::mmio());
+                result.flush(active_table);
+            }
+
+            if page == end_page {
+                break;
+            }
+            page = page + 1;
+        }
+    }
+
+    /// Return the pixel value for a colour.
+    pub fn color(&self, red: u8, green: u8, blue: u8) -> u32 {
+        self.red.pack(red) | self.green.pack(green) | self.blue.pack(blue)
+    }
+
+    /// Fill a rectangle with `color`. The rectangle is clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let x_end = cmp::min(x + width, self.width);
+        let y_end = cmp::min(y + height, self.height);
+
+        for row in y..y_end {
+            for col in x..x_end {
+                self.put_pixel(col, row, color);
+            }
+        }
+    }
+
+    /// Draw a one bit per pixel glyph with its top left corner at (`x`, `y`). Each byte of `glyph`
+    /// is a row of up to 8 pixels, most significant bit first.
+    pub fn draw_glyph(
+        &mut self,
+        x: usize,
+        y: usize,
+        glyph: &[u8],
+        width: usize,
+        fg: u32,
+        bg: u32,
+    ) {
+        for (row, &bits) in glyph.iter().enumerate() {
+            if y + row >= self.height {
+                return;
+            }
+
+            for col in 0..width {
+                if x + col >= self.width {
+                    break;
+                }
+
+                let color = if bits & (0x80 >> col) != 0 { fg } else { bg };
+                self.put_pixel(x + col, y + row, color);
+            }
+        }
+    }
+
+    /// Write one pixel. The caller must check that (`x`, `y`) is on the screen.
+    fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        let offset = self.address + y * self.pitch + x * self.bytes_per_pixel;
+
+        unsafe {
+            if self.bytes_per_pixel == 4 {
+                ptr::write_volatile(offset as *mut u32, color);
+            } else {
+                ptr::write_volatile(offset as *mut u8, color as u8);
+                ptr::write_volatile((offset + 1) as *mut u8, (color >> 8) as u8);
+                ptr::write_volatile((offset + 2) as *mut u8, (color >> 16) as u8);
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 8].as_ptr() as *const u64) }
+}
@@ -0,0 +1,41 @@
+//! Graphics output through a linear framebuffer, when the bootloader sets one up.
+
+pub mod console;
+pub mod font;
+pub mod framebuffer;
+
+use arch::memory::paging::ActivePageTable;
+use arch::multiboot::{self, TAG_FRAMEBUFFER};
+use multiboot2::BootInformation;
+use self::framebuffer::Framebuffer;
+
+/// Find the framebuffer and start the framebuffer console on it. Without a usable framebuffer the
+/// VGA text buffer stays in use. This must be called after the heap is set up.
+pub fn init(boot_info: &BootInformation, active_table: &mut ActivePageTable) {
+    let tag = match multiboot::find_tag(boot_info, TAG_FRAMEBUFFER) {
+        Some(tag) => tag,
+        None => {
+            println!("[ fb ] No framebuffer, using VGA text mode.");
+            return;
+        }
+    };
+
+    let framebuffer = match Framebuffer::from_tag(tag.data) {
+        Ok(framebuffer) => framebuffer,
+        Err(e) => {
+            println!("[ fb ] Not using the framebuffer: {}.", e);
+            return;
+        }
+    };
+
+    framebuffer.map(active_table);
+
+    println!(
+        "[ fb ] {}x{} framebuffer, {} bits per pixel.",
+        framebuffer.width(),
+        framebuffer.height(),
+        framebuffer.bits_per_pixel()
+    );
+
+    console::init(framebuffer);
+}
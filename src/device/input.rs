@@ -0,0 +1,84 @@
+//! A single stream of input from every device. Each driver queues its own events, and
+//! `poll_input` merges them so that a consumer can handle a key, a mouse packet or a serial byte in
+//! one loop.
+//!
+//! Sources are polled in priority order: keyboard, then serial, then mouse. A source which has
+//! produced `BURST` events in a row is moved to the back of the order until another source produces
+//! an event, so a flood from one device, such as a stream of mouse movement, cannot starve the
+//! others.
+
+use device::keyboard::{self, KeyInput};
+use device::mouse::{MouseEvent, MOUSE_EVENTS};
+use device::serial;
+use spin::Mutex;
+
+/// An event from any input device.
+#[derive(Debug, Clone, Copy)]
+pub enum InputEvent {
+    Key(KeyInput),
+    Mouse(MouseEvent),
+    Serial(u8),
+}
+
+const KEYBOARD: usize = 0;
+const SERIAL: usize = 1;
+const MOUSE: usize = 2;
+const SOURCES: usize = 3;
+
+/// Number of events a source may produce in a row before the others are polled first.
+const BURST: usize = 16;
+
+/// The source which produced the last event, and how many it has produced in a row.
+static STREAK: Mutex<(usize, usize)> = Mutex::new((KEYBOARD, 0));
+
+fn poll_source(source: usize) -> Option<InputEvent> {
+    match source {
+        KEYBOARD => {
+            // Keep going until a byte completes a key, so that multi-byte sequences are not split
+            // across polls.
+            while let Some(byte) = keyboard::SCANCODES.pop() {
+                if let Some(key) = keyboard::parse_key(byte) {
+                    return Some(InputEvent::Key(key));
+                }
+            }
+
+            None
+        }
+        SERIAL => {
+            serial::poll();
+            serial::SERIAL_INPUT.pop().map(InputEvent::Serial)
+        }
+        MOUSE => MOUSE_EVENTS.pop().map(InputEvent::Mouse),
+        _ => None,
+    }
+}
+
+/// Return the next input event from any device, or `None` if there is none waiting.
+pub fn poll_input() -> Option<InputEvent> {
+    let mut streak = STREAK.lock();
+
+    let demoted = if streak.1 >= BURST {
+        Some(streak.0)
+    } else {
+        None
+    };
+
+    let order = (0..SOURCES)
+        .filter(|&source| Some(source) != demoted)
+        .chain(demoted);
+
+    for source in order {
+        if let Some(event) = poll_source(source) {
+            if source == streak.0 {
+                streak.1 += 1;
+            } else {
+                *streak = (source, 1);
+            }
+
+            return Some(event);
+        }
+    }
+
+    *streak = (KEYBOARD, 0);
+    None
+}
@@ -0,0 +1,81 @@
+//! Fixed-capacity event queues for passing input from interrupt handlers to tasks.
+//!
+//! Each driver defines its own event type and owns a static `EventQueue`. The interrupt handler
+//! calls `push`, and a task later calls `drain` to handle everything that has arrived. The queue
+//! never allocates, so it is safe to push from an interrupt handler. When the queue is full, new
+//! events are dropped and counted rather than overwriting older ones.
+
+use arch::interrupts::InterruptGuard;
+use core::marker::Unsize;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use heapless::RingBuffer;
+use spin::Mutex;
+
+/// A queue of events of type `T`, backed by the array type `A` (e.g. `[u8; 64]`).
+pub struct EventQueue<T, A>
+where
+    A: Unsize<[T]>,
+{
+    buffer: Mutex<RingBuffer<T, A>>,
+    dropped: AtomicUsize,
+}
+
+impl<T, A> EventQueue<T, A>
+where
+    A: Unsize<[T]>,
+{
+    pub const fn new() -> Self {
+        EventQueue {
+            buffer: Mutex::new(RingBuffer::new()),
+            dropped: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Add an event to the back of the queue. Returns false if the queue was full and the event was
+    /// dropped.
+    pub fn push(&self, event: T) -> bool {
+        let _guard = InterruptGuard::new();
+
+        match self.buffer.lock().enqueue(event) {
+            Ok(()) => true,
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    /// Remove the event at the front of the queue.
+    pub fn pop(&self) -> Option<T> {
+        // Interrupts are disabled so that a handler pushing to this queue cannot spin on the lock
+        // while we hold it.
+        let _guard = InterruptGuard::new();
+
+        self.buffer.lock().dequeue()
+    }
+
+    /// Call `handler` with each queued event, oldest first, until the queue is empty. The lock is
+    /// not held while `handler` runs, so events pushed meanwhile are handled too.
+    pub fn drain<F: FnMut(T)>(&self, mut handler: F) {
+        while let Some(event) = self.pop() {
+            handler(event);
+        }
+    }
+
+    /// Return the number of queued events.
+    pub fn len(&self) -> usize {
+        let _guard = InterruptGuard::new();
+
+        self.buffer.lock().len()
+    }
+
+    /// Return true if no events are queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of events dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
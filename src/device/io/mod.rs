@@ -1,4 +1,6 @@
 pub mod cpuio;
+pub mod event;
 pub mod mmio;
 
 pub use self::cpuio::Port;
+pub use self::event::EventQueue;
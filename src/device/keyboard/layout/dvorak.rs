@@ -0,0 +1,9 @@
+use super::Layout;
+
+/// US Dvorak, given by the characters on each physical QWERTY key.
+pub static DVORAK: Layout = Layout {
+    name: "dvorak",
+    unshifted: concat!("`1234567890[]", "',.pyfgcrl/=\\", "aoeuidhtns-", "\\;qjkxbmwvz"),
+    shifted: concat!("~!@#$%^&*(){}", "\"<>PYFGCRL?+|", "AOEUIDHTNS_", "|:QJKXBMWVZ"),
+    altgr: "",
+};
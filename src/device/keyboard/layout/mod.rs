@@ -1,9 +1,129 @@
-#[cfg(feature = "uk")]
+//! Keyboard layouts. The scancode decoder tells us which physical key was pressed, and a layout
+//! maps that key position to a character.
+//!
+//! Each table lists the characters produced by the printable keys in the order given by
+//! `position`: the number row, the three letter rows from top to bottom, and the ISO key next to
+//! left shift at the start of the bottom row. A `'\0'` means the key produces nothing in that
+//! table. The AltGr table is used while right Alt is held, and may be empty for layouts without
+//! AltGr.
+
+use device::keyboard::scancode::KeyCode;
+use spin::Mutex;
+
+pub mod dvorak;
 pub mod uk_std;
-#[cfg(feature = "us")]
 pub mod us_std;
 
-#[cfg(feature = "uk")]
-pub use self::uk_std::map_to_upper;
+/// Mapping from key positions to characters.
+pub struct Layout {
+    pub name: &'static str,
+    unshifted: &'static str,
+    shifted: &'static str,
+    altgr: &'static str,
+}
+
+impl Layout {
+    /// Return the character produced by `code`, given whether shift and AltGr are held.
+    pub fn map(&self, code: KeyCode, shift: bool, altgr: bool) -> Option<char> {
+        let index = position(code)?;
+
+        if altgr {
+            if let Some(c) = lookup(self.altgr, index) {
+                return Some(c);
+            }
+        }
+
+        if shift {
+            lookup(self.shifted, index)
+        } else {
+            lookup(self.unshifted, index)
+        }
+    }
+}
+
+fn lookup(table: &str, index: usize) -> Option<char> {
+    table.chars().nth(index).and_then(|c| if c == '\0' { None } else { Some(c) })
+}
+
+/// Index of a printable key in the layout tables.
+pub fn position(code: KeyCode) -> Option<usize> {
+    use self::KeyCode::*;
+
+    let index = match code {
+        Backtick => 0,
+        Key1 => 1,
+        Key2 => 2,
+        Key3 => 3,
+        Key4 => 4,
+        Key5 => 5,
+        Key6 => 6,
+        Key7 => 7,
+        Key8 => 8,
+        Key9 => 9,
+        Key0 => 10,
+        Minus => 11,
+        Equals => 12,
+        Q => 13,
+        W => 14,
+        E => 15,
+        R => 16,
+        T => 17,
+        Y => 18,
+        U => 19,
+        I => 20,
+        O => 21,
+        P => 22,
+        LeftBracket => 23,
+        RightBracket => 24,
+        Backslash => 25,
+        A => 26,
+        S => 27,
+        D => 28,
+        F => 29,
+        G => 30,
+        H => 31,
+        J => 32,
+        K => 33,
+        L => 34,
+        Semicolon => 35,
+        Quote => 36,
+        NonUsBackslash => 37,
+        Z => 38,
+        X => 39,
+        C => 40,
+        V => 41,
+        B => 42,
+        N => 43,
+        M => 44,
+        Comma => 45,
+        Period => 46,
+        Slash => 47,
+        _ => return None,
+    };
+
+    Some(index)
+}
+
+static LAYOUTS: [&'static Layout; 3] = [&us_std::US, &uk_std::UK, &dvorak::DVORAK];
+
+/// The layout in use. The `us` and `uk` features choose the layout used until `set_layout` is
+/// called.
 #[cfg(feature = "us")]
-pub use self::us_std::map_to_upper;
+static CURRENT: Mutex<&'static Layout> = Mutex::new(&us_std::US);
+#[cfg(not(feature = "us"))]
+static CURRENT: Mutex<&'static Layout> = Mutex::new(&uk_std::UK);
+
+/// Return the layout in use.
+pub fn current() -> &'static Layout {
+    *CURRENT.lock()
+}
+
+/// Change the layout used for all further key presses.
+pub fn set_layout(layout: &'static Layout) {
+    *CURRENT.lock() = layout;
+}
+
+/// Find a built-in layout by name.
+pub fn by_name(name: &str) -> Option<&'static Layout> {
+    LAYOUTS.iter().map(|layout| *layout).find(|layout| layout.name == name)
+}
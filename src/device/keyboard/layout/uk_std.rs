@@ -1,35 +1,14 @@
-use alloc::Vec;
+use super::Layout;
 
-pub fn map_to_upper(lower: char) -> Vec<char> {
-    if lower.is_alphabetic() {
-        lower.to_uppercase().collect()
-    } else {
-        let upper = match lower {
-            '`' => '¬',
-            '1' => '!',
-            '2' => '"',
-            '3' => '£',
-            '4' => '$',
-            '5' => '%',
-            '6' => '^',
-            '7' => '&',
-            '8' => '*',
-            '9' => '(',
-            '0' => ')',
-            '-' => '_',
-            '=' => '+',
-            '[' => '{',
-            ']' => '}',
-            '\\' => '|',
-            ';' => ':',
-            '\'' => '@',
-            '#' => '~',
-            ',' => '<',
-            '.' => '>',
-            '/' => '?',
-            _ => 0x0 as char,
-        };
-
-        vec![upper]
-    }
-}
+/// UK QWERTY. The key to the left of enter is `#`, and the ISO key next to left shift is `\`.
+pub static UK: Layout = Layout {
+    name: "uk",
+    unshifted: concat!("`1234567890-=", "qwertyuiop[]#", "asdfghjkl;'", "\\zxcvbnm,./"),
+    shifted: concat!("¬!\"£$%^&*()_+", "QWERTYUIOP{}~", "ASDFGHJKL:@", "|ZXCVBNM<>?"),
+    altgr: concat!(
+        "¦\0\0\0€\0\0\0\0\0\0\0\0",
+        "\0\0é\0\0\0úíó\0\0\0\0",
+        "á\0\0\0\0\0\0\0\0\0\0",
+        "\0\0\0\0\0\0\0\0\0\0\0"
+    ),
+};
@@ -1,6 +1,8 @@
 pub mod keyboard;
 pub mod layout;
 pub mod ps2_keyboard;
+pub mod scancode;
 
 pub use self::keyboard::*;
 pub use self::ps2_keyboard::*;
+pub use self::scancode::{KeyCode, KeyState, ScancodeSet};
@@ -1,7 +1,10 @@
-use device::ps2_8042;
+use arch::cmdline;
+use device::io::EventQueue;
+use device::ps2_8042::{self, Ps2};
 use device::keyboard;
-use alloc::Vec;
-use alloc::string::{String, ToString};
+use device::keyboard::layout;
+use device::keyboard::scancode::{Decoder, KeyCode, KeyState, ScancodeSet};
+use alloc::string::String;
 use spin::Mutex;
 
 /// A pair of keys on the left and the right of the keyboard.
@@ -63,11 +66,6 @@ impl ModifierState {
         }
     }
 
-    /// Should we use uppercase letters?
-    fn use_uppercase_letters(&self) -> bool {
-        self.shift.is_pressed() ^ self.caps_lock
-    }
-
     fn should_switch_tty(&self) -> (bool, usize) {
         let is_ctrl: bool = self.control.left || self.control.right;
 
@@ -83,20 +81,23 @@ impl ModifierState {
         (false, 0)
     }
 
-    /// Apply modifiers to ascii and return updated ascii.
-    fn apply_to(&self, ascii: char) -> String {
-        if self.use_uppercase_letters() {
-            use device::keyboard::layout::map_to_upper;
-
-            map_to_upper(ascii).iter().collect()
-        } else if self.should_switch_tty().0 {
+    /// Map a printable key to a character using the current layout and modifiers. Caps lock only
+    /// affects keys which produce a letter.
+    fn apply_to(&self, code: KeyCode) -> Option<char> {
+        if self.should_switch_tty().0 {
             let index = self.should_switch_tty().1;
             tty_switch!(index);
 
-            ascii.to_string()
-        } else {
-            ascii.to_string()
+            return None;
         }
+
+        let layout = layout::current();
+        let is_letter = layout
+            .map(code, false, false)
+            .map_or(false, |c| c.is_alphabetic());
+        let shift = self.shift.is_pressed() ^ (self.caps_lock && is_letter);
+
+        layout.map(code, shift, self.alt.right)
     }
 
     /// Update modifier state.
@@ -122,7 +123,21 @@ impl ModifierState {
 pub enum Key {
     Ascii(u8),
     Meta(Modifiers),
-    LowerAscii(u8),
+    /// A key which produces a character, depending on the layout and modifiers.
+    Printable(KeyCode),
+    /// A key with no character or modifier meaning, such as the arrow keys.
+    Special(KeyCode),
+}
+
+/// A decoded key press or release, along with the character it produced under the current layout
+/// and modifiers, if any.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyInput {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub character: Option<char>,
+    /// Whether either shift key was held.
+    pub shift: bool,
 }
 
 /// A key can be pressed or released and there are different scancodes as such.
@@ -133,46 +148,100 @@ pub enum KeyEvent {
 
 static STATE: Mutex<ModifierState> = Mutex::new(ModifierState::new());
 
-/// Parse the retrieved key and print the output or update modifier state dependant on the type of
-/// key received. This is called by our keyboard IRQ handler.
-pub fn parse_key(scancode: u8) {
-    let sequence: u64 = retrieve_bytes(scancode);
+/// Decoder for the bytes received from the keyboard. Set 1 is assumed until `init` has asked the
+/// keyboard.
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(ScancodeSet::Set1));
+
+/// Bytes received by the keyboard IRQ handler which have not been decoded yet.
+pub static SCANCODES: EventQueue<u8, [u8; 64]> = EventQueue::new();
+
+/// Keyboard command acknowledged.
+const ACK: u8 = 0xFA;
+/// Keyboard command: get or set the current scancode set.
+const SCANCODE_SET: u8 = 0xF0;
+
+/// Send a command byte to the keyboard and return whether it was acknowledged.
+fn send_command(ps2: &mut Ps2, byte: u8) -> bool {
+    ps2.wait_then_write(byte);
+    ps2.wait_then_read() == ACK
+}
+
+/// Work out which scancode set we will be receiving. If the controller translates scancodes then
+/// we always see set 1. Otherwise ask the keyboard, and switch it to set 2 if it is using a set we
+/// cannot decode.
+fn detect_scancode_set(ps2: &mut Ps2) -> ScancodeSet {
+    ps2.controller.write(0x20);
+    let config_byte = ps2.wait_then_read();
 
-    if let Some(key) = keyboard::get_key(sequence) {
-        match key {
-            Key::Ascii(k) => print_char(k as char),
-            Key::Meta(modifier) => STATE.lock().update(modifier),
-            Key::LowerAscii(byte) => print_str(STATE.lock().apply_to(byte as char)),
+    if config_byte & (1 << 6) != 0 {
+        return ScancodeSet::Set1;
+    }
+
+    if send_command(ps2, SCANCODE_SET) && send_command(ps2, 0x00) {
+        match ps2.wait_then_read() {
+            // Some keyboards reply with the translated form of the set number.
+            0x01 | 0x43 => return ScancodeSet::Set1,
+            0x02 | 0x41 => return ScancodeSet::Set2,
+            _ => (),
         }
     }
+
+    if !(send_command(ps2, SCANCODE_SET) && send_command(ps2, 0x02)) {
+        println!("[ dev ] Keyboard did not accept scancode set 2, assuming it is in use.");
+    }
+
+    ScancodeSet::Set2
 }
 
-/// Read bytes until end of sequence and combine into a number.
-fn retrieve_bytes(scancode: u8) -> u64 {
-    let mut byte_sequence: Vec<u8> = vec![scancode];
+/// Detect the scancode set the keyboard is using. This must be called after the 8042 has been
+/// initialised.
+pub fn init() {
+    let set = detect_scancode_set(&mut ps2_8042::PS2.lock());
+    DECODER.lock().set_scancode_set(set);
 
-    // These scancodes are special - they indicate the start of a byte sequence which is sent when
-    // some keys are pressed. If they are the byte we receive, read until the end of the sequence.
-    if scancode == 0xE0 || scancode == 0xE1 {
-        // Read another byte from the keyboard.
-        let check: u8 = ps2_8042::read_char();
+    println!("[ dev ] Keyboard is using scancode {:?}.", set);
 
-        if let Some(byte) = keyboard::is_special_key(check) {
-            byte_sequence.push(byte);
+    if let Some(name) = cmdline::option("kbd") {
+        match layout::by_name(name) {
+            Some(l) => layout::set_layout(l),
+            None => println!("[ dev ] Unknown keyboard layout \"{}\", ignoring.", name),
         }
     }
 
-    byte_sequence
-        .iter()
-        .rev()
-        .fold(0, |acc, &b| (acc << 1) + b as u64)
+    println!("[ dev ] Keyboard layout: {}.", layout::current().name);
+}
+
+/// Parse the retrieved byte, updating modifier state, and return the key it completes. Bytes which
+/// are only part of a multi-byte sequence return `None`. This is called with each byte the keyboard
+/// sends, in order.
+pub fn parse_key(scancode: u8) -> Option<KeyInput> {
+    let (code, state) = DECODER.lock().feed(scancode)?;
+
+    let character = match keyboard::get_key(code, state) {
+        Some(Key::Ascii(k)) => Some(k as char),
+        Some(Key::Meta(modifier)) => {
+            STATE.lock().update(modifier);
+            None
+        }
+        Some(Key::Printable(code)) => STATE.lock().apply_to(code),
+        Some(Key::Special(_)) | None => None,
+    };
+
+    Some(KeyInput {
+        code: code,
+        state: state,
+        character: character,
+        shift: STATE.lock().shift.is_pressed(),
+    })
 }
 
-/// Print an ascii character.
+/// Echo a typed character to the console. Control characters other than newline, tab and
+/// backspace are not printed.
 pub fn print_char(character: char) {
     match character {
-        '\n' | ' ' | '\t' | '\x08' => print!("{}", character),
-        _ => (),
+        '\n' | '\t' | '\x08' => print!("{}", character),
+        c if c.is_control() => (),
+        c => print!("{}", c),
     }
 }
 
@@ -0,0 +1,520 @@
+//! Scancode decoding. The keyboard sends a sequence of bytes for each key press and release, and
+//! the format of that sequence depends on the scancode set in use. The `Decoder` consumes bytes one
+//! at a time and produces a `KeyCode` naming the physical key, independent of the scancode set.
+//!
+//! Set 1: a release is the make code with bit 7 set. Extended keys are prefixed with `0xE0`.
+//! Set 2: a release is the make code prefixed with `0xF0`. Extended keys are prefixed with `0xE0`,
+//! so an extended release is `0xE0 0xF0 <code>`.
+//! In both sets, Pause/Break sends a fixed sequence starting with `0xE1` on press, and nothing on
+//! release.
+
+/// The scancode sets we can decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// A physical key, named after its legend on a US keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// The extra key between left shift and Z on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftControl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightControl,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+    NumLock,
+    KeypadSlash,
+    KeypadStar,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+/// Whether a key went down or up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+/// Number of bytes in the Pause/Break sequence, including the leading `0xE1`.
+const PAUSE_LEN_SET1: usize = 6;
+const PAUSE_LEN_SET2: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    Start,
+    /// Seen `0xE0`.
+    Extended,
+    /// Seen `0xF0` (set 2 only).
+    Release,
+    /// Seen `0xE0 0xF0` (set 2 only).
+    ExtendedRelease,
+    /// Inside the Pause/Break sequence, having consumed this many bytes.
+    Pause(usize),
+}
+
+/// A state machine turning scancode bytes into key events.
+pub struct Decoder {
+    set: ScancodeSet,
+    state: DecodeState,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Decoder {
+            set: set,
+            state: DecodeState::Start,
+        }
+    }
+
+    /// Return the scancode set being decoded.
+    pub fn scancode_set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Change the scancode set being decoded, discarding any partial sequence.
+    pub fn set_scancode_set(&mut self, set: ScancodeSet) {
+        self.set = set;
+        self.state = DecodeState::Start;
+    }
+
+    /// Feed one byte from the keyboard. Returns a key event once a full sequence has been read.
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match self.set {
+            ScancodeSet::Set1 => self.feed_set1(byte),
+            ScancodeSet::Set2 => self.feed_set2(byte),
+        }
+    }
+
+    fn feed_set1(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        let state = if byte & 0x80 != 0 {
+            KeyState::Released
+        } else {
+            KeyState::Pressed
+        };
+
+        match self.state {
+            DecodeState::Start => match byte {
+                0xE0 => {
+                    self.state = DecodeState::Extended;
+                    None
+                }
+                0xE1 => {
+                    self.state = DecodeState::Pause(1);
+                    None
+                }
+                _ => set1_key(byte & 0x7f).map(|key| (key, state)),
+            },
+            DecodeState::Extended => {
+                self.state = DecodeState::Start;
+                set1_extended_key(byte & 0x7f).map(|key| (key, state))
+            }
+            DecodeState::Pause(n) => self.feed_pause(n, PAUSE_LEN_SET1),
+            _ => {
+                self.state = DecodeState::Start;
+                None
+            }
+        }
+    }
+
+    fn feed_set2(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match self.state {
+            DecodeState::Start => match byte {
+                0xE0 => {
+                    self.state = DecodeState::Extended;
+                    None
+                }
+                0xE1 => {
+                    self.state = DecodeState::Pause(1);
+                    None
+                }
+                0xF0 => {
+                    self.state = DecodeState::Release;
+                    None
+                }
+                _ => set2_key(byte).map(|key| (key, KeyState::Pressed)),
+            },
+            DecodeState::Release => {
+                self.state = DecodeState::Start;
+                set2_key(byte).map(|key| (key, KeyState::Released))
+            }
+            DecodeState::Extended => match byte {
+                0xF0 => {
+                    self.state = DecodeState::ExtendedRelease;
+                    None
+                }
+                _ => {
+                    self.state = DecodeState::Start;
+                    set2_extended_key(byte).map(|key| (key, KeyState::Pressed))
+                }
+            },
+            DecodeState::ExtendedRelease => {
+                self.state = DecodeState::Start;
+                set2_extended_key(byte).map(|key| (key, KeyState::Released))
+            }
+            DecodeState::Pause(n) => self.feed_pause(n, PAUSE_LEN_SET2),
+        }
+    }
+
+    /// Consume the rest of the Pause/Break sequence as a unit.
+    fn feed_pause(&mut self, consumed: usize, len: usize) -> Option<(KeyCode, KeyState)> {
+        if consumed + 1 == len {
+            self.state = DecodeState::Start;
+            Some((KeyCode::Pause, KeyState::Pressed))
+        } else {
+            self.state = DecodeState::Pause(consumed + 1);
+            None
+        }
+    }
+}
+
+/// Set 1 make codes, without a prefix.
+fn set1_key(code: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    let key = match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0A => Key9,
+        0x0B => Key0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftControl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4,
+        0x4C => Keypad5,
+        0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Set 1 make codes following an `0xE0` prefix. The fake shifts sent around Print Screen and the
+/// navigation keys are ignored.
+fn set1_extended_key(code: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    let key = match code {
+        0x1C => KeypadEnter,
+        0x1D => RightControl,
+        0x35 => KeypadSlash,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4B => Left,
+        0x4D => Right,
+        0x4F => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftGui,
+        0x5C => RightGui,
+        0x5D => Menu,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Set 2 make codes, without a prefix.
+fn set2_key(code: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    let key = match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftControl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Key7,
+        0x3E => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadStar,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Set 2 make codes following an `0xE0` prefix. The fake shifts sent around Print Screen and the
+/// navigation keys are ignored.
+fn set2_extended_key(code: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    let key = match code {
+        0x11 => RightAlt,
+        0x14 => RightControl,
+        0x1F => LeftGui,
+        0x27 => RightGui,
+        0x2F => Menu,
+        0x4A => KeypadSlash,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => Left,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    };
+
+    Some(key)
+}
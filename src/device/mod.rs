@@ -1,6 +1,9 @@
 #[macro_use]
 pub mod io;
+pub mod input;
+pub mod graphics;
 pub mod keyboard;
+pub mod mouse;
 pub mod ps2_8042;
 pub mod vga;
 pub mod pic;
@@ -9,16 +12,73 @@ pub mod ahci;
 pub mod pci;
 pub mod apic;
 pub mod serial;
+pub mod serial_command;
 
 pub use self::io::cpuio::{Port, UnsafePort};
 pub use self::io::mmio;
 
+use core::fmt;
 use raw_cpuid::CpuId;
 
+/// Write to COM1 and to the active console: the framebuffer console if there is one, otherwise the
+/// VGA text buffer. This is what `print!` calls.
+///
+/// The timer interrupt prints, so the locks are taken with interrupts disabled: otherwise it could
+/// interrupt a `print` holding them and spin on them forever.
+pub fn print(args: fmt::Arguments) {
+    use arch::interrupts::InterruptGuard;
+    use core::fmt::Write;
+
+    let _guard = InterruptGuard::new();
+    let _ = serial::COM1.lock().write_fmt(args);
+
+    if !graphics::console::print(args) {
+        vga::buffer::print(args);
+    }
+}
+
+/// Write `s` to COM1 and the VGA text buffer without taking any lock or allocating, so that it can
+/// be called from any context, even with the `print!` locks held by the code that was interrupted.
+/// Output can interleave with other output, so this is only for paths which are about to halt, and
+/// for NMIs. The text is not shown on a framebuffer console.
+pub fn emergency_print(s: &str) {
+    use core::fmt::Write;
+
+    let _ = serial::RawSerial.write_str(s);
+    vga::vga::emergency_write(s);
+}
+
+/// A writer for `emergency_println!`.
+pub struct EmergencyWriter;
+
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        emergency_print(s);
+        Ok(())
+    }
+}
+
+/// Rate of the APIC timer interrupt when it drives the scheduler.
+const APIC_TIMER_HZ: u32 = 100;
+
 /// Perform hardware init.
 pub unsafe fn init() {
     vga::init();
     pit::init();
+
+    // With `apic_timer` on the command line, the local APIC timer drives the scheduler instead of
+    // the PIT.
+    if ::arch::cmdline::flag("apic_timer") {
+        apic::init_timer(APIC_TIMER_HZ);
+        pit::stop();
+    }
+
+    // With `serial_echo` on the command line, serial input is echoed back to the terminal.
+    serial::set_echo(::arch::cmdline::flag("serial_echo"));
+
+    serial_command::init();
+
     ps2_8042::PS2.lock().init();
+    keyboard::init();
     pci::init();
 }
@@ -0,0 +1,77 @@
+//! PS/2 mouse packets. A standard mouse sends a 3-byte packet for every movement or button change:
+//! a flags byte holding the buttons and the sign bits, then the X and Y movement.
+//!
+//! `handle_byte` assembles packets and queues a `MouseEvent` for each one. The 8042's auxiliary
+//! port is not enabled yet; once it is, its IRQ handler should pass every byte it reads here.
+
+use device::io::EventQueue;
+use spin::Mutex;
+
+/// A single packet from the mouse. Positive `dy` is upwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: u8,
+}
+
+impl MouseEvent {
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+
+    /// Return true if this packet only reports movement, with no buttons held.
+    pub fn is_move(&self) -> bool {
+        self.buttons == 0
+    }
+}
+
+/// Mouse packets which have not been handled yet.
+pub static MOUSE_EVENTS: EventQueue<MouseEvent, [MouseEvent; 128]> = EventQueue::new();
+
+/// The packet being assembled, and how many bytes of it we have.
+static PACKET: Mutex<([u8; 3], usize)> = Mutex::new(([0; 3], 0));
+
+/// Bit 3 of the flags byte is always set, which lets us resynchronise if a byte is lost.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Add a byte received from the mouse to the current packet.
+pub fn handle_byte(byte: u8) {
+    let mut packet = PACKET.lock();
+
+    if packet.1 == 0 && byte & ALWAYS_ONE == 0 {
+        // Not the start of a packet, so drop it.
+        return;
+    }
+
+    let index = packet.1;
+    packet.0[index] = byte;
+    packet.1 += 1;
+
+    if packet.1 == 3 {
+        packet.1 = 0;
+
+        let flags = packet.0[0];
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return;
+        }
+
+        let extend = |value: u8, negative: bool| {
+            if negative {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+
+        MOUSE_EVENTS.push(MouseEvent {
+            dx: extend(packet.0[1], flags & X_SIGN != 0),
+            dy: extend(packet.0[2], flags & Y_SIGN != 0),
+            buttons: flags & 0x7,
+        });
+    }
+}
@@ -202,6 +202,13 @@ fn init_dev(bus: u8, dev: u8) {
     }
 }
 
+fn lspci(_args: &[&str]) -> i32 {
+    for device in DEVICES.lock().iter() {
+        println!("[ cmd ] {}", device);
+    }
+    0
+}
+
 fn init_bus(bus: u8) {
     for dev in 0..MAX_DEVICE {
         init_dev(bus, dev);
@@ -215,6 +222,8 @@ pub fn init() {
 
     println!("[ dev ] Discovered {} PCI devices.", DEVICES.lock().len());
 
+    ::shell::register("lspci", "List the PCI devices.", lspci).expect("lspci registered twice");
+
     for dev in DEVICES.lock().iter_mut() {
         // Check the type of device, in order to identify important stuff that we will use.
         match dev.class {
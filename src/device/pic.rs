@@ -1,8 +1,9 @@
-use spin::Mutex;
+use sync::{LockRank, RankedMutex};
 use device::Port;
 
 /// Global interface to the PIC.
-pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(0x20, 0x28) });
+pub static PICS: RankedMutex<ChainedPics> =
+    RankedMutex::new(LockRank::Pics, unsafe { ChainedPics::new(0x20, 0x28) });
 
 /// Command to begin init of the PIC chip.
 const CMD_INIT: u8 = 0x11;
@@ -1,10 +1,19 @@
 use device::Port;
+use core::cmp;
 use spin::Mutex;
-use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, ATOMIC_USIZE_INIT};
 
 /// Configuration data. Use channel 0 and mode 3, square wave generator. Use lohi operation.
 const PIT_SET: u8 = 0x36;
+/// Channel 0, lohi operation, mode 0 (interrupt on terminal count), which stops after one count.
+const PIT_ONE_SHOT: u8 = 0x30;
+/// Channel 2, lohi operation, mode 0 (interrupt on terminal count).
+const CHANNEL_2_ONE_SHOT: u8 = 0xb0;
 static DIVISOR: u16 = 2685;
+/// Input clock of the PIT in Hz.
+pub const PIT_FREQUENCY: u32 = 1193182;
+/// Longest wait which fits in one count of channel 2, in milliseconds.
+const MAX_WAIT_MS: u32 = 50;
 
 /// Simple interface to the PIT.
 pub static PIT: Mutex<[Port<u8>; 2]> = Mutex::new(unsafe { [Port::new(0x43), Port::new(0x40)] });
@@ -30,3 +39,41 @@ pub fn init() {
 }
 
 pub static PIT_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Stop channel 0 from interrupting periodically, for when another timer drives the scheduler. The
+/// channel is left in one-shot mode, so it raises at most one more interrupt.
+pub fn stop() {
+    let mut pit = PIT.lock();
+    pit[0].write(PIT_ONE_SHOT);
+    pit[1].write(0);
+    pit[1].write(0);
+}
+
+/// Busy-wait for `ms` milliseconds using channel 2, which is not connected to an interrupt and so
+/// does not disturb channel 0. This works with interrupts disabled.
+pub fn wait_ms(ms: u32) {
+    // Channel 2's gate and output are in bits 0 and 5 of port 0x61. Bit 1 drives the speaker.
+    let mut control: Port<u8> = unsafe { Port::new(0x61) };
+    let mut data: Port<u8> = unsafe { Port::new(0x42) };
+
+    let mut remaining = ms;
+    while remaining > 0 {
+        let chunk = cmp::min(remaining, MAX_WAIT_MS);
+        let count = PIT_FREQUENCY * chunk / 1000;
+
+        // Hold the gate low while programming, then raise it to start counting down.
+        let gate = control.read() & !0x03;
+        control.write(gate);
+        PIT.lock()[0].write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        control.write(gate | 0x01);
+
+        // The output goes high when the count reaches zero.
+        while control.read() & 0x20 == 0 {
+            spin_loop_hint();
+        }
+
+        remaining -= chunk;
+    }
+}
@@ -1,3 +1,4 @@
+use core::sync::atomic::spin_loop_hint;
 use spin::Mutex;
 use device::io::Port;
 
@@ -16,13 +17,17 @@ impl Ps2 {
 
     /// Poll bit 0 of status register: "Output buffer empty/full"
     pub fn wait_then_read(&mut self) -> u8 {
-        while self.controller.read() & 0x1 == 0 {}
+        while self.controller.read() & 0x1 == 0 {
+            spin_loop_hint();
+        }
         self.device.read()
     }
 
     /// Poll bit 1 of status register: "Input buffer empty/full"
     pub fn wait_then_write(&mut self, data: u8) {
-        while self.controller.read() & 0x2 == 1 {}
+        while self.controller.read() & 0x2 != 0 {
+            spin_loop_hint();
+        }
         self.device.write(data);
     }
 
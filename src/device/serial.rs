@@ -1,7 +1,11 @@
+use arch::interrupts::InterruptGuard;
 use device::io::cpuio::Port;
+use device::io::EventQueue;
 use self::Register::*;
-use spin::Mutex;
+use sync::{LockRank, RankedMutex};
 use core::fmt::{self, Write};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
 
 #[repr(C, u8)]
 #[allow(dead_code)]
@@ -17,10 +21,24 @@ enum Register {
     Scratch = 7,
 }
 
+/// Polls of the line status register made while waiting for the transmitter, before the bytes
+/// waiting to be sent are dropped. At the port's 57600 baud this is far longer than the FIFO takes
+/// to drain, so only a stuck UART hits it.
+const TRANSMIT_SPIN_LIMIT: usize = 100_000;
+
+/// Bytes dropped because the transmitter never became ready.
+static DROPPED_BYTES: AtomicUsize = ATOMIC_USIZE_INIT;
+
 /// An interface to a serial port.
 pub struct SerialPort {
     base: u16,
     is_initialized: bool,
+    /// Bytes which can be written at once when the transmitter is empty: 16 for a UART with a
+    /// working FIFO, otherwise 1.
+    fifo_size: usize,
+    /// Set when the last wait for the transmitter timed out, so that a stuck UART costs one
+    /// timeout rather than one per byte.
+    stalled: bool,
 }
 
 impl SerialPort {
@@ -28,6 +46,8 @@ impl SerialPort {
         SerialPort {
             base: base,
             is_initialized: false,
+            fifo_size: 1,
+            stalled: false,
         }
     }
 
@@ -49,7 +69,12 @@ impl SerialPort {
         self.port(LineControl).write(0x03);
         self.port(InterruptIdentAndFifo).write(0xc7);
         self.port(ModemControl).write(0x0b);
-        // Done!
+
+        // Both FIFO enabled bits are set only on a 16550A or later, the first with a FIFO that
+        // works.
+        if self.port(InterruptIdentAndFifo).read() & 0xc0 == 0xc0 {
+            self.fifo_size = 16;
+        }
     }
 
     /// Check if it is safe to read from this port.
@@ -59,21 +84,68 @@ impl SerialPort {
 
     /// Wait until we can get a hold on the data register, and then read from the serial port.
     pub fn read(&mut self) -> u8 {
-        while self.can_read() {}
+        while self.can_read() {
+            spin_loop_hint();
+        }
 
         self.port(DataOrBaudLsb).read()
     }
 
-    /// Check if we can safely write the data to the serial port.
+    /// Read a byte if one has been received, without waiting.
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.can_read() {
+            None
+        } else {
+            Some(self.port(DataOrBaudLsb).read())
+        }
+    }
+
+    /// Check whether the transmit holding register is empty, which with the FIFO enabled means the
+    /// whole FIFO is.
     fn is_transmit_empty(&mut self) -> bool {
-        (self.port(LineStatus).read() & 0x20) == 0
+        (self.port(LineStatus).read() & 0x20) != 0
+    }
+
+    /// Wait until the transmitter is empty. Returns false if it does not empty within
+    /// `TRANSMIT_SPIN_LIMIT` polls, or at once if the previous wait already timed out, so that this
+    /// is safe to use from an interrupt handler.
+    fn wait_for_transmit(&mut self) -> bool {
+        let limit = if self.stalled { 1 } else { TRANSMIT_SPIN_LIMIT };
+
+        for _ in 0..limit {
+            if self.is_transmit_empty() {
+                self.stalled = false;
+                return true;
+            }
+            spin_loop_hint();
+        }
+
+        self.stalled = true;
+        false
     }
 
-    /// Wait until we can get a hold on the data register, and then write to the serial port.
+    /// Wait until we can get a hold on the data register, and then write to the serial port. The
+    /// byte is dropped and counted if the transmitter is stuck.
     pub fn write(&mut self, data: u8) {
-        while self.is_transmit_empty() {}
+        if self.wait_for_transmit() {
+            self.port(DataOrBaudLsb).write(data);
+        } else {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-        self.port(DataOrBaudLsb).write(data);
+    /// Write `bytes`, filling the whole FIFO each time it empties instead of waiting for each byte.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(self.fifo_size) {
+            if !self.wait_for_transmit() {
+                DROPPED_BYTES.fetch_add(chunk.len(), Ordering::Relaxed);
+                continue;
+            }
+
+            for &byte in chunk {
+                self.port(DataOrBaudLsb).write(byte);
+            }
+        }
     }
 
     fn port(&mut self, register: Register) -> Port<u8> {
@@ -83,15 +155,105 @@ impl SerialPort {
 
 impl Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            self.write(byte);
-        }
-
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
-pub static COM1: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(0x3f8) });
+/// Return the number of bytes dropped on any serial port because its transmitter was stuck.
+pub fn dropped_bytes() -> usize {
+    DROPPED_BYTES.load(Ordering::Relaxed)
+}
+
+pub static COM1: RankedMutex<SerialPort> =
+    RankedMutex::new(LockRank::Serial, unsafe { SerialPort::new(0x3f8) });
+
+/// Access to COM1 without taking the `COM1` lock, for code which may run while the lock is held,
+/// such as the debugger. Output can interleave with output written through `COM1`.
+pub struct RawSerial;
+
+impl RawSerial {
+    fn port() -> SerialPort {
+        unsafe { SerialPort::new(0x3f8) }
+    }
+
+    /// Read a byte if one has been received, without waiting.
+    pub fn try_read(&mut self) -> Option<u8> {
+        RawSerial::port().try_read()
+    }
+}
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        RawSerial::port().write_str(s)
+    }
+}
+
+/// Bytes received on COM1 which have not been handled yet.
+pub static SERIAL_INPUT: EventQueue<u8, [u8; 256]> = EventQueue::new();
+
+/// Whether input is echoed back to the remote terminal.
+static ECHO: AtomicBool = ATOMIC_BOOL_INIT;
+/// Whether the last input byte was `\r`, so that the `\n` of a CRLF pair can be dropped.
+static LAST_WAS_CR: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Turn echoing of serial input on or off. With echo on, each input byte is written back out of
+/// COM1 so that the remote terminal shows what was typed. With it off, input is only queued, for
+/// programs which handle their own output.
+pub fn set_echo(echo: bool) {
+    ECHO.store(echo, Ordering::SeqCst);
+}
+
+/// Queue a byte of ordinary input in `SERIAL_INPUT`, echoing it if echo is on. Enter may arrive
+/// as `\r`, `\n` or both, and is always queued as a single `\n`. COM1 must not be locked.
+pub fn queue_input(byte: u8) {
+    let last_was_cr = LAST_WAS_CR.swap(byte == b'\r', Ordering::SeqCst);
+
+    let byte = match byte {
+        b'\n' if last_was_cr => return,
+        b'\r' => b'\n',
+        byte => byte,
+    };
+
+    if ECHO.load(Ordering::SeqCst) {
+        let _guard = InterruptGuard::new();
+        let mut com1 = COM1.lock();
+        match byte {
+            b'\n' => {
+                com1.write(b'\r');
+                com1.write(b'\n');
+            }
+            // Step back, overwrite the character with a space, and step back again.
+            0x8 | 0x7f => for &b in b"\x08 \x08" {
+                com1.write(b);
+            },
+            byte => com1.write(byte),
+        }
+    }
+
+    SERIAL_INPUT.push(byte);
+}
+
+/// Move any bytes received on COM1 into `SERIAL_INPUT`, running any commands among them (see
+/// `serial_command`). COM1 is set up without interrupts, so this has to be polled.
+pub fn poll() {
+    use device::serial_command;
+
+    loop {
+        // Commands print, so COM1 must not be held while they run. Nor may it be held with
+        // interrupts enabled, since the timer interrupt prints.
+        let byte = {
+            let _guard = InterruptGuard::new();
+            COM1.lock().try_read()
+        };
+        let byte = match byte {
+            Some(byte) => byte,
+            None => return,
+        };
+
+        serial_command::receive(byte);
+    }
+}
 
 pub fn init() {
     COM1.lock().do_init();
@@ -0,0 +1,208 @@
+//! A command protocol on COM1, so that a script on the host can drive the kernel without a
+//! keyboard.
+//!
+//! A command is a line which starts with the DLE control byte (0x10) and ends with `\n` or `\r`.
+//! Every other byte is ordinary serial input. A literal DLE is sent as two DLE bytes. Commands:
+//!
+//! - `key <byte> ...`: queue the given scancode bytes, written in hex, as if they came from the
+//!   keyboard. They are decoded in the keyboard's scancode set, so in set 1 `key 1e 9e` presses
+//!   and releases `A`.
+//! - `tasks`: print the PID, name and state of every process.
+//! - `stats`: print the scheduler's counters of resched calls and context switches.
+//! - `shutdown`: exit QEMU with a success status, or halt on real hardware.
+//! - `echo on|off`: turn echoing of serial input back to the terminal on or off.
+//! - `help`: list every command registered with the shell, including those of other subsystems.
+//!
+//! Command lines are run by `shell::run`, so any command registered with the shell can be used.
+//! Replies start with `[ cmd ]`. A line longer than `MAX_LINE` bytes is rejected.
+
+use core::str;
+use device::keyboard::SCANCODES;
+use device::serial;
+use shell::{self, Command};
+use spin::Mutex;
+use task::SCHEDULER;
+
+/// The byte which starts a command.
+pub const DLE: u8 = 0x10;
+/// Longest command line in bytes.
+const MAX_LINE: usize = 64;
+
+struct Parser {
+    /// Whether the last byte was a DLE which has not been handled yet.
+    escape: bool,
+    /// Whether a command line is being read.
+    in_command: bool,
+    line: [u8; MAX_LINE],
+    len: usize,
+    /// Set when the line did not fit, so that it is rejected once it ends.
+    overflowed: bool,
+}
+
+static PARSER: Mutex<Parser> = Mutex::new(Parser {
+    escape: false,
+    in_command: false,
+    line: [0; MAX_LINE],
+    len: 0,
+    overflowed: false,
+});
+
+/// Handle a byte received on COM1: pass it on to `serial::queue_input` as input, or collect it as
+/// part of a command and run the command once its line is complete. COM1 must not be locked,
+/// since commands print their replies and input may be echoed.
+pub fn receive(byte: u8) {
+    let command = {
+        let mut parser = PARSER.lock();
+        parser.feed(byte)
+    };
+
+    if let Some((line, len)) = command {
+        match str::from_utf8(&line[..len]) {
+            Ok(line) => run(line.trim()),
+            Err(_) => println!("[ cmd ] Command is not valid UTF-8."),
+        }
+    }
+}
+
+impl Parser {
+    /// Take one byte. Returns a completed command line.
+    fn feed(&mut self, byte: u8) -> Option<([u8; MAX_LINE], usize)> {
+        if self.in_command {
+            return self.feed_command(byte);
+        }
+
+        if self.escape {
+            self.escape = false;
+
+            if byte == DLE {
+                serial::queue_input(DLE);
+                return None;
+            }
+
+            self.in_command = true;
+            return self.feed_command(byte);
+        }
+
+        if byte == DLE {
+            self.escape = true;
+        } else {
+            serial::queue_input(byte);
+        }
+
+        None
+    }
+
+    fn feed_command(&mut self, byte: u8) -> Option<([u8; MAX_LINE], usize)> {
+        if byte != b'\n' && byte != b'\r' {
+            if self.len < MAX_LINE {
+                self.line[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflowed = true;
+            }
+
+            return None;
+        }
+
+        let command = (self.line, self.len);
+        let overflowed = self.overflowed;
+
+        self.in_command = false;
+        self.len = 0;
+        self.overflowed = false;
+
+        if overflowed {
+            println!("[ cmd ] Command is longer than {} bytes.", MAX_LINE);
+            None
+        } else {
+            Some(command)
+        }
+    }
+}
+
+/// Register the commands which drive the kernel from the host. This must be called after
+/// `shell::init`.
+pub fn init() {
+    let commands: [(&'static str, &'static str, Command); 5] = [
+        ("key", "Queue scancodes, in hex, as if typed.", key),
+        ("tasks", "List the processes.", tasks),
+        ("stats", "Print the scheduler's counters.", stats),
+        ("shutdown", "Exit QEMU, or halt on real hardware.", shutdown),
+        ("echo", "Turn echoing of serial input on or off.", echo),
+    ];
+
+    for &(name, description, command) in commands.iter() {
+        shell::register(name, description, command).expect("serial command registered twice");
+    }
+}
+
+/// Run a command line through the shell, reporting failures.
+fn run(line: &str) {
+    match shell::run(line) {
+        Some(0) => (),
+        Some(code) => println!("[ cmd ] Command failed with exit code {}.", code),
+        None => match line.split_whitespace().next() {
+            Some(command) => println!("[ cmd ] Unknown command: {}", command),
+            None => (),
+        },
+    }
+}
+
+fn key(args: &[&str]) -> i32 {
+    for word in args {
+        match u8::from_str_radix(word, 16) {
+            Ok(scancode) => if !SCANCODES.push(scancode) {
+                println!("[ cmd ] Keyboard queue is full.");
+                return 1;
+            },
+            Err(_) => {
+                println!("[ cmd ] Bad scancode: {}", word);
+                return shell::EXIT_USAGE;
+            }
+        }
+    }
+    0
+}
+
+fn tasks(_args: &[&str]) -> i32 {
+    SCHEDULER.print_tasks();
+    0
+}
+
+fn stats(_args: &[&str]) -> i32 {
+    let stats = SCHEDULER.stats();
+    println!(
+        "[ cmd ] {} resched calls, {} switches, {} timed.",
+        stats.resched_calls, stats.switches, stats.timed_switches
+    );
+    match stats.average_switch_cycles() {
+        Some(cycles) => println!("[ cmd ] {} cycles per switch.", cycles),
+        None => println!("[ cmd ] No switches timed yet."),
+    }
+    0
+}
+
+fn shutdown(_args: &[&str]) -> i32 {
+    use arch::platform;
+    use testing::{exit_qemu, QemuExitCode};
+
+    if platform::is_qemu() {
+        println!("[ cmd ] Shutting down.");
+    } else {
+        // There is no ACPI power off yet, so the most we can do is stop.
+        println!("[ cmd ] Cannot power off outside QEMU, halting.");
+    }
+    exit_qemu(QemuExitCode::Success)
+}
+
+fn echo(args: &[&str]) -> i32 {
+    match args.first() {
+        Some(&"on") => serial::set_echo(true),
+        Some(&"off") => serial::set_echo(false),
+        _ => {
+            println!("[ cmd ] Usage: echo on|off");
+            return shell::EXIT_USAGE;
+        }
+    }
+    0
+}
@@ -1,8 +1,10 @@
 use spin::Mutex;
+use sync::{LockRank, RankedMutex};
 use device::vga::vga::{Color, ColorCode, VGA};
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-/// Main print interface.
+/// Write to the VGA text buffer. Used by `print!` when there is no framebuffer console.
 pub fn print(args: fmt::Arguments) {
     use core::fmt::Write;
     SCREEN.lock().write_fmt(args).unwrap();
@@ -10,14 +12,47 @@ pub fn print(args: fmt::Arguments) {
 
 /// The width of the VGA text buffer.
 pub const BUFFER_WIDTH: usize = 80;
-/// The height of the VGA text buffer.
-pub const BUFFER_HEIGHT: usize = 25;
+/// The number of rows in the standard 80x25 text mode, which the BIOS leaves us in.
+pub const STANDARD_HEIGHT: usize = 25;
+/// The most rows the VGA text buffer can have, in the 80x50 mode. Buffers are always this big, and
+/// only the first `height()` rows are shown.
+pub const MAX_BUFFER_HEIGHT: usize = 50;
+
+/// The number of rows on screen in the current text mode, or zero until the mode is first changed.
+static HEIGHT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Return the number of rows on screen in the current text mode.
+pub fn height() -> usize {
+    match HEIGHT.load(Ordering::SeqCst) {
+        0 => STANDARD_HEIGHT,
+        height => height,
+    }
+}
+
+/// Change the number of rows on screen, clearing every TTY so that nothing is left over from the
+/// old layout. This only changes the buffers; `vga::set_mode` reprograms the hardware.
+pub fn set_height(height: usize) {
+    assert!(height > 0 && height <= MAX_BUFFER_HEIGHT);
+    HEIGHT.store(height, Ordering::SeqCst);
+
+    if let Some(ref mut ttys) = *TTYS.lock() {
+        for tty in ttys.iter_mut() {
+            tty.clear();
+        }
+    }
+
+    let mut screen = SCREEN.lock();
+    screen.clear();
+    if screen.active {
+        screen.sync();
+    }
+}
 
 #[derive(Copy, Clone)]
 /// A virtual text buffer.
 pub struct TextBuffer {
     /// Array of rows of characters.
-    pub chars: [[u8; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    pub chars: [[u8; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
     /// How far along a row we are.
     pub column_position: usize,
     /// Represents the colour of the TTY buffer.
@@ -27,7 +62,7 @@ pub struct TextBuffer {
 
 /// Clear the VGA buffer.
 pub fn clear_screen() {
-    for _row in 0..BUFFER_HEIGHT {
+    for _row in 0..height() {
         SCREEN.lock().new_line();
     }
 }
@@ -37,14 +72,22 @@ impl TextBuffer {
     fn sync(&self) {
         VGA.lock().sync_buffer(&self);
         VGA.lock()
-            .update_cursor(BUFFER_HEIGHT - 1, self.column_position);
+            .update_cursor(height() - 1, self.column_position);
     }
 
     /// Return the current character array.
-    pub fn chars(&self) -> &[[u8; BUFFER_WIDTH]; BUFFER_HEIGHT] {
+    pub fn chars(&self) -> &[[u8; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT] {
         &self.chars
     }
 
+    /// Blank every row and move to the start of the line.
+    pub fn clear(&mut self) {
+        for row in 0..MAX_BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+
     /// Return the current colour code.
     pub fn color_code(&self) -> ColorCode {
         self.color_code
@@ -68,7 +111,7 @@ impl TextBuffer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = height() - 1;
                 let col = self.column_position;
                 self.chars[row][col] = byte;
                 self.column_position += 1;
@@ -89,7 +132,7 @@ impl TextBuffer {
 
         let col = self.column_position - 1;
 
-        self.chars[BUFFER_HEIGHT - 1][col] = b' ';
+        self.chars[height() - 1][col] = b' ';
         self.column_position -= 1;
 
         if self.active {
@@ -100,13 +143,15 @@ impl TextBuffer {
     /// Newline. This method will be called when a `\n` character is written
     /// to the virtual buffer.
     pub fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        let height = height();
+
+        for row in 1..height {
             for col in 0..BUFFER_WIDTH {
                 self.chars[row - 1][col] = self.chars[row][col]
             }
         }
 
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.clear_row(height - 1);
         //Set position to start of row.
         self.column_position = 0;
 
@@ -135,12 +180,15 @@ impl ::core::fmt::Write for TextBuffer {
 }
 
 /// Global interface to the VGA text mode.
-pub static SCREEN: Mutex<TextBuffer> = Mutex::new(TextBuffer {
-    column_position: 0,
-    color_code: ColorCode::new(Color::LightGray, Color::Black),
-    chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
-    active: true,
-});
+pub static SCREEN: RankedMutex<TextBuffer> = RankedMutex::new(
+    LockRank::Screen,
+    TextBuffer {
+        column_position: 0,
+        color_code: ColorCode::new(Color::LightGray, Color::Black),
+        chars: [[b' '; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+        active: true,
+    },
+);
 
 pub static TTYS: Mutex<Option<[TextBuffer; 6]>> = Mutex::new(None);
 
@@ -168,7 +216,7 @@ pub fn tty_init() {
     let buffers: [TextBuffer; 6] = [TextBuffer {
         column_position: 0,
         color_code: ColorCode::new(Color::LightGray, Color::Black),
-        chars: [[b' '; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        chars: [[b' '; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
         active: false,
     }; 6];
 
@@ -1,6 +1,63 @@
 pub mod buffer;
 pub mod vga;
 
+/// A VGA text mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 80 columns by 25 rows, with 16 scanline characters. This is the mode the BIOS sets.
+    Text80x25,
+    /// 80 columns by 50 rows, with 8 scanline characters.
+    Text80x50,
+}
+
 pub fn init() {
     self::buffer::tty_init();
+
+    // With `vga_rows=50` on the command line, use the taller text mode.
+    if ::arch::cmdline::option("vga_rows") == Some("50") {
+        if let Err(e) = set_mode(Mode::Text80x50) {
+            println!("[ vga ] Could not switch to 80x50: {}.", e);
+        }
+    }
+
+    ::shell::register("vgamode", "Switch the VGA text mode: vgamode 25|50.", vgamode)
+        .expect("vgamode registered twice");
+}
+
+/// Switch the VGA text mode. Every TTY is cleared, since its rows no longer line up with the
+/// screen. Fails if the console is on a framebuffer rather than in VGA text mode.
+pub fn set_mode(mode: Mode) -> Result<(), &'static str> {
+    use device::graphics::console;
+
+    if console::is_active() {
+        return Err("the console is not in VGA text mode");
+    }
+
+    let (scanlines, rows) = match mode {
+        Mode::Text80x25 => (16, buffer::STANDARD_HEIGHT),
+        Mode::Text80x50 => (8, buffer::MAX_BUFFER_HEIGHT),
+    };
+
+    vga::VGA.lock().set_char_height(scanlines);
+    buffer::set_height(rows);
+    Ok(())
+}
+
+fn vgamode(args: &[&str]) -> i32 {
+    let mode = match args.first() {
+        Some(&"25") => Mode::Text80x25,
+        Some(&"50") => Mode::Text80x50,
+        _ => {
+            println!("[ cmd ] Usage: vgamode 25|50");
+            return ::shell::EXIT_USAGE;
+        }
+    };
+
+    match set_mode(mode) {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("[ cmd ] Could not switch mode: {}.", e);
+            1
+        }
+    }
 }
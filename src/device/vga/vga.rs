@@ -1,12 +1,16 @@
 //! VGA - Interface to the VGA text buffer at physical address 0xb8000.
 
-use device::vga::buffer::{TextBuffer, BUFFER_HEIGHT, BUFFER_WIDTH};
-use core::ptr::Unique;
+use arch::memory::Frame;
+use arch::memory::paging::{ActivePageTable, EntryFlags, Page, PhysicalAddress, VirtualAddress};
+use device::Port;
+use device::vga::buffer::{self, TextBuffer, BUFFER_WIDTH, MAX_BUFFER_HEIGHT};
+use core::ptr::{self, Unique};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use spin::Mutex;
 use volatile::Volatile;
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// The possible colours that characters on the VGA buffer can be.
 pub enum Color {
     Black = 0,
@@ -49,9 +53,102 @@ pub struct ScreenChar {
     pub color_code: ColorCode,
 }
 
-/// A 2D array of `ScreenChar`s, with 80*25 elements.
+/// A 2D array of `ScreenChar`s, big enough for the 80x50 mode.
 struct ScreenBuffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+}
+
+// Sequencer, graphics controller and CRT controller index ports. The data port of each is the
+// next port up.
+const SEQUENCER: u16 = 0x3c4;
+const GRAPHICS_CONTROLLER: u16 = 0x3ce;
+const CRT_CONTROLLER: u16 = 0x3d4;
+
+/// Where plane 2, which holds the fonts, appears while `with_font_plane` has it mapped in.
+const FONT_MEMORY: usize = 0xa0000;
+/// Offset in plane 2 of the font bank used for the 8 scanline font. Bank 0 holds the BIOS's 16
+/// scanline font, which is kept for switching back.
+const SHORT_FONT_OFFSET: usize = 0x4000;
+/// Size of a font bank: 256 glyphs, each given 32 bytes however many scanlines it uses.
+const FONT_BANK_SIZE: usize = 256 * GLYPH_STRIDE;
+const GLYPH_STRIDE: usize = 32;
+
+/// Read register `index` of the VGA register group at `base`.
+unsafe fn read_register(base: u16, index: u8) -> u8 {
+    Port::<u8>::new(base).write(index);
+    Port::<u8>::new(base + 1).read()
+}
+
+/// Write register `index` of the VGA register group at `base`.
+unsafe fn write_register(base: u16, index: u8, value: u8) {
+    Port::<u8>::new(base).write(index);
+    Port::<u8>::new(base + 1).write(value);
+}
+
+/// Run `f` with plane 2 mapped linearly at `FONT_MEMORY`, then put text mode addressing back.
+unsafe fn with_font_plane<F: FnOnce()>(f: F) {
+    // Only plane 2, with sequential rather than odd/even addressing.
+    write_register(SEQUENCER, 0x02, 0x04);
+    write_register(SEQUENCER, 0x04, 0x07);
+    // Read plane 2, in read mode 0, mapped at 0xa0000.
+    write_register(GRAPHICS_CONTROLLER, 0x04, 0x02);
+    write_register(GRAPHICS_CONTROLLER, 0x05, 0x00);
+    write_register(GRAPHICS_CONTROLLER, 0x06, 0x04);
+
+    f();
+
+    // Back to text mode: planes 0 and 1 with odd/even addressing, mapped at 0xb8000.
+    write_register(SEQUENCER, 0x02, 0x03);
+    write_register(SEQUENCER, 0x04, 0x02);
+    write_register(GRAPHICS_CONTROLLER, 0x04, 0x00);
+    write_register(GRAPHICS_CONTROLLER, 0x05, 0x10);
+    write_register(GRAPHICS_CONTROLLER, 0x06, 0x0e);
+}
+
+/// Build the 8 scanline font in bank 1 from every other scanline of the BIOS font in bank 0.
+fn build_short_font() {
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    // Font memory is only mapped while it is being copied.
+    let regions = [FONT_MEMORY, FONT_MEMORY + SHORT_FONT_OFFSET];
+    let frames = || {
+        regions.iter().flat_map(|&start| {
+            Frame::range_inclusive(
+                Frame::containing_address(PhysicalAddress::new(start)),
+                Frame::containing_address(PhysicalAddress::new(start + FONT_BANK_SIZE - 1)),
+            )
+        })
+    };
+
+    for frame in frames() {
+        active_table.identity_map(frame, EntryFlags::mmio()).flush(&mut active_table);
+    }
+
+    unsafe {
+        with_font_plane(|| {
+            let font = FONT_MEMORY as *mut u8;
+            let short_font = (FONT_MEMORY + SHORT_FONT_OFFSET) as *mut u8;
+
+            for glyph in 0..256 {
+                let start = (glyph * GLYPH_STRIDE) as isize;
+
+                for line in 0..GLYPH_STRIDE as isize {
+                    let value = if line < 8 {
+                        ptr::read_volatile(font.offset(start + line * 2))
+                    } else {
+                        0
+                    };
+                    ptr::write_volatile(short_font.offset(start + line), value);
+                }
+            }
+        });
+    }
+
+    for frame in frames() {
+        let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
+        let (result, _frame) = active_table.unmap(page);
+        result.flush(&mut active_table);
+    }
 }
 
 /// Interface to the VGA buffer.
@@ -60,6 +157,53 @@ pub struct Vga {
     frame: Unique<ScreenBuffer>,
 }
 
+/// Column of the bottom row that `emergency_write` writes to next.
+static EMERGENCY_COLUMN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Write `s` to the bottom of the screen in white on red, straight to VGA memory and without taking
+/// any lock, scrolling the screen up on each new line. This is for `device::emergency_print` only:
+/// anything else drawing at the same time can garble the output, and the next sync of a text
+/// buffer overwrites it.
+pub fn emergency_write(s: &str) {
+    let frame = 0xb8000 as *mut ScreenChar;
+    let color_code = ColorCode::new(Color::White, Color::Red);
+    let height = buffer::height();
+    let blank = ScreenChar {
+        ascii_character: b' ',
+        color_code: color_code,
+    };
+
+    for byte in s.bytes() {
+        let mut column = EMERGENCY_COLUMN.load(Ordering::SeqCst);
+
+        if byte == b'\n' || column >= BUFFER_WIDTH {
+            unsafe {
+                for cell in 0..(height - 1) * BUFFER_WIDTH {
+                    let below = ptr::read_volatile(frame.offset((cell + BUFFER_WIDTH) as isize));
+                    ptr::write_volatile(frame.offset(cell as isize), below);
+                }
+                for col in 0..BUFFER_WIDTH {
+                    let cell = (height - 1) * BUFFER_WIDTH + col;
+                    ptr::write_volatile(frame.offset(cell as isize), blank);
+                }
+            }
+            column = 0;
+        }
+
+        if byte != b'\n' {
+            let character = ScreenChar {
+                ascii_character: byte,
+                color_code: color_code,
+            };
+            let cell = (height - 1) * BUFFER_WIDTH + column;
+            unsafe { ptr::write_volatile(frame.offset(cell as isize), character) };
+            column += 1;
+        }
+
+        EMERGENCY_COLUMN.store(column, Ordering::SeqCst);
+    }
+}
+
 /// Static VGA interface. We cast the base address `0xb8000` of VGA memory to a `ScreenBuffer`
 /// struct, which makes it useful to us.
 pub static VGA: Mutex<Vga> = Mutex::new(Vga {
@@ -76,7 +220,7 @@ impl Vga {
     pub fn sync_buffer(&mut self, buffer: &TextBuffer) {
         let frame = self.frame();
 
-        for row in 0..BUFFER_HEIGHT {
+        for row in 0..buffer::height() {
             for col in 0..BUFFER_WIDTH {
                 // Update using the text buffer.
                 let character = ScreenChar {
@@ -89,11 +233,36 @@ impl Vga {
         }
     }
 
+    /// Make each character cell `scanlines` high, 8 or 16, and the cursor the bottom two lines of
+    /// it. The screen stays 400 lines high, so this gives 50 or 25 rows.
+    pub fn set_char_height(&mut self, scanlines: u8) {
+        assert!(scanlines == 8 || scanlines == 16);
+
+        if scanlines == 8 {
+            build_short_font();
+        }
+
+        unsafe {
+            // Character map select: both maps from bank 1 for the short font, else bank 0.
+            write_register(SEQUENCER, 0x03, if scanlines == 8 { 0x05 } else { 0x00 });
+
+            // Maximum scan line, then cursor start and end, each in the low 5 bits.
+            for &(index, value) in [
+                (0x09, scanlines - 1),
+                (0x0a, scanlines - 2),
+                (0x0b, scanlines - 1),
+            ].iter()
+            {
+                let old = read_register(CRT_CONTROLLER, index);
+                write_register(CRT_CONTROLLER, index, (old & 0xe0) | value);
+            }
+        }
+    }
+
     #[allow(exceeding_bitshifts)]
     /// Update the text mode cursor to coordinates (row, col).
     pub fn update_cursor(&self, row: usize, col: usize) {
         let pos = ((BUFFER_WIDTH as u16) * (row as u16)) + col as u16;
-        use device::Port;
 
         unsafe {
             let mut control_port: Port<u8> = Port::new(0x3D4);
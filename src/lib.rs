@@ -11,6 +11,7 @@
 #![feature(global_allocator)]
 #![feature(ptr_internals)]
 #![feature(integer_atomics)]
+#![feature(unsize)]
 #![no_std]
 
 #[macro_use]
@@ -33,11 +34,16 @@ extern crate heapless;
 
 #[macro_use]
 mod macros;
+#[macro_use]
+pub mod testing;
 pub mod device;
 pub mod task;
 pub mod syscall;
 pub mod arch;
 pub mod acpi;
+pub mod shell;
+pub mod smbios;
+pub mod sync;
 mod runtime_glue;
 
 pub use runtime_glue::*;
@@ -46,7 +52,27 @@ pub use runtime_glue::*;
 pub extern "C" fn kmain(multiboot_information_address: usize) {
     unsafe { arch::init(multiboot_information_address) };
 
-    loop {}
+    #[cfg(feature = "kernel-test")]
+    testing::run_tests(testing::tests::TESTS);
+
+    loop {
+        use device::graphics::console;
+        use device::input::{poll_input, InputEvent};
+        use device::keyboard::print_char;
+        use task::SCHEDULER;
+
+        match poll_input() {
+            Some(InputEvent::Key(key)) => if !console::handle_key(&key) {
+                if let Some(c) = key.character {
+                    print_char(c);
+                }
+            },
+            Some(InputEvent::Serial(byte)) => print_char(byte as char),
+            // Nothing to handle until the next interrupt, unless a process is ready to run.
+            None => SCHEDULER.idle(),
+            _ => (),
+        }
+    }
 }
 
 // TODO: Move this to the memory module once some bugs with Rust get figured out.
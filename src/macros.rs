@@ -1,9 +1,6 @@
 macro_rules! print {
     ($($arg:tt)*) => ({
-        use device::serial;
-        use core::fmt::Write;
-
-        let _ = write!(serial::COM1.lock(), $($arg)*);
+        ::device::print(format_args!($($arg)*));
     });
 }
 
@@ -12,6 +9,19 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Like `println!`, but through `device::emergency_print`, so that it works while other code holds
+/// the console locks.
+macro_rules! emergency_println {
+    ($fmt:expr) => ({
+        use core::fmt::Write;
+        let _ = write!(::device::EmergencyWriter, concat!($fmt, "\n"));
+    });
+    ($fmt:expr, $($arg:tt)*) => ({
+        use core::fmt::Write;
+        let _ = write!(::device::EmergencyWriter, concat!($fmt, "\n"), $($arg)*);
+    });
+}
+
 macro_rules! format {
     ($($arg:tt)*) => ({
         use alloc::string::String;
@@ -1,4 +1,6 @@
+use arch::interrupts::halt_forever;
 use core;
+use device::serial::RawSerial;
 
 #[cfg(not(test))]
 #[lang = "eh_personality"]
@@ -9,13 +11,20 @@ pub extern "C" fn eh_personality() {}
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
-    println!("\n\nPANIC in {} at line {}:", file, line);
-    println!("    {}", fmt);
-    loop {}
+    // The panic may have happened with the console or serial lock held.
+    emergency_println!("\n\nPANIC in {} at line {}:", file, line);
+    emergency_println!("    {}", fmt);
+
+    let _ = ::arch::backtrace::write(&mut RawSerial);
+
+    #[cfg(feature = "kernel-test")]
+    ::testing::test_panicked(fmt, file, line);
+
+    halt_forever()
 }
 
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn _Unwind_Resume() -> ! {
-    loop {}
+    halt_forever()
 }
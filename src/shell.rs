@@ -0,0 +1,96 @@
+//! The registry of shell commands. Each subsystem registers its own diagnostic commands from its
+//! init function, and the serial command protocol looks commands up here by name, so adding a
+//! command never means touching the shell.
+
+use alloc::Vec;
+use spin::RwLock;
+
+/// A command. It is given its arguments, without the command name, and returns an exit code,
+/// which is 0 on success.
+pub type Command = fn(&[&str]) -> i32;
+
+/// Exit code for a command used with the wrong arguments.
+pub const EXIT_USAGE: i32 = 2;
+
+struct Entry {
+    name: &'static str,
+    description: &'static str,
+    command: Command,
+}
+
+/// A table of commands, kept in the order they were registered so that `help` is stable.
+pub struct CommandRegistry {
+    entries: Vec<Entry>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        CommandRegistry {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add a command. Fails if a command with the same name is already registered.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        description: &'static str,
+        command: Command,
+    ) -> Result<(), &'static str> {
+        if self.find(name).is_some() {
+            return Err("command already registered");
+        }
+
+        self.entries.push(Entry {
+            name: name,
+            description: description,
+            command: command,
+        });
+        Ok(())
+    }
+
+    /// Return the command called `name`.
+    pub fn find(&self, name: &str) -> Option<Command> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.command)
+    }
+}
+
+lazy_static! {
+    static ref COMMANDS: RwLock<CommandRegistry> = RwLock::new(CommandRegistry::new());
+}
+
+/// Register the shell's own commands. This must be called after the heap is set up, and before
+/// any other subsystem registers commands.
+pub fn init() {
+    register("help", "List the available commands.", help).expect("help registered twice");
+}
+
+/// Add a command to the shell.
+pub fn register(
+    name: &'static str,
+    description: &'static str,
+    command: Command,
+) -> Result<(), &'static str> {
+    COMMANDS.write().register(name, description, command)
+}
+
+/// Run a command line. Words may be separated by any amount of whitespace. Returns the command's
+/// exit code, or `None` if there is no command of that name or the line is blank.
+pub fn run(line: &str) -> Option<i32> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = words.split_first()?;
+
+    // The registry is not locked while the command runs, so that it may register commands itself.
+    let command = COMMANDS.read().find(name)?;
+    Some(command(args))
+}
+
+fn help(_args: &[&str]) -> i32 {
+    for entry in COMMANDS.read().entries.iter() {
+        println!("[ cmd ] {:10} {}", entry.name, entry.description);
+    }
+    0
+}
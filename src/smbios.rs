@@ -0,0 +1,302 @@
+//! SMBIOS, which describes the machine we are running on.
+//!
+//! The entry point is found through the multiboot SMBIOS tag, or else by searching the BIOS area
+//! for the `_SM3_` (64-bit) or `_SM_` (32-bit) anchor on a 16-byte boundary. It gives the address
+//! and length of the structure table.
+//!
+//! Every structure starts with a 4-byte header giving its type and the length of its formatted
+//! area. The formatted area is followed by a set of NUL-terminated strings, ended by an extra NUL.
+//! Fields which hold strings store a 1-based index into this set, with 0 meaning no string.
+
+use alloc::String;
+use arch::memory::Frame;
+use arch::memory::paging::{ActivePageTable, Page, PhysicalAddress, VirtualAddress};
+use arch::memory::paging::entry::EntryFlags;
+use arch::multiboot::{self, TAG_SMBIOS};
+use core::{cmp, ptr, slice};
+use multiboot2::BootInformation;
+use spin::Once;
+
+/// The BIOS area searched for the entry point.
+const SEARCH_START: usize = 0xf0000;
+const SEARCH_END: usize = 0xfffff;
+
+/// Structure types.
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+const TYPE_END: u8 = 127;
+
+/// Basic facts about the machine, gathered from SMBIOS.
+#[derive(Debug, Clone, Default)]
+pub struct SystemInfo {
+    /// The SMBIOS version, as (major, minor).
+    pub version: (u8, u8),
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Installed memory in bytes, summed over the mapped address ranges.
+    pub memory_bytes: u64,
+}
+
+static SYSTEM_INFO: Once<SystemInfo> = Once::new();
+
+/// Where the structure table is, according to the entry point.
+struct EntryPoint {
+    version: (u8, u8),
+    table_address: usize,
+    table_len: usize,
+}
+
+/// Find and parse the SMBIOS tables. This must be called after the heap is set up.
+pub fn init(boot_info: &BootInformation, active_table: &mut ActivePageTable) {
+    let entry = match find_entry_point(boot_info, active_table) {
+        Some(entry) => entry,
+        None => {
+            println!("[ smbios ] No SMBIOS entry point found.");
+            return;
+        }
+    };
+
+    println!(
+        "[ smbios ] SMBIOS {}.{}, structure table at {:#x}, {} bytes.",
+        entry.version.0, entry.version.1, entry.table_address, entry.table_len
+    );
+
+    identity_map(entry.table_address, entry.table_len, active_table);
+    let table =
+        unsafe { slice::from_raw_parts(entry.table_address as *const u8, entry.table_len) };
+
+    let info = SYSTEM_INFO.call_once(|| parse_table(entry.version, table));
+    print_system_info(info);
+}
+
+/// Register the `sysinfo` command. `init` runs before the shell is set up, so this must be called
+/// separately, once it is.
+pub fn register_command() {
+    ::shell::register("sysinfo", "Show the machine and BIOS described by SMBIOS.", sysinfo)
+        .expect("sysinfo registered twice");
+}
+
+fn sysinfo(_args: &[&str]) -> i32 {
+    match system_info() {
+        Some(info) => {
+            print_system_info(info);
+            0
+        }
+        None => {
+            println!("[ smbios ] No SMBIOS entry point was found.");
+            1
+        }
+    }
+}
+
+fn print_system_info(info: &SystemInfo) {
+    println!(
+        "[ smbios ] {} {}, BIOS {} {}, {} MiB of memory.",
+        info.manufacturer.as_ref().map_or("unknown", |s| s.as_str()),
+        info.product.as_ref().map_or("unknown", |s| s.as_str()),
+        info.bios_vendor.as_ref().map_or("unknown", |s| s.as_str()),
+        info.bios_version.as_ref().map_or("unknown", |s| s.as_str()),
+        info.memory_bytes / (1024 * 1024)
+    );
+}
+
+/// Return what SMBIOS told us about the machine, if `init` found it.
+pub fn system_info() -> Option<&'static SystemInfo> {
+    SYSTEM_INFO.try()
+}
+
+fn find_entry_point(
+    boot_info: &BootInformation,
+    active_table: &mut ActivePageTable,
+) -> Option<EntryPoint> {
+    // The tag holds the SMBIOS version and 6 reserved bytes, then a copy of the entry point.
+    if let Some(tag) = multiboot::find_tag(boot_info, TAG_SMBIOS) {
+        if tag.data.len() > 8 {
+            if let Some(entry) = parse_entry_point(&tag.data[8..]) {
+                return Some(entry);
+            }
+        }
+    }
+
+    identity_map(SEARCH_START, SEARCH_END + 1 - SEARCH_START, active_table);
+
+    // Prefer the 64-bit entry point, which can describe a table above 4 GiB.
+    for anchor in &[&b"_SM3_"[..], &b"_SM_"[..]] {
+        for i in 0..(SEARCH_END + 1 - SEARCH_START) / 16 {
+            let address = SEARCH_START + i * 16;
+            let len = cmp::min(0x20, SEARCH_END + 1 - address);
+            let bytes = unsafe { slice::from_raw_parts(address as *const u8, len) };
+
+            if bytes.starts_with(anchor) {
+                if let Some(entry) = parse_entry_point(bytes) {
+                    return Some(entry);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Parse and checksum a 32-bit or 64-bit entry point.
+fn parse_entry_point(bytes: &[u8]) -> Option<EntryPoint> {
+    let (len_offset, is_64) = if bytes.starts_with(b"_SM3_") {
+        (0x06, true)
+    } else if bytes.starts_with(b"_SM_") {
+        (0x05, false)
+    } else {
+        return None;
+    };
+
+    let len = *bytes.get(len_offset)? as usize;
+    let minimum = if is_64 { 0x18 } else { 0x1f };
+    if len < minimum || len > bytes.len() || checksum(&bytes[..len]) != 0 {
+        return None;
+    }
+
+    let entry = if is_64 {
+        EntryPoint {
+            version: (bytes[0x07], bytes[0x08]),
+            table_address: read_u64(bytes, 0x10) as usize,
+            table_len: read_u32(bytes, 0x0c) as usize,
+        }
+    } else {
+        EntryPoint {
+            version: (bytes[0x06], bytes[0x07]),
+            table_address: read_u32(bytes, 0x18) as usize,
+            table_len: read_u16(bytes, 0x16) as usize,
+        }
+    };
+
+    Some(entry)
+}
+
+/// Walk the structure table, collecting the structures we know about.
+fn parse_table(version: (u8, u8), table: &[u8]) -> SystemInfo {
+    let mut info = SystemInfo {
+        version: version,
+        ..SystemInfo::default()
+    };
+
+    let mut offset = 0;
+    while offset + 4 <= table.len() {
+        let typ = table[offset];
+        let len = table[offset + 1] as usize;
+
+        if len < 4 || offset + len > table.len() {
+            break;
+        }
+
+        let formatted = &table[offset..offset + len];
+        let strings_end = match strings_end(table, offset + len) {
+            Some(end) => end,
+            None => break,
+        };
+        let strings = &table[offset + len..strings_end];
+
+        match typ {
+            TYPE_BIOS if len >= 0x06 => {
+                info.bios_vendor = string(strings, formatted[0x04]);
+                info.bios_version = string(strings, formatted[0x05]);
+            }
+            TYPE_SYSTEM if len >= 0x06 => {
+                info.manufacturer = string(strings, formatted[0x04]);
+                info.product = string(strings, formatted[0x05]);
+            }
+            TYPE_MEMORY_ARRAY_MAPPED_ADDRESS if len >= 0x0f => {
+                info.memory_bytes += mapped_range_size(formatted);
+            }
+            TYPE_END => break,
+            _ => (),
+        }
+
+        offset = strings_end;
+    }
+
+    info
+}
+
+/// Return the offset just past the double NUL ending the strings which start at `start`.
+fn strings_end(table: &[u8], start: usize) -> Option<usize> {
+    let mut i = start;
+
+    while i + 1 < table.len() {
+        if table[i] == 0 && table[i + 1] == 0 {
+            return Some(i + 2);
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Return string `index` from a structure's string set. Indices start at 1, and 0 means none.
+fn string(strings: &[u8], index: u8) -> Option<String> {
+    if index == 0 {
+        return None;
+    }
+
+    strings
+        .split(|&b| b == 0)
+        .nth(index as usize - 1)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+}
+
+/// Return the size in bytes of the range described by a type 19 structure.
+fn mapped_range_size(formatted: &[u8]) -> u64 {
+    let start = read_u32(formatted, 0x04);
+    let end = read_u32(formatted, 0x08);
+
+    // The extended addresses are in bytes, and only used if the 32-bit start is all ones.
+    if start == 0xffff_ffff {
+        if formatted.len() < 0x1f {
+            return 0;
+        }
+
+        let start = read_u64(formatted, 0x0f);
+        let end = read_u64(formatted, 0x17);
+        return end.saturating_sub(start) + 1;
+    }
+
+    // Otherwise the addresses are in KiB.
+    (end as u64).saturating_sub(start as u64).saturating_add(1) * 1024
+}
+
+/// Identity map the physical range `start..start + len`, skipping pages which are already mapped.
+fn identity_map(start: usize, len: usize, active_table: &mut ActivePageTable) {
+    if len == 0 {
+        return;
+    }
+
+    let start_page = Page::containing_address(VirtualAddress::new(start));
+    let end_page = Page::containing_address(VirtualAddress::new(start + len - 1));
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        if active_table.translate_page(page).is_none() {
+            let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().get()));
+            let result =
+                active_table.map_to(page, frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE);
+            result.flush(active_table);
+        }
+    }
+}
+
+/// Sum `bytes`, wrapping. SMBIOS entry points are valid if this is zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 2].as_ptr() as *const u16) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 4].as_ptr() as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    unsafe { ptr::read_unaligned(bytes[offset..offset + 8].as_ptr() as *const u64) }
+}
@@ -0,0 +1,263 @@
+//! Locks with a rank, for catching lock-order inversions.
+//!
+//! Every ranked lock must be taken after any lock of a lower rank, and before any lock of a higher
+//! rank. Two code paths which take the same pair of locks in opposite orders can deadlock, so in
+//! debug builds each CPU counts the locks of each rank it holds, and taking a lock while a
+//! higher-ranked lock is held prints a diagnostic on COM1. Locks of equal rank may nest, since
+//! readers of an `RankedRwLock` can. The counts belong to the running task, so the scheduler saves
+//! and restores them on a context switch.
+//!
+//! The order, from outermost to innermost, is:
+//!
+//! 1. `Scheduler`: the scheduler's task table.
+//! 2. `FrameAllocator`: the physical frame allocator.
+//! 3. `Pics`: the 8259 PICs.
+//! 4. `Screen`: the VGA text buffer and the framebuffer console.
+//! 5. `Serial`: COM1, which every `print!` takes, so it must be innermost.
+//!
+//! Ranks do not help with a lock that is also taken by an interrupt handler: if the handler
+//! interrupts a holder of the lock on the same CPU, it spins forever whatever the order. Interrupt
+//! handlers already run with interrupts disabled, so code outside them must use
+//! `disable_interrupts_and_then` while it holds such a lock.
+
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A lock's place in the lock order. Lower ranks must be taken first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockRank {
+    Scheduler = 0,
+    FrameAllocator = 1,
+    Pics = 2,
+    Screen = 3,
+    Serial = 4,
+}
+
+/// Number of ranks.
+#[cfg(debug_assertions)]
+const RANK_COUNT: usize = 5;
+
+/// The per-CPU word of lock counts has this many bits for each rank, the lowest for `Scheduler`.
+#[cfg(debug_assertions)]
+const COUNT_BITS: usize = 8;
+
+/// Return the count of locks held of `rank` as the amount to add to the word of lock counts.
+#[cfg(debug_assertions)]
+fn one_lock(rank: LockRank) -> usize {
+    1 << (rank as usize * COUNT_BITS)
+}
+
+/// Return the mask of ranks, a bit each, which have locks held in the word of lock counts `counts`.
+#[cfg(debug_assertions)]
+fn held_ranks(counts: usize) -> usize {
+    (0..RANK_COUNT)
+        .filter(|&rank| (counts >> (rank * COUNT_BITS)) & ((1 << COUNT_BITS) - 1) != 0)
+        .fold(0, |held, rank| held | 1 << rank)
+}
+
+/// Record that this CPU is taking a lock of `rank`, reporting it if a higher rank is already held.
+/// Returns the rank if it was counted, to be passed to `release` when the lock is released.
+#[cfg(debug_assertions)]
+fn acquire(rank: LockRank) -> Option<LockRank> {
+    use arch::percpu;
+    use core::fmt::Write;
+    use device::serial::RawSerial;
+
+    // Nothing to record into before per-CPU data is installed.
+    if !percpu::is_installed() {
+        return None;
+    }
+
+    let counts = percpu::held_locks();
+    let held = held_ranks(counts);
+    let bit = 1 << rank as usize;
+
+    // Any held bit above ours is a higher rank.
+    if held & !((bit << 1) - 1) != 0 {
+        // Printing normally would take COM1, which may be the lock we are checking.
+        let _ = writeln!(
+            RawSerial,
+            "[ lock ] Taking {:?} while holding higher ranked locks {:#b}.",
+            rank,
+            held
+        );
+    }
+
+    percpu::set_held_locks(counts + one_lock(rank));
+    Some(rank)
+}
+
+#[cfg(not(debug_assertions))]
+fn acquire(_rank: LockRank) -> Option<LockRank> {
+    None
+}
+
+/// Record that this CPU has released a lock which `acquire` counted as being of `counted`. Locks
+/// may be released in any order, since each rank is counted separately.
+#[cfg(debug_assertions)]
+fn release(counted: Option<LockRank>) {
+    use arch::percpu;
+
+    if let Some(rank) = counted {
+        percpu::set_held_locks(percpu::held_locks() - one_lock(rank));
+    }
+}
+
+#[cfg(not(debug_assertions))]
+fn release(_counted: Option<LockRank>) {}
+
+/// A spinlock with a rank in the lock order.
+pub struct RankedMutex<T> {
+    rank: LockRank,
+    inner: Mutex<T>,
+}
+
+impl<T> RankedMutex<T> {
+    pub const fn new(rank: LockRank, value: T) -> Self {
+        RankedMutex {
+            rank: rank,
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> RankedMutexGuard<T> {
+        let counted = acquire(self.rank);
+
+        RankedMutexGuard {
+            guard: self.inner.lock(),
+            counted: counted,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<RankedMutexGuard<T>> {
+        let counted = acquire(self.rank);
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(RankedMutexGuard {
+                guard: guard,
+                counted: counted,
+            }),
+            None => {
+                release(counted);
+                None
+            }
+        }
+    }
+}
+
+pub struct RankedMutexGuard<'a, T: 'a> {
+    guard: MutexGuard<'a, T>,
+    counted: Option<LockRank>,
+}
+
+impl<'a, T> Deref for RankedMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for RankedMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for RankedMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        release(self.counted);
+    }
+}
+
+/// A reader-writer spinlock with a rank in the lock order.
+pub struct RankedRwLock<T> {
+    rank: LockRank,
+    inner: RwLock<T>,
+}
+
+impl<T> RankedRwLock<T> {
+    pub const fn new(rank: LockRank, value: T) -> Self {
+        RankedRwLock {
+            rank: rank,
+            inner: RwLock::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RankedRwLockReadGuard<T> {
+        let counted = acquire(self.rank);
+
+        RankedRwLockReadGuard {
+            guard: self.inner.read(),
+            counted: counted,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RankedRwLockReadGuard<T>> {
+        let counted = acquire(self.rank);
+
+        match self.inner.try_read() {
+            Some(guard) => Some(RankedRwLockReadGuard {
+                guard: guard,
+                counted: counted,
+            }),
+            None => {
+                release(counted);
+                None
+            }
+        }
+    }
+
+    pub fn write(&self) -> RankedRwLockWriteGuard<T> {
+        let counted = acquire(self.rank);
+
+        RankedRwLockWriteGuard {
+            guard: self.inner.write(),
+            counted: counted,
+        }
+    }
+}
+
+pub struct RankedRwLockReadGuard<'a, T: 'a> {
+    guard: RwLockReadGuard<'a, T>,
+    counted: Option<LockRank>,
+}
+
+impl<'a, T> Deref for RankedRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> Drop for RankedRwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        release(self.counted);
+    }
+}
+
+pub struct RankedRwLockWriteGuard<'a, T: 'a> {
+    guard: RwLockWriteGuard<'a, T>,
+    counted: Option<LockRank>,
+}
+
+impl<'a, T> Deref for RankedRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for RankedRwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for RankedRwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        release(self.counted);
+    }
+}
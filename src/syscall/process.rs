@@ -1,6 +1,7 @@
 use alloc::String;
-use task::{ProcessId, Scheduling, SCHEDULER};
+use task::{ExitCode, ProcessId, Scheduling, SCHEDULER};
 use arch::interrupts::disable_interrupts_and_then;
+use arch::percpu;
 
 /// Simple system call that wraps creating a process and marking it as ready.
 pub fn create(new: extern "C" fn(), name: String) -> ProcessId {
@@ -12,3 +13,44 @@ pub fn create(new: extern "C" fn(), name: String) -> ProcessId {
         pid
     })
 }
+
+/// Create a process pinned to the logical CPU `cpu` and mark it as ready. Fails if the CPU is not
+/// online.
+pub fn create_pinned(new: extern "C" fn(), name: String, cpu: u8) -> Result<ProcessId, i16> {
+    // Checked first, so that a process is never created only to be thrown away.
+    if cpu as usize >= percpu::online_cpus() {
+        return Err(-1);
+    }
+
+    disable_interrupts_and_then(|| -> Result<ProcessId, i16> {
+        let pid = SCHEDULER.create(new, name)?;
+        SCHEDULER.set_affinity(pid, Some(cpu))?;
+        SCHEDULER.ready(pid);
+        Ok(pid)
+    })
+}
+
+/// Exit the calling process with the given exit code. This does not return.
+pub fn exit(code: ExitCode) {
+    SCHEDULER.exit(code);
+}
+
+/// Block until the process `id` exits, returning its exit code.
+pub fn join(id: ProcessId) -> Result<ExitCode, i16> {
+    SCHEDULER.join(id)
+}
+
+/// Block the calling process for `ticks` timer ticks.
+pub fn sleep(ticks: u64) -> Result<(), i16> {
+    ::task::sleep::sleep(ticks)
+}
+
+/// Return task-local storage slot `slot` of the calling process.
+pub fn tls_get(slot: usize) -> Result<usize, i16> {
+    SCHEDULER.tls_get(slot)
+}
+
+/// Set task-local storage slot `slot` of the calling process.
+pub fn tls_set(slot: usize, value: usize) -> Result<(), i16> {
+    SCHEDULER.tls_set(slot, value)
+}
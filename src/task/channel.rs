@@ -0,0 +1,222 @@
+//! Bounded multi-producer, single-consumer channels for passing messages between processes.
+//! Messages are stored in a fixed ring allocated when the channel is created. Blocking is done
+//! through `WaitQueue`s, so a blocked sender or receiver does not use any CPU time.
+
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use task::WaitQueue;
+
+/// A fixed-capacity ring of messages.
+struct Ring<T> {
+    slots: Vec<Option<T>>,
+    head: usize,
+    len: usize,
+}
+
+impl<T> Ring<T> {
+    fn new(capacity: usize) -> Self {
+        let mut slots = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            slots.push(None);
+        }
+
+        Ring {
+            slots: slots,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.slots.len()
+    }
+
+    /// Push a message onto the back of the ring. The caller must check that the ring is not full.
+    fn push(&mut self, value: T) {
+        let tail = (self.head + self.len) % self.slots.len();
+        self.slots[tail] = Some(value);
+        self.len += 1;
+    }
+
+    /// Pop the oldest message from the ring.
+    fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let value = self.slots[self.head].take();
+        self.head = (self.head + 1) % self.slots.len();
+        self.len -= 1;
+        value
+    }
+}
+
+/// State shared between all the senders and the receiver of a channel.
+struct Shared<T> {
+    ring: Mutex<Ring<T>>,
+    /// Number of live `Sender`s.
+    senders: AtomicUsize,
+    /// Whether the `Receiver` is still alive.
+    receiver: AtomicBool,
+    /// Receivers waiting for a message.
+    not_empty: WaitQueue,
+    /// Senders waiting for space in the ring.
+    not_full: WaitQueue,
+}
+
+/// The receiving half has been dropped. The message that could not be sent is returned.
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+/// Every sender has been dropped and no messages are left.
+#[derive(Debug, Eq, PartialEq)]
+pub struct RecvError;
+
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiving half has been dropped.
+    Closed(T),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TryRecvError {
+    /// No messages are waiting.
+    Empty,
+    /// Every sender has been dropped and no messages are left.
+    Closed,
+}
+
+/// The sending half of a channel. This can be cloned to give multiple producers.
+pub struct Sender<T: Send> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T: Send> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Create a channel that can hold up to `capacity` messages before senders block.
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+
+    let shared = Arc::new(Shared {
+        ring: Mutex::new(Ring::new(capacity)),
+        senders: AtomicUsize::new(1),
+        receiver: AtomicBool::new(true),
+        not_empty: WaitQueue::new(),
+        not_full: WaitQueue::new(),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared: shared },
+    )
+}
+
+impl<T: Send> Sender<T> {
+    /// Send a message without blocking. This is safe to call from an interrupt handler.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.receiver.load(Ordering::SeqCst) {
+            return Err(TrySendError::Closed(value));
+        }
+
+        {
+            let mut ring = self.shared.ring.lock();
+            if ring.is_full() {
+                return Err(TrySendError::Full(value));
+            }
+            ring.push(value);
+        }
+
+        self.shared.not_empty.wake_one();
+        Ok(())
+    }
+
+    /// Send a message, blocking the current process while the channel is full.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = value;
+
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => value = v,
+            }
+
+            let shared = &self.shared;
+            shared.not_full.wait_until(|| {
+                !shared.ring.lock().is_full() || !shared.receiver.load(Ordering::SeqCst)
+            });
+        }
+    }
+}
+
+impl<T: Send> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Send> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Wake the receiver if this was the last sender, so it can see the channel has closed.
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.not_empty.wake_all();
+        }
+    }
+}
+
+impl<T: Send> Receiver<T> {
+    /// Receive a message without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let value = self.shared.ring.lock().pop();
+
+        match value {
+            Some(value) => {
+                self.shared.not_full.wake_one();
+                Ok(value)
+            }
+            None if self.shared.senders.load(Ordering::SeqCst) == 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Receive a message, blocking the current process until one arrives. Returns an error once
+    /// every sender has been dropped and the channel has been drained.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Closed) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let shared = &self.shared;
+            shared.not_empty.wait_until(|| {
+                !shared.ring.lock().is_empty() || shared.senders.load(Ordering::SeqCst) == 0
+            });
+        }
+    }
+}
+
+impl<T: Send> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver.store(false, Ordering::SeqCst);
+        self.shared.not_full.wake_all();
+    }
+}
@@ -1,43 +1,110 @@
 use alloc::VecDeque;
 use alloc::vec::Vec;
 use alloc::String;
-use core::mem;
+use core::{cmp, mem};
 use core::ops::DerefMut;
+use arch::interrupts::{self, disable_interrupts_and_then};
+use arch::memory::stack_allocator::Stack;
+use arch::percpu;
+use arch::time;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use task::{Process, ProcessId, ProcessList, Scheduling, State, INITIAL_STACK};
+use task::{ExitCode, Process, ProcessId, ProcessList, ProcessName, Scheduling, State,
+           INITIAL_STACK, STACK_FILL, TLS_SLOTS};
+use task::cwd::PathName;
+use task::policy::{self, SchedulerPolicy};
 use task::process;
-use spin::RwLock;
+use task::sleep;
+use spin::{Mutex, RwLock};
+use sync::{LockRank, RankedRwLock};
+
+/// Fewest timer ticks between two attempts by the same CPU to steal work, so that two idle CPUs do
+/// not keep fighting over each other's ready list locks.
+const STEAL_INTERVAL: usize = 2;
+
+/// Counters of scheduler activity, returned by `CoopScheduler::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerStats {
+    /// Calls to `resched()`.
+    pub resched_calls: usize,
+    /// Switches from one process to another.
+    pub switches: usize,
+    /// Switches which were timed. A switch into a process which has never run does not come back
+    /// out of `switch_to`, so it cannot be timed.
+    pub timed_switches: usize,
+    /// TSC cycles spent saving and restoring registers in the timed switches.
+    pub switch_cycles: usize,
+}
+
+impl SchedulerStats {
+    /// Return the average cost of a timed switch in TSC cycles, or `None` if none were timed.
+    pub fn average_switch_cycles(&self) -> Option<usize> {
+        if self.timed_switches == 0 {
+            None
+        } else {
+            Some(self.switch_cycles / self.timed_switches)
+        }
+    }
+}
 
 /// Global kernel scheduler type.
 pub type Scheduler = CoopScheduler;
 
-/// A simple cooperative scheduler. It uses round-robin scheduling, where the next available, ready
-/// process is the next process to be ran.
+/// A simple cooperative scheduler. Which ready process runs next is up to its `SchedulerPolicy`,
+/// which is round-robin by default.
+///
+/// The PID of the running process is kept in per-CPU data, so each CPU has its own current process.
+/// Each CPU also has its own ready list, and only runs processes from it. A process pinned to a CPU
+/// is only ever queued on that CPU's list, and any other process goes on the shortest list. A CPU
+/// which runs out of work steals an unpinned process from the longest list.
 pub struct CoopScheduler {
-    current_pid: AtomicUsize,
-    task_table: RwLock<ProcessList>,
-    ready_list: RwLock<VecDeque<ProcessId>>,
+    task_table: RankedRwLock<ProcessList>,
+    /// One ready list per logical CPU, indexed by CPU ID.
+    ready_lists: Vec<RwLock<VecDeque<ProcessId>>>,
+    /// Picks the next process from a ready list.
+    policy: RwLock<&'static SchedulerPolicy>,
+    /// The tick of each CPU's last attempt to steal work plus one, or zero if it has not tried.
+    last_steal: Vec<AtomicUsize>,
+    /// The TSC when each CPU last started a context switch, read once the switch has finished.
+    switch_started: Vec<AtomicUsize>,
+    /// The stack of the process which last exited on each CPU. It is still in use until the CPU
+    /// switches away, so it is only freed when the next process exits there.
+    dying_stacks: Vec<Mutex<Option<Stack>>>,
+    resched_calls: AtomicUsize,
+    switches: AtomicUsize,
+    timed_switches: AtomicUsize,
+    switch_cycles: AtomicUsize,
 }
 
 impl Scheduling for CoopScheduler {
-    /// Create a process using a C-declared function pointer as an argument. This function allocates a
-    /// 1 KiB stack.
+    /// Create a process using a C-declared function pointer as an argument. This function allocates
+    /// an 8 KiB stack with a guard page below it, filled with `STACK_FILL`.
     fn create(&self, func: extern "C" fn(), name: String) -> Result<ProcessId, i16> {
-        use arch::memory::paging;
-
-        let mut stack: Vec<usize> = vec![0; INITIAL_STACK];
+        use arch::memory::{paging, stack_allocator, PAGE_SIZE};
+        use core::slice;
+
+        let mut active_table = unsafe { paging::ActivePageTable::new() };
+        let pages = INITIAL_STACK * mem::size_of::<usize>() / PAGE_SIZE;
+        let stack = stack_allocator::alloc_stack(&mut active_table, pages).ok_or(-1)?;
+
+        let words = (stack.top() - stack.bottom()) / mem::size_of::<usize>();
+        // The stack pages were mapped for this stack alone.
+        let stack_words = unsafe { slice::from_raw_parts_mut(stack.bottom() as *mut usize, words) };
+        let fill = usize::max_value() / 0xff * STACK_FILL as usize;
+        for word in stack_words.iter_mut() {
+            *word = fill;
+        }
 
-        let proc_top: usize = stack.len() - 3;
+        let proc_top: usize = words - 3;
 
-        let proc_sp = stack.as_ptr() as usize + (proc_top * mem::size_of::<usize>());
+        let proc_sp = stack.bottom() + (proc_top * mem::size_of::<usize>());
 
         use alloc::boxed::Box;
         let self_ptr: Box<&Scheduling> = Box::new(self);
 
         // Reserve three elements on the stack.
-        // stack.len() - 3 -> pointer to the entry point of the process. This is what RSP is set to
-        // under Context::switch_to().
-        // stack.len() - 2 -> function that we jump to after process return.
+        // words - 3 -> pointer to the entry point of the process. This is what RSP is set to under
+        // Context::switch_to().
+        // words - 2 -> function that we jump to after process return.
 
         let stack_vals: Vec<usize> = vec![
             func as usize,
@@ -46,23 +113,32 @@ impl Scheduling for CoopScheduler {
         ];
 
         for (i, val) in stack_vals.iter().enumerate() {
-            stack[proc_top + i] = *val;
+            stack_words[proc_top + i] = *val;
         }
 
+        // A new process starts in the directory of the one which created it.
+        let cwd = self.cwd();
+
         let mut task_table_lock = self.task_table.write();
 
-        let proc_lock = task_table_lock.add()?;
+        let proc_lock = match task_table_lock.add() {
+            Ok(proc_lock) => proc_lock,
+            Err(e) => {
+                // No process owns the stack yet, so nothing else would ever free it.
+                stack_allocator::free_stack(&mut active_table, stack);
+                return Err(e);
+            }
+        };
         {
             let mut process = proc_lock.write();
 
             process.stack = Some(stack);
-            process.name = name;
+            process.name = ProcessName::new(&name);
+            process.cwd = cwd;
 
             // Create a new page table. This saves the address placed in cr3 after page table
             // creation for a context switch later on.
-            process
-                .ctx
-                .set_page_table(unsafe { paging::ActivePageTable::new().address() });
+            process.ctx.set_page_table(active_table.address());
 
             // Set the stack pointer.
             process.ctx.set_stack(proc_sp);
@@ -73,15 +149,15 @@ impl Scheduling for CoopScheduler {
 
     /// Returns the PID of the current process.
     fn get_id(&self) -> ProcessId {
-        ProcessId(self.current_pid.load(Ordering::SeqCst))
+        ProcessId(percpu::current_task_id())
     }
 
-    /// Kill the process. We do this by marking it as free in the task table.
-    /// To free memory held by the process, we drop the String that holds the process name,
-    /// and mark the Option stack as None - this causes the memory held by the Some() to be
-    /// dropped.
+    /// Kill the process. We do this by marking it as free in the task table, and freeing its
+    /// stack. See `release_stack`.
+    ///
+    /// The process stays in the task table with its exit code so that it can still be joined.
     fn kill(&self, id: ProcessId) {
-        {
+        let (joiners, stack) = {
             let task_table_lock = self.task_table.read();
             let mut proc_lock = task_table_lock
                 .get(id)
@@ -89,26 +165,267 @@ impl Scheduling for CoopScheduler {
                 .write();
 
             proc_lock.set_state(State::Free);
-            proc_lock.stack = None;
-            drop(&mut proc_lock.name);
+            if proc_lock.exit_code.is_none() {
+                proc_lock.exit_code = Some(ExitCode::KILLED);
+            }
+            let stack = proc_lock.stack.take();
+
+            (proc_lock.joiners.clone(), stack)
+        };
+
+        // A dead process must never be picked by resched().
+        self.unqueue(id);
+
+        if let Some(stack) = stack {
+            self.release_stack(id, stack);
         }
 
+        joiners.wake_all();
+
         unsafe {
             self.resched();
         }
     }
 
-    /// Mark a process as ready which enables it to be ran under resched().
+    /// Exit the current process with the given exit code, waking any processes joining it.
+    fn exit(&self, code: ExitCode) {
+        let current = self.get_id();
+
+        {
+            let task_table_lock = self.task_table.read();
+            let mut proc_lock = task_table_lock
+                .get(current)
+                .expect("Could not find current process")
+                .write();
+
+            proc_lock.exit_code = Some(code);
+        }
+
+        self.kill(current);
+    }
+
+    /// Block until the process `id` exits and return its exit code. Joining a process that has
+    /// already exited returns immediately, and joining a non-existent process returns an error.
+    fn join(&self, id: ProcessId) -> Result<ExitCode, i16> {
+        let joiners = match self.task_table.read().get(id) {
+            Some(proc_lock) => proc_lock.read().joiners.clone(),
+            None => return Err(-1),
+        };
+
+        joiners.wait_until(|| self.exit_code(id).is_some());
+
+        Ok(self.exit_code(id).unwrap_or(ExitCode::KILLED))
+    }
+
+    /// Estimate the most stack the process `id` has used so far, in bytes. See
+    /// `Process::stack_usage`.
+    fn stack_usage(&self, id: ProcessId) -> Result<usize, i16> {
+        let task_table_lock = self.task_table.read();
+        let proc_lock = task_table_lock.get(id).ok_or(-1)?.read();
+
+        proc_lock.stack_usage().ok_or(-1)
+    }
+
+    /// Rename the current process. This fails from an interrupt handler, which runs on behalf of
+    /// whichever process it happened to interrupt rather than the current one.
+    fn set_name(&self, name: &str) -> Result<(), i16> {
+        if percpu::in_interrupt() {
+            return Err(-1);
+        }
+
+        let task_table_lock = self.task_table.read();
+        let mut proc_lock = task_table_lock
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .write();
+
+        proc_lock.name = ProcessName::new(name);
+
+        Ok(())
+    }
+
+    /// Return the name of the current process.
+    fn current_name(&self) -> ProcessName {
+        self.task_table
+            .read()
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .read()
+            .name
+    }
+
+    /// Return task-local storage slot `slot` of the current process. Like `set_name`, this fails
+    /// from an interrupt handler, and also for a slot past `TLS_SLOTS`.
+    fn tls_get(&self, slot: usize) -> Result<usize, i16> {
+        if percpu::in_interrupt() || slot >= TLS_SLOTS {
+            return Err(-1);
+        }
+
+        let task_table_lock = self.task_table.read();
+        let proc_lock = task_table_lock
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .read();
+
+        Ok(proc_lock.tls[slot])
+    }
+
+    /// Set task-local storage slot `slot` of the current process.
+    fn tls_set(&self, slot: usize, value: usize) -> Result<(), i16> {
+        if percpu::in_interrupt() || slot >= TLS_SLOTS {
+            return Err(-1);
+        }
+
+        let task_table_lock = self.task_table.read();
+        let mut proc_lock = task_table_lock
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .write();
+
+        proc_lock.tls[slot] = value;
+
+        Ok(())
+    }
+
+    /// Return the working directory of the current process.
+    fn cwd(&self) -> PathName {
+        self.task_table
+            .read()
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .read()
+            .cwd
+    }
+
+    /// Change the working directory of the current process. Fails from an interrupt handler.
+    fn set_cwd(&self, path: PathName) -> Result<(), i16> {
+        if percpu::in_interrupt() {
+            return Err(-1);
+        }
+
+        let task_table_lock = self.task_table.read();
+        let mut proc_lock = task_table_lock
+            .get(self.get_id())
+            .expect("Could not find current process")
+            .write();
+
+        proc_lock.cwd = path;
+
+        Ok(())
+    }
+
+    /// Mark a process as ready which enables it to be ran under resched(), on the CPU it is pinned
+    /// to if it has an affinity.
     fn ready(&self, id: ProcessId) {
-        self.ready_list.write().push_back(id);
+        let affinity = match self.task_table.read().get(id) {
+            Some(proc_lock) => proc_lock.read().cpu_affinity,
+            None => return,
+        };
+
+        let cpu = match affinity {
+            Some(cpu) => cpu as usize,
+            None => self.shortest_ready_list(),
+        };
+        self.ready_lists[cpu].write().push_back(id);
+    }
+
+    /// Pin the process `id` to the logical CPU `cpu`, or let it run anywhere if `cpu` is `None`.
+    /// Fails if the process does not exist or the CPU is not online.
+    fn set_affinity(&self, id: ProcessId, cpu: Option<u8>) -> Result<(), i16> {
+        if let Some(cpu) = cpu {
+            if cpu as usize >= percpu::online_cpus() || cpu as usize >= percpu::MAX_CPUS {
+                return Err(-1);
+            }
+        }
+
+        let ready = {
+            let task_table_lock = self.task_table.read();
+            let mut proc_lock = task_table_lock.get(id).ok_or(-1)?.write();
+
+            proc_lock.cpu_affinity = cpu;
+            proc_lock.state == State::Ready
+        };
+
+        // Move a queued process to the list it now belongs on. A running process is moved when it
+        // is next switched away from.
+        if ready {
+            self.unqueue(id);
+            self.ready(id);
+        }
+
+        Ok(())
+    }
+
+    /// Mark a process as blocked so that resched() will not place it back on the ready list, then
+    /// switch away from it if it is the current process.
+    ///
+    /// If nothing else is ready to run on this CPU, resched() comes straight back and the process
+    /// carries on running. It is marked current again, since a process left marked blocked while
+    /// it runs would be queued by the next wakeup. A caller which has to wait regardless should
+    /// halt until the next interrupt and check again, as `WaitQueue::wait_until` does.
+    unsafe fn block(&self, id: ProcessId) {
+        {
+            let task_table_lock = self.task_table.read();
+            let mut proc_lock = task_table_lock
+                .get(id)
+                .expect("Cannot block a non-existent process")
+                .write();
+
+            proc_lock.set_state(State::Blocked);
+        }
+
+        self.unqueue(id);
+
+        if id == self.get_id() {
+            self.resched();
+
+            let woken = {
+                let task_table_lock = self.task_table.read();
+                let mut proc_lock = task_table_lock
+                    .get(id)
+                    .expect("Cannot block a non-existent process")
+                    .write();
+
+                let woken = proc_lock.state == State::Ready;
+                proc_lock.set_state(State::Current);
+                woken
+            };
+
+            // Another CPU may have woken the process after it was unqueued above.
+            if woken {
+                self.unqueue(id);
+            }
+        }
+    }
+
+    /// Make a blocked process ready again. Waking a process that is not blocked does nothing.
+    fn wake(&self, id: ProcessId) {
+        {
+            let task_table_lock = self.task_table.read();
+            let mut proc_lock = match task_table_lock.get(id) {
+                Some(proc_lock) => proc_lock.write(),
+                None => return,
+            };
+
+            if proc_lock.state != State::Blocked {
+                return;
+            }
+
+            proc_lock.set_state(State::Ready);
+        }
+
+        self.ready(id);
     }
 
     /// Perform a context switch to the new process. This method will deadlock if any software
     /// locks are still held - it is therefore important to scope locking of data structures to
     /// ensure that these locks will be dropped.
     unsafe fn resched(&self) {
+        let cpu = percpu::this_cpu().cpu_id;
+        self.resched_calls.fetch_add(1, Ordering::Relaxed);
+
         {
-            if self.ready_list.read().is_empty() {
+            if self.ready_lists[cpu].read().is_empty() {
                 return;
             }
         }
@@ -119,7 +436,6 @@ impl Scheduling for CoopScheduler {
         // Separate the locks from the context switch through scoping
         {
             let task_table_lock = self.task_table.read();
-            let mut ready_list_lock = self.ready_list.write();
 
             let curr_id: ProcessId = self.get_id();
 
@@ -128,26 +444,54 @@ impl Scheduling for CoopScheduler {
                 .expect("Could not find old process")
                 .write();
 
-            if prev.state == State::Current {
-                prev.set_state(State::Ready);
-                ready_list_lock.push_back(curr_id);
+            let running = prev.state == State::Current;
+            let prev_cpu = prev.cpu_affinity.map_or(cpu, |affinity| affinity as usize);
+
+            // The current process is queued again only once the policy has chosen, so that it
+            // is never chosen to succeed itself.
+            let next_id = {
+                let mut ready_list_lock = self.ready_lists[cpu].write();
+                let next_id = self.policy().pick_next(&ready_list_lock);
+
+                if let Some(next_id) = next_id {
+                    let position = ready_list_lock.iter().position(|&id| id == next_id);
+                    if let Some(position) = position {
+                        ready_list_lock.remove(position);
+                    }
+                    if running && prev_cpu == cpu {
+                        ready_list_lock.push_back(curr_id);
+                    }
+                }
+                next_id
+            };
+
+            // A process pinned to another CPU is handed over to it, unless there is nothing else
+            // to run here, in which case it carries on until the next resched().
+            if running && prev_cpu != cpu && next_id.is_some() {
+                self.ready_lists[prev_cpu].write().push_back(curr_id);
             }
 
-            if let Some(next_id) = ready_list_lock.pop_front() {
-                if next_id != self.get_id() {
+            match next_id {
+                Some(next_id) if next_id != curr_id => {
+                    if running {
+                        prev.set_state(State::Ready);
+                    }
+
                     let mut next = task_table_lock
                         .get(next_id)
                         .expect("Could not find new process")
                         .write();
 
                     next.set_state(State::Current);
+                    next.ran_on |= 1 << cpu;
 
-                    self.current_pid.store(next.pid.inner(), Ordering::SeqCst);
+                    percpu::set_current_task_id(next.pid.inner());
 
                     // Save process pointers for out of scope context switch
                     prev_ptr = prev.deref_mut() as *mut Process;
                     next_ptr = next.deref_mut() as *mut Process;
                 }
+                _ => (),
             }
         }
 
@@ -160,19 +504,211 @@ impl Scheduling for CoopScheduler {
             let prev: &mut Process = &mut *prev_ptr;
             let next: &mut Process = &mut *next_ptr;
 
+            self.switches.fetch_add(1, Ordering::Relaxed);
+            self.switch_started[cpu].store(time::rdtsc() as usize, Ordering::Relaxed);
+
+            // The locks counted are held by the process, not by the CPU, which may run it next
+            // time round while some other process holds locks here. Likewise for preemption.
+            prev.held_locks = percpu::held_locks();
+            percpu::set_held_locks(next.held_locks);
+            prev.preempt_count = percpu::preempt_count();
+            percpu::set_preempt_count(next.preempt_count);
+
             prev.ctx.switch_to(&mut next.ctx);
+
+            // This is now the process switched to, which may have been switched away from on a
+            // different CPU, so the CPU has to be looked up again.
+            let cpu = percpu::this_cpu().cpu_id;
+            let started = self.switch_started[cpu].swap(0, Ordering::Relaxed);
+            if started != 0 {
+                self.timed_switches.fetch_add(1, Ordering::Relaxed);
+                self.switch_cycles
+                    .fetch_add(time::rdtsc() as usize - started, Ordering::Relaxed);
+            }
         }
     }
 }
 
 impl CoopScheduler {
-    /// Initialise the cooperative scheduler. This sets the current PID as the null kernel process,
-    /// and creates an empty task table and ready list.
+    /// Free the stack of the killed process `id`. A process killing itself is still running on its
+    /// stack, so that is kept in its CPU's `dying_stacks` slot instead, and the stack it replaces
+    /// there, whose process has long since switched away, is freed.
+    fn release_stack(&self, id: ProcessId, stack: Stack) {
+        use arch::memory::{paging, stack_allocator};
+
+        let stack = if id == self.get_id() {
+            // Interrupts stay off so that the process is not moved to another CPU meanwhile.
+            let older = disable_interrupts_and_then(|| {
+                let cpu = percpu::this_cpu().cpu_id;
+                let older = mem::replace(&mut *self.dying_stacks[cpu].lock(), Some(stack));
+                older
+            });
+            match older {
+                Some(older) => older,
+                None => return,
+            }
+        } else {
+            stack
+        };
+
+        let mut active_table = unsafe { paging::ActivePageTable::new() };
+        stack_allocator::free_stack(&mut active_table, stack);
+    }
+
+    /// Return the exit code of a process, or `None` if it is still running or does not exist.
+    fn exit_code(&self, id: ProcessId) -> Option<ExitCode> {
+        self.task_table
+            .read()
+            .get(id)
+            .and_then(|proc_lock| proc_lock.read().exit_code)
+    }
+
+    /// Return the name of the current process, or `None` if the task table is locked. This is for
+    /// fault handlers, which may have interrupted the holder of the lock.
+    pub fn try_current_name(&self) -> Option<ProcessName> {
+        let task_table_lock = self.task_table.try_read()?;
+        let proc_lock = task_table_lock.get(self.get_id())?.try_read()?;
+        Some(proc_lock.name)
+    }
+
+    /// Return the policy which picks the next process to run.
+    pub fn policy(&self) -> &'static SchedulerPolicy {
+        *self.policy.read()
+    }
+
+    /// Pick the next process to run with `policy` from now on.
+    pub fn set_policy(&self, policy: &'static SchedulerPolicy) {
+        *self.policy.write() = policy;
+    }
+
+    /// Return the scheduler's activity counters.
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            resched_calls: self.resched_calls.load(Ordering::Relaxed),
+            switches: self.switches.load(Ordering::Relaxed),
+            timed_switches: self.timed_switches.load(Ordering::Relaxed),
+            switch_cycles: self.switch_cycles.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Return a bit mask of the logical CPUs the process `id` has been switched to on.
+    pub fn cpus_run_on(&self, id: ProcessId) -> Result<usize, i16> {
+        let task_table_lock = self.task_table.read();
+        let proc_lock = task_table_lock.get(id).ok_or(-1)?.read();
+
+        Ok(proc_lock.ran_on)
+    }
+
+    /// Move a ready process which is not pinned from the longest ready list to the list of CPU
+    /// `thief`, and return it. Nothing is stolen if `thief` tried too recently, if no other list
+    /// has an unpinned process, or if the longest list is locked, since waiting for it is what the
+    /// rate limit is there to avoid.
+    pub fn steal_for(&self, thief: usize) -> Option<ProcessId> {
+        let now = sleep::ticks();
+        let last = self.last_steal[thief].load(Ordering::SeqCst);
+        if last != 0 && now < last - 1 + STEAL_INTERVAL {
+            return None;
+        }
+        self.last_steal[thief].store(now + 1, Ordering::SeqCst);
+
+        let victim = (0..percpu::MAX_CPUS)
+            .filter(|&cpu| cpu != thief)
+            .max_by_key(|&cpu| self.ready_lists[cpu].read().len())?;
+
+        let stolen = {
+            let task_table_lock = self.task_table.read();
+            let mut victim_list = self.ready_lists[victim].try_write()?;
+
+            // Ready lists only hold processes which are not running, but check the state anyway,
+            // since the list may be mid-way through a resched() on the victim. The most recently
+            // queued process is taken, as it is the least likely to still be in the victim's cache.
+            let position = victim_list.iter().rposition(|&pid| {
+                task_table_lock.get(pid).map_or(false, |proc_lock| {
+                    let process = proc_lock.read();
+                    process.cpu_affinity.is_none() && process.state == State::Ready
+                })
+            })?;
+
+            victim_list.remove(position)?
+        };
+
+        self.ready_lists[thief].write().push_back(stolen);
+        Some(stolen)
+    }
+
+    /// Run on a CPU with nothing else to do, from its idle loop. This switches to any ready
+    /// process, steals one from another CPU if this CPU has none, and otherwise halts until the
+    /// next interrupt.
+    pub fn idle(&self) {
+        // Checked with interrupts disabled, so that an interrupt which readies a process either
+        // comes before the check or ends the halt.
+        unsafe { asm!("cli" : : : "memory" : "volatile") };
+
+        let cpu = percpu::this_cpu().cpu_id;
+        if !self.ready_lists[cpu].read().is_empty() || self.steal_for(cpu).is_some() {
+            unsafe {
+                self.resched();
+                asm!("sti" : : : "memory" : "volatile");
+            }
+        } else {
+            unsafe { interrupts::enable_and_halt() };
+        }
+    }
+
+    /// Remove the process `id` from every ready list.
+    fn unqueue(&self, id: ProcessId) {
+        for ready_list in self.ready_lists.iter() {
+            ready_list.write().retain(|&pid| pid != id);
+        }
+    }
+
+    /// Return the online CPU with the fewest ready processes.
+    fn shortest_ready_list(&self) -> usize {
+        let online = cmp::max(cmp::min(percpu::online_cpus(), percpu::MAX_CPUS), 1);
+
+        (0..online)
+            .min_by_key(|&cpu| self.ready_lists[cpu].read().len())
+            .unwrap_or(0)
+    }
+
+    /// Print the PID, name and state of every process in the task table.
+    pub fn print_tasks(&self) {
+        let task_table_lock = self.task_table.read();
+
+        for (pid, proc_lock) in task_table_lock.iter() {
+            let process = proc_lock.read();
+            println!("{:>5} {} {:?}", pid.inner(), process.name, process.state);
+        }
+    }
+
+    /// Initialise the cooperative scheduler. This creates an empty task table and ready lists. The
+    /// current PID starts as the null kernel process, since per-CPU data is zeroed at init.
     pub fn new() -> Self {
+        let mut ready_lists = Vec::with_capacity(percpu::MAX_CPUS);
+        for _ in 0..percpu::MAX_CPUS {
+            ready_lists.push(RwLock::new(VecDeque::<ProcessId>::new()));
+        }
+
+        let mut last_steal = Vec::with_capacity(percpu::MAX_CPUS);
+        let mut switch_started = Vec::with_capacity(percpu::MAX_CPUS);
+        let mut dying_stacks = Vec::with_capacity(percpu::MAX_CPUS);
+        for _ in 0..percpu::MAX_CPUS {
+            last_steal.push(AtomicUsize::new(0));
+            switch_started.push(AtomicUsize::new(0));
+            dying_stacks.push(Mutex::new(None));
+        }
+
         CoopScheduler {
-            current_pid: AtomicUsize::new(ProcessId::NULL_PROC.inner()),
-            task_table: RwLock::new(ProcessList::new()),
-            ready_list: RwLock::new(VecDeque::<ProcessId>::new()),
+            task_table: RankedRwLock::new(LockRank::Scheduler, ProcessList::new()),
+            ready_lists: ready_lists,
+            policy: RwLock::new(&policy::ROUND_ROBIN),
+            last_steal: last_steal,
+            switch_started: switch_started,
+            dying_stacks: dying_stacks,
+            resched_calls: AtomicUsize::new(0),
+            switches: AtomicUsize::new(0),
+            timed_switches: AtomicUsize::new(0),
+            switch_cycles: AtomicUsize::new(0),
         }
     }
 }
@@ -0,0 +1,125 @@
+//! The current working directory of each process, and resolving paths against it.
+//!
+//! Paths are stored inline with a fixed maximum length, like process names, so changing directory
+//! never allocates. A resolved path is always absolute and normalised: it starts with `/`, has no
+//! empty, `.` or `..` components, and has no trailing `/` unless it is the root. `..` at the root
+//! stays at the root. A new process starts in the working directory of the one which created it.
+
+use core::{fmt, str};
+use task::{Scheduling, SCHEDULER};
+
+/// Longest path in bytes, including the leading `/`.
+pub const PATH_MAX: usize = 128;
+
+/// An absolute, normalised path.
+#[derive(Clone, Copy)]
+pub struct PathName {
+    bytes: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl PathName {
+    /// Return the root directory.
+    pub fn root() -> Self {
+        let mut bytes = [0; PATH_MAX];
+        bytes[0] = b'/';
+
+        PathName {
+            bytes: bytes,
+            len: 1,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from `&str` components joined by `/`.
+        unsafe { str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+
+    /// Append a component, which must not be empty or contain `/`.
+    fn push(&mut self, component: &str) -> Result<(), &'static str> {
+        let separator = if self.len == 1 { 0 } else { 1 };
+        if self.len + separator + component.len() > PATH_MAX {
+            return Err("path too long");
+        }
+
+        if separator == 1 {
+            self.bytes[self.len] = b'/';
+        }
+        let start = self.len + separator;
+        self.bytes[start..start + component.len()].copy_from_slice(component.as_bytes());
+        self.len = start + component.len();
+
+        Ok(())
+    }
+
+    /// Remove the last component. Does nothing at the root.
+    fn pop(&mut self) {
+        let last_slash = self.bytes[..self.len]
+            .iter()
+            .rposition(|&byte| byte == b'/')
+            .unwrap_or(0);
+
+        self.len = if last_slash == 0 { 1 } else { last_slash };
+    }
+
+    /// Resolve `path` against this directory. An absolute path ignores the directory.
+    pub fn join(&self, path: &str) -> Result<PathName, &'static str> {
+        let mut resolved = if path.starts_with('/') {
+            PathName::root()
+        } else {
+            *self
+        };
+
+        for component in path.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => resolved.pop(),
+                component => resolved.push(component)?,
+            }
+        }
+
+        Ok(resolved)
+    }
+}
+
+impl PartialEq for PathName {
+    fn eq(&self, other: &PathName) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for PathName {}
+
+impl fmt::Debug for PathName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for PathName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Return the working directory of the current process.
+pub fn cwd() -> PathName {
+    SCHEDULER.cwd()
+}
+
+/// Resolve `path` against the working directory of the current process.
+pub fn resolve(path: &str) -> Result<PathName, &'static str> {
+    cwd().join(path)
+}
+
+/// Change the working directory of the current process to `path`, which may be relative. Fails if
+/// the result would be too long, or when called from an interrupt handler.
+///
+/// There is no file system to check the directory against yet, so any well-formed path is
+/// accepted.
+pub fn chdir(path: &str) -> Result<(), &'static str> {
+    let path = resolve(path)?;
+    SCHEDULER
+        .set_cwd(path)
+        .map_err(|_| "cannot change directory from an interrupt handler")
+}
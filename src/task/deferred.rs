@@ -0,0 +1,87 @@
+//! Deferred work, for interrupt handlers which have more to do than they should do with interrupts
+//! disabled.
+//!
+//! A handler does the minimum the hardware needs, such as reading a byte and sending the EOI, and
+//! passes the rest to `defer`. The work is queued on this CPU and runs at the tail of the
+//! outermost interrupt handler, from `irq_exit`, with interrupts enabled again. Tasks may also call
+//! `run_pending`, so work deferred from somewhere without an `irq_exit` is not held up until the
+//! next interrupt.
+//!
+//! Deferred work still runs in interrupt context, so it must not block or yield. Items queued on
+//! one CPU run in order, but a nested interrupt may run later items while an earlier one is still
+//! running.
+
+use arch::percpu::{self, MAX_CPUS};
+use device::io::EventQueue;
+use task::{preempt_disable, preempt_enable};
+
+/// Work waiting to run, one queue per CPU. The queues disable interrupts while they are touched,
+/// so a handler pushing onto one cannot spin on a lock held by the code it interrupted.
+static QUEUES: [EventQueue<fn(), [fn(); 64]>; MAX_CPUS] = [
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+    EventQueue::new(),
+];
+
+fn this_queue() -> &'static EventQueue<fn(), [fn(); 64]> {
+    &QUEUES[percpu::this_cpu().cpu_id]
+}
+
+/// Queue `work` to run on this CPU once the current interrupt handler is done. Returns false if the
+/// queue was full and the work was dropped.
+pub fn defer(work: fn()) -> bool {
+    this_queue().push(work)
+}
+
+/// Run the work queued on this CPU, including anything queued while it runs. Returns how many
+/// items ran.
+pub fn run_pending() -> usize {
+    // The queue belongs to this CPU, so we must not be moved off it halfway through.
+    preempt_disable();
+    let count = drain();
+    preempt_enable();
+
+    count
+}
+
+fn drain() -> usize {
+    let mut count = 0;
+    this_queue().drain(|work| {
+        work();
+        count += 1;
+    });
+
+    count
+}
+
+/// Return the number of items waiting on this CPU.
+pub fn pending() -> usize {
+    this_queue().len()
+}
+
+/// Return the number of items dropped on every CPU because a queue was full.
+pub fn dropped() -> usize {
+    QUEUES.iter().map(|queue| queue.dropped()).sum()
+}
+
+/// Run deferred work at the end of an interrupt handler. This must be called after the EOI, while
+/// the handler's `InterruptContext` is alive. Only the outermost handler runs the work, so an
+/// interrupt taken while it runs just queues more.
+pub fn irq_exit() {
+    if percpu::interrupt_depth() != 1 || pending() == 0 {
+        return;
+    }
+
+    // Timer ticks during the work must not switch tasks under the handler. A resched they defer
+    // is left for the next tick, since this is no place to switch from.
+    preempt_disable();
+    unsafe { asm!("sti" : : : "memory" : "volatile") };
+    drain();
+    unsafe { asm!("cli" : : : "memory" : "volatile") };
+    percpu::set_preempt_count(percpu::preempt_count() - 1);
+}
@@ -2,12 +2,28 @@ pub mod context;
 pub mod process;
 pub mod proc_list;
 pub mod coop_sched;
+pub mod cwd;
+pub mod wait_queue;
+pub mod channel;
+pub mod deferred;
+pub mod policy;
+pub mod preempt;
+pub mod semaphore;
+pub mod sleep;
+pub mod timer_wheel;
 
 use self::coop_sched as scheduler;
+use self::cwd::PathName;
 
-pub use self::process::{Process, ProcessId, State};
+pub use self::process::{ExitCode, Process, ProcessId, ProcessName, State, TLS_SLOTS};
 pub use self::proc_list::ProcessList;
-pub use self::scheduler::Scheduler;
+pub use self::scheduler::{Scheduler, SchedulerStats};
+pub use self::wait_queue::WaitQueue;
+pub use self::channel::{channel, Receiver, Sender};
+pub use self::deferred::defer;
+pub use self::preempt::{preempt_disable, preempt_enable};
+pub use self::semaphore::Semaphore;
+pub use self::timer_wheel::TimerWheel;
 use core::result::Result;
 use alloc::string::String;
 
@@ -16,16 +32,31 @@ pub trait Scheduling {
     fn create(&self, func: extern "C" fn(), name: String) -> Result<ProcessId, i16>;
     fn get_id(&self) -> ProcessId;
     fn kill(&self, id: ProcessId);
+    fn exit(&self, code: ExitCode);
+    fn join(&self, id: ProcessId) -> Result<ExitCode, i16>;
+    fn stack_usage(&self, id: ProcessId) -> Result<usize, i16>;
+    fn set_name(&self, name: &str) -> Result<(), i16>;
+    fn current_name(&self) -> ProcessName;
+    fn tls_get(&self, slot: usize) -> Result<usize, i16>;
+    fn tls_set(&self, slot: usize, value: usize) -> Result<(), i16>;
+    fn cwd(&self) -> PathName;
+    fn set_cwd(&self, path: PathName) -> Result<(), i16>;
     fn ready(&self, id: ProcessId);
+    fn set_affinity(&self, id: ProcessId, cpu: Option<u8>) -> Result<(), i16>;
+    unsafe fn block(&self, id: ProcessId);
+    fn wake(&self, id: ProcessId);
     unsafe fn resched(&self);
 }
 
 /// Max no. of processes we can handle.
 pub const MAX_PROCS: usize = usize::max_value() - 1;
 
-/// Initial size of vector stack.
+/// Size of each task's stack, in words. A whole number of pages.
 pub const INITIAL_STACK: usize = 1024;
 
+/// Byte new stacks are filled with, so that `stack_usage` can tell which parts have been written.
+pub const STACK_FILL: u8 = 0xcc;
+
 lazy_static! {
     /// Global kernel scheduler.
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
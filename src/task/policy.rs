@@ -0,0 +1,75 @@
+//! Scheduling policies, which decide which ready process runs next.
+//!
+//! The scheduler keeps the ready lists and does the switching, and asks its policy which process
+//! on the CPU's ready list to switch to. The process being switched away from is queued again
+//! after the choice is made, so a policy never picks the process which asked to resched. The
+//! policy is round-robin unless the command line asks for another with `sched=<name>`.
+
+use alloc::VecDeque;
+use arch::cmdline;
+use task::ProcessId;
+use task::SCHEDULER;
+
+/// The processes waiting to run on one CPU, in the order they were queued.
+pub type ReadyQueue = VecDeque<ProcessId>;
+
+/// A rule for choosing the next process to run.
+pub trait SchedulerPolicy: Sync {
+    /// Name used to pick the policy on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Return the process in `ready` to run next, or `None` if `ready` is empty, in which case the
+    /// CPU idles. The scheduler removes the process from the queue.
+    fn pick_next(&self, ready: &ReadyQueue) -> Option<ProcessId>;
+}
+
+/// Run processes in the order they became ready.
+pub struct RoundRobin;
+
+impl SchedulerPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn pick_next(&self, ready: &ReadyQueue) -> Option<ProcessId> {
+        ready.front().cloned()
+    }
+}
+
+/// Run the process which became ready most recently. This starves older processes, so it is only
+/// useful for experiments.
+pub struct Lifo;
+
+impl SchedulerPolicy for Lifo {
+    fn name(&self) -> &'static str {
+        "lifo"
+    }
+
+    fn pick_next(&self, ready: &ReadyQueue) -> Option<ProcessId> {
+        ready.back().cloned()
+    }
+}
+
+pub static ROUND_ROBIN: RoundRobin = RoundRobin;
+pub static LIFO: Lifo = Lifo;
+
+/// Every policy which can be picked by name.
+static POLICIES: [&'static SchedulerPolicy; 2] = [&ROUND_ROBIN, &LIFO];
+
+/// Return the policy called `name`.
+pub fn by_name(name: &str) -> Option<&'static SchedulerPolicy> {
+    POLICIES.iter().find(|policy| policy.name() == name).cloned()
+}
+
+/// Switch to the policy given on the command line, if any. This must be called after the command
+/// line is parsed.
+pub fn init() {
+    if let Some(name) = cmdline::option("sched") {
+        match by_name(name) {
+            Some(policy) => SCHEDULER.set_policy(policy),
+            None => println!("[ sched ] Unknown scheduling policy \"{}\", ignoring.", name),
+        }
+    }
+
+    println!("[ sched ] Scheduling policy: {}.", SCHEDULER.policy().name());
+}
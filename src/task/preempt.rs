@@ -0,0 +1,37 @@
+//! Disabling preemption on this CPU, for sections which must not be switched away from but can
+//! still take interrupts.
+//!
+//! While the count is non-zero the timer interrupt still runs, but instead of rescheduling it
+//! marks a resched as pending, and `preempt_enable` performs it once the count drops back to zero.
+//! Only the timer's preemption is held off: code which blocks or yields with preemption disabled
+//! still switches, and must not, since the count belongs to the CPU rather than the task.
+
+use arch::interrupts::disable_interrupts_and_then;
+use arch::percpu;
+use task::{Scheduling, SCHEDULER};
+
+/// Stop the timer from switching tasks on this CPU until the matching `preempt_enable`. Calls
+/// nest.
+pub fn preempt_disable() {
+    // A single instruction, so an interrupt sees the count either before or after.
+    unsafe { asm!("add qword ptr gs:[56], 1" : : : "memory" : "intel", "volatile") };
+}
+
+/// Undo one `preempt_disable`. When the last one is undone, run any resched the timer deferred.
+pub fn preempt_enable() {
+    disable_interrupts_and_then(|| {
+        let count = percpu::preempt_count();
+        assert!(count > 0, "preempt_enable without preempt_disable");
+        percpu::set_preempt_count(count - 1);
+
+        if count == 1 && percpu::resched_pending() {
+            percpu::set_resched_pending(false);
+            unsafe { SCHEDULER.resched() };
+        }
+    })
+}
+
+/// Return whether preemption is disabled on this CPU.
+pub fn preempt_disabled() -> bool {
+    percpu::preempt_count() != 0
+}
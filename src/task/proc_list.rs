@@ -1,9 +1,8 @@
 use alloc::btree_map::{self, BTreeMap};
-use alloc::vec::Vec;
 use alloc::arc::Arc;
 use core::result::Result;
 use spin::RwLock;
-use task::{Process, ProcessId, State};
+use task::{Process, ProcessId, ProcessName, State};
 
 /// System task table.
 pub struct ProcessList {
@@ -20,8 +19,8 @@ impl ProcessList {
 
         // The inital kernel thread, with pid 0.
         let mut null_proc: Process = Process::new(ProcessId::NULL_PROC);
+        null_proc.name = ProcessName::new("kernel");
         null_proc.state = State::Current;
-        null_proc.stack = Some(Vec::new());
 
         // Insert this process into the list.
         list.insert(ProcessId::NULL_PROC, Arc::new(RwLock::new(null_proc)));
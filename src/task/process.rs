@@ -1,6 +1,10 @@
-use alloc::string::String;
-use alloc::vec::Vec;
+use alloc::arc::Arc;
+use arch::memory::Stack;
+use core::{cmp, fmt, slice, str};
 use task::context::Context;
+use task::cwd::PathName;
+use task::wait_queue::WaitQueue;
+use task::STACK_FILL;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// Current state of the process.
@@ -13,6 +17,8 @@ pub enum State {
     Suspended,
     /// Process is ready to be ran by the scheduler.
     Ready,
+    /// Process is waiting on a `WaitQueue` and will not be scheduled until woken.
+    Blocked,
 }
 
 #[derive(Clone, Debug)]
@@ -32,27 +38,112 @@ impl ProcessId {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// The value a process returned when it exited.
+pub struct ExitCode(pub i32);
+
+impl ExitCode {
+    /// Exit code of a process that returned normally from its entry point.
+    pub const SUCCESS: ExitCode = ExitCode(0);
+    /// Exit code of a process that was killed rather than exiting itself.
+    pub const KILLED: ExitCode = ExitCode(-1);
+    /// Exit code of a process that was terminated for raising a fault in user mode.
+    pub const FAULTED: ExitCode = ExitCode(-2);
+}
+
+/// Longest process name in bytes. Longer names are truncated.
+pub const NAME_LEN: usize = 32;
+
+/// Number of task-local storage slots each process has.
+pub const TLS_SLOTS: usize = 4;
+
+#[derive(Clone, Copy)]
+/// A process name, stored inline so that renaming a process never allocates.
+pub struct ProcessName {
+    bytes: [u8; NAME_LEN],
+    len: usize,
+}
+
+impl ProcessName {
+    /// Create a name from `name`, truncated to `NAME_LEN` bytes on a character boundary.
+    pub fn new(name: &str) -> Self {
+        let mut len = cmp::min(name.len(), NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+        ProcessName {
+            bytes: bytes,
+            len: len,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a `&str` cut on a character boundary.
+        unsafe { str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
+
+impl fmt::Debug for ProcessName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for ProcessName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
 /// A single process on the system.
 /// It has register context, id, name and an Optional process stack.
 pub struct Process {
     pub pid: ProcessId,
-    pub name: String,
+    pub name: ProcessName,
     pub state: State,
     pub priority: Priority,
     pub ctx: Context,
-    pub stack: Option<Vec<usize>>,
+    pub stack: Option<Stack>,
+    /// Set once the process has exited.
+    pub exit_code: Option<ExitCode>,
+    /// Processes waiting for this process to exit.
+    pub joiners: Arc<WaitQueue>,
+    /// The logical CPU the process is pinned to, or `None` to run on any CPU.
+    pub cpu_affinity: Option<u8>,
+    /// Bit mask of the logical CPUs the process has been switched to on.
+    pub ran_on: usize,
+    /// Task-local storage, zero until the process sets it.
+    pub tls: [usize; TLS_SLOTS],
+    /// Directory relative paths are resolved against.
+    pub cwd: PathName,
+    /// The per-CPU lock counts (see `sync`) while the process is switched out.
+    pub held_locks: usize,
+    /// The per-CPU preemption count (see `preempt`) while the process is switched out.
+    pub preempt_count: usize,
 }
 
 impl Process {
     pub fn new(id: ProcessId) -> Self {
         Process {
             pid: id,
-            name: String::from("new_proc"),
+            name: ProcessName::new("new_proc"),
             state: State::Suspended,
             priority: Priority(0),
             ctx: Context::new(),
             stack: None,
+            exit_code: None,
+            joiners: Arc::new(WaitQueue::new()),
+            cpu_affinity: None,
+            ran_on: 0,
+            tls: [0; TLS_SLOTS],
+            cwd: PathName::root(),
+            held_locks: 0,
+            preempt_count: 0,
         }
     }
 
@@ -66,6 +157,21 @@ impl Process {
         self.ctx.set_page_table(addr);
     }
 
+    /// Estimate the most stack this process has used, in bytes, or `None` if it has no stack.
+    ///
+    /// Stacks start out filled with `STACK_FILL` and grow down, so everything below the lowest
+    /// byte which differs from it has never been written. This is only an estimate: a process
+    /// which happens to write `STACK_FILL` at the deepest point it reached is under-counted.
+    pub fn stack_usage(&self) -> Option<usize> {
+        let stack = self.stack.as_ref()?;
+        let bytes = unsafe {
+            slice::from_raw_parts(stack.bottom() as *const u8, stack.top() - stack.bottom())
+        };
+
+        let untouched = bytes.iter().take_while(|&&byte| byte == STACK_FILL).count();
+        Some(bytes.len() - untouched)
+    }
+
     /// Set the stack pointer register.
     pub fn set_stack(&mut self, addr: usize) {
         self.ctx.set_stack(addr);
@@ -85,8 +191,6 @@ pub unsafe extern "C" fn process_return() {
 
     let scheduler = Box::from_raw(scheduler_ptr);
 
-    let current: ProcessId = scheduler.get_id();
-
-    // Process returned, we kill it
-    scheduler.kill(current);
+    // Process returned, so it exited successfully.
+    scheduler.exit(ExitCode::SUCCESS);
 }
@@ -0,0 +1,58 @@
+//! Counting semaphores for bounding how many processes use a resource at once. A process which
+//! finds the count at zero blocks on a `WaitQueue`, so it does not use any CPU time while waiting.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use task::WaitQueue;
+
+/// A counting semaphore. `acquire` takes one unit of the count, blocking while there are none
+/// left, and `release` gives one back.
+#[derive(Debug)]
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Create a semaphore which lets `count` processes hold it at once.
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take one unit of the count without blocking. Returns `false` if the count is zero.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::SeqCst);
+
+        while count > 0 {
+            match self.count
+                .compare_exchange(count, count - 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(current) => count = current,
+            }
+        }
+
+        false
+    }
+
+    /// Take one unit of the count, blocking the current process while it is zero. The count is
+    /// checked with interrupts disabled before blocking, so a `release` from an interrupt handler
+    /// between the check and the block is never missed.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Give back one unit of the count and wake the longest waiting process. This never blocks, so
+    /// it is safe to call from an interrupt handler.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    /// Return the number of units currently available.
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
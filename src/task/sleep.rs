@@ -0,0 +1,57 @@
+//! Putting processes to sleep for a number of timer ticks. Sleeping processes are kept in a
+//! `TimerWheel`, so the timer interrupt only looks at the processes due to wake on that tick.
+
+use arch::interrupts::disable_interrupts_and_then;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use task::{ProcessId, Scheduling, SCHEDULER};
+use task::timer_wheel::TimerWheel;
+
+/// Most processes which can sleep at once.
+const MAX_SLEEPERS: usize = 256;
+
+/// Number of timer ticks since boot.
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref SLEEPERS: Mutex<TimerWheel<ProcessId>> = Mutex::new(TimerWheel::new(MAX_SLEEPERS));
+}
+
+/// Block the current process for `ticks` timer ticks. Fails if too many processes are asleep.
+pub fn sleep(ticks: u64) -> Result<(), i16> {
+    disable_interrupts_and_then(|| {
+        let current = SCHEDULER.get_id();
+
+        {
+            let mut sleepers = SLEEPERS.lock();
+            let deadline = sleepers.now() + ticks;
+            if sleepers.insert(deadline, current).is_err() {
+                return Err(-1);
+            }
+        }
+
+        unsafe { SCHEDULER.block(current) };
+        Ok(())
+    })
+}
+
+/// Advance the sleep clock by one tick and wake the processes due. Called by the timer interrupt.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    SLEEPERS.lock().advance();
+
+    loop {
+        // The wheel is not locked while waking, since waking takes the scheduler's locks.
+        let next = SLEEPERS.lock().pop_expired();
+        match next {
+            Some(id) => SCHEDULER.wake(id),
+            None => return,
+        }
+    }
+}
+
+/// Return the number of timer ticks since boot. Unlike the sleep clock, this can be read without
+/// taking a lock.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,184 @@
+//! A hierarchical timer wheel, which finds the timers due on a tick without looking at the others.
+//!
+//! Timers due within `LEVEL_SLOTS` ticks sit in the first level, one slot per tick. Timers due
+//! within `LEVEL_SLOTS * LEVEL_SLOTS` ticks sit in the second level, one slot per `LEVEL_SLOTS`
+//! ticks, and a slot is moved down into the first level when the wheel reaches it. Anything later
+//! goes on an overflow list, which is sorted back into the wheel each time the second level comes
+//! round. So a tick only touches the timers due on it, plus one second level slot every
+//! `LEVEL_SLOTS` ticks and the overflow list once per rotation.
+//!
+//! Timers are nodes in a table allocated up front and linked into the slots by index, so neither
+//! inserting nor advancing allocates, and the wheel can be advanced from an interrupt handler.
+
+use alloc::vec::Vec;
+
+/// Number of slots in each level of the wheel.
+const LEVEL_SLOTS: u64 = 64;
+/// Number of ticks covered by the first level, and by each slot of the second.
+const LEVEL_0_SPAN: u64 = LEVEL_SLOTS;
+/// Number of ticks covered by the whole wheel.
+const WHEEL_SPAN: u64 = LEVEL_SLOTS * LEVEL_SLOTS;
+
+struct Node<T> {
+    deadline: u64,
+    value: Option<T>,
+    next: Option<usize>,
+}
+
+pub struct TimerWheel<T> {
+    /// The current tick.
+    now: u64,
+    nodes: Vec<Node<T>>,
+    /// Head of the list of unused nodes.
+    free: Option<usize>,
+    level_0: [Option<usize>; LEVEL_SLOTS as usize],
+    level_1: [Option<usize>; LEVEL_SLOTS as usize],
+    /// Timers due after the end of the wheel.
+    overflow: Option<usize>,
+    /// Timers which are due, in the order they became due, waiting for `pop_expired`.
+    expired_head: Option<usize>,
+    expired_tail: Option<usize>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    /// Create a wheel starting at tick 0, with room for `capacity` timers.
+    pub fn new(capacity: usize) -> Self {
+        let mut nodes = Vec::with_capacity(capacity);
+        for index in 0..capacity {
+            nodes.push(Node {
+                deadline: 0,
+                value: None,
+                next: if index + 1 < capacity {
+                    Some(index + 1)
+                } else {
+                    None
+                },
+            });
+        }
+
+        TimerWheel {
+            now: 0,
+            nodes: nodes,
+            free: if capacity > 0 { Some(0) } else { None },
+            level_0: [None; LEVEL_SLOTS as usize],
+            level_1: [None; LEVEL_SLOTS as usize],
+            overflow: None,
+            expired_head: None,
+            expired_tail: None,
+            len: 0,
+        }
+    }
+
+    /// Return the current tick.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Return the number of timers which have not been popped yet.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Add a timer which expires with `value` on tick `deadline`. A deadline which has already
+    /// passed expires on the next tick. Gives the value back if the wheel is full.
+    pub fn insert(&mut self, deadline: u64, value: T) -> Result<(), T> {
+        let index = match self.free {
+            Some(index) => index,
+            None => return Err(value),
+        };
+
+        self.free = self.nodes[index].next;
+        self.nodes[index].deadline = if deadline > self.now {
+            deadline
+        } else {
+            self.now + 1
+        };
+        self.nodes[index].value = Some(value);
+        self.len += 1;
+
+        self.place(index);
+        Ok(())
+    }
+
+    /// Move to the next tick, and queue the timers due on it for `pop_expired`. Returns the number
+    /// of timers touched, which is the work done for this tick.
+    pub fn advance(&mut self) -> usize {
+        let mut work = 0;
+        self.now += 1;
+
+        // The overflow list is sorted first, since some of it may belong in the second level slot
+        // which is about to be moved down.
+        if self.now % WHEEL_SPAN == 0 {
+            let mut next = self.overflow.take();
+            while let Some(index) = next {
+                next = self.nodes[index].next;
+                self.place(index);
+                work += 1;
+            }
+        }
+
+        if self.now % LEVEL_0_SPAN == 0 {
+            let slot = ((self.now / LEVEL_0_SPAN) % LEVEL_SLOTS) as usize;
+            let mut next = self.level_1[slot].take();
+            while let Some(index) = next {
+                next = self.nodes[index].next;
+                self.place(index);
+                work += 1;
+            }
+        }
+
+        let slot = (self.now % LEVEL_SLOTS) as usize;
+        let mut next = self.level_0[slot].take();
+        while let Some(index) = next {
+            next = self.nodes[index].next;
+            self.push_expired(index);
+            work += 1;
+        }
+
+        work
+    }
+
+    /// Take the value of the next timer which has expired.
+    pub fn pop_expired(&mut self) -> Option<T> {
+        let index = self.expired_head?;
+
+        self.expired_head = self.nodes[index].next;
+        if self.expired_head.is_none() {
+            self.expired_tail = None;
+        }
+
+        self.nodes[index].next = self.free;
+        self.free = Some(index);
+        self.len -= 1;
+
+        self.nodes[index].value.take()
+    }
+
+    /// Link node `index` into the slot for its deadline, which must not have passed.
+    fn place(&mut self, index: usize) {
+        let deadline = self.nodes[index].deadline;
+        let delta = deadline - self.now;
+
+        let head = if delta < LEVEL_0_SPAN {
+            &mut self.level_0[(deadline % LEVEL_SLOTS) as usize]
+        } else if delta < WHEEL_SPAN {
+            &mut self.level_1[((deadline / LEVEL_0_SPAN) % LEVEL_SLOTS) as usize]
+        } else {
+            &mut self.overflow
+        };
+
+        self.nodes[index].next = *head;
+        *head = Some(index);
+    }
+
+    fn push_expired(&mut self, index: usize) {
+        self.nodes[index].next = None;
+
+        match self.expired_tail {
+            Some(tail) => self.nodes[tail].next = Some(index),
+            None => self.expired_head = Some(index),
+        }
+        self.expired_tail = Some(index);
+    }
+}
@@ -0,0 +1,103 @@
+use alloc::VecDeque;
+use arch::interrupts::{self, disable_interrupts_and_then};
+use spin::Mutex;
+use task::{ProcessId, Scheduling, SCHEDULER};
+
+/// A queue of processes blocked on some event. A process calling `wait` is marked as blocked and
+/// removed from the ready list until another process (or an interrupt handler) wakes it.
+#[derive(Debug)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<ProcessId>>,
+}
+
+impl WaitQueue {
+    /// Create an empty wait queue.
+    pub fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current process until it is woken by `wake_one` or `wake_all`. Interrupts are
+    /// disabled between queueing the process and blocking it, so a wakeup cannot be lost.
+    pub fn wait(&self) {
+        disable_interrupts_and_then(|| {
+            let current = SCHEDULER.get_id();
+            self.waiters.lock().push_back(current);
+
+            unsafe { SCHEDULER.block(current) };
+        });
+    }
+
+    /// Block the current process until `condition` returns true. The condition is checked with
+    /// interrupts disabled before each wait, so an event that happens between the check and the
+    /// wait is never missed.
+    ///
+    /// If nothing else is ready to run on this CPU, `block` comes straight back with the process
+    /// still queued. It then halts until the next interrupt, rather than spin, and is not queued a
+    /// second time.
+    pub fn wait_until<F>(&self, condition: F)
+    where
+        F: Fn() -> bool,
+    {
+        let current = SCHEDULER.get_id();
+
+        loop {
+            let done = disable_interrupts_and_then(|| {
+                if condition() {
+                    return true;
+                }
+
+                {
+                    let mut waiters = self.waiters.lock();
+                    if !waiters.contains(&current) {
+                        waiters.push_back(current);
+                    }
+                }
+                unsafe { SCHEDULER.block(current) };
+
+                false
+            });
+
+            if done {
+                break;
+            }
+
+            // Checked with interrupts disabled, so that a wakeup from an interrupt handler either
+            // comes before the check or ends the halt.
+            unsafe { asm!("cli" : : : "memory" : "volatile") };
+            if self.waiters.lock().contains(&current) {
+                unsafe { interrupts::enable_and_halt() };
+            } else {
+                unsafe { asm!("sti" : : : "memory" : "volatile") };
+            }
+        }
+
+        // A process which stopped waiting without being woken is still queued, where it would
+        // take a wakeup meant for another waiter.
+        disable_interrupts_and_then(|| self.waiters.lock().retain(|&id| id != current));
+    }
+
+    /// Wake the process that has been waiting the longest. Returns `false` if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        let next = self.waiters.lock().pop_front();
+
+        match next {
+            Some(id) => {
+                SCHEDULER.wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every process waiting on this queue.
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+
+    /// Return true if no processes are waiting on this queue.
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
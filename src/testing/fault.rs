@@ -0,0 +1,101 @@
+//! Expected faults, for tests which check that an operation faults without ending the test.
+//!
+//! A test sets an expectation with `expect_fault`, giving the vector and the length of the
+//! instruction which should fault. When the fault arrives, the exception handler records it, clears
+//! the expectation and resumes execution just after the faulting instruction, so the test carries
+//! on and can inspect the fault with `take_fault`. A fault on any other vector, or when no fault is
+//! expected, still fails the test.
+//!
+//! `probe_read` and `probe_write` wrap this for single memory accesses, using instructions of a
+//! known length.
+
+use arch::interrupts::redirect_frame;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering, ATOMIC_U8_INIT, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+/// The expected fault's vector plus one, or zero if no fault is expected.
+pub static EXPECTED_FAULT: AtomicU8 = ATOMIC_U8_INIT;
+/// Length of the instruction expected to fault.
+static INSTRUCTION_LEN: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The last expected fault which was caught.
+static LAST_FAULT: Mutex<Option<Fault>> = Mutex::new(None);
+
+/// A fault caught while it was expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    pub instruction_pointer: usize,
+    /// The stack pointer of the exception handler which caught the fault.
+    pub stack_pointer: usize,
+}
+
+/// Expect the next instruction of length `instruction_len` to raise exception `vector`.
+pub fn expect_fault(vector: u8, instruction_len: usize) {
+    *LAST_FAULT.lock() = None;
+    INSTRUCTION_LEN.store(instruction_len, Ordering::SeqCst);
+    EXPECTED_FAULT.store(vector + 1, Ordering::SeqCst);
+}
+
+/// Clear the expectation, and return the fault caught since `expect_fault`, if there was one.
+pub fn take_fault() -> Option<Fault> {
+    EXPECTED_FAULT.store(0, Ordering::SeqCst);
+    LAST_FAULT.lock().take()
+}
+
+/// If exception `vector` was expected, record it and move `stack_frame` past the faulting
+/// instruction. Returns whether the fault was recovered from.
+pub fn recover(
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &mut ExceptionStackFrame,
+) -> bool {
+    if EXPECTED_FAULT.compare_and_swap(vector + 1, 0, Ordering::SeqCst) != vector + 1 {
+        return false;
+    }
+
+    let stack_pointer: usize;
+    unsafe { asm!("mov $0, rsp" : "=r"(stack_pointer) : : : "intel", "volatile") };
+
+    let instruction_pointer = stack_frame.instruction_pointer.0;
+    *LAST_FAULT.lock() = Some(Fault {
+        vector: vector,
+        error_code: error_code,
+        instruction_pointer: instruction_pointer,
+        stack_pointer: stack_pointer,
+    });
+
+    let resume = instruction_pointer + INSTRUCTION_LEN.load(Ordering::SeqCst);
+    let stack_pointer = stack_frame.stack_pointer.0;
+    redirect_frame(stack_frame, resume, stack_pointer);
+
+    true
+}
+
+/// Read a `u64` from `address`, expecting it to raise exception `vector`. Returns the value if it
+/// did not fault.
+pub unsafe fn probe_read(vector: u8, address: usize) -> Result<u64, Fault> {
+    let value: u64;
+
+    // `mov rcx, [rax]` is encoded in 3 bytes: 48 8b 08.
+    expect_fault(vector, 3);
+    asm!("mov rcx, [rax]" : "={rcx}"(value) : "{rax}"(address) : "memory" : "intel", "volatile");
+
+    match take_fault() {
+        Some(fault) => Err(fault),
+        None => Ok(value),
+    }
+}
+
+/// Write a `u64` to `address`, expecting it to raise exception `vector`.
+pub unsafe fn probe_write(vector: u8, address: usize, value: u64) -> Result<(), Fault> {
+    // `mov [rax], rcx` is encoded in 3 bytes: 48 89 08.
+    expect_fault(vector, 3);
+    asm!("mov [rax], rcx" : : "{rax}"(address), "{rcx}"(value) : "memory" : "intel", "volatile");
+
+    match take_fault() {
+        Some(fault) => Err(fault),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,246 @@
+//! In-kernel test harness.
+//!
+//! Building with the `kernel-test` feature makes `kmain` run every test in `tests::TESTS` after
+//! init instead of starting the kernel proper. QEMU is told to exit through its `isa-debug-exit`
+//! device, so the exit status of QEMU reports whether the tests passed. `make test` builds the
+//! kernel this way and runs it.
+//!
+//! A test is a plain function which panics on failure, usually through `assert!`. A test can
+//! instead be expected to panic, or to raise a particular CPU exception. When a test panics or
+//! faults, the panic handler or exception handler hands control back to the harness, which records
+//! the result and carries on with the next test. The failed test's stack is abandoned. After an
+//! exception, the harness leaves the exception handler first, since the handler may be running on
+//! an interrupt stack which the next exception of its kind would reuse. To check that an operation
+//! faults and then carry on with the test, see `fault`.
+//!
+//! Results are written to serial one per line, so that a script on the host can collect them:
+//!
+//! ```text
+//! TEST <name> START
+//! TEST <name> OK
+//! TEST <name> FAIL <message>
+//! TESTS DONE <passed> passed, <failed> failed
+//! ```
+//!
+//! A `START` line with no result after it means the test hung, which the host detects with a
+//! timeout.
+
+use arch::interrupts::{halt_forever, redirect_frame};
+use arch::platform;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use device::io::Port;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+pub mod fault;
+
+/// The I/O port QEMU's `isa-debug-exit` device is configured to listen on.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Values written to the `isa-debug-exit` device. QEMU exits with status `(value << 1) | 1`, so
+/// success is 33 and failure is 35. Neither can be confused with QEMU exiting normally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exit QEMU with the given code. On real hardware, or without the `isa-debug-exit` device, nothing
+/// happens and we halt instead. The port is only written under QEMU, since on a real machine it
+/// may belong to another device.
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    if platform::is_qemu() {
+        unsafe {
+            let mut port: Port<u32> = Port::new(ISA_DEBUG_EXIT_PORT);
+            port.write(code as u32);
+        }
+    }
+
+    halt_forever()
+}
+
+/// How a test is expected to end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    /// Return normally.
+    Pass,
+    /// Panic.
+    Panic,
+    /// Raise the CPU exception with this vector.
+    Exception(u8),
+}
+
+/// A named kernel test.
+pub struct TestCase {
+    pub name: &'static str,
+    pub func: fn(),
+    pub expect: Expect,
+}
+
+/// Build a `TestCase` from a test function, named after the function's path. A test can be marked
+/// `should_panic`, or `exception = <vector>` if it is expected to raise that CPU exception.
+macro_rules! test_case {
+    ($func:ident) => {
+        test_case!($func, ::testing::Expect::Pass)
+    };
+    ($func:ident, should_panic) => {
+        test_case!($func, ::testing::Expect::Panic)
+    };
+    ($func:ident, exception = $vector:expr) => {
+        test_case!($func, ::testing::Expect::Exception($vector))
+    };
+    ($func:ident, $expect:expr) => {
+        ::testing::TestCase {
+            name: concat!(module_path!(), "::", stringify!($func)),
+            func: $func,
+            expect: $expect,
+        }
+    };
+}
+
+// Declared after `test_case!` so that the tests can use it.
+#[cfg(feature = "kernel-test")]
+pub mod tests;
+
+/// The tests being run.
+static mut TESTS: &'static [TestCase] = &[];
+/// Index of the test currently running.
+static CURRENT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Whether a test is currently running.
+static RUNNING: AtomicBool = ATOMIC_BOOL_INIT;
+/// The stack pointer of `run_from` when it started the running test.
+static TEST_STACK_POINTER: AtomicUsize = ATOMIC_USIZE_INIT;
+static PASSED: AtomicUsize = ATOMIC_USIZE_INIT;
+static FAILED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Run each test in turn, then exit QEMU reporting whether they all passed.
+pub fn run_tests(tests: &'static [TestCase]) -> ! {
+    unsafe { TESTS = tests };
+
+    println!("TESTS START {}", tests.len());
+    run_from(0);
+}
+
+fn run_from(first: usize) -> ! {
+    let tests = unsafe { TESTS };
+
+    for (index, test) in tests.iter().enumerate().skip(first) {
+        let stack_pointer: usize;
+        unsafe { asm!("mov $0, rsp" : "=r"(stack_pointer) : : : "intel", "volatile") };
+        TEST_STACK_POINTER.store(stack_pointer, Ordering::SeqCst);
+
+        CURRENT.store(index, Ordering::SeqCst);
+        RUNNING.store(true, Ordering::SeqCst);
+        fault::take_fault();
+        println!("TEST {} START", test.name);
+
+        (test.func)();
+
+        RUNNING.store(false, Ordering::SeqCst);
+        match test.expect {
+            Expect::Pass => pass(test),
+            Expect::Panic => fail(test, format_args!("returned, but was expected to panic")),
+            Expect::Exception(vector) => fail(
+                test,
+                format_args!("returned, but was expected to raise exception {}", vector),
+            ),
+        }
+    }
+
+    let passed = PASSED.load(Ordering::SeqCst);
+    let failed = FAILED.load(Ordering::SeqCst);
+    println!("TESTS DONE {} passed, {} failed", passed, failed);
+
+    if failed == 0 {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+fn pass(test: &TestCase) {
+    PASSED.fetch_add(1, Ordering::SeqCst);
+    println!("TEST {} OK", test.name);
+}
+
+fn fail(test: &TestCase, message: fmt::Arguments) {
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    println!("TEST {} FAIL {}", test.name, message);
+}
+
+/// Return the test which is running, if any.
+fn current() -> Option<&'static TestCase> {
+    if RUNNING.load(Ordering::SeqCst) {
+        unsafe { TESTS.get(CURRENT.load(Ordering::SeqCst)) }
+    } else {
+        None
+    }
+}
+
+/// Called by the panic handler in test builds. Records the result of the running test and moves on
+/// to the next one. A panic outside of a test fails the whole run.
+pub fn test_panicked(message: fmt::Arguments, file: &'static str, line: u32) -> ! {
+    let test = match current() {
+        Some(test) => test,
+        None => exit_qemu(QemuExitCode::Failed),
+    };
+
+    RUNNING.store(false, Ordering::SeqCst);
+    if test.expect == Expect::Panic {
+        pass(test);
+    } else {
+        fail(test, format_args!("panicked at {}:{}: {}", file, line, message));
+    }
+
+    run_from(CURRENT.load(Ordering::SeqCst) + 1);
+}
+
+/// Called by exception handlers in test builds before they handle an exception themselves.
+///
+/// If the fault was expected through `fault::expect_fault`, the stack frame is updated to resume
+/// after the faulting instruction and this returns true. Otherwise, if a test is running, the
+/// result is recorded and the stack frame is pointed at the harness, which moves on to the next
+/// test once the handler returns, so this returns true as well. If no test is running it returns
+/// false and the exception is handled as usual.
+pub fn exception_raised(
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &mut ExceptionStackFrame,
+) -> bool {
+    if fault::recover(vector, error_code, stack_frame) {
+        return true;
+    }
+
+    let test = match current() {
+        Some(test) => test,
+        None => return false,
+    };
+
+    RUNNING.store(false, Ordering::SeqCst);
+    if test.expect == Expect::Exception(vector) {
+        pass(test);
+    } else {
+        fail(
+            test,
+            format_args!(
+                "raised exception {} (error code {:?}) at {:#x}",
+                vector, error_code, stack_frame.instruction_pointer.0
+            ),
+        );
+    }
+
+    // Return to the harness on the stack the tests run on rather than carrying on in the handler,
+    // which may be on an interrupt stack. The stack pointer is aligned as if `continue_tests` had
+    // been called.
+    let stack_pointer = (TEST_STACK_POINTER.load(Ordering::SeqCst) & !0xf) - 8;
+    redirect_frame(stack_frame, continue_tests as usize, stack_pointer);
+
+    true
+}
+
+/// Where an exception handler returns to after a test ended in an exception.
+extern "C" fn continue_tests() -> ! {
+    run_from(CURRENT.load(Ordering::SeqCst) + 1);
+}
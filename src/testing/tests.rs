@@ -0,0 +1,2014 @@
+//! The kernel's tests. Every test must be listed in `TESTS` to be run.
+
+use alloc::{String, Vec};
+use arch::interrupts::{disable_interrupts_and_then, IdtBuilder, InterruptGuard};
+use arch::interrupts::exceptions::{GPF_VECTOR, INVALID_OPCODE_VECTOR, PAGE_FAULT_VECTOR};
+use arch::interrupts::probe;
+use arch::msr::Msr;
+use arch::memory::{self, dma, peek, poke, Frame, LOW_MEMORY_END, PAGE_SIZE};
+use arch::memory::paging::{ActivePageTable, EntryFlags, Mapper, Page, PageTableWalker};
+use arch::memory::paging::PhysicalAddress;
+use arch::memory::paging::VirtualAddress;
+use arch::memory::shared::{self, ShmHandle};
+use arch::percpu;
+use arch::symbols;
+use arch::time;
+use core::{ptr, usize};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use device::apic::{self, APIC_MANAGER};
+use device::io::EventQueue;
+use device::serial::{self, SERIAL_INPUT};
+use device::vga::{self, buffer};
+use device::serial_command::{self, DLE};
+use shell;
+use spin::Mutex;
+use syscall;
+use task::{preempt_disable, preempt_enable, sleep, ExitCode, Scheduling, Semaphore, TimerWheel};
+use task::{deferred, INITIAL_STACK, SCHEDULER};
+use task::cwd::{self, PathName};
+use testing::TestCase;
+use testing::fault::probe_write;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+pub static TESTS: &[TestCase] = &[
+    test_case!(event_queue_overflow),
+    test_case!(event_queue_wraparound),
+    test_case!(serial_input_normalizes_enter),
+    test_case!(serial_bulk_output_drops_nothing),
+    test_case!(vga_80x50_scrolls_all_rows),
+    test_case!(deferred_work_runs_after_handler),
+    test_case!(usable_frames_leave_out_reserved_memory),
+    test_case!(efi_memory_map_steps_by_descriptor_size),
+    test_case!(unmap_returns_the_mapped_frame),
+    test_case!(tls_slots_are_per_task),
+    test_case!(paths_resolve_against_cwd),
+    test_case!(qemu_is_detected),
+    test_case!(lifo_policy_runs_newest_first),
+    test_case!(nmi_profiler_samples_cli_sections),
+    test_case!(emergency_print_ignores_held_locks),
+    test_case!(kernel_code_is_never_writable),
+    test_case!(interrupts_are_counted_per_vector),
+    test_case!(page_fault_runs_on_its_own_stack),
+    test_case!(alignment_at_page_and_huge_page_boundaries),
+    test_case!(heap_grows_lazily),
+    test_case!(frame_bitmap_hands_back_lowest_first),
+    test_case!(freed_frames_are_reused),
+    test_case!(huge_page_maps_two_mebibytes),
+    test_case!(physical_memory_is_linearly_mapped),
+    test_case!(demand_paged_region_maps_on_first_touch),
+    test_case!(copy_on_write_copies_on_first_write),
+    test_case!(address_space_tracks_areas),
+    test_case!(small_allocations_come_from_slabs),
+    test_case!(heap_grows_past_its_initial_size),
+    test_case!(kernel_stacks_have_guard_pages),
+    test_case!(freed_stacks_are_reused),
+    test_case!(batched_flushes_are_targeted),
+    test_case!(shootdowns_cover_ranges),
+    test_case!(ranges_map_and_unmap_in_windows),
+    test_case!(mappings_can_be_made_read_only),
+    test_case!(empty_page_tables_are_freed),
+    test_case!(writable_executable_mappings_need_an_exception),
+    test_case!(user_copies_check_their_buffers),
+    test_case!(lock_counts_survive_out_of_order_release),
+    test_case!(shell_dispatches_registered_command),
+    test_case!(write_to_rodata_faults, exception = PAGE_FAULT_VECTOR),
+    test_case!(probe_write_to_rodata_recovers),
+    test_case!(probe_catches_invalid_msr),
+    test_case!(nested_probe_unwinds_to_catching_probe),
+    test_case!(peek_vga_buffer),
+    test_case!(poke_unmapped_fails),
+    test_case!(peek_across_unmapped_page_fails),
+    test_case!(map_apic_at_chosen_page),
+    test_case!(bsp_apic_id_is_read_from_the_bsp),
+    test_case!(dma_buffer_translates),
+    test_case!(scattered_frames_are_all_or_nothing),
+    test_case!(low_memory_is_kept_apart),
+    test_case!(walker_finds_vga_and_kernel),
+    test_case!(is_mapped_agrees_with_translate),
+    test_case!(symbols_resolve_to_preceding_symbol),
+    test_case!(page_iter_stops_at_last_page),
+    test_case!(page_checked_add_stays_canonical),
+    test_case!(page_add_past_last_page_panics, should_panic),
+    test_case!(frame_iter_stops_at_last_frame),
+    test_case!(semaphore_admits_two_tasks),
+    test_case!(stack_usage_covers_recursion),
+    test_case!(shared_memory_outlives_creator_mapping),
+    test_case!(timer_wheel_expires_in_deadline_order),
+    test_case!(pinned_task_runs_only_on_its_cpu),
+    test_case!(steal_takes_only_unpinned_tasks),
+    test_case!(tsc_times_context_switch),
+    test_case!(scheduler_stats_count_switches),
+    test_case!(preempt_disable_defers_resched),
+    test_case!(unhandled_interrupt_returns),
+    test_case!(idt_builder_requires_double_fault),
+    test_case!(idt_builder_rejects_double_assignment),
+];
+
+/// The VGA text buffer, which is identity mapped.
+const VGA_BUFFER: usize = 0xb8000;
+/// A page in a P4 slot nothing else uses, for tests which need a free virtual page.
+const SCRATCH_PAGE: usize = 0o_000_003_000_000_0000;
+/// A 2 MiB aligned page in the same P4 slot, whose P2 entry no other test uses.
+const SCRATCH_HUGE_PAGE: usize = 0o_000_003_001_000_0000;
+/// Another 2 MiB of the same P4 slot, for tests which reserve a demand-paged region.
+const SCRATCH_DEMAND: usize = 0o_000_003_002_000_0000;
+/// Two more 2 MiB of the same P4 slot, for tests which map a range across a P1 table boundary.
+const SCRATCH_RANGE: usize = 0o_000_003_003_000_0000;
+/// Another 2 MiB of the same P4 slot, whose P1 table no other test creates.
+const SCRATCH_TABLES: usize = 0o_000_003_005_000_0000;
+
+/// Fill a queue, returning how many events fit.
+fn fill(queue: &EventQueue<usize, [usize; 8]>) -> usize {
+    let mut pushed = 0;
+    while queue.push(pushed) {
+        pushed += 1;
+    }
+
+    pushed
+}
+
+/// Pushing to a full queue drops the new event and counts it, keeping the old events intact.
+fn event_queue_overflow() {
+    let queue: EventQueue<usize, [usize; 8]> = EventQueue::new();
+
+    let capacity = fill(&queue);
+    assert!(capacity > 0);
+    assert_eq!(queue.dropped(), 1);
+
+    assert!(!queue.push(100));
+    assert_eq!(queue.dropped(), 2);
+    assert_eq!(queue.len(), capacity);
+
+    for expected in 0..capacity {
+        assert_eq!(queue.pop(), Some(expected));
+    }
+    assert_eq!(queue.pop(), None);
+}
+
+/// Events come out in order after the ring buffer's indices have wrapped around several times.
+fn event_queue_wraparound() {
+    let queue: EventQueue<usize, [usize; 8]> = EventQueue::new();
+
+    let mut next_in = 0;
+    let mut next_out = 0;
+
+    for _ in 0..5 {
+        for _ in 0..5 {
+            assert!(queue.push(next_in));
+            next_in += 1;
+        }
+
+        let mut drained = 0;
+        queue.drain(|event| {
+            assert_eq!(event, next_out);
+            next_out += 1;
+            drained += 1;
+        });
+        assert_eq!(drained, 5);
+    }
+
+    assert!(queue.is_empty());
+    assert_eq!(queue.dropped(), 0);
+}
+
+/// Enter sent as CR, LF or CRLF is queued as a single `\n`.
+fn serial_input_normalizes_enter() {
+    while SERIAL_INPUT.pop().is_some() {}
+
+    for &byte in b"a\r\nb\rc\n" {
+        serial::queue_input(byte);
+    }
+
+    let mut queued = [0u8; 6];
+    let mut len = 0;
+    while let Some(byte) = SERIAL_INPUT.pop() {
+        queued[len] = byte;
+        len += 1;
+    }
+
+    assert_eq!(&queued[..len], b"a\nb\nc\n");
+}
+
+/// A few KiB written to COM1 in one go all reach the UART, the FIFO being refilled as it drains.
+fn serial_bulk_output_drops_nothing() {
+    let dropped = serial::dropped_bytes();
+
+    let mut line = [b'.'; 64];
+    line[63] = b'\n';
+    {
+        let _guard = InterruptGuard::new();
+        let mut com1 = serial::COM1.lock();
+        for _ in 0..64 {
+            com1.write_bytes(&line);
+        }
+    }
+
+    assert_eq!(serial::dropped_bytes(), dropped);
+}
+
+/// In the 80x50 mode all 50 rows are used: text scrolls up through row 0 from row 49. Switching
+/// back gives 25 rows again.
+fn vga_80x50_scrolls_all_rows() {
+    use core::fmt::Write;
+    use device::graphics::console;
+
+    // With a framebuffer console there is no text mode to switch.
+    if console::is_active() {
+        assert!(vga::set_mode(vga::Mode::Text80x50).is_err());
+        return;
+    }
+
+    vga::set_mode(vga::Mode::Text80x50).expect("could not switch to 80x50");
+    assert_eq!(buffer::height(), 50);
+
+    {
+        let mut screen = buffer::SCREEN.lock();
+        for line in 0..60 {
+            write!(screen, "\nline {:02}", line).unwrap();
+        }
+
+        assert_eq!(&screen.chars()[49][..7], b"line 59");
+        assert_eq!(&screen.chars()[0][..7], b"line 10");
+    }
+
+    vga::set_mode(vga::Mode::Text80x25).expect("could not switch back to 80x25");
+    assert_eq!(buffer::height(), 25);
+}
+
+/// Times `deferred_decode` has run.
+static DEFERRED_RUNS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Stands in for a driver decoding what its interrupt handler read.
+fn deferred_decode() {
+    let mut state = 0usize;
+    for byte in 0..4096usize {
+        state = unsafe { ptr::read_volatile(&state) }.wrapping_mul(31).wrapping_add(byte);
+    }
+
+    assert!(state != 1);
+    DEFERRED_RUNS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Deferred work runs once each when the queue is run, and not before. A handler which
+/// defers its decoding returns sooner than one which decodes inline.
+fn deferred_work_runs_after_handler() {
+    DEFERRED_RUNS.store(0, Ordering::SeqCst);
+
+    // No timer tick may run the queue before we look at it.
+    let (inline, deferred) = disable_interrupts_and_then(|| {
+        let start = time::rdtsc();
+        deferred_decode();
+        let inline = time::rdtsc() - start;
+
+        let start = time::rdtsc();
+        assert!(deferred::defer(deferred_decode));
+        let deferred = time::rdtsc() - start;
+        assert!(deferred::defer(deferred_decode));
+
+        assert_eq!(DEFERRED_RUNS.load(Ordering::SeqCst), 1);
+        assert_eq!(deferred::run_pending(), 2);
+        assert_eq!(DEFERRED_RUNS.load(Ordering::SeqCst), 3);
+        assert_eq!(deferred::pending(), 0);
+
+        (inline, deferred)
+    });
+
+    println!(
+        "[ test ] Decoding inline took {} cycles, deferring it took {}.",
+        inline, deferred
+    );
+    assert!(deferred < inline, "deferring took {} cycles, inline {}", deferred, inline);
+}
+
+/// Usable frames leave out low memory and both reserved ranges, counting an overlap between them
+/// once, and a map whose only area above low memory holds the kernel has none at all.
+fn usable_frames_leave_out_reserved_memory() {
+    use arch::memory::area_frame_allocator::usable_frames;
+
+    const MIB: usize = 0x10_0000;
+    let kernel = (256, 300);
+
+    let areas = [(0, 640 * 1024), (MIB, 2 * MIB)];
+    assert_eq!(usable_frames(areas.iter().cloned(), kernel, (301, 301)), 512 - 45 - 1);
+    assert_eq!(usable_frames(areas.iter().cloned(), kernel, (290, 310)), 512 - 55);
+
+    let kernel_only = [(0, 640 * 1024), (MIB, 64 * 1024)];
+    assert_eq!(usable_frames(kernel_only.iter().cloned(), kernel, (301, 301)), 0);
+    assert_eq!(usable_frames([].iter().cloned(), kernel, (301, 301)), 0);
+}
+
+/// An EFI memory map tag, with 48-byte descriptors: conventional memory, a reserved area, an
+/// empty area and boot services data.
+static EFI_MEMORY_MAP: [u64; 25] = [
+    48 | 1 << 32,
+    7, 0x10_0000, 0, 0x100, 0xf, 0,
+    0, 0x20_0000, 0, 0x10, 0xf, 0,
+    7, 0x30_0000, 0, 0, 0xf, 0,
+    4, 0x40_0000, 0, 0x20, 0xf, 0,
+];
+
+/// EFI descriptors are found by the size the map gives, not the size of the fields we read, and
+/// only memory free after boot is usable.
+fn efi_memory_map_steps_by_descriptor_size() {
+    use arch::memory::memory_map::{EfiMemoryAreaIter, PhysicalArea};
+    use core::{mem, slice};
+
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            EFI_MEMORY_MAP.as_ptr() as *const u8,
+            mem::size_of_val(&EFI_MEMORY_MAP),
+        )
+    };
+
+    let mut areas = EfiMemoryAreaIter::new(bytes).expect("EFI memory map rejected");
+    assert_eq!(areas.next(), Some(PhysicalArea::new(0x10_0000, 0x100 * 4096)));
+    assert_eq!(areas.next(), Some(PhysicalArea::new(0x40_0000, 0x20 * 4096)));
+    assert_eq!(areas.next(), None);
+
+    // Too short to hold the descriptor size.
+    assert!(EfiMemoryAreaIter::new(&bytes[..4]).is_none());
+}
+
+/// Unmapping hands back the frame without freeing it, so it can be mapped again with its contents
+/// intact, or freed, in which case it goes to the frame pool.
+fn unmap_returns_the_mapped_frame() {
+    use arch::memory::frame_pool;
+
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE;
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    active_table.map(page, flags).flush(&mut active_table);
+    let phys = active_table.translate(page.start_address()).expect("page not mapped");
+    unsafe { ptr::write_volatile(SCRATCH_PAGE as *mut u64, 0x1234_5678) };
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    assert_eq!(frame.start_address(), phys);
+    assert!(active_table.translate(page.start_address()).is_none());
+
+    // Unmapped without freeing, so the contents are still there.
+    active_table.map_to(page, frame, flags).flush(&mut active_table);
+    assert_eq!(unsafe { ptr::read_volatile(SCRATCH_PAGE as *const u64) }, 0x1234_5678);
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+
+    // The refill task must not run in between.
+    disable_interrupts_and_then(|| {
+        // Make room, in case the pool is full.
+        let spare = frame_pool::take();
+        let available = frame_pool::available();
+
+        memory::deallocate_frame(frame);
+        assert_eq!(frame_pool::available(), available + 1);
+
+        if let Some(spare) = spare {
+            memory::deallocate_frame(spare);
+        }
+    });
+}
+
+/// Workers of `tls_slots_are_per_task` which found their slot zeroed at start and still holding
+/// their own value after the others ran.
+static TLS_GOOD_WORKERS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn tls_worker() {
+    let mine = 0x7150_0000 + SCHEDULER.get_id().inner();
+    let fresh = syscall::tls_get(1) == Ok(0);
+    syscall::tls_set(1, mine).expect("cannot set TLS slot");
+
+    // Let the other workers set their own slot.
+    disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+
+    if fresh && syscall::tls_get(1) == Ok(mine) {
+        TLS_GOOD_WORKERS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Each task sees only its own task-local storage, which starts zeroed, and slots past the end
+/// are refused.
+fn tls_slots_are_per_task() {
+    use task::TLS_SLOTS;
+
+    TLS_GOOD_WORKERS.store(0, Ordering::SeqCst);
+    let ours = syscall::tls_get(1).expect("cannot read TLS slot");
+
+    let workers = [
+        syscall::create(tls_worker, String::from("tls_worker_0")),
+        syscall::create(tls_worker, String::from("tls_worker_1")),
+    ];
+    for &worker in workers.iter() {
+        assert_eq!(syscall::join(worker), Ok(ExitCode::SUCCESS));
+    }
+
+    assert_eq!(TLS_GOOD_WORKERS.load(Ordering::SeqCst), 2);
+    assert_eq!(syscall::tls_get(1), Ok(ours));
+    assert!(syscall::tls_get(TLS_SLOTS).is_err());
+    assert!(syscall::tls_set(TLS_SLOTS, 1).is_err());
+}
+
+/// Working directory `cwd_worker` started in.
+static CWD_WORKER_START: Mutex<Option<PathName>> = Mutex::new(None);
+
+extern "C" fn cwd_worker() {
+    *CWD_WORKER_START.lock() = Some(cwd::cwd());
+    cwd::chdir("/elsewhere").expect("cannot change directory");
+}
+
+/// Relative paths resolve against the working directory, with `.` and `..` normalised away. A new
+/// task starts in its creator's directory, and changing it does not affect anyone else.
+fn paths_resolve_against_cwd() {
+    use task::cwd::PATH_MAX;
+
+    let root = PathName::root();
+    let foo = root.join("/foo").unwrap();
+    assert_eq!(foo.as_str(), "/foo");
+    assert_eq!(foo.join("bar").unwrap().as_str(), "/foo/bar");
+    assert_eq!(foo.join("./bar//baz/").unwrap().as_str(), "/foo/bar/baz");
+    assert_eq!(foo.join("bar/../..").unwrap().as_str(), "/");
+    assert_eq!(foo.join("../../..").unwrap().as_str(), "/");
+    assert_eq!(foo.join("/qux/.").unwrap().as_str(), "/qux");
+
+    let mut long = String::new();
+    while long.len() < PATH_MAX {
+        long.push_str("/0123456789");
+    }
+    assert!(root.join(&long).is_err());
+
+    let before = cwd::cwd();
+    cwd::chdir("/foo").unwrap();
+    assert_eq!(cwd::resolve("bar").unwrap(), foo.join("bar").unwrap());
+
+    let worker = syscall::create(cwd_worker, String::from("cwd_worker"));
+    assert_eq!(syscall::join(worker), Ok(ExitCode::SUCCESS));
+    assert_eq!(*CWD_WORKER_START.lock(), Some(foo));
+    assert_eq!(cwd::cwd(), foo);
+
+    assert!(cwd::chdir(&long).is_err());
+    assert_eq!(cwd::cwd(), foo);
+
+    cwd::chdir(before.as_str()).unwrap();
+}
+
+/// Tests only run under QEMU, which is detected, and hypervisors are told apart by their vendor
+/// string.
+fn qemu_is_detected() {
+    use arch::platform::{self, Hypervisor};
+
+    assert!(platform::is_qemu());
+
+    assert_eq!(Hypervisor::from_vendor(*b"TCGTCGTCGTCG"), Hypervisor::Tcg);
+    assert_eq!(Hypervisor::from_vendor(*b"KVMKVMKVM\0\0\0"), Hypervisor::Kvm);
+    assert_eq!(
+        Hypervisor::from_vendor(*b"VMwareVMware"),
+        Hypervisor::Other(*b"VMwareVMware")
+    );
+}
+
+lazy_static! {
+    /// PIDs of the `policy_worker`s, in the order they ran.
+    static ref POLICY_ORDER: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
+
+extern "C" fn policy_worker() {
+    POLICY_ORDER.lock().push(SCHEDULER.get_id().inner());
+}
+
+/// With the LIFO policy, the process queued last runs first, where round-robin would run it last.
+fn lifo_policy_runs_newest_first() {
+    use task::policy::{self, SchedulerPolicy};
+
+    POLICY_ORDER.lock().clear();
+    assert!(policy::by_name("lifo").is_some());
+    assert!(policy::by_name("fastest").is_none());
+    let previous = SCHEDULER.policy();
+    SCHEDULER.set_policy(&policy::LIFO);
+
+    // No worker may run before they are all queued.
+    let workers = disable_interrupts_and_then(|| {
+        let workers = [
+            syscall::create(policy_worker, String::from("policy_worker_0")),
+            syscall::create(policy_worker, String::from("policy_worker_1")),
+            syscall::create(policy_worker, String::from("policy_worker_2")),
+        ];
+        unsafe { SCHEDULER.resched() };
+        workers
+    });
+
+    for &worker in workers.iter() {
+        assert_eq!(syscall::join(worker), Ok(ExitCode::SUCCESS));
+    }
+    SCHEDULER.set_policy(previous);
+
+    let newest_first: Vec<usize> = workers.iter().rev().map(|worker| worker.inner()).collect();
+    assert_eq!(*POLICY_ORDER.lock(), newest_first);
+    assert_eq!(SCHEDULER.policy().name(), previous.name());
+}
+
+/// Spin with interrupts disabled, where no timer tick can sample.
+#[inline(never)]
+fn spin_with_interrupts_disabled() {
+    let mut count = 0usize;
+    while count < 20_000_000 {
+        unsafe { ptr::write_volatile(&mut count, count + 1) };
+    }
+}
+
+/// NMI samples are taken inside a `cli` section, and land in the bucket of the code running there.
+/// Without performance counters, as under TCG, there is nothing to test.
+fn nmi_profiler_samples_cli_sections() {
+    use arch::profiler;
+
+    let was_enabled = profiler::profiling_enabled();
+    let spin = spin_with_interrupts_disabled as usize;
+
+    // Interrupts stay off until sampling stops, so we cannot move to a CPU with no counter set up.
+    let started = disable_interrupts_and_then(|| {
+        if profiler::start_nmi_sampling().is_err() {
+            return false;
+        }
+
+        let before = profiler::samples_at(spin);
+        spin_with_interrupts_disabled();
+        profiler::stop_nmi_sampling();
+
+        assert!(profiler::samples_at(spin) > before);
+        true
+    });
+
+    profiler::set_enabled(was_enabled);
+    if !started {
+        println!("[ test ] No performance counters, skipping.");
+    }
+}
+
+/// `emergency_print` reaches the screen while every `print!` lock is held, as they may be when a
+/// panic or NMI interrupts printing. Panicking here for real would leave the locks held for the
+/// rest of the run.
+fn emergency_print_ignores_held_locks() {
+    use device;
+    use device::vga::vga::VGA;
+
+    disable_interrupts_and_then(|| {
+        let _screen = buffer::SCREEN.lock();
+        let _com1 = serial::COM1.lock();
+        let _vga = VGA.lock();
+
+        device::emergency_print("\nEMERGENCY");
+
+        let bottom_row = VGA_BUFFER + (buffer::height() - 1) * 80 * 2;
+        for (i, &byte) in b"EMERGENCY".iter().enumerate() {
+            let cell = unsafe { ptr::read_volatile((bottom_row + i * 2) as *const u8) };
+            assert_eq!(cell, byte);
+        }
+    });
+}
+
+/// The check made when the kernel's page table was switched to found every section mapped as it
+/// asks, and nothing writable and executable. The kernel's code is still read-only now.
+fn kernel_code_is_never_writable() {
+    use arch::memory::paging::permissions;
+
+    extern "C" {
+        static __text_start: u8;
+        static __text_end: u8;
+    }
+
+    let report = permissions::boot_report().expect("kernel permissions were never checked");
+    assert_eq!(report.wrong, 0);
+    assert_eq!(report.writable_executable, 0);
+
+    let (start, end) = unsafe {
+        (
+            &__text_start as *const u8 as usize,
+            &__text_end as *const u8 as usize,
+        )
+    };
+    let active_table = unsafe { ActivePageTable::new() };
+
+    for page in Page::range_inclusive(
+        Page::containing_address(VirtualAddress::new(start)),
+        Page::containing_address(VirtualAddress::new(end - 1)),
+    ) {
+        let flags = active_table.page_flags(page).expect("kernel code not mapped");
+        assert!(!flags.contains(EntryFlags::WRITABLE));
+        assert!(!flags.contains(EntryFlags::NO_EXECUTE));
+    }
+}
+
+/// Software interrupts are counted on their own vector, and whichever timer drives the scheduler
+/// keeps counting ticks.
+fn interrupts_are_counted_per_vector() {
+    use arch::interrupts::stats;
+
+    let timer_ticks = || stats::count_of(0x20) + stats::count_of(0x30)
+        + stats::count_of(apic::TIMER_VECTOR);
+
+    let (unhandled, breakpoints) = (stats::count_of(0x70), stats::count_of(3));
+    unsafe {
+        asm!("int 0x70" : : : "memory" : "intel", "volatile");
+        asm!("int3" : : : "memory" : "intel", "volatile");
+    }
+    assert_eq!(stats::count_of(0x70), unhandled + 1);
+    assert_eq!(stats::count_of(3), breakpoints + 1);
+
+    let ticks = timer_ticks();
+    syscall::sleep(5).unwrap();
+    assert!(timer_ticks() >= ticks + 5);
+
+    assert_eq!(stats::vector_name(apic::TIMER_VECTOR), Some("APIC timer"));
+    assert_eq!(stats::vector_name(0x70), None);
+}
+
+/// Stands in for a kernel stack which is almost used up: the last `NEARLY_FULL_STACK_LEFT` bytes
+/// of the stack, above canaries for whatever lies below it.
+static mut NEARLY_FULL_STACK: [u64; 128] = [0; 128];
+const NEARLY_FULL_STACK_LEFT: usize = 64;
+const STACK_CANARY: u64 = 0xdead_beef_dead_beef;
+
+/// A page fault raised with almost no stack left runs on the page fault's own interrupt stack,
+/// leaving the stack it interrupted alone.
+fn page_fault_runs_on_its_own_stack() {
+    use arch::interrupts::{ist_stack, PAGE_FAULT_IST_INDEX};
+    use testing::fault::{expect_fault, take_fault};
+
+    let (bottom, top) = ist_stack(PAGE_FAULT_IST_INDEX).expect("no page fault stack");
+
+    let (canaries, stack_top) = unsafe {
+        let canaries = NEARLY_FULL_STACK.len() - NEARLY_FULL_STACK_LEFT / 8;
+        for word in NEARLY_FULL_STACK.iter_mut() {
+            *word = STACK_CANARY;
+        }
+        let left = &NEARLY_FULL_STACK[canaries] as *const u64 as usize;
+        (canaries, (left + NEARLY_FULL_STACK_LEFT) & !0xf)
+    };
+
+    // An interrupt would run on the borrowed stack and overflow it, so keep them off.
+    disable_interrupts_and_then(|| unsafe {
+        // `mov [rax], rcx` is encoded in 3 bytes: 48 89 08.
+        expect_fault(PAGE_FAULT_VECTOR, 3);
+        asm!("mov r12, rsp
+              mov rsp, rdx
+              mov [rax], rcx
+              mov rsp, r12"
+             : : "{rax}"(SCRATCH_PAGE), "{rcx}"(0u64), "{rdx}"(stack_top)
+             : "r12", "memory" : "intel", "volatile");
+    });
+
+    let fault = take_fault().expect("writing to an unmapped page did not fault");
+    assert!(
+        bottom <= fault.stack_pointer && fault.stack_pointer < top,
+        "handler ran at {:#x}, outside the page fault stack at {:#x}-{:#x}",
+        fault.stack_pointer,
+        bottom,
+        top
+    );
+
+    let overwritten = unsafe { NEARLY_FULL_STACK[..canaries].iter() }
+        .filter(|&&word| word != STACK_CANARY)
+        .count();
+    assert_eq!(overwritten, 0, "the page fault handler overflowed the interrupted stack");
+}
+
+/// Rounding to page and huge page boundaries leaves aligned addresses alone, and rounding up
+/// near the top of the address space fails rather than wrapping.
+fn alignment_at_page_and_huge_page_boundaries() {
+    use arch::memory::addr::*;
+
+    assert_eq!(align_down(0x1234, PAGE_SIZE), 0x1000);
+    assert_eq!(align_up(0x1234, PAGE_SIZE), Some(0x2000));
+    assert_eq!(align_down(0x2000, PAGE_SIZE), 0x2000);
+    assert_eq!(align_up(0x2000, PAGE_SIZE), Some(0x2000));
+    assert!(is_aligned(0x2000, PAGE_SIZE));
+    assert!(!is_aligned(0x2001, PAGE_SIZE));
+
+    assert_eq!(align_down(0x40_1000, HUGE_PAGE_SIZE), 0x40_0000);
+    assert_eq!(align_up(0x40_1000, HUGE_PAGE_SIZE), Some(0x60_0000));
+    assert_eq!(align_up(0x60_0000, HUGE_PAGE_SIZE), Some(0x60_0000));
+    assert!(is_aligned(0x60_0000, HUGE_PAGE_SIZE));
+    assert!(!is_aligned(0x60_1000, HUGE_PAGE_SIZE));
+
+    assert_eq!(align_up(0, PAGE_SIZE), Some(0));
+    assert_eq!(align_up(usize::MAX, PAGE_SIZE), None);
+    assert_eq!(align_up(usize::MAX - PAGE_SIZE, HUGE_PAGE_SIZE), None);
+    assert_eq!(page_align_up(usize::MAX - PAGE_SIZE + 1), Some(usize::MAX - PAGE_SIZE + 1));
+
+    assert_eq!(page_number(0x3fff), 3);
+    assert_eq!(page_offset(0x3fff), 0xfff);
+    assert_eq!(pages_for(1), Some(1));
+    assert_eq!(pages_for(PAGE_SIZE), Some(1));
+    assert_eq!(pages_for(PAGE_SIZE + 1), Some(2));
+    assert_eq!(pages_for(usize::MAX), None);
+}
+
+/// Frames in use outside the page fault handler's pool, which moving frames into the pool does
+/// not change.
+fn frames_in_use() -> usize {
+    use arch::memory::frame_pool;
+
+    memory::used_frames() - frame_pool::available()
+}
+
+/// Allocating from the heap maps nothing until the memory is touched, and then one frame for each
+/// page touched for the first time.
+fn heap_grows_lazily() {
+    use arch::memory::heap_allocator;
+
+    let active_table = unsafe { ActivePageTable::new() };
+
+    // Keep the refill task from moving frames into the pool half way through a count.
+    preempt_disable();
+    let mut untouched = 0;
+    for &size in [64 * 1024, 256 * 1024, 1024 * 1024].iter() {
+        let before = frames_in_use();
+        let mut buffer: Vec<u8> = Vec::with_capacity(size);
+
+        // The allocator only writes its bookkeeping next to the allocation.
+        assert!(frames_in_use() <= before + 2, "allocating {} bytes mapped it all", size);
+
+        let start = buffer.as_mut_ptr() as usize;
+        let pages: Vec<Page> = (0..4)
+            .map(|i| Page::containing_address(VirtualAddress::new(start + i * size / 4)))
+            .collect();
+        let fresh = pages
+            .iter()
+            .filter(|&&page| active_table.translate_page(page).is_none())
+            .count();
+        untouched += fresh;
+
+        let before = frames_in_use();
+        let mapped = heap_allocator::mapped_pages();
+        for &page in pages.iter() {
+            let offset = page.start_address().get().saturating_sub(start);
+            unsafe { ptr::write_volatile(buffer.as_mut_ptr().offset(offset as isize), 1) };
+        }
+        assert_eq!(frames_in_use(), before + fresh);
+        assert_eq!(heap_allocator::mapped_pages(), mapped + fresh);
+    }
+    preempt_enable();
+
+    assert!(untouched > 0, "every page was already mapped, so the heap did not grow");
+}
+
+/// The bitmap hands freed frames back lowest first, and refuses frames past its end.
+fn frame_bitmap_hands_back_lowest_first() {
+    use arch::memory::frame_bitmap::FrameBitmap;
+
+    let mut words = [0u64; 2];
+    let mut bitmap = FrameBitmap::new(&mut words);
+    let frame = |address| Frame::containing_address(PhysicalAddress::new(address));
+
+    assert!(bitmap.insert(frame(70 * PAGE_SIZE)).is_ok());
+    assert!(bitmap.insert(frame(3 * PAGE_SIZE)).is_ok());
+    assert!(bitmap.insert(frame(128 * PAGE_SIZE)).is_err());
+    assert_eq!(bitmap.len(), 2);
+    assert!(bitmap.contains(&frame(70 * PAGE_SIZE)));
+
+    assert_eq!(bitmap.take(), Some(frame(3 * PAGE_SIZE)));
+    assert_eq!(bitmap.take(), Some(frame(70 * PAGE_SIZE)));
+    assert_eq!(bitmap.take(), None);
+    assert!(bitmap.is_empty());
+}
+
+/// A frame given back to the frame allocator is handed out again, and stops counting as used
+/// until it is.
+fn freed_frames_are_reused() {
+    use arch::memory::{FrameAllocator, ALLOCATOR};
+
+    disable_interrupts_and_then(|| {
+        let mut allocator = ALLOCATOR.lock();
+        let allocator = match *allocator {
+            Some(ref mut allocator) => allocator,
+            None => panic!("no frame allocator"),
+        };
+
+        let frame = allocator.allocate_frame(1).expect("no frames");
+        let address = frame.start_address();
+        let used = allocator.used_frames();
+        let freed = allocator.freed_frames();
+
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.used_frames(), used - 1);
+        assert_eq!(allocator.freed_frames(), freed + 1);
+
+        let again = allocator.allocate_frame(1).expect("no frames");
+        assert_eq!(allocator.used_frames(), used);
+        assert_eq!(allocator.freed_frames(), freed);
+        if freed == 0 {
+            assert_eq!(again.start_address(), address);
+        }
+        allocator.deallocate_frame(again);
+    });
+}
+
+/// A huge page maps 2 MiB of contiguous frames with one entry, which translation sees through.
+fn huge_page_maps_two_mebibytes() {
+    use arch::memory::addr::HUGE_PAGE_SIZE;
+
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_HUGE_PAGE));
+    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    assert!(active_table.huge_page_is_free(page));
+    active_table.map_huge(page, flags).flush(&mut active_table);
+    assert!(!active_table.huge_page_is_free(page));
+
+    let start = active_table.translate(page.start_address()).expect("huge page not mapped");
+    assert_eq!(start.get() % HUGE_PAGE_SIZE, 0);
+
+    let offset = HUGE_PAGE_SIZE - PAGE_SIZE + 8;
+    let inside = VirtualAddress::new(SCRATCH_HUGE_PAGE + offset);
+    assert_eq!(active_table.translate(inside), Some(PhysicalAddress::new(start.get() + offset)));
+    let pointer = inside.get() as *mut u64;
+    unsafe { ptr::write_volatile(pointer, 0x2_0000_0000) };
+    assert_eq!(unsafe { ptr::read_volatile(pointer) }, 0x2_0000_0000);
+
+    let (result, frame) = active_table.unmap_huge(page);
+    result.flush(&mut active_table);
+    assert_eq!(frame.start_address(), start);
+    assert!(active_table.translate(inside).is_none());
+    assert!(active_table.huge_page_is_free(page));
+
+    let frames = HUGE_PAGE_SIZE / PAGE_SIZE;
+    for number in 0..frames {
+        let address = start.get() + number * PAGE_SIZE;
+        memory::deallocate_frame(Frame::containing_address(PhysicalAddress::new(address)));
+    }
+}
+
+/// Physical memory is reachable through the linear mapping, which agrees with the page tables on
+/// what each address maps to.
+fn physical_memory_is_linearly_mapped() {
+    use arch::memory::paging::{phys_to_virt, physmap, virt_to_phys, PHYSICAL_MEMORY_OFFSET};
+
+    let frame = memory::allocate_frames(1).expect("no frames");
+    let physical = frame.start_address();
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let mut active_table = unsafe { ActivePageTable::new() };
+    active_table.map_to(page, frame, EntryFlags::WRITABLE).flush(&mut active_table);
+
+    let linear = phys_to_virt(physical).expect("frame not in the linear mapping");
+    assert_eq!(linear.get(), PHYSICAL_MEMORY_OFFSET + physical.get());
+    assert_eq!(virt_to_phys(linear), Some(physical));
+    assert_eq!(virt_to_phys(page.start_address()), Some(physical));
+
+    // A write through one mapping shows through the other.
+    unsafe { ptr::write_volatile(linear.get() as *mut u64, 0x1ea7_0000) };
+    assert_eq!(unsafe { ptr::read_volatile(SCRATCH_PAGE as *const u64) }, 0x1ea7_0000);
+
+    // The VGA buffer sits in low memory, which is mapped too.
+    let vga = phys_to_virt(PhysicalAddress::new(VGA_BUFFER)).expect("VGA buffer not mapped");
+    assert_eq!(
+        unsafe { ptr::read_volatile(vga.get() as *const u16) },
+        unsafe { ptr::read_volatile(VGA_BUFFER as *const u16) }
+    );
+
+    // Device memory is uncached there, and the kernel image, which is identity mapped, read-only.
+    let vga_flags = active_table.page_flags(Page::containing_address(vga)).expect("not mapped");
+    assert!(vga_flags.contains(EntryFlags::NO_CACHE));
+    let code = physical_memory_is_linearly_mapped as usize;
+    let code = phys_to_virt(PhysicalAddress::new(code)).expect("kernel not in the linear mapping");
+    let code_flags = active_table.page_flags(Page::containing_address(code)).expect("not mapped");
+    assert!(!code_flags.contains(EntryFlags::WRITABLE));
+    assert!(code_flags.contains(EntryFlags::NO_EXECUTE));
+
+    assert!(physmap::mapped_end() >= 0x1_0000_0000);
+    assert!(phys_to_virt(PhysicalAddress::new(physmap::mapped_end())).is_none());
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    memory::deallocate_frame(frame);
+}
+
+/// Pages of a demand-paged region are only mapped, and zeroed, the first time they are touched.
+fn demand_paged_region_maps_on_first_touch() {
+    use arch::memory::demand;
+
+    let start = VirtualAddress::new(SCRATCH_DEMAND);
+    let size = 8 * PAGE_SIZE;
+    let page = |i: usize| {
+        Page::containing_address(VirtualAddress::new(SCRATCH_DEMAND + i * PAGE_SIZE))
+    };
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    demand::reserve(&mut active_table, start, size, flags).expect("could not reserve region");
+    assert!(demand::reserve(&mut active_table, page(4).start_address(), size, flags).is_err());
+    assert!(demand::is_reserved(SCRATCH_DEMAND + size - 1));
+    assert!(!demand::is_reserved(SCRATCH_DEMAND + size));
+    assert_eq!(demand::mapped_pages(start), Some(0));
+    assert!(active_table.translate_page(page(0)).is_none());
+
+    unsafe {
+        ptr::write_volatile(page(0).start_address().get() as *mut u64, 0xdead_beef);
+        ptr::write_volatile(page(5).start_address().get() as *mut u64, 0xfeed);
+        assert_eq!(ptr::read_volatile(page(0).start_address().get() as *const u64), 0xdead_beef);
+        assert_eq!(ptr::read_volatile(page(3).start_address().get() as *const u64), 0);
+    }
+    assert_eq!(demand::mapped_pages(start), Some(3));
+    assert!(active_table.translate_page(page(1)).is_none());
+
+    // Past the end of the region is not demand paged.
+    let fault = unsafe { probe_write(PAGE_FAULT_VECTOR, SCRATCH_DEMAND + size, 1) };
+    assert!(fault.is_err(), "a write past the region did not fault");
+
+    demand::release(&mut active_table, start).expect("could not release region");
+    assert_eq!(demand::mapped_pages(start), None);
+    assert!(active_table.translate_page(page(0)).is_none());
+    assert!(!demand::is_reserved(SCRATCH_DEMAND));
+}
+
+/// A shared frame is copied for the first page written to, and the last page left sharing it
+/// gets it back writable.
+fn copy_on_write_copies_on_first_write() {
+    use arch::memory::paging::cow;
+
+    let original = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let sharer = original + 1;
+    let read = |page: Page| unsafe { ptr::read_volatile(page.start_address().get() as *const u64) };
+    let write = |page: Page, value: u64| unsafe {
+        ptr::write_volatile(page.start_address().get() as *mut u64, value)
+    };
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    active_table
+        .map(original, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)
+        .flush(&mut active_table);
+    write(original, 0xc0de);
+
+    let (result, frame, flags) = cow::share(&mut active_table, original).expect("could not share");
+    result.flush(&mut active_table);
+    assert!(flags.contains(EntryFlags::COPY_ON_WRITE) && !flags.contains(EntryFlags::WRITABLE));
+    assert_eq!(cow::references(&frame), 2);
+    active_table.map_to(sharer, frame, flags).flush(&mut active_table);
+    assert_eq!(read(sharer), 0xc0de);
+
+    // The first write gets a copy, and leaves the other page alone.
+    write(sharer, 0xbeef);
+    assert_eq!(read(sharer), 0xbeef);
+    assert_eq!(read(original), 0xc0de);
+    let frame = active_table.translate_page(original).unwrap();
+    assert!(active_table.translate_page(sharer).unwrap() != frame);
+    assert_eq!(cow::references(&frame), 1);
+
+    // The frame has a single owner again, which just gets it back writable.
+    write(original, 0xf00d);
+    assert_eq!(active_table.translate_page(original), Some(frame));
+    let flags = active_table.page_flags(original).unwrap();
+    assert!(flags.contains(EntryFlags::WRITABLE) && !flags.contains(EntryFlags::COPY_ON_WRITE));
+
+    cow::unmap(&mut active_table, original).flush(&mut active_table);
+    cow::unmap(&mut active_table, sharer).flush(&mut active_table);
+}
+
+/// Areas never overlap, are found by any address inside them, and leave gaps `find_free` finds.
+fn address_space_tracks_areas() {
+    use arch::memory::address_space::{self, AddressSpace, Backing, VirtualMemoryArea};
+    use arch::memory::heap_allocator::HEAP_START;
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let area = |start: usize, pages: usize, name| {
+        VirtualMemoryArea::new(
+            VirtualAddress::new(start),
+            pages * PAGE_SIZE,
+            flags,
+            Backing::Anonymous,
+            name,
+        ).unwrap()
+    };
+    let mut space = AddressSpace::new();
+
+    space.insert(area(SCRATCH_PAGE, 2, "low")).unwrap();
+    space.insert(area(SCRATCH_PAGE + 4 * PAGE_SIZE, 1, "high")).unwrap();
+    assert!(space.insert(area(SCRATCH_PAGE + PAGE_SIZE, 2, "overlap")).is_err());
+    assert!(space.insert(area(SCRATCH_PAGE + 4 * PAGE_SIZE, 1, "same")).is_err());
+    assert_eq!(space.len(), 2);
+
+    assert_eq!(space.find(SCRATCH_PAGE).map(|a| a.name()), Some("low"));
+    assert_eq!(space.find(SCRATCH_PAGE + 2 * PAGE_SIZE - 1).map(|a| a.name()), Some("low"));
+    assert!(space.find(SCRATCH_PAGE + 2 * PAGE_SIZE).is_none());
+    assert!(space.find(SCRATCH_PAGE - 1).is_none());
+
+    // Two pages fit in the gap, three only go above the high area.
+    {
+        let free = |pages: usize| {
+            space
+                .find_free(pages * PAGE_SIZE, SCRATCH_PAGE, SCRATCH_PAGE + 16 * PAGE_SIZE)
+                .map(|address| address.get())
+        };
+        assert_eq!(free(2), Some(SCRATCH_PAGE + 2 * PAGE_SIZE));
+        assert_eq!(free(3), Some(SCRATCH_PAGE + 5 * PAGE_SIZE));
+        assert_eq!(free(12), None);
+    }
+
+    let removed = space.remove(VirtualAddress::new(SCRATCH_PAGE)).unwrap();
+    assert_eq!(removed.size(), 2 * PAGE_SIZE);
+    assert!(space.find(SCRATCH_PAGE).is_none());
+
+    let unaligned = VirtualAddress::new(SCRATCH_PAGE + 1);
+    assert!(VirtualMemoryArea::new(unaligned, PAGE_SIZE, flags, Backing::Anonymous, "").is_err());
+
+    // `memory::init` records the heap in the kernel's address space.
+    let heap = address_space::area_at(HEAP_START).expect("heap is not recorded");
+    assert_eq!(heap.name(), "heap");
+    assert_eq!(heap.backing(), Backing::Anonymous);
+}
+
+/// Small allocations are rounded up to a size class, aligned to it, and a freed object is the
+/// next one handed out.
+fn small_allocations_come_from_slabs() {
+    use alloc::boxed::Box;
+    use arch::memory::slab::{class_size, size_class, MAX_OBJECT_SIZE};
+
+    assert_eq!(size_class(1, 1), Some(0));
+    assert_eq!(size_class(16, 8), Some(0));
+    assert_eq!(size_class(17, 8), Some(1));
+    assert_eq!(size_class(40, 1), Some(2));
+    assert_eq!(size_class(8, 256), Some(4));
+    assert_eq!(size_class(MAX_OBJECT_SIZE, 8), Some(7));
+    assert_eq!(size_class(MAX_OBJECT_SIZE + 1, 8), None);
+    assert_eq!(class_size(2), 64);
+
+    // Interrupt handlers may allocate too, which would upset the counts.
+    disable_interrupts_and_then(|| {
+        let class = size_class(40, 1).unwrap();
+        let before = ::HEAP_ALLOCATOR.slab_stats(class);
+
+        let first = Box::new([1u8; 40]);
+        let second = Box::new([2u8; 40]);
+        let first_address = &*first as *const [u8; 40] as usize;
+        let second_address = &*second as *const [u8; 40] as usize;
+        assert_eq!(first_address % 64, 0);
+        assert_eq!(second_address % 64, 0);
+        assert!(first_address != second_address);
+        assert_eq!(::HEAP_ALLOCATOR.slab_stats(class).in_use, before.in_use + 2);
+
+        drop(second);
+        let third = Box::new([3u8; 40]);
+        assert_eq!(&*third as *const [u8; 40] as usize, second_address);
+        assert_eq!(first[0] + third[0], 4);
+
+        drop(first);
+        drop(third);
+        let after = ::HEAP_ALLOCATOR.slab_stats(class);
+        assert_eq!(after.in_use, before.in_use);
+        assert_eq!(after.allocations, before.allocations + 3);
+    });
+}
+
+/// An allocation bigger than the heap makes it grow, up to its limit.
+fn heap_grows_past_its_initial_size() {
+    use alloc::allocator::{Alloc, Layout};
+    use arch::memory::heap_allocator::{self, HEAP_SIZE};
+
+    // This cannot fit in the heap as it was at boot. Its pages are only mapped when touched.
+    let before = ::HEAP_ALLOCATOR.size();
+    let mut buffer: Vec<u8> = Vec::with_capacity(HEAP_SIZE);
+    assert!(::HEAP_ALLOCATOR.size() > before, "the heap did not grow");
+    unsafe { ptr::write_volatile(buffer.as_mut_ptr().offset(HEAP_SIZE as isize - 1), 1) };
+    drop(buffer);
+
+    // At its limit, the heap fails an allocation rather than grow.
+    let limit = heap_allocator::limit();
+    let size = ::HEAP_ALLOCATOR.size();
+    heap_allocator::set_limit(size);
+    let layout = Layout::from_size_align(size + PAGE_SIZE, PAGE_SIZE).unwrap();
+    let mut heap = &::HEAP_ALLOCATOR;
+    let result = unsafe { heap.alloc(layout) };
+    heap_allocator::set_limit(limit);
+
+    assert!(result.is_err(), "an allocation past the limit succeeded");
+    assert_eq!(::HEAP_ALLOCATOR.size(), size);
+}
+
+/// Set by `guarded_task` if there is a guard page below its stack.
+static TASK_STACK_GUARDED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn guarded_task() {
+    use arch::memory::addr::page_align_down;
+    use arch::memory::stack_allocator::is_guard_page;
+
+    // The stack is two pages, and this is on one of them.
+    let local = 0u8;
+    let page = page_align_down(&local as *const u8 as usize);
+    if is_guard_page(page - PAGE_SIZE) || is_guard_page(page - 2 * PAGE_SIZE) {
+        TASK_STACK_GUARDED.store(1, Ordering::SeqCst);
+    }
+}
+
+/// Every kernel stack, a task's included, has an unmapped guard page below it, and a fault there
+/// is reported as a stack overflow.
+fn kernel_stacks_have_guard_pages() {
+    use arch::interrupts::page_fault::{classify, PageFaultKind};
+    use arch::memory::stack_allocator;
+    use testing::fault::probe_read;
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let stack = stack_allocator::alloc_stack(&mut active_table, 2).expect("no stack left");
+    let guard = stack.bottom() - PAGE_SIZE;
+
+    assert!(stack_allocator::is_guard_page(guard));
+    assert!(stack_allocator::is_guard_page(stack.bottom() - 1));
+    assert!(!stack_allocator::is_guard_page(stack.bottom()));
+    assert!(!active_table.is_mapped(Page::containing_address(VirtualAddress::new(guard))));
+    assert!(unsafe { probe_read(PAGE_FAULT_VECTOR, guard) }.is_err());
+    assert_eq!(
+        classify(guard, PageFaultErrorCode::CAUSED_BY_WRITE),
+        PageFaultKind::StackOverflow
+    );
+    stack_allocator::free_stack(&mut active_table, stack);
+
+    let task = syscall::create(guarded_task, String::from("guarded_task"));
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+    assert_eq!(TASK_STACK_GUARDED.load(Ordering::SeqCst), 1, "task stack has no guard page");
+}
+
+extern "C" fn short_lived_task() {}
+
+/// A freed stack is unmapped, and its pages handed out again for the next stack of its size. The
+/// stacks of tasks which have exited are freed too, so creating tasks does not use the range up.
+fn freed_stacks_are_reused() {
+    use arch::memory::{frame_pool, stack_allocator, used_frames};
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let stack = stack_allocator::alloc_stack(&mut active_table, 3).expect("no stack left");
+    let (bottom, top) = (stack.bottom(), stack.top());
+    unsafe { *(bottom as *mut u64) = 0x5717 };
+    // Frames in the page fault handler's pool are counted as used, but are not held by anyone.
+    let held = || used_frames() - frame_pool::available();
+    let frames = held();
+
+    stack_allocator::free_stack(&mut active_table, stack);
+    assert!(!active_table.is_mapped(Page::containing_address(VirtualAddress::new(bottom))));
+    assert!(held() < frames, "the stack's frames were not freed");
+    assert!(stack_allocator::is_guard_page(bottom - PAGE_SIZE));
+
+    let stack = stack_allocator::alloc_stack(&mut active_table, 3).expect("no stack left");
+    assert_eq!((stack.bottom(), stack.top()), (bottom, top));
+    stack_allocator::free_stack(&mut active_table, stack);
+
+    let task = syscall::create(short_lived_task, String::from("short_lived_task"));
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+    let left = stack_allocator::pages_left();
+    for _ in 0..32 {
+        let task = syscall::create(short_lived_task, String::from("short_lived_task"));
+        assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+    }
+    // Only the last stack to exit on each CPU may still be waiting to be freed.
+    let waiting = (percpu::online_cpus() + 1) * 3;
+    assert!(left - stack_allocator::pages_left() <= waiting, "task stacks are not reused");
+}
+
+/// A small batch of flushes invalidates its pages one at a time, and only a large one reloads
+/// `cr3`.
+fn batched_flushes_are_targeted() {
+    use arch::memory::paging::full_flushes;
+    use arch::memory::paging::mapper::{MapperFlush, MapperFlushAll};
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+
+    let before = full_flushes();
+    let mut batch = MapperFlushAll::new();
+    for offset in 0..4 {
+        batch.consume(MapperFlush::new(page + offset));
+    }
+    batch.flush(&mut active_table);
+    assert_eq!(full_flushes(), before, "four pages were flushed with the whole TLB");
+
+    let mut batch = MapperFlushAll::new();
+    for offset in 0..64 {
+        batch.consume(MapperFlush::new(page + offset));
+    }
+    batch.flush(&mut active_table);
+    assert!(full_flushes() > before, "64 pages were flushed one at a time");
+}
+
+/// A range shootdown sends each other CPU a single IPI, and flushes the whole TLB only for a large
+/// range.
+fn shootdowns_cover_ranges() {
+    use arch::interrupts::stats;
+    use arch::memory::paging::full_flushes;
+    use arch::memory::paging::tlb::{shootdown_range, SHOOTDOWN_VECTOR};
+
+    let first = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let others = percpu::online_cpus() as u64 - 1;
+
+    let (ipis, flushes) = (stats::count_of(SHOOTDOWN_VECTOR), full_flushes());
+    shootdown_range(first, first + 3);
+    assert_eq!(stats::count_of(SHOOTDOWN_VECTOR), ipis + others);
+    assert_eq!(full_flushes(), flushes, "four pages were flushed with the whole TLB");
+
+    let ipis = stats::count_of(SHOOTDOWN_VECTOR);
+    shootdown_range(first, first + 63);
+    assert_eq!(stats::count_of(SHOOTDOWN_VECTOR), ipis + others);
+    assert!(full_flushes() > flushes, "64 pages were flushed one at a time");
+}
+
+/// A range of pages is mapped to consecutive frames and unmapped again, across the boundary of two
+/// P1 tables.
+fn ranges_map_and_unmap_in_windows() {
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let frame = memory::allocate_frames(4).expect("no frames left");
+    let physical = frame.start_address().get();
+    let first = Page::containing_address(VirtualAddress::new(SCRATCH_RANGE)) + 510;
+    let pages = Page::range_inclusive(first, first + 3);
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    active_table
+        .map_range(pages, frame, flags)
+        .flush(&mut active_table);
+    for (index, page) in pages.enumerate() {
+        let translated = active_table.translate(page.start_address());
+        assert_eq!(translated.map(|a| a.get()), Some(physical + index * PAGE_SIZE));
+    }
+
+    let mut unmapped = 0;
+    active_table.unmap_range(pages, |page, frame| {
+        let offset = page.start_address().get() - first.start_address().get();
+        assert_eq!(frame.start_address().get(), physical + offset);
+        memory::deallocate_frame(frame);
+        unmapped += 1;
+    });
+    assert_eq!(unmapped, 4);
+    for page in pages {
+        assert!(!active_table.is_mapped(page));
+    }
+}
+
+/// Changing a mapping's flags keeps its frame, and takes effect at once.
+fn mappings_can_be_made_read_only() {
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let writable = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    active_table.map(page, writable).flush(&mut active_table);
+    let (frame, flags) = active_table.translate_with_flags(page).expect("page not mapped");
+    assert!(flags.contains(EntryFlags::PRESENT | writable));
+    let physical = frame.start_address();
+
+    active_table
+        .change_flags(page, EntryFlags::NO_EXECUTE)
+        .expect("flags not changed")
+        .flush(&mut active_table);
+    let (frame, flags) = active_table.translate_with_flags(page).expect("page not mapped");
+    assert_eq!(frame.start_address(), physical);
+    assert!(!flags.contains(EntryFlags::WRITABLE));
+    assert!(unsafe { probe_write(PAGE_FAULT_VECTOR, SCRATCH_PAGE, 1) }.is_err());
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    memory::deallocate_frame(frame);
+    assert!(active_table.change_flags(page, writable).is_err());
+}
+
+/// Unmapping the last page a page table maps frees the table, and any table above it left empty.
+fn empty_page_tables_are_freed() {
+    use arch::memory::{frame_pool, used_frames};
+
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_TABLES));
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let mut active_table = unsafe { ActivePageTable::new() };
+    // Frames in the page fault handler's pool are counted as used, but are not held by anyone.
+    let held = || used_frames() - frame_pool::available();
+
+    disable_interrupts_and_then(|| {
+        let before = held();
+        active_table.map(page, flags).flush(&mut active_table);
+        // The page's frame, and at least its P1 table.
+        assert!(held() >= before + 2);
+
+        let (result, frame) = active_table.unmap(page);
+        result.flush(&mut active_table);
+        memory::deallocate_frame(frame);
+        assert_eq!(held(), before, "the page tables were not freed");
+    });
+
+    // The tables are created afresh for the next mapping.
+    active_table.map(page, flags).flush(&mut active_table);
+    unsafe { ptr::write_volatile(SCRATCH_TABLES as *mut u64, 0xdead_beef) };
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    memory::deallocate_frame(frame);
+}
+
+/// An audit finds a writable and executable mapping, unless its range has been allowed to be.
+fn writable_executable_mappings_need_an_exception() {
+    use arch::memory::paging::permissions;
+
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let (violations, allowed) = permissions::audit_writable_executable(&active_table);
+
+    active_table
+        .map(page, EntryFlags::WRITABLE)
+        .flush(&mut active_table);
+    assert_eq!(
+        permissions::audit_writable_executable(&active_table),
+        (violations + 1, allowed)
+    );
+
+    permissions::allow_writable_executable(page.start_address(), PAGE_SIZE, "a test")
+        .expect("exception not added");
+    assert_eq!(permissions::writable_executable_reason(page), Some("a test"));
+    assert_eq!(
+        permissions::audit_writable_executable(&active_table),
+        (violations, allowed + 1)
+    );
+
+    assert!(permissions::disallow_writable_executable(page.start_address()));
+    assert_eq!(permissions::writable_executable_reason(page), None);
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    memory::deallocate_frame(frame);
+}
+
+/// The user copy helpers move data to and from a user accessible page, and refuse any buffer which
+/// is not all user memory mapped with the access they need. Under SMAP, the kernel cannot touch
+/// the page directly.
+fn user_copies_check_their_buffers() {
+    use arch::user_access::{copy_from_user, copy_to_user, smap_enabled, USER_END};
+    use testing::fault::probe_read;
+
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let flags = EntryFlags::USER_ACCESSIBLE | EntryFlags::NO_EXECUTE;
+    let mut active_table = unsafe { ActivePageTable::new() };
+    active_table
+        .map(page, flags | EntryFlags::WRITABLE)
+        .flush(&mut active_table);
+
+    let mut buffer = [0u8; 6];
+    copy_to_user(SCRATCH_PAGE + 8, b"lambda").expect("copy to a user page failed");
+    copy_from_user(&mut buffer, SCRATCH_PAGE + 8).expect("copy from a user page failed");
+    assert_eq!(&buffer, b"lambda");
+
+    // The buffer runs onto the next page, which is not mapped.
+    assert!(copy_from_user(&mut buffer, SCRATCH_PAGE + PAGE_SIZE - 2).is_err());
+    assert!(copy_from_user(&mut buffer, VGA_BUFFER).is_err());
+    assert!(copy_to_user(USER_END - 2, b"lambda").is_err());
+
+    // Touching the page directly faults both ways, and the write is not mistaken for a stale
+    // translation of a page some other CPU made writable.
+    if smap_enabled() {
+        assert!(unsafe { probe_read(PAGE_FAULT_VECTOR, SCRATCH_PAGE) }.is_err());
+        assert!(unsafe { probe_write(PAGE_FAULT_VECTOR, SCRATCH_PAGE, 1) }.is_err());
+    }
+
+    active_table
+        .change_flags(page, flags)
+        .expect("flags not changed")
+        .flush(&mut active_table);
+    assert!(copy_to_user(SCRATCH_PAGE, b"lambda").is_err());
+    assert!(copy_from_user(&mut buffer, SCRATCH_PAGE + 8).is_ok());
+
+    let (result, frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    memory::deallocate_frame(frame);
+}
+
+/// Releasing ranked locks in another order than they were taken leaves the lock counts as they
+/// were, rather than restoring a stale snapshot. The counts are only kept in debug builds.
+fn lock_counts_survive_out_of_order_release() {
+    use sync::{LockRank, RankedMutex};
+
+    static OUTER: RankedMutex<()> = RankedMutex::new(LockRank::Pics, ());
+    static INNER: RankedMutex<()> = RankedMutex::new(LockRank::Screen, ());
+
+    let before = percpu::held_locks();
+    let outer = OUTER.lock();
+    let inner = INNER.lock();
+    drop(outer);
+    if cfg!(debug_assertions) {
+        assert!(percpu::held_locks() != before);
+    }
+    drop(inner);
+
+    assert_eq!(percpu::held_locks(), before);
+}
+
+/// Number of times `count_args` has run.
+static SHELL_TEST_CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn count_args(args: &[&str]) -> i32 {
+    assert_eq!(args, &["a", "b"]);
+    SHELL_TEST_CALLS.fetch_add(1, Ordering::SeqCst);
+    7
+}
+
+/// A command registered from outside the shell is run with its arguments split on any amount of
+/// whitespace, both directly and through the serial command path, and its exit code is returned.
+fn shell_dispatches_registered_command() {
+    shell::register("test-args", "Test command.", count_args).expect("could not register");
+    assert!(shell::register("test-args", "Test command.", count_args).is_err());
+
+    assert_eq!(shell::run("  test-args   a \t b "), Some(7));
+    assert_eq!(shell::run("no-such-command"), None);
+    assert_eq!(shell::run("   "), None);
+    assert_eq!(SHELL_TEST_CALLS.load(Ordering::SeqCst), 1);
+
+    serial_command::receive(DLE);
+    for &byte in b"test-args a  b\n" {
+        serial_command::receive(byte);
+    }
+    assert_eq!(SHELL_TEST_CALLS.load(Ordering::SeqCst), 2);
+}
+
+/// Read-only kernel sections are mapped without `WRITABLE`, and `CR0.WP` makes that apply to the
+/// kernel too.
+fn write_to_rodata_faults() {
+    static READ_ONLY: u64 = 0;
+
+    unsafe { ptr::write_volatile(&READ_ONLY as *const u64 as *mut u64, 1) };
+}
+
+/// A probed write to a read-only page is caught as a protection violation caused by a write, and
+/// the test continues afterwards.
+fn probe_write_to_rodata_recovers() {
+    static READ_ONLY: u64 = 0;
+
+    let address = &READ_ONLY as *const u64 as usize;
+    let fault = unsafe { probe_write(PAGE_FAULT_VECTOR, address, 1) }
+        .expect_err("write to a read-only page did not fault");
+
+    // Present (bit 0) and caused by a write (bit 1).
+    assert_eq!(fault.error_code, Some(0b11));
+    assert_eq!(unsafe { ptr::read_volatile(&READ_ONLY) }, 0);
+}
+
+/// Reading an MSR which does not exist returns the GPF instead of halting, and a probe which does
+/// not fault returns the closure's value.
+fn probe_catches_invalid_msr() {
+    let fault = Msr(0xdead_0000).try_read().expect_err("reserved MSR was readable");
+    assert_eq!(fault.vector, GPF_VECTOR);
+    assert_eq!(fault.error_code, Some(0));
+
+    assert_eq!(probe(&[GPF_VECTOR], || 42), Ok(42));
+    assert_eq!(percpu::probe_catcher(), 0);
+}
+
+/// A fault which the inner probe does not catch unwinds to the outer probe which does, and the
+/// outer probe leaves the catcher and preemption state as it found them.
+fn nested_probe_unwinds_to_catching_probe() {
+    let preempt_count = percpu::preempt_count();
+
+    let outer = probe(&[GPF_VECTOR], || {
+        let inner = probe(&[INVALID_OPCODE_VECTOR], || unsafe { Msr(0xdead_0000).read() });
+        unreachable!("inner probe returned {:?}", inner);
+    });
+    assert_eq!(outer.map_err(|fault| fault.vector), Err(GPF_VECTOR));
+
+    let ud2 = probe(&[INVALID_OPCODE_VECTOR], || unsafe {
+        asm!("ud2" : : : : "intel", "volatile");
+    });
+    assert_eq!(ud2.map_err(|fault| fault.vector), Err(INVALID_OPCODE_VECTOR));
+
+    assert_eq!(percpu::probe_catcher(), 0);
+    assert_eq!(percpu::preempt_count(), preempt_count);
+}
+
+/// The VGA buffer can be read back through `peek`, and holds what has been printed.
+fn peek_vga_buffer() {
+    let screen = unsafe { peek(VirtualAddress::new(VGA_BUFFER), 80 * 25 * 2) }
+        .expect("the VGA buffer is not mapped");
+
+    // Boot messages have been printed, so some cell holds a character other than a space.
+    assert!(screen.chunks(2).any(|cell| cell[0] != b' ' && cell[0] != 0));
+}
+
+/// Poking the null page fails instead of faulting. Non-canonical addresses can't even be named.
+fn poke_unmapped_fails() {
+    assert!(unsafe { poke(VirtualAddress::new(0), &[1]) }.is_err());
+    assert!(VirtualAddress::try_new(0x0000_8000_0000_0000).is_none());
+}
+
+/// A read which starts in a mapped page and runs into an unmapped one fails as a whole.
+fn peek_across_unmapped_page_fails() {
+    let next = Page::containing_address(VirtualAddress::new(VGA_BUFFER)) + 1;
+    assert!(unsafe { Mapper::new() }.page_flags(next).is_none());
+
+    let last_bytes = VirtualAddress::new(next.start_address().get() - 8);
+    unsafe {
+        assert!(peek(last_bytes, 8).is_ok());
+        assert!(peek(last_bytes, 16).is_err());
+        assert!(poke(last_bytes, &[0; 16]).is_err());
+    }
+}
+
+/// A frame can be mapped at any page. The local APIC's ID register reads the same through an
+/// uncached mapping at a chosen page as through its identity mapping.
+fn map_apic_at_chosen_page() {
+    const APIC_ID: usize = 0x20;
+
+    let base = APIC_MANAGER.lock().as_ref().expect("no APIC").lapic_base as usize;
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+    let frame = Frame::containing_address(PhysicalAddress::new(base));
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    active_table.map_to(page, frame, EntryFlags::mmio()).flush(&mut active_table);
+
+    let id = unsafe { ptr::read_volatile((SCRATCH_PAGE + APIC_ID) as *const u32) };
+    let identity_id = unsafe { ptr::read_volatile((base + APIC_ID) as *const u32) };
+
+    let (result, _frame) = active_table.unmap(page);
+    result.flush(&mut active_table);
+    assert_eq!(id, identity_id);
+}
+
+/// The stored BSP APIC ID is what this CPU, the BSP, reads from its own local APIC, and agrees with
+/// the initial APIC ID CPUID reported at boot.
+fn bsp_apic_id_is_read_from_the_bsp() {
+    let lapic_id = APIC_MANAGER.lock().as_ref().expect("no APIC").lapic_id();
+
+    assert_eq!(apic::bsp_apic_id(), lapic_id);
+    assert_eq!(apic::bsp_apic_id() as usize, percpu::this_cpu().apic_id);
+}
+
+/// A DMA buffer's virtual address translates to the physical address it reports, and is unmapped
+/// again once it is dropped.
+fn dma_buffer_translates() {
+    const BELOW_4_GIB: usize = 0x1_0000_0000;
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let buffer = dma::alloc_dma(&mut active_table, PAGE_SIZE, BELOW_4_GIB).expect("no DMA buffer");
+    let virt = buffer.virt;
+
+    assert_eq!(buffer.len, PAGE_SIZE);
+    assert!(buffer.phys.get() + buffer.len <= BELOW_4_GIB);
+    assert_eq!(active_table.translate(virt), Some(buffer.phys));
+
+    unsafe {
+        ptr::write_volatile(buffer.as_mut_ptr(), 0xa5);
+        assert_eq!(ptr::read_volatile(buffer.as_mut_ptr()), 0xa5);
+    }
+
+    drop(buffer);
+    assert_eq!(active_table.translate(virt), None);
+}
+
+/// A scattered allocation returns exactly the frames asked for, all different, and one which cannot
+/// be satisfied allocates nothing. Memory is fragmented first, by freeing every other frame of a
+/// run, so that the frames have to be gathered from the gaps.
+fn scattered_frames_are_all_or_nothing() {
+    use arch::memory::{FrameAllocator, ALLOCATOR};
+
+    let frame_at = |run: &Frame, offset: usize| {
+        Frame::containing_address(PhysicalAddress::new(
+            run.start_address().get() + offset * PAGE_SIZE,
+        ))
+    };
+    let freed_frames = || {
+        let _guard = InterruptGuard::new();
+        ALLOCATOR.lock().as_ref().expect("no allocator").freed_frames()
+    };
+
+    // Straight back to the allocator, since `deallocate_frame` would fill the frame pool first.
+    let run = memory::allocate_frames(16).expect("no frames");
+    for offset in (0..16).filter(|offset| offset % 2 == 0) {
+        let _guard = InterruptGuard::new();
+        let mut allocator = ALLOCATOR.lock();
+        allocator
+            .as_mut()
+            .expect("no allocator")
+            .deallocate_frame(frame_at(&run, offset));
+    }
+
+    let used = memory::used_frames();
+    let freed = freed_frames();
+    let mut frames = memory::allocate_scattered_frames(8).expect("no frames");
+
+    assert_eq!(frames.len(), 8);
+    assert_eq!(memory::used_frames(), used + 8);
+    assert_eq!(freed_frames(), freed - 8);
+    frames.sort();
+    frames.dedup();
+    assert_eq!(frames.len(), 8);
+
+    assert!(memory::allocate_scattered_frames(usize::MAX / PAGE_SIZE).is_none());
+    assert_eq!(memory::used_frames(), used + 8);
+
+    for frame in frames {
+        memory::deallocate_frame(frame);
+    }
+    for offset in (0..16).filter(|offset| offset % 2 == 1) {
+        memory::deallocate_frame(frame_at(&run, offset));
+    }
+}
+
+/// General allocation never returns a frame below 1 MiB, and `allocate_low_frame` returns one
+/// which is neither the IVT frame nor above 1 MiB. A freed low frame is handed out again.
+fn low_memory_is_kept_apart() {
+    let low = memory::allocate_low_frame().expect("no low memory");
+    let general = memory::allocate_frames(1).expect("no frames");
+
+    assert!(low.start_address().get() >= PAGE_SIZE);
+    assert!(low.start_address().get() < LOW_MEMORY_END);
+    assert!(general.start_address().get() >= LOW_MEMORY_END);
+
+    let address = low.start_address().get();
+    memory::deallocate_low_frame(low);
+    let again = memory::allocate_low_frame().expect("freed low frame was lost");
+    assert_eq!(again.start_address().get(), address);
+
+    memory::deallocate_low_frame(again);
+    memory::deallocate_frame(general);
+}
+
+/// Walking the active table finds the VGA buffer and the code of this test, and never reports the
+/// recursive mapping of the page tables.
+fn walker_finds_vga_and_kernel() {
+    const RECURSIVE_REGION: usize = 0xffff_ff80_0000_0000;
+
+    let vga = Page::containing_address(VirtualAddress::new(0xb8000));
+    let code = Page::containing_address(VirtualAddress::new(walker_finds_vga_and_kernel as usize));
+    let covers = |page: Page, mapped: Page, flags: EntryFlags| {
+        let size = if flags.contains(EntryFlags::HUGE_PAGE) {
+            512 * PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let start = mapped.start_address().get();
+        page.start_address().get() >= start && page.start_address().get() < start + size
+    };
+
+    let active_table = unsafe { ActivePageTable::new() };
+    let (mut found_vga, mut found_code) = (false, false);
+
+    for (page, _, flags) in PageTableWalker::new(&active_table) {
+        assert!(page.start_address().get() < RECURSIVE_REGION);
+        found_vga |= covers(vga, page, flags);
+        found_code |= covers(code, page, flags);
+    }
+
+    assert!(found_vga, "VGA buffer not mapped");
+    assert!(found_code, "kernel code not mapped");
+}
+
+/// The VGA buffer is mapped and an arbitrary high page is not, and `is_mapped` agrees with
+/// `translate_page` on both.
+fn is_mapped_agrees_with_translate() {
+    let vga = Page::containing_address(VirtualAddress::new(0xb8000));
+    let unmapped = Page::containing_address(VirtualAddress::new(0x0000_7654_3210_0000));
+
+    let active_table = unsafe { ActivePageTable::new() };
+
+    assert!(active_table.is_mapped(vga));
+    assert!(!active_table.is_mapped(unmapped));
+    assert_eq!(active_table.is_mapped(vga), active_table.translate_page(vga).is_some());
+    assert_eq!(active_table.is_mapped(unmapped), active_table.translate_page(unmapped).is_some());
+}
+
+/// An address resolves to the greatest symbol at or below it, an address before the first symbol
+/// does not resolve, and data symbols are ignored. The linked in table names this test.
+fn symbols_resolve_to_preceding_symbol() {
+    let table = symbols::parse("0000000000101000 T first\n0000000000101400 D data\n\
+                                0000000000101800 t <a::B as c::D>::e\n");
+
+    assert_eq!(table.len(), 2);
+    assert_eq!(symbols::nearest(&table, 0x100fff), None);
+    assert_eq!(symbols::nearest(&table, 0x101000), Some(("first", 0)));
+    assert_eq!(symbols::nearest(&table, 0x10141c), Some(("first", 0x41c)));
+    assert_eq!(symbols::nearest(&table, 0x101810), Some(("<a::B as c::D>::e", 0x10)));
+
+    let address = symbols_resolve_to_preceding_symbol as usize;
+    if let Some((name, offset)) = symbols::resolve(address + 4) {
+        assert!(name.contains("symbols_resolve_to_preceding_symbol"), "resolved to {}", name);
+        assert_eq!(offset, 4);
+    }
+}
+
+/// Iterating up to the very last page yields it once and stops, instead of wrapping to page 0.
+fn page_iter_stops_at_last_page() {
+    let last = Page::containing_address(VirtualAddress::new(usize::MAX));
+    let before_last = Page::containing_address(VirtualAddress::new(usize::MAX - PAGE_SIZE));
+
+    assert_eq!(Page::range_inclusive(last, last).count(), 1);
+    assert_eq!(Page::range_inclusive(before_last, last).count(), 2);
+    assert_eq!(Page::range_inclusive(before_last, last).last(), Some(last));
+}
+
+/// `checked_add` refuses to overflow or to step into the non-canonical hole.
+fn page_checked_add_stays_canonical() {
+    let last = Page::containing_address(VirtualAddress::new(usize::MAX));
+    let last_lower = Page::containing_address(VirtualAddress::new(0x0000_7fff_ffff_ffff));
+
+    assert_eq!(last.checked_add(1), None);
+    assert_eq!(last.checked_add(usize::MAX), None);
+    assert_eq!(last_lower.checked_add(1), None);
+    assert_eq!(last_lower.checked_add(0), Some(last_lower));
+}
+
+fn page_add_past_last_page_panics() {
+    let last = Page::containing_address(VirtualAddress::new(usize::MAX));
+    let _ = last + 1;
+}
+
+/// Iterating up to the highest frame number stops after it.
+fn frame_iter_stops_at_last_frame() {
+    let last = || Frame::containing_address(PhysicalAddress::new(usize::MAX));
+    let before_last = Frame::containing_address(PhysicalAddress::new(usize::MAX - PAGE_SIZE));
+
+    assert_eq!(Frame::range_inclusive(last(), last()).count(), 1);
+    assert_eq!(Frame::range_inclusive(before_last, last()).count(), 2);
+}
+
+lazy_static! {
+    static ref TWO_SLOTS: Semaphore = Semaphore::new(2);
+}
+
+/// Workers currently holding `TWO_SLOTS`, and the most that ever held it at once.
+static INSIDE: AtomicUsize = ATOMIC_USIZE_INIT;
+static MOST_INSIDE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" fn semaphore_worker() {
+    TWO_SLOTS.acquire();
+
+    let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+    if inside > MOST_INSIDE.load(Ordering::SeqCst) {
+        MOST_INSIDE.store(inside, Ordering::SeqCst);
+    }
+
+    // Let the other workers try to get in while we hold the semaphore.
+    disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+
+    INSIDE.fetch_sub(1, Ordering::SeqCst);
+    TWO_SLOTS.release();
+}
+
+/// A semaphore with a count of two lets exactly two of four workers in at once.
+fn semaphore_admits_two_tasks() {
+    let workers = [
+        syscall::create(semaphore_worker, String::from("sem_worker_0")),
+        syscall::create(semaphore_worker, String::from("sem_worker_1")),
+        syscall::create(semaphore_worker, String::from("sem_worker_2")),
+        syscall::create(semaphore_worker, String::from("sem_worker_3")),
+    ];
+
+    for &worker in workers.iter() {
+        assert_eq!(syscall::join(worker), Ok(ExitCode::SUCCESS));
+    }
+
+    assert_eq!(MOST_INSIDE.load(Ordering::SeqCst), 2);
+    assert_eq!(INSIDE.load(Ordering::SeqCst), 0);
+    assert_eq!(TWO_SLOTS.available(), 2);
+}
+
+/// Depth and frame size of the recursion in `stack_user`.
+const RECURSION_DEPTH: usize = 16;
+const FRAME_BYTES: usize = 128;
+
+/// Stack usage `stack_user` measured for itself, since its stack is freed when it exits.
+static MEASURED_USAGE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Recurse `depth` times, using at least `FRAME_BYTES` of stack for each call.
+fn recurse(depth: usize) -> u8 {
+    let mut frame = [0u8; FRAME_BYTES];
+    unsafe { ptr::write_volatile(&mut frame[FRAME_BYTES - 1], depth as u8) };
+
+    if depth == 0 {
+        return 0;
+    }
+
+    recurse(depth - 1).wrapping_add(unsafe { ptr::read_volatile(&frame[FRAME_BYTES - 1]) })
+}
+
+extern "C" fn stack_user() {
+    recurse(RECURSION_DEPTH);
+
+    let usage = SCHEDULER.stack_usage(SCHEDULER.get_id()).unwrap_or(0);
+    MEASURED_USAGE.store(usage, Ordering::SeqCst);
+}
+
+/// The high-water mark of a task's stack covers a recursion of known depth.
+fn stack_usage_covers_recursion() {
+    let task = syscall::create(stack_user, String::from("stack_user"));
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+
+    let usage = MEASURED_USAGE.load(Ordering::SeqCst);
+    assert!(usage >= RECURSION_DEPTH * FRAME_BYTES, "usage {} too small", usage);
+    assert!(usage <= INITIAL_STACK * 8, "usage {} larger than the stack", usage);
+}
+
+/// The shared region `shared_memory_writer` maps, and the value it writes there.
+static SHARED_REGION: Mutex<Option<ShmHandle>> = Mutex::new(None);
+const SHARED_VALUE: u64 = 0x5eed_f00d;
+
+extern "C" fn shared_memory_writer() {
+    let handle = SHARED_REGION.lock().clone().expect("no shared region");
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE + PAGE_SIZE));
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mapping = shared::map_shared(&mut active_table, &handle, page, EntryFlags::WRITABLE)
+        .expect("cannot map shared region");
+
+    unsafe { ptr::write_volatile(mapping.as_mut_ptr() as *mut u64, SHARED_VALUE) };
+}
+
+/// A task's write to a shared region is seen through another mapping of it, and the frames are
+/// only freed once every mapping and handle is gone.
+fn shared_memory_outlives_creator_mapping() {
+    let live_before = shared::live_regions();
+    let handle = shared::create_shared(1).expect("no shared region");
+    let page = Page::containing_address(VirtualAddress::new(SCRATCH_PAGE));
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mapping = shared::map_shared(&mut active_table, &handle, page, EntryFlags::WRITABLE)
+        .expect("cannot map shared region");
+    *SHARED_REGION.lock() = Some(handle.clone());
+
+    let task = syscall::create(shared_memory_writer, String::from("shm_writer"));
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+
+    let value = unsafe { ptr::read_volatile(mapping.as_mut_ptr() as *const u64) };
+    assert_eq!(value, SHARED_VALUE);
+    assert_eq!(handle.mappings(), 1);
+
+    drop(mapping);
+    assert_eq!(handle.mappings(), 0);
+    assert_eq!(active_table.translate_page(page), None);
+
+    SHARED_REGION.lock().take();
+    assert_eq!(shared::live_regions(), live_before + 1);
+    drop(handle);
+    assert_eq!(shared::live_regions(), live_before);
+}
+
+/// A software interrupt on a vector with no handler is reported, and execution carries on.
+fn unhandled_interrupt_returns() {
+    use arch::interrupts::irq::UNHANDLED_INTERRUPTS;
+
+    let before = UNHANDLED_INTERRUPTS.load(Ordering::SeqCst);
+    unsafe { asm!("int 0x70" : : : "memory" : "intel", "volatile") };
+
+    assert_eq!(UNHANDLED_INTERRUPTS.load(Ordering::SeqCst), before + 1);
+}
+
+extern "x86-interrupt" fn test_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {}
+
+/// An IDT without a double fault handler is rejected, even if other vectors are assigned. The
+/// IDT is never loaded, so the exception entries are claimed without installing anything.
+fn idt_builder_requires_double_fault() {
+    let mut builder = IdtBuilder::new();
+    builder
+        .set(GPF_VECTOR, |_| ())
+        .and_then(|builder| builder.set(PAGE_FAULT_VECTOR, |_| ()))
+        .and_then(|builder| builder.interrupt(0x70, test_interrupt_handler))
+        .expect("could not assign vectors");
+
+    assert!(builder.finalize().is_err());
+}
+
+/// Claiming a vector which already has a handler is an error.
+fn idt_builder_rejects_double_assignment() {
+    let mut builder = IdtBuilder::new();
+
+    assert!(builder.interrupt(0x70, test_interrupt_handler).is_ok());
+    assert!(builder.interrupt(0x70, test_interrupt_handler).is_err());
+    assert!(builder.interrupt(0x71, test_interrupt_handler).is_ok());
+}
+
+/// A thousand timers with scattered deadlines, some past the end of the wheel, each expire on
+/// their own tick, and no tick does work on more than a fraction of them.
+fn timer_wheel_expires_in_deadline_order() {
+    const SLEEPS: u64 = 1000;
+    const LATEST: u64 = 6000;
+
+    let mut wheel = TimerWheel::new(SLEEPS as usize);
+    for i in 0..SLEEPS {
+        let deadline = (i * 4099) % LATEST + 1;
+        assert!(wheel.insert(deadline, deadline).is_ok());
+    }
+    assert_eq!(wheel.insert(1, 1), Err(1));
+
+    let mut expired = 0;
+    let mut most_work = 0;
+    while wheel.len() > 0 {
+        let work = wheel.advance();
+        if work > most_work {
+            most_work = work;
+        }
+
+        while let Some(deadline) = wheel.pop_expired() {
+            assert_eq!(deadline, wheel.now());
+            expired += 1;
+        }
+    }
+
+    assert_eq!(expired, SLEEPS);
+    assert!(wheel.now() <= LATEST);
+    assert!(most_work < SLEEPS as usize / 2, "a tick did {} units of work", most_work);
+}
+
+extern "C" fn pinned_worker() {
+    for _ in 0..4 {
+        disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+    }
+}
+
+/// A process pinned to a CPU is only ever switched to on that CPU, and pinning to a CPU which is
+/// not online fails. With a single CPU online, this pins to the BSP.
+fn pinned_task_runs_only_on_its_cpu() {
+    const CPU: u8 = 0;
+
+    let offline = percpu::online_cpus() as u8;
+    assert_eq!(
+        syscall::create_pinned(pinned_worker, String::from("pinned_offline"), offline),
+        Err(-1)
+    );
+
+    let task = syscall::create_pinned(pinned_worker, String::from("pinned"), CPU)
+        .expect("cannot pin to the BSP");
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+    assert_eq!(SCHEDULER.cpus_run_on(task), Ok(1 << CPU));
+}
+
+/// Stealing for another CPU skips a pinned process queued after an unpinned one, and a second
+/// attempt straight away is refused by the rate limit. The stolen process is handed back by
+/// clearing its affinity, since no other CPU runs here to pick it up.
+fn steal_takes_only_unpinned_tasks() {
+    const THIEF: usize = 1;
+
+    let (pinned, unpinned) = disable_interrupts_and_then(|| {
+        let unpinned = SCHEDULER
+            .create(pinned_worker, String::from("steal_unpinned"))
+            .expect("cannot create process");
+        SCHEDULER.ready(unpinned);
+
+        let pinned = syscall::create_pinned(pinned_worker, String::from("steal_pinned"), 0)
+            .expect("cannot pin to the BSP");
+
+        assert_eq!(SCHEDULER.steal_for(THIEF), Some(unpinned));
+        assert_eq!(SCHEDULER.steal_for(THIEF), None);
+        assert_eq!(SCHEDULER.set_affinity(unpinned, None), Ok(()));
+
+        (pinned, unpinned)
+    });
+
+    assert_eq!(syscall::join(pinned), Ok(ExitCode::SUCCESS));
+    assert_eq!(syscall::join(unpinned), Ok(ExitCode::SUCCESS));
+    assert_eq!(SCHEDULER.cpus_run_on(unpinned), Ok(1));
+}
+
+extern "C" fn yield_back() {}
+
+/// The TSC advances across a switch to another process and back, and the cost of the round trip
+/// converts to a plausible time.
+fn tsc_times_context_switch() {
+    let task = syscall::create(yield_back, String::from("yield_back"));
+
+    let start = time::rdtsc();
+    disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+    let cycles = time::rdtsc() - start;
+
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+    assert!(cycles > 0);
+
+    if let Some(ns) = time::tsc_to_ns(cycles) {
+        println!("[ test ] Switching away and back took {} cycles ({} ns).", cycles, ns);
+        assert!(ns < 1_000_000_000, "a context switch took {} ns", ns);
+    }
+}
+
+/// Switching to a new process and back is counted as two switches, of which only the switch back
+/// can be timed.
+fn scheduler_stats_count_switches() {
+    let before = SCHEDULER.stats();
+
+    let task = syscall::create(yield_back, String::from("yield_back"));
+    disable_interrupts_and_then(|| unsafe { SCHEDULER.resched() });
+    assert_eq!(syscall::join(task), Ok(ExitCode::SUCCESS));
+
+    let after = SCHEDULER.stats();
+    assert!(after.resched_calls > before.resched_calls);
+    assert!(after.switches >= before.switches + 2);
+    assert!(after.timed_switches > before.timed_switches);
+    assert!(after.average_switch_cycles().is_some());
+}
+
+/// With preemption disabled, timer ticks past the end of a time slice leave a resched pending
+/// instead of switching, and it runs when the outermost `preempt_enable` is reached.
+fn preempt_disable_defers_resched() {
+    let calls = SCHEDULER.stats().resched_calls;
+
+    preempt_disable();
+    preempt_disable();
+
+    // Long enough for two time slices to run out.
+    let start = sleep::ticks();
+    while sleep::ticks() < start + 25 {
+        spin_loop_hint();
+    }
+
+    assert_eq!(SCHEDULER.stats().resched_calls, calls);
+    assert!(percpu::resched_pending());
+
+    preempt_enable();
+    assert_eq!(SCHEDULER.stats().resched_calls, calls);
+    assert!(percpu::resched_pending());
+
+    preempt_enable();
+    assert!(!percpu::resched_pending());
+    assert!(SCHEDULER.stats().resched_calls > calls);
+}